@@ -0,0 +1,350 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::{timeout_at, Instant};
+
+use crate::util::*;
+use crate::key::Key;
+use crate::error::{HandshakeError, NewSessionError, InvalidKeyError};
+use crate::crypto::{Crypto, CipherSuite, Role};
+use crate::rekey::RekeyPolicy;
+use crate::message::{MessageType, Header};
+use crate::handshake::{Initiator, Responder};
+use crate::peer::{HANDSHAKE_ATTEMPTS, HANDSHAKE_RETRY_INTERVAL};
+
+/// An asynchronous UDP peer for tokio, the counterpart of [`Peer`](crate::Peer).
+///
+/// Uses the same encryption and wire format as [`Peer`](crate::Peer), so the
+/// two can talk to each other. All methods take `&self`, wrap the peer in an
+/// [`Arc`](std::sync::Arc) to send and receive from several tasks at once.
+///
+/// Requires the `tokio` feature.
+pub struct AsyncPeer {
+  socket: UdpSocket,
+  key: Key,
+  crypto: Crypto,
+  responder: Mutex<Responder>,
+}
+
+impl AsyncPeer {
+
+  /// Number of bytes added to every message sent without associated data or a connection ID
+  ///
+  /// Peers set up without a handshake always send a connection ID, which adds another 8 bytes.
+  pub const OVERHEAD: usize = Crypto::MINIMUM_BUFFER_LENGTH;
+
+  /// Creates a new peer with the given socket and encryption key, that can send right away.
+  ///
+  /// See [`Peer::new`](crate::Peer::new).
+  pub fn new(socket: UdpSocket, key: Key) -> Self {
+    Self::with_suite(socket, key, CipherSuite::for_key(&key))
+      .expect("key length always matches its default suite")
+  }
+
+  /// Creates a new peer with the given socket, encryption key and cipher suite.
+  ///
+  /// See [`Peer::with_suite`](crate::Peer::with_suite).
+  pub fn with_suite(socket: UdpSocket, key: Key, suite: CipherSuite) -> Result<Self, InvalidKeyError> {
+    let crypto = Crypto::with_static_key(key, suite, random_connection_id())?;
+    Ok(Self::with_crypto(socket, key, crypto))
+  }
+
+  /// Creates a new peer with the given socket, encryption key and role, that
+  /// has to [`handshake`](AsyncPeer::handshake) before sending.
+  ///
+  /// See [`Peer::with_handshake`](crate::Peer::with_handshake).
+  pub fn with_handshake(socket: UdpSocket, key: Key, role: Role) -> Self {
+    Self::with_handshake_and_suite(socket, key, role, CipherSuite::for_key(&key))
+      .expect("key length always matches its default suite")
+  }
+
+  /// Creates a new peer with the given socket, encryption key, role and cipher
+  /// suite, that has to [`handshake`](AsyncPeer::handshake) before sending.
+  ///
+  /// See [`Peer::with_handshake_and_suite`](crate::Peer::with_handshake_and_suite).
+  pub fn with_handshake_and_suite(socket: UdpSocket, key: Key, role: Role, suite: CipherSuite) -> Result<Self, InvalidKeyError> {
+    suite.check(&key)?;
+    Ok(Self::with_crypto(socket, key, Crypto::without_session(role, suite, RekeyPolicy::default())))
+  }
+
+  fn with_crypto(socket: UdpSocket, key: Key, crypto: Crypto) -> Self {
+    Self {
+      socket,
+      key,
+      crypto,
+      responder: Mutex::new(Responder::new()),
+    }
+  }
+
+  /// Creates a new peer, binds to `bind_addr`, and connects to `connect_addr`.
+  ///
+  /// Use `"0.0.0.0:0"` or `"[::]:0"` for `connect_addr` to create an unconnected peer.
+  pub async fn setup<A1, A2>(bind_addr: A1, connect_addr: A2, key: Key) -> io::Result<Self>
+  where
+    A1: ToSocketAddrs,
+    A2: ToSocketAddrs,
+  {
+    let socket = UdpSocket::bind(bind_addr).await?;
+    let peer = Self::new(socket, key);
+    peer.connect(connect_addr).await?;
+    Ok(peer)
+  }
+
+  /// Creates a new peer like [`AsyncPeer::setup`], that has to [`handshake`](AsyncPeer::handshake)
+  /// before sending.
+  pub async fn setup_with_handshake<A1, A2>(bind_addr: A1, connect_addr: A2, key: Key, role: Role) -> io::Result<Self>
+  where
+    A1: ToSocketAddrs,
+    A2: ToSocketAddrs,
+  {
+    let socket = UdpSocket::bind(bind_addr).await?;
+    let peer = Self::with_handshake(socket, key, role);
+    peer.connect(connect_addr).await?;
+    Ok(peer)
+  }
+
+  /// Returns the role this peer was set up with, or `None` if it was set up without a handshake.
+  pub fn role(&self) -> Option<Role> {
+    self.crypto.role()
+  }
+
+  /// Returns the cipher suite messages are sealed with.
+  pub fn suite(&self) -> CipherSuite {
+    self.crypto.suite()
+  }
+
+  /// Returns the policy for ratcheting keys forward.
+  pub fn rekey_policy(&self) -> RekeyPolicy {
+    self.crypto.rekey_policy()
+  }
+
+  /// Sets the policy for ratcheting keys forward.
+  pub fn set_rekey_policy(&self, policy: RekeyPolicy) {
+    self.crypto.set_rekey_policy(policy)
+  }
+
+  /// Returns the connection ID sent with every message, if any.
+  pub fn connection_id(&self) -> Option<u64> {
+    self.crypto.connection_id()
+  }
+
+  /// Sets the connection ID sent with every message.
+  ///
+  /// See [`Peer::set_connection_id`](crate::Peer::set_connection_id).
+  pub fn set_connection_id(&self, connection_id: Option<u64>) {
+    self.crypto.set_connection_id(connection_id)
+  }
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    &self.socket
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.socket.local_addr().expect("couldn't get local address")
+  }
+
+  /// Returns the remote socket address if connected, otherwise `None`.
+  pub fn remote_addr_optional(&self) -> Option<SocketAddr> {
+    self.socket.peer_addr().ok().filter(|addr| !is_unspecified(*addr))
+  }
+
+  /// Returns the remote socket address, or an unspecified address if not connected.
+  pub fn remote_addr(&self) -> SocketAddr {
+    self.remote_addr_optional()
+      .unwrap_or_else(|| to_unspecified(self.local_addr()))
+  }
+
+  /// Connects to the specified remote address.
+  ///
+  /// Connecting to an unspecified address disconnects the peer.
+  pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    if !addrs.is_empty() && addrs.iter().all(|addr| is_unspecified(*addr)) {
+      self.disconnect().await
+    } else {
+      self.socket.connect(addrs.as_slice()).await
+    }
+  }
+
+  /// Disconnects from the current remote address.
+  pub async fn disconnect(&self) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return disconnect_socket(&self.socket);
+    #[cfg(not(target_os = "linux"))]
+    return self.socket.connect(to_unspecified(self.local_addr())).await;
+  }
+
+  /// Performs a handshake with the connected peer, starting a session with a fresh key.
+  ///
+  /// Only peers set up with [`AsyncPeer::with_handshake`] handshake, and nothing
+  /// can be sent before their first handshake, see [`Peer::handshake`](crate::Peer::handshake).
+  /// The responder waits for a handshake indefinitely, wrap the call in
+  /// [`tokio::time::timeout`] to give up.
+  pub async fn handshake(&self) -> io::Result<()> {
+    match self.role() {
+      Some(Role::Initiator) => self.initiate().await,
+      Some(Role::Responder) => self.await_initiation().await,
+      None => Err(io::Error::new(io::ErrorKind::Unsupported, "peer was set up without a handshake")),
+    }
+  }
+
+  async fn initiate(&self) -> io::Result<()> {
+    let initiator = Initiator::new(self.key, self.suite());
+    let mut buffer = [0u8; Responder::RESPONSE_SIZE + 1];
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+      self.socket.send(initiator.packet()).await?;
+      let deadline = Instant::now() + HANDSHAKE_RETRY_INTERVAL;
+      while let Ok(result) = timeout_at(deadline, self.socket.recv(&mut buffer)).await {
+        match result {
+          Ok(len) => {
+            if let Ok(key) = initiator.finish(self.key, &buffer[..len]) {
+              self.crypto.set_key(key)?;
+              return Ok(());
+            }
+          }
+          Err(e) if can_retry(&e) => break,
+          Err(e) => return Err(e),
+        }
+      }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, HandshakeError))
+  }
+
+  async fn await_initiation(&self) -> io::Result<()> {
+    let mut buffer = [0u8; Initiator::INITIATION_SIZE + 1];
+    loop {
+      let len = self.socket.recv(&mut buffer).await?;
+      if Header::message_type(&buffer[..len]) == Some(MessageType::HandshakeInitiation)
+        && self.answer_initiation(&buffer[..len]).await?
+      {
+        return Ok(());
+      }
+    }
+  }
+
+  /// Answers a handshake initiation, returning `true` if a new session was started.
+  ///
+  /// Only the responder of a handshake answers.
+  async fn answer_initiation(&self, packet: &[u8]) -> io::Result<bool> {
+    if self.role() != Some(Role::Responder) {
+      return Ok(false);
+    }
+    // the lock can't be held across the send, so copy the response out
+    let mut response = [0u8; Responder::RESPONSE_SIZE];
+    let key = {
+      let mut responder = self.responder.lock().unwrap_or_else(|e| e.into_inner());
+      let Ok((packet, key)) = responder.respond(self.key, self.suite(), packet) else {
+        return Ok(false);
+      };
+      response.copy_from_slice(packet);
+      key
+    };
+    self.socket.send(&response).await?;
+    match key {
+      Some(key) => {
+        self.crypto.set_key(key)?;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  /// Encrypts and sends the contents of the buffer to the connected peer.
+  ///
+  /// See [`Peer::send`](crate::Peer::send).
+  pub async fn send(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.crypto.encrypt(buffer)?;
+    self.socket.send(buffer).await?;
+    Ok(())
+  }
+
+  /// Encrypts and sends the contents of the buffer along with associated data.
+  ///
+  /// See [`Peer::send_with_aad`](crate::Peer::send_with_aad).
+  pub async fn send_with_aad(&self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.crypto.encrypt_with_aad(aad, buffer)?;
+    self.socket.send(buffer).await?;
+    Ok(())
+  }
+
+  /// Encrypts the message into `scratch` and sends it to the connected peer, without allocating.
+  ///
+  /// See [`Peer::send_slice`](crate::Peer::send_slice).
+  pub async fn send_slice(&self, message: &[u8], scratch: &mut [u8]) -> io::Result<()> {
+    let len = self.crypto.encrypt_into(&[], message, scratch)?;
+    self.socket.send(&scratch[..len]).await?;
+    Ok(())
+  }
+
+  /// Encrypts the message in the first `len` bytes of the buffer in-place and
+  /// sends it to the connected peer, without allocating.
+  ///
+  /// See [`Peer::send_in_place`](crate::Peer::send_in_place).
+  pub async fn send_in_place(&self, buffer: &mut [u8], len: usize) -> io::Result<()> {
+    let len = self.crypto.encrypt_slice(&[], buffer, len)?;
+    self.socket.send(&buffer[..len]).await?;
+    Ok(())
+  }
+
+  /// Receives and decrypts a message into the buffer.
+  ///
+  /// See [`Peer::recv`](crate::Peer::recv), except that the buffer is grown to
+  /// fit the largest possible datagram, since the other side may send any size.
+  pub async fn recv(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    reserve_datagram(buffer);
+    let len = self.recv_datagram(buffer).await?;
+    buffer.truncate(len);
+    self.crypto.decrypt(buffer)
+  }
+
+  /// Receives and decrypts a message into the buffer, along with its associated data.
+  ///
+  /// See [`Peer::recv_with_aad`](crate::Peer::recv_with_aad).
+  pub async fn recv_with_aad(&self, buffer: &mut Vec<u8>, aad: &mut Vec<u8>) -> io::Result<()> {
+    reserve_datagram(buffer);
+    let len = self.recv_datagram(buffer).await?;
+    buffer.truncate(len);
+    self.crypto.decrypt_with_aad(buffer, aad)
+  }
+
+  /// Receives and decrypts a message into the start of the buffer, without
+  /// allocating, and returns the message length.
+  ///
+  /// See [`Peer::recv_into`](crate::Peer::recv_into).
+  pub async fn recv_into(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    let len = self.recv_datagram(buffer).await?;
+    self.crypto.decrypt_slice(&mut buffer[..len])
+  }
+
+  /// Receives datagrams until a data message arrives, answering handshakes
+  /// and discarding keepalives in the meantime.
+  async fn recv_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+      let len = self.socket.recv(buffer).await?;
+      let datagram = &mut buffer[..len];
+      match Header::parse(datagram)?.message_type {
+        MessageType::Data => return Ok(len),
+        MessageType::Keepalive => {
+          self.crypto.decrypt_slice_as(MessageType::Keepalive, datagram)?;
+        }
+        MessageType::HandshakeInitiation => {
+          let replaced = self.crypto.has_session();
+          if self.answer_initiation(datagram).await? && replaced {
+            return Err(NewSessionError.into());
+          }
+        }
+        // late duplicate of a response to a finished handshake
+        MessageType::HandshakeResponse => {}
+        // reliable delivery, fragmentation, path MTU discovery, channels, hole punching and rendezvous are only supported by Peer
+        MessageType::Reliable | MessageType::Ack | MessageType::Fragment | MessageType::Probe | MessageType::ProbeAck
+          | MessageType::Channel | MessageType::ChannelAck | MessageType::Punch | MessageType::PunchAck
+          | MessageType::Register | MessageType::Introduction => {}
+      }
+    }
+  }
+
+}
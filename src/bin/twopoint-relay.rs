@@ -0,0 +1,66 @@
+//! Relay server that forwards datagrams between twopoint peers that cannot reach each other.
+//!
+//! ```text
+//! twopoint-relay [BIND_ADDR]
+//! ```
+//!
+//! Listens on `0.0.0.0:7475` unless another address is given. Peers that fail to punch
+//! fall back to it with `Peer::set_relay`, the relay never needs their key. It only
+//! takes in registrations that authenticate with the registration key in
+//! `TWOPOINT_REGISTRATION_KEY`, which `twopoint registration-key` prints.
+
+use std::env;
+use std::process::ExitCode;
+
+use twopoint::{RelayServer, Key};
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:7475";
+
+/// Environment variable the registration key is read from
+const REGISTRATION_KEY_VAR: &str = "TWOPOINT_REGISTRATION_KEY";
+
+const USAGE: &str = "usage: TWOPOINT_REGISTRATION_KEY=<HEX> twopoint-relay [BIND_ADDR]";
+
+fn main() -> ExitCode {
+  let mut args = env::args().skip(1);
+  let bind_addr = match (args.next(), args.next()) {
+    (Some(arg), _) if arg == "-h" || arg == "--help" => {
+      println!("{USAGE}");
+      return ExitCode::SUCCESS;
+    }
+    (bind_addr, None) => bind_addr.unwrap_or_else(|| DEFAULT_BIND_ADDR.to_string()),
+    (_, Some(_)) => {
+      eprintln!("{USAGE}");
+      return ExitCode::FAILURE;
+    }
+  };
+
+  let registration_key = match env::var(REGISTRATION_KEY_VAR).map(|key| key.parse::<Key>()) {
+    Ok(Ok(key)) => key,
+    Ok(Err(e)) => {
+      eprintln!("twopoint-relay: invalid {REGISTRATION_KEY_VAR}: {e}");
+      return ExitCode::FAILURE;
+    }
+    Err(_) => {
+      eprintln!("twopoint-relay: missing {REGISTRATION_KEY_VAR}\n\n{USAGE}");
+      return ExitCode::FAILURE;
+    }
+  };
+
+  let mut server = match RelayServer::bind(&bind_addr, registration_key) {
+    Ok(server) => server,
+    Err(e) => {
+      eprintln!("twopoint-relay: failed to bind {bind_addr}: {e}");
+      return ExitCode::FAILURE;
+    }
+  };
+  eprintln!("twopoint-relay: listening on {}", server.local_addr());
+
+  match server.serve() {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("twopoint-relay: {e}");
+      ExitCode::FAILURE
+    }
+  }
+}
@@ -0,0 +1,329 @@
+//! Point-to-point VPN over twopoint, using a Linux TUN device.
+//!
+//! ```text
+//! twopoint-vpn --role <initiator|responder> --address <ADDR/PREFIX> [OPTIONS]
+//! ```
+//!
+//! Every IP packet routed to the TUN device is sent to the other side as one
+//! message, and every message from the other side is written back to the
+//! device. The device MTU defaults to the largest message that fits into a
+//! single datagram, so packets are never fragmented on the way.
+//!
+//! The peer roams, so the side started without `--peer` waits for the other
+//! side to show up, and both sides follow each other to new addresses. Once
+//! the other side stops answering, the side started with `--peer` handshakes
+//! again, in case it restarted.
+//!
+//! To try it out between two network namespaces, as root:
+//!
+//! ```text
+//! ip netns add a && ip netns add b
+//! ip link add veth-a netns a type veth peer name veth-b netns b
+//! ip -n a addr add 192.0.2.1/24 dev veth-a && ip -n a link set veth-a up
+//! ip -n b addr add 192.0.2.2/24 dev veth-b && ip -n b link set veth-b up
+//! export TWOPOINT_KEY=$(openssl rand -hex 32)
+//! ip netns exec a twopoint-vpn --role initiator --address 10.74.0.1/24 --peer 192.0.2.2:7476 &
+//! ip netns exec b twopoint-vpn --role responder --address 10.74.0.2/24 &
+//! ip netns exec a ping 10.74.0.2
+//! ```
+
+#[cfg(target_os = "linux")]
+mod tun;
+
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: twopoint-vpn --role <initiator|responder> --address <ADDR/PREFIX> [OPTIONS]
+
+options:
+  --role <ROLE>          initiator on one side, responder on the other
+  --address <ADDR/LEN>   IPv4 address and prefix length of the TUN device
+  --peer <ADDR>          address of the other side, waits for it if not given
+  --bind <ADDR>          local address to bind [default: 0.0.0.0:7476]
+  --key <HEX>            pre-shared key, or set TWOPOINT_KEY
+  --name <NAME>          TUN device name [default: twopoint%d]
+  --mtu <BYTES>          TUN device MTU [default: largest single-datagram message]";
+
+#[cfg(not(target_os = "linux"))]
+fn main() -> ExitCode {
+  eprintln!("twopoint-vpn: TUN devices are only supported on Linux");
+  ExitCode::FAILURE
+}
+
+#[cfg(target_os = "linux")]
+fn main() -> ExitCode {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+    println!("{USAGE}");
+    return ExitCode::SUCCESS;
+  }
+  let options = match vpn::Options::parse(&args, std::env::var("TWOPOINT_KEY").ok()) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("twopoint-vpn: {e}\n\n{USAGE}");
+      return ExitCode::FAILURE;
+    }
+  };
+  match vpn::run(options) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("twopoint-vpn: {e}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod vpn {
+
+  use std::io::{self, Read, Write};
+  use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+  use std::process;
+  use std::thread;
+
+  use twopoint::{Peer, Key, Role, KeepalivePolicy, CryptoError, ReplayError, can_reconnect, is_dead_peer, is_new_session, is_message_too_large};
+
+  use crate::tun::Tun;
+
+  const DEFAULT_BIND_ADDR: &str = "0.0.0.0:7476";
+  const DEFAULT_NAME: &str = "twopoint%d";
+
+  /// Largest IP packet a TUN device hands out
+  const MAXIMUM_PACKET_SIZE: usize = 65535;
+
+  pub struct Options {
+    role: Role,
+    address: (Ipv4Addr, u8),
+    peer: Option<SocketAddr>,
+    bind: SocketAddr,
+    key: Key,
+    name: String,
+    mtu: Option<usize>,
+  }
+
+  impl Options {
+
+    /// Parses the command-line arguments, falling back to `key` from the environment.
+    pub fn parse(args: &[String], mut key: Option<String>) -> Result<Self, String> {
+      let mut role = None;
+      let mut address = None;
+      let mut peer = None;
+      let mut bind = None;
+      let mut name = None;
+      let mut mtu = None;
+      let mut args = args.iter();
+      while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("missing value for {flag}"))?;
+        match flag.as_str() {
+          "--role" => role = Some(value),
+          "--address" => address = Some(value),
+          "--peer" => peer = Some(value),
+          "--bind" => bind = Some(value),
+          "--key" => key = Some(value.clone()),
+          "--name" => name = Some(value),
+          "--mtu" => mtu = Some(value),
+          _ => return Err(format!("unknown option {flag}")),
+        }
+      }
+      let role = match role.map(String::as_str) {
+        Some("initiator") => Role::Initiator,
+        Some("responder") => Role::Responder,
+        Some(role) => return Err(format!("invalid role {role}")),
+        None => return Err("missing --role".to_string()),
+      };
+      let address = address.ok_or("missing --address")?;
+      let (ip, prefix) = address.split_once('/').ok_or_else(|| format!("missing prefix length in {address}"))?;
+      let ip = ip.parse().map_err(|e| format!("invalid address {ip}: {e}"))?;
+      let prefix = prefix.parse().ok().filter(|&prefix| prefix <= 32).ok_or_else(|| format!("invalid prefix length {prefix}"))?;
+      let peer = peer.map(|peer| peer.parse().map_err(|e| format!("invalid peer address {peer}: {e}"))).transpose()?;
+      let bind = bind.map_or(DEFAULT_BIND_ADDR, String::as_str);
+      let bind = bind.parse().map_err(|e| format!("invalid bind address {bind}: {e}"))?;
+      let key = key.ok_or("missing --key or TWOPOINT_KEY")?;
+      let key = key.parse().map_err(|e| format!("invalid key: {e}"))?;
+      let name = name.map_or(DEFAULT_NAME, String::as_str).to_string();
+      let mtu = mtu.map(|mtu| mtu.parse().map_err(|e| format!("invalid MTU {mtu}: {e}"))).transpose()?;
+      Ok(Self { role, address: (ip, prefix), peer, bind, key, name, mtu })
+    }
+
+  }
+
+  pub fn run(options: Options) -> io::Result<()> {
+    let mut peer = Peer::with_handshake(UdpSocket::bind(options.bind)?, options.key, options.role);
+    peer.set_roaming(true)?;
+    if let Some(addr) = options.peer {
+      peer.connect(addr)?;
+      // the responder answers the handshake from its receive loop, once it is up
+      until_answered(addr, || peer.handshake())?;
+    }
+    peer.set_keepalive_policy(Some(KeepalivePolicy::default()));
+
+    let mtu = device_mtu(options.mtu, &peer);
+    let (address, prefix) = options.address;
+    let tun = Tun::open(&options.name)?;
+    tun.set_mtu(mtu)?;
+    tun.set_address(address, prefix)?;
+    tun.up()?;
+    eprintln!("twopoint-vpn: {} is up with address {address}/{prefix} and MTU {mtu}", tun.name());
+
+    let (mut sender, mut receiver) = peer.split();
+
+    let mut reader = tun.try_clone()?;
+    thread::spawn(move || {
+      let mut packet = vec![0u8; MAXIMUM_PACKET_SIZE];
+      // room for the overhead and the connection ID
+      let mut scratch = vec![0u8; MAXIMUM_PACKET_SIZE + Peer::OVERHEAD + 8];
+      loop {
+        let len = match reader.read(&mut packet) {
+          Ok(len) => len,
+          Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+          Err(e) => {
+            eprintln!("twopoint-vpn: failed to read from {}: {e}", reader.name());
+            process::exit(1);
+          }
+        };
+        match sender.send_slice(&packet[..len], &mut scratch) {
+          Ok(()) => {}
+          // packets for a peer that isn't there yet or can't be reached are lost, like on any link
+          Err(e) if can_reconnect(&e) || is_message_too_large(&e) => {}
+          Err(e) => {
+            eprintln!("twopoint-vpn: failed to send: {e}");
+            process::exit(1);
+          }
+        }
+      }
+    });
+
+    let mut writer = tun;
+    let mut packet = Vec::new();
+    let mut alive = false;
+    loop {
+      match receiver.recv(&mut packet) {
+        Ok(()) => {
+          if !alive {
+            eprintln!("twopoint-vpn: connected to {}", receiver.remote_addr());
+            alive = true;
+          }
+          if let Err(e) = writer.write(&packet) {
+            eprintln!("twopoint-vpn: dropped a packet that {} did not take: {e}", writer.name());
+          }
+        }
+        Err(e) if is_dead_peer(&e) => {
+          let remote = receiver.remote_addr();
+          eprintln!("twopoint-vpn: {remote} stopped answering");
+          alive = false;
+          if receiver.role() == Some(Role::Initiator) {
+            until_answered(remote, || receiver.handshake())?;
+          }
+        }
+        // packets are not kept in step with the other side, so nothing has to start over
+        Err(e) if is_new_session(&e) => eprintln!("twopoint-vpn: {} started a new session", receiver.remote_addr()),
+        Err(e) if can_reconnect(&e) || is_bad_datagram(&e) => {}
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Handshakes until the other side answers.
+  fn until_answered(addr: SocketAddr, mut handshake: impl FnMut() -> io::Result<()>) -> io::Result<()> {
+    while let Err(e) = handshake() {
+      if !can_reconnect(&e) {
+        return Err(e);
+      }
+      eprintln!("twopoint-vpn: waiting for {addr} to answer");
+    }
+    Ok(())
+  }
+
+  /// Returns the MTU for the TUN device, unless one was asked for the largest
+  /// packet that fits into a single message. Call it once the peer roams, so
+  /// that the connection ID is taken into account.
+  fn device_mtu(requested: Option<usize>, peer: &Peer) -> usize {
+    requested.unwrap_or_else(|| peer.max_payload_size())
+  }
+
+  /// Returns `true` if the error was caused by a datagram that was not from the other side.
+  fn is_bad_datagram(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidData
+      || e.get_ref().is_some_and(|inner| inner.is::<CryptoError>() || inner.is::<ReplayError>())
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const KEY: &str = "5adf5e4a8a779d4cd7985a881b270bcf";
+
+    fn parse(args: &str, key: Option<&str>) -> Result<Options, String> {
+      let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+      Options::parse(&args, key.map(String::from))
+    }
+
+    #[test]
+    fn test_parse() {
+      let options = parse("--role responder --address 10.74.0.2/24", Some(KEY)).expect("failed to parse");
+      assert_eq!(options.role, Role::Responder);
+      assert_eq!(options.address, (Ipv4Addr::new(10, 74, 0, 2), 24));
+      assert_eq!(options.peer, None);
+      assert_eq!(options.bind, DEFAULT_BIND_ADDR.parse().unwrap());
+      assert_eq!(options.key, KEY.parse().unwrap());
+      assert_eq!(options.name, DEFAULT_NAME);
+      assert_eq!(options.mtu, None);
+
+      // options given on the command line win over the environment
+      let args = format!("--role initiator --address 10.74.0.1/32 --peer 192.0.2.2:7476 --bind 0.0.0.0:9000 --key {KEY} --name vpn0 --mtu 1280");
+      let options = parse(&args, Some("not a key")).expect("failed to parse");
+      assert_eq!(options.role, Role::Initiator);
+      assert_eq!(options.address, (Ipv4Addr::new(10, 74, 0, 1), 32));
+      assert_eq!(options.peer, Some("192.0.2.2:7476".parse().unwrap()));
+      assert_eq!(options.bind, "0.0.0.0:9000".parse().unwrap());
+      assert_eq!(options.name, "vpn0");
+      assert_eq!(options.mtu, Some(1280));
+
+      for (args, key, error) in [
+        ("--address 10.74.0.1/24", Some(KEY), "missing --role"),
+        ("--role server --address 10.74.0.1/24", Some(KEY), "invalid role server"),
+        ("--role initiator", Some(KEY), "missing --address"),
+        ("--role initiator --address 10.74.0.1", Some(KEY), "missing prefix length in 10.74.0.1"),
+        ("--role initiator --address 10.74.0.1/33", Some(KEY), "invalid prefix length 33"),
+        ("--role initiator --address 10.74.0.1/24", None, "missing --key or TWOPOINT_KEY"),
+        ("--role initiator --address 10.74.0.1/24 --peer", Some(KEY), "missing value for --peer"),
+        ("--role initiator --address 10.74.0.1/24 --port 1", Some(KEY), "unknown option --port"),
+      ] {
+        assert_eq!(parse(args, key).err().as_deref(), Some(error), "{args}");
+      }
+      for args in ["--role initiator --address 10.74.0.1/24 --peer nowhere", "--role initiator --address 10.74.0.1/24 --mtu big"] {
+        parse(args, Some(KEY)).err().expect(args);
+      }
+    }
+
+    #[test]
+    fn test_device_mtu() {
+      let key = KEY.parse().unwrap();
+      let mut peer = Peer::with_handshake(UdpSocket::bind("127.0.0.1:0").unwrap(), key, Role::Initiator);
+      let mut other = Peer::with_handshake(UdpSocket::bind("127.0.0.1:0").unwrap(), key, Role::Responder);
+      other.connect(peer.local_addr()).unwrap();
+      peer.connect(other.local_addr()).unwrap();
+      let without_roaming = device_mtu(None, &peer);
+      peer.set_roaming(true).unwrap();
+      let mtu = device_mtu(None, &peer);
+      assert_eq!(mtu, without_roaming - 8, "connection ID was not left room for");
+      assert_eq!(device_mtu(Some(1280), &peer), 1280);
+
+      thread::scope(|scope| {
+        let responding = scope.spawn(|| other.handshake());
+        peer.handshake().expect("initiator handshake failed");
+        responding.join().unwrap().expect("responder handshake failed");
+      });
+
+      // a packet of the device MTU fills a datagram up to the MTU of the link
+      let mut scratch = vec![0u8; MAXIMUM_PACKET_SIZE];
+      peer.send_slice(&vec![1u8; mtu], &mut scratch).expect("packet of the device MTU was not sent");
+      other.socket().set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+      let len = other.socket().recv(&mut scratch).expect("packet was not received");
+      assert_eq!(len, peer.mtu());
+    }
+
+  }
+
+}
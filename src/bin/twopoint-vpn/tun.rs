@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::mem;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::fd::AsRawFd;
+
+/// A Linux TUN device, reading and writing bare IP packets.
+pub struct Tun {
+  file: File,
+  name: String,
+}
+
+impl Tun {
+
+  /// Creates a TUN device, or attaches to an existing one with the given
+  /// name. A `%d` in the name is replaced by the kernel with the first free number.
+  pub fn open(name: &str) -> io::Result<Self> {
+    let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+    let mut request = Self::request(name)?;
+    request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    // SAFETY: the file is a valid TUN file descriptor, and the request is a valid ifreq
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF as _, &mut request) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let name = request.ifr_name.iter()
+      .take_while(|&&c| c != 0)
+      .map(|&c| c as u8 as char)
+      .collect();
+    Ok(Self { file, name })
+  }
+
+  /// Returns the name the kernel gave the device.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Returns another handle to the same device.
+  pub fn try_clone(&self) -> io::Result<Self> {
+    Ok(Self { file: self.file.try_clone()?, name: self.name.clone() })
+  }
+
+  /// Sets the IPv4 address and prefix length of the device.
+  pub fn set_address(&self, address: Ipv4Addr, prefix: u8) -> io::Result<()> {
+    let netmask = Ipv4Addr::from(u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0));
+    let mut request = Self::request(&self.name)?;
+    request.ifr_ifru.ifru_addr = Self::sockaddr(address);
+    Self::control(libc::SIOCSIFADDR as _, &mut request)?;
+    request.ifr_ifru.ifru_netmask = Self::sockaddr(netmask);
+    Self::control(libc::SIOCSIFNETMASK as _, &mut request)
+  }
+
+  /// Sets the MTU of the device.
+  pub fn set_mtu(&self, mtu: usize) -> io::Result<()> {
+    let mut request = Self::request(&self.name)?;
+    request.ifr_ifru.ifru_mtu = libc::c_int::try_from(mtu)
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MTU is too large"))?;
+    Self::control(libc::SIOCSIFMTU as _, &mut request)
+  }
+
+  /// Brings the device up.
+  pub fn up(&self) -> io::Result<()> {
+    let mut request = Self::request(&self.name)?;
+    Self::control(libc::SIOCGIFFLAGS as _, &mut request)?;
+    // SAFETY: SIOCGIFFLAGS filled in the flags
+    let flags = unsafe { request.ifr_ifru.ifru_flags };
+    request.ifr_ifru.ifru_flags = flags | (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    Self::control(libc::SIOCSIFFLAGS as _, &mut request)
+  }
+
+  /// Returns an ifreq for the device with the given name and nothing else set.
+  fn request(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid device name"));
+    }
+    // SAFETY: ifreq is plain old data, all zeroes is a valid value
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (c, &byte) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+      *c = byte as libc::c_char;
+    }
+    Ok(request)
+  }
+
+  fn sockaddr(address: Ipv4Addr) -> libc::sockaddr {
+    let address = libc::sockaddr_in {
+      sin_family: libc::AF_INET as libc::sa_family_t,
+      sin_port: 0,
+      sin_addr: libc::in_addr { s_addr: u32::from(address).to_be() },
+      sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in and sockaddr have the same size, and any bytes are a valid sockaddr
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(address) }
+  }
+
+  /// Runs an interface ioctl, which has to go through a socket rather than the device.
+  fn control(command: libc::Ioctl, request: &mut libc::ifreq) -> io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    // SAFETY: the socket is valid for the whole call, and the request is a valid ifreq
+    if unsafe { libc::ioctl(socket.as_raw_fd(), command, request as *mut libc::ifreq) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+}
+
+impl Read for Tun {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.file.read(buffer)
+  }
+}
+
+impl Write for Tun {
+  fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+    self.file.write(buffer)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
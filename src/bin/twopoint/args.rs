@@ -0,0 +1,173 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use twopoint::Key;
+
+/// Environment variable the key is read from when there is no `--key`
+const KEY_VAR: &str = "TWOPOINT_KEY";
+
+/// Error for a command that could not run.
+pub enum Error {
+  /// The command line was wrong, worth showing the usage for.
+  Usage(String),
+  /// The command failed while running.
+  Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    Self::Io(e)
+  }
+}
+
+impl From<String> for Error {
+  fn from(e: String) -> Self {
+    Self::Usage(e)
+  }
+}
+
+impl From<&str> for Error {
+  fn from(e: &str) -> Self {
+    Self::Usage(e.to_string())
+  }
+}
+
+/// Command-line options of a command, taken out one at a time.
+pub struct Args {
+  args: Vec<String>,
+}
+
+impl Args {
+
+  pub fn parse(args: &[String]) -> Result<Self, Error> {
+    Ok(Self { args: args.to_vec() })
+  }
+
+  /// Takes the value of an option given as `--flag value`, if it is there.
+  pub fn optional<T>(&mut self, flag: &str) -> Result<Option<T>, Error>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    let Some(index) = self.args.iter().position(|arg| arg == flag) else {
+      return Ok(None);
+    };
+    if index + 1 >= self.args.len() {
+      return Err(format!("missing value for {flag}").into());
+    }
+    let value = self.args.remove(index + 1);
+    self.args.remove(index);
+    let value = value.parse().map_err(|e| format!("invalid value {value} for {flag}: {e}"))?;
+    Ok(Some(value))
+  }
+
+  /// Takes the value of an option that has to be there.
+  pub fn required<T>(&mut self, flag: &str) -> Result<T, Error>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    self.optional(flag)?.ok_or_else(|| format!("missing {flag}").into())
+  }
+
+  /// Takes the argument right after the command, like the address in `connect <PEER>`.
+  pub fn operand<T>(&mut self, name: &str) -> Result<T, Error>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    if self.args.first().is_none_or(|arg| arg.starts_with('-')) {
+      return Err(format!("missing {name}").into());
+    }
+    let value = self.args.remove(0);
+    value.parse().map_err(|e| format!("invalid {name} {value}: {e}").into())
+  }
+
+  /// Takes an option without a value, returning whether it was there.
+  pub fn switch(&mut self, flag: &str) -> bool {
+    let index = self.args.iter().position(|arg| arg == flag);
+    index.map(|index| self.args.remove(index)).is_some()
+  }
+
+  /// Takes the key from `--key`, or from the environment.
+  pub fn key(&mut self) -> Result<Key, Error> {
+    if let Some(key) = self.optional("--key")? {
+      return Ok(key);
+    }
+    let key = env::var(KEY_VAR).map_err(|_| format!("missing --key or {KEY_VAR}"))?;
+    key.parse().map_err(|e| format!("invalid key in {KEY_VAR}: {e}").into())
+  }
+
+  /// Fails if any options were not taken.
+  pub fn finish(self) -> Result<(), Error> {
+    match self.args.first() {
+      Some(arg) => Err(format!("unexpected argument {arg}").into()),
+      None => Ok(()),
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::SocketAddr;
+
+  use crate::tests::KEY;
+
+  fn parse(line: &str) -> Args {
+    let args = line.split_whitespace().map(String::from).collect::<Vec<_>>();
+    ok(Args::parse(&args))
+  }
+
+  fn ok<T>(result: Result<T, Error>) -> T {
+    match result {
+      Ok(value) => value,
+      Err(Error::Usage(e)) => panic!("usage error: {e}"),
+      Err(Error::Io(e)) => panic!("error: {e}"),
+    }
+  }
+
+  fn usage<T>(result: Result<T, Error>) -> String {
+    match result {
+      Err(Error::Usage(e)) => e,
+      _ => panic!("not a usage error"),
+    }
+  }
+
+  #[test]
+  fn test_options() {
+    let mut args = parse("192.0.2.1:7477 --count 3 --verbose --bind 0.0.0.0:9000");
+    assert_eq!(ok(args.operand::<SocketAddr>("peer address")), "192.0.2.1:7477".parse().unwrap());
+    assert_eq!(ok(args.optional::<u64>("--count")), Some(3));
+    assert_eq!(ok(args.optional::<u64>("--count")), None);
+    assert!(args.switch("--verbose"));
+    assert!(!args.switch("--verbose"));
+    assert_eq!(ok(args.required::<SocketAddr>("--bind")), "0.0.0.0:9000".parse().unwrap());
+    ok(args.finish());
+
+    // options are taken wherever they are, the operand only right at the start
+    let mut args = parse(&format!("--key {KEY} 192.0.2.1:7477"));
+    assert_eq!(ok(args.key()), KEY.parse().unwrap());
+    assert_eq!(ok(args.operand::<SocketAddr>("peer address")), "192.0.2.1:7477".parse().unwrap());
+    ok(args.finish());
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(usage(parse("--count").optional::<u64>("--count")), "missing value for --count");
+    assert!(usage(parse("--count many").optional::<u64>("--count")).starts_with("invalid value many for --count"));
+    assert_eq!(usage(parse("--count 3").required::<SocketAddr>("--bind")), "missing --bind");
+    assert_eq!(usage(parse("--count 3").operand::<SocketAddr>("peer address")), "missing peer address");
+    assert_eq!(usage(parse("").operand::<SocketAddr>("peer address")), "missing peer address");
+    assert!(usage(parse("nowhere").operand::<SocketAddr>("peer address")).starts_with("invalid peer address nowhere"));
+    assert!(usage(parse("--key 1234").key()).starts_with("invalid value 1234 for --key"));
+
+    let mut leftover = parse("--count 3 extra");
+    ok(leftover.optional::<u64>("--count"));
+    assert_eq!(usage(leftover.finish()), "unexpected argument extra");
+  }
+
+}
@@ -0,0 +1,646 @@
+//! `twopoint forward-tcp`, carrying a TCP service over a link.
+//!
+//! The side with `--listen` accepts connections from local clients, and the
+//! side with `--to` opens a connection to the target for each of them. Every
+//! connection is a stream on a reliable ordered channel of its own, so the
+//! channel ID is the stream ID, and every message on it starts with a byte
+//! saying what kind of message it is:
+//!
+//! ```text
+//! open       a client connected, the other side should connect to the target
+//! data       bytes read from the connection
+//! eof        the connection was shut down for writing, nothing more follows
+//! reset      the connection failed or was aborted, both directions are done
+//! consumed   the other side wrote this many bytes to its connection
+//! ```
+//!
+//! Each side sends at most [`WINDOW`] bytes that the other side has not
+//! written out yet, and then stops reading from its connection until it hears
+//! back, so a slow reader on one end slows down the writer on the other
+//! instead of piling up data in between. A stream is forgotten once both
+//! directions have ended with an eof, or with a reset.
+//!
+//! When the link starts a new session, because either side restarted or the
+//! other side stopped answering for a while, channels start over, so every
+//! stream is reset on both sides and later connections get new streams.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread;
+
+use twopoint::{Channel, ChannelMode, NewReno, PeerSender, PeerReceiver, can_reconnect, is_new_session, random_connection_id};
+
+use crate::args::{self, Args};
+use crate::lock;
+
+/// Kinds of message on a stream, in the first byte
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const EOF: u8 = 2;
+const RESET: u8 = 3;
+const CONSUMED: u8 = 4;
+
+/// Bytes a side may send on a stream before the other side has written them out
+const WINDOW: usize = 256 * 1024;
+
+/// Bytes written out before the other side is told, unless there is nothing more to write
+const CONSUMED_BATCH: usize = WINDOW / 4;
+
+/// Added to each channel message by the kind byte
+const STREAM_OVERHEAD: usize = 1;
+
+pub fn run(mut args: Args) -> Result<(), args::Error> {
+  let listen = args.optional::<SocketAddr>("--listen")?;
+  let to = args.optional::<SocketAddr>("--to")?;
+  let via = args.optional::<SocketAddr>("--via")?;
+  let peer = crate::link(&mut args, via)?;
+  args.finish()?;
+  // streams send in bursts of up to a window each, which would overrun the socket buffers otherwise
+  peer.set_congestion_control(Some(Box::new(NewReno::new())));
+  let (sender, receiver) = peer.split();
+  match (listen, to) {
+    (Some(listen), None) => Ok(listen_side(TcpListener::bind(listen)?, sender, receiver)?),
+    (None, Some(to)) => Ok(target_side(to, sender, receiver)?),
+    _ => Err("forward-tcp needs either --listen or --to".into()),
+  }
+}
+
+/// Sends a message on a stream, see [`crate::send_reliable`].
+fn send(channel: &mut Channel, kind: u8, payload: &[u8]) -> io::Result<()> {
+  let mut message = Vec::with_capacity(1 + payload.len());
+  message.push(kind);
+  message.extend_from_slice(payload);
+  crate::send_reliable(channel, &mut message)
+}
+
+/// What the other side sent on a stream, for the connection to act on.
+enum Segment {
+  Data(Vec<u8>),
+  Eof,
+  Reset,
+}
+
+/// One connection carried over the link.
+struct Stream {
+  state: Mutex<State>,
+  /// Signalled when bytes are consumed, or the stream is reset
+  changed: Condvar,
+  /// Segments waiting to be written to the connection
+  segments: mpsc::Sender<Segment>,
+}
+
+#[derive(Default)]
+struct State {
+  /// Bytes sent that the other side has not written out yet
+  unconsumed: usize,
+  /// Directions of the stream that have ended
+  ended: u8,
+  reset: bool,
+}
+
+impl Stream {
+
+  fn new() -> (Arc<Self>, mpsc::Receiver<Segment>) {
+    let (segments, queue) = mpsc::channel();
+    let stream = Self { state: Mutex::new(State::default()), changed: Condvar::new(), segments };
+    (Arc::new(stream), queue)
+  }
+
+  /// Hands a segment to the connection, which drops it if it is not writing anymore.
+  fn push(&self, segment: Segment) {
+    let _ = self.segments.send(segment);
+  }
+
+  fn consumed(&self, len: usize) {
+    let mut state = lock(&self.state);
+    state.unconsumed = state.unconsumed.saturating_sub(len);
+    self.changed.notify_all();
+  }
+
+  fn reset(&self) {
+    lock(&self.state).reset = true;
+    self.changed.notify_all();
+  }
+
+  fn is_reset(&self) -> bool {
+    lock(&self.state).reset
+  }
+
+}
+
+/// Streams of a link by ID, with the next ID to hand out.
+#[derive(Default)]
+struct Streams {
+  by_id: HashMap<u16, Arc<Stream>>,
+  next: u16,
+}
+
+impl Streams {
+
+  /// Creates an empty set of streams that hands out IDs from a random one on,
+  /// so that a restarted side doesn't take up the IDs of streams that the
+  /// other side is still winding down.
+  fn random() -> Self {
+    Self { by_id: HashMap::new(), next: random_connection_id() as u16 }
+  }
+
+  /// Makes up an ID that is not in use for a new stream.
+  fn allocate(&mut self, stream: &Arc<Stream>) -> io::Result<u16> {
+    let id = (0..=u16::MAX)
+      .map(|offset| self.next.wrapping_add(offset))
+      .find(|id| !self.by_id.contains_key(id))
+      .ok_or_else(|| io::Error::other("too many streams"))?;
+    self.next = id.wrapping_add(1);
+    self.by_id.insert(id, Arc::clone(stream));
+    Ok(id)
+  }
+
+}
+
+/// Resets every stream, once the link started a new session that the streams don't carry over to.
+fn reset_all(streams: &Mutex<Streams>) {
+  let by_id = std::mem::take(&mut lock(streams).by_id);
+  if !by_id.is_empty() {
+    eprintln!("twopoint: link started over, resetting {} connections", by_id.len());
+  }
+  for stream in by_id.into_values() {
+    stream.reset();
+    stream.push(Segment::Reset);
+  }
+}
+
+/// Receives from the other side and hands messages to their streams, until the link fails.
+fn receive(receiver: &mut PeerReceiver, streams: &Mutex<Streams>, mut open: impl FnMut(u16)) -> io::Result<()> {
+  let mut buffer = Vec::new();
+  loop {
+    match crate::recv_channel(receiver, &mut buffer) {
+      Ok(Some(id)) if buffer.first() == Some(&OPEN) => open(id),
+      Ok(Some(id)) => deliver(streams, id, &buffer),
+      Ok(None) => {}
+      Err(e) if is_new_session(&e) => reset_all(streams),
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Ends one direction of a stream, forgetting the stream once both have ended.
+fn end(streams: &Mutex<Streams>, id: u16, stream: &Arc<Stream>) {
+  let mut state = lock(&stream.state);
+  state.ended += 1;
+  if state.ended == 2 {
+    let mut streams = lock(streams);
+    // the ID may have been taken over by a new stream already
+    if streams.by_id.get(&id).is_some_and(|existing| Arc::ptr_eq(existing, stream)) {
+      streams.by_id.remove(&id);
+    }
+  }
+}
+
+/// Carries a connected stream in both directions, on two threads of its own.
+fn start(id: u16, connection: TcpStream, sender: &PeerSender, stream: Arc<Stream>, queue: mpsc::Receiver<Segment>, streams: Arc<Mutex<Streams>>) -> io::Result<()> {
+  let chunk = sender.max_channel_payload_size().saturating_sub(STREAM_OVERHEAD).max(1);
+  let (reader, channel) = (connection.try_clone()?, sender.channel(id, ChannelMode::ReliableOrdered));
+  let (stream_, streams_) = (Arc::clone(&stream), Arc::clone(&streams));
+  thread::spawn(move || {
+    uplink(reader, channel, &stream_, chunk);
+    end(&streams_, id, &stream_);
+  });
+  let channel = sender.channel(id, ChannelMode::ReliableOrdered);
+  thread::spawn(move || {
+    downlink(connection, channel, &stream, &queue);
+    end(&streams, id, &stream);
+  });
+  Ok(())
+}
+
+/// Reads from the connection and sends it to the other side, within the window.
+fn uplink(mut connection: TcpStream, mut channel: Channel, stream: &Stream, chunk: usize) {
+  let mut buffer = vec![0u8; chunk];
+  loop {
+    {
+      let state = lock(&stream.state);
+      let state = stream.changed.wait_while(state, |state| !state.reset && state.unconsumed >= WINDOW)
+        .unwrap_or_else(|e| e.into_inner());
+      if state.reset {
+        return;
+      }
+    }
+    let result = match connection.read(&mut buffer) {
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      // a reset from the other side shuts the connection down, which ends the read too
+      _ if stream.is_reset() => return,
+      Ok(0) => send(&mut channel, EOF, &[]),
+      Ok(len) => {
+        lock(&stream.state).unconsumed += len;
+        match send(&mut channel, DATA, &buffer[..len]) {
+          Ok(()) => continue,
+          Err(e) => Err(e),
+        }
+      }
+      Err(_) => {
+        // stops the downlink too, as if the other side had reset the stream
+        stream.push(Segment::Reset);
+        send(&mut channel, RESET, &[])
+      }
+    };
+    if let Err(e) = result {
+      eprintln!("twopoint: failed to send on stream {}: {e}", channel.id());
+      stream.push(Segment::Reset);
+    }
+    return;
+  }
+}
+
+/// Writes what the other side sent to the connection, and tells it how much was written.
+fn downlink(mut connection: TcpStream, mut channel: Channel, stream: &Stream, queue: &mpsc::Receiver<Segment>) {
+  let mut consumed = 0;
+  loop {
+    let segment = match queue.try_recv() {
+      Ok(segment) => segment,
+      Err(mpsc::TryRecvError::Empty) => {
+        // nothing more to write for now, so the other side can send again
+        if consumed > 0 && send(&mut channel, CONSUMED, &(consumed as u32).to_be_bytes()).is_ok() {
+          consumed = 0;
+        }
+        match queue.recv() {
+          Ok(segment) => segment,
+          Err(mpsc::RecvError) => return,
+        }
+      }
+      Err(mpsc::TryRecvError::Disconnected) => return,
+    };
+    match segment {
+      Segment::Data(data) => {
+        if connection.write_all(&data).is_err() {
+          stream.reset();
+          let _ = connection.shutdown(Shutdown::Both);
+          let _ = send(&mut channel, RESET, &[]);
+          return;
+        }
+        consumed += data.len();
+        if consumed >= CONSUMED_BATCH && send(&mut channel, CONSUMED, &(consumed as u32).to_be_bytes()).is_ok() {
+          consumed = 0;
+        }
+      }
+      Segment::Eof => {
+        let _ = connection.shutdown(Shutdown::Write);
+        return;
+      }
+      Segment::Reset => {
+        stream.reset();
+        let _ = connection.shutdown(Shutdown::Both);
+        return;
+      }
+    }
+  }
+}
+
+/// Hands a message from the other side to its stream.
+///
+/// Runs on the thread receiving from the peer, so it must never block.
+fn deliver(streams: &Mutex<Streams>, id: u16, message: &[u8]) {
+  let Some((&kind, payload)) = message.split_first() else {
+    return;
+  };
+  // messages for streams that are gone are dropped
+  let Some(stream) = lock(streams).by_id.get(&id).cloned() else {
+    return;
+  };
+  match kind {
+    DATA => stream.push(Segment::Data(payload.to_vec())),
+    EOF => stream.push(Segment::Eof),
+    RESET => {
+      stream.reset();
+      stream.push(Segment::Reset);
+    }
+    CONSUMED => {
+      if let Ok(len) = <[u8; 4]>::try_from(payload) {
+        stream.consumed(u32::from_be_bytes(len) as usize);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn listen_side(listener: TcpListener, sender: PeerSender, mut receiver: PeerReceiver) -> io::Result<()> {
+  eprintln!("twopoint: forwarding connections to {}", listener.local_addr()?);
+  let streams = Arc::new(Mutex::new(Streams::random()));
+
+  let receiving = {
+    let streams = Arc::clone(&streams);
+    // only this side opens streams
+    thread::spawn(move || receive(&mut receiver, &streams, |_| {}))
+  };
+
+  for connection in listener.incoming() {
+    if receiving.is_finished() {
+      return receiving.join().expect("receiving thread panicked");
+    }
+    let connection = match connection {
+      Ok(connection) => connection,
+      // clients that gave up before they were accepted
+      Err(e) if can_reconnect(&e) => continue,
+      Err(e) => return Err(e),
+    };
+    accept(connection, &sender, &streams)?;
+  }
+  Ok(())
+}
+
+/// Carries a connection from a local client on a new stream, which the other
+/// side is told to open, and returns the stream's ID.
+fn accept(connection: TcpStream, sender: &PeerSender, streams: &Arc<Mutex<Streams>>) -> io::Result<u16> {
+  connection.set_nodelay(true)?;
+  let (stream, queue) = Stream::new();
+  let id = lock(streams).allocate(&stream)?;
+  let mut channel = sender.channel(id, ChannelMode::ReliableOrdered);
+  send(&mut channel, OPEN, &[])?;
+  start(id, connection, sender, stream, queue, Arc::clone(streams))?;
+  Ok(id)
+}
+
+fn target_side(to: SocketAddr, sender: PeerSender, mut receiver: PeerReceiver) -> io::Result<()> {
+  eprintln!("twopoint: forwarding connections on to {to}");
+  let sender = Arc::new(sender);
+  let streams = Arc::new(Mutex::new(Streams::default()));
+  receive(&mut receiver, &streams, |id| {
+    let (stream, queue) = Stream::new();
+    lock(&streams).by_id.insert(id, Arc::clone(&stream));
+    let (sender, streams) = (Arc::clone(&sender), Arc::clone(&streams));
+    // connecting can take a while, and the peer has to keep receiving meanwhile
+    thread::spawn(move || {
+      let result = TcpStream::connect(to).and_then(|connection| {
+        connection.set_nodelay(true)?;
+        start(id, connection, &sender, Arc::clone(&stream), queue, Arc::clone(&streams))
+      });
+      if let Err(e) = result {
+        eprintln!("twopoint: failed to connect to {to}: {e}");
+        let _ = send(&mut sender.channel(id, ChannelMode::ReliableOrdered), RESET, &[]);
+        end(&streams, id, &stream);
+        end(&streams, id, &stream);
+      }
+    });
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::{Duration, Instant};
+
+  use twopoint::{Peer, Role, can_retry};
+
+  use crate::tests::{TIMEOUT, peers};
+
+  /// The side of a link under test, carrying connections like the side with `--listen`.
+  struct Side {
+    sender: PeerSender,
+    streams: Arc<Mutex<Streams>>,
+  }
+
+  impl Side {
+
+    /// Connects a client to the side, returning the client's end of the connection and the stream's ID.
+    fn open(&self) -> (TcpStream, u16) {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+      client.set_read_timeout(Some(TIMEOUT)).unwrap();
+      let (connection, _) = listener.accept().unwrap();
+      let id = accept(connection, &self.sender, &self.streams).expect("failed to start the stream");
+      (client, id)
+    }
+
+    fn stream(&self, id: u16) -> Option<Arc<Stream>> {
+      lock(&self.streams).by_id.get(&id).cloned()
+    }
+
+    fn wait_forgotten(&self, id: u16) {
+      let deadline = Instant::now() + TIMEOUT;
+      while self.stream(id).is_some() {
+        assert!(Instant::now() < deadline, "stream {id} was never forgotten");
+        thread::sleep(Duration::from_millis(10));
+      }
+    }
+
+  }
+
+  /// Sets up a link between a side under test and a peer standing in for the other side.
+  fn link() -> (Side, Peer) {
+    let (local, remote) = peers(Role::Initiator);
+    local.set_congestion_control(Some(Box::new(NewReno::new())));
+    let (sender, mut receiver) = local.split();
+    let streams = Arc::new(Mutex::new(Streams::default()));
+    let receiving = Arc::clone(&streams);
+    thread::spawn(move || receive(&mut receiver, &receiving, |_| {}));
+    (Side { sender, streams }, remote)
+  }
+
+  /// Receives the next message on any stream, as its ID, kind and payload.
+  fn recv_message(remote: &mut Peer) -> io::Result<(u16, u8, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    loop {
+      if let Some(id) = remote.recv_channel(&mut buffer)? {
+        let (&kind, payload) = buffer.split_first().expect("stream messages are never empty");
+        return Ok((id, kind, payload.to_vec()));
+      }
+    }
+  }
+
+  /// Receives the next message like [`recv_message`], skipping consumed counts.
+  fn recv(remote: &mut Peer) -> io::Result<(u16, u8, Vec<u8>)> {
+    loop {
+      let message = recv_message(remote)?;
+      if message.1 != CONSUMED {
+        return Ok(message);
+      }
+    }
+  }
+
+  /// Receives the data of a stream up to its eof.
+  fn recv_until_eof(remote: &mut Peer, id: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+      match recv(remote).expect("the stream did not end") {
+        (other, _, _) if other != id => panic!("message on stream {other} instead of {id}"),
+        (_, DATA, payload) => data.extend(payload),
+        (_, EOF, _) => return data,
+        (_, kind, _) => panic!("unexpected message of kind {kind}"),
+      }
+    }
+  }
+
+  fn send_to(remote: &Peer, id: u16, kind: u8, payload: &[u8]) {
+    send(&mut remote.channel(id, ChannelMode::ReliableOrdered), kind, payload).expect("failed to send");
+  }
+
+  /// Checks that the connection was shut down, without any more data.
+  fn assert_shut_down(client: &mut TcpStream) {
+    match client.read(&mut [0u8; 1]) {
+      Ok(len) => assert_eq!(len, 0, "data arrived after the stream ended"),
+      Err(e) => assert!(!can_retry(&e), "connection was not shut down"),
+    }
+  }
+
+  #[test]
+  fn test_half_close() {
+    let (side, mut remote) = link();
+
+    // the client finishes its request first, and still gets the whole response
+    let (mut client, id) = side.open();
+    assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+    client.write_all(b"request").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(recv_until_eof(&mut remote, id), b"request");
+    assert!(side.stream(id).is_some(), "stream was forgotten with one direction still open");
+    send_to(&remote, id, DATA, b"response");
+    send_to(&remote, id, EOF, &[]);
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response, b"response");
+    side.wait_forgotten(id);
+
+    // the other side finishes first, and the client can still write
+    let (mut client, id) = side.open();
+    assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+    send_to(&remote, id, DATA, b"greeting");
+    send_to(&remote, id, EOF, &[]);
+    let mut greeting = Vec::new();
+    client.read_to_end(&mut greeting).unwrap();
+    assert_eq!(greeting, b"greeting");
+    assert!(side.stream(id).is_some(), "stream was forgotten with one direction still open");
+    client.write_all(b"late reply").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(recv_until_eof(&mut remote, id), b"late reply");
+    side.wait_forgotten(id);
+  }
+
+  #[test]
+  fn test_window() {
+    let (side, mut remote) = link();
+    let (client, id) = side.open();
+    assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+
+    // the side stops reading from the client once a window is waiting to be written out
+    let upload = (0..WINDOW * 3 / 2).map(|i| i as u8).collect::<Vec<_>>();
+    let writer = {
+      let (mut client, upload) = (client.try_clone().unwrap(), upload.clone());
+      thread::spawn(move || client.write_all(&upload))
+    };
+    let mut uploaded = Vec::new();
+    while uploaded.len() < WINDOW {
+      let (_, kind, payload) = recv(&mut remote).unwrap();
+      assert_eq!(kind, DATA);
+      uploaded.extend(payload);
+    }
+    remote.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let error = recv(&mut remote).expect_err("more than a window was sent");
+    assert!(can_retry(&error), "{error}");
+    assert!(lock(&side.stream(id).unwrap().state).unconsumed >= WINDOW);
+
+    // and goes on once the other side has written it out
+    remote.set_read_timeout(Some(TIMEOUT)).unwrap();
+    send_to(&remote, id, CONSUMED, &(uploaded.len() as u32).to_be_bytes());
+    writer.join().expect("writer panicked").expect("failed to write");
+    while uploaded.len() < upload.len() {
+      let (_, kind, payload) = recv(&mut remote).unwrap();
+      assert_eq!(kind, DATA);
+      uploaded.extend(payload);
+    }
+    assert_eq!(uploaded, upload);
+
+    // the other way round, the side says how much it wrote to the client, all of it in the end
+    let download = vec![7u8; WINDOW / 2];
+    for chunk in download.chunks(1000) {
+      send_to(&remote, id, DATA, chunk);
+    }
+    // read on another thread, lost chunks are only sent again while the remote receives
+    let reader = {
+      let (mut client, len) = (client.try_clone().unwrap(), download.len());
+      thread::spawn(move || {
+        let mut downloaded = vec![0u8; len];
+        client.read_exact(&mut downloaded).map(|()| downloaded)
+      })
+    };
+    let mut consumed = 0;
+    while consumed < download.len() {
+      let (_, kind, payload) = recv_message(&mut remote).unwrap();
+      assert_eq!(kind, CONSUMED);
+      consumed += u32::from_be_bytes(payload.try_into().expect("consumed count is 4 bytes")) as usize;
+    }
+    assert_eq!(consumed, download.len());
+    assert_eq!(reader.join().expect("reader panicked").unwrap(), download);
+
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(recv_until_eof(&mut remote, id), b"");
+  }
+
+  #[test]
+  fn test_reset_while_blocked() {
+    for by_new_session in [false, true] {
+      let (side, mut remote) = link();
+      let (mut client, id) = side.open();
+      assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+      let stream = side.stream(id).unwrap();
+
+      // fill the window, so that the side waits for the other side to write it out
+      let mut writer = client.try_clone().unwrap();
+      thread::spawn(move || writer.write_all(&vec![0u8; WINDOW * 2]));
+      let mut uploaded = 0;
+      while uploaded < WINDOW {
+        uploaded += recv(&mut remote).unwrap().2.len();
+      }
+      thread::sleep(Duration::from_millis(100));
+
+      if by_new_session {
+        reset_all(&side.streams);
+      } else {
+        send_to(&remote, id, RESET, &[]);
+      }
+      assert_shut_down(&mut client);
+      side.wait_forgotten(id);
+      // both directions let go of the stream, the one waiting for the window too
+      let deadline = Instant::now() + TIMEOUT;
+      while Arc::strong_count(&stream) > 1 {
+        assert!(Instant::now() < deadline, "stream was not released after a reset");
+        thread::sleep(Duration::from_millis(10));
+      }
+    }
+  }
+
+  #[test]
+  fn test_stream_id_reuse() {
+    let (side, mut remote) = link();
+
+    // a stream that has not ended keeps its ID, so a new stream skips it
+    let (mut first, id) = side.open();
+    assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+    lock(&side.streams).next = id;
+    let (_second, other) = side.open();
+    assert_ne!(other, id);
+    assert_eq!(recv(&mut remote).unwrap(), (other, OPEN, vec![]));
+
+    // once it has ended the ID is handed out again, and messages on it go to the new connection only
+    first.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(recv_until_eof(&mut remote, id), b"");
+    send_to(&remote, id, EOF, &[]);
+    assert_shut_down(&mut first);
+    side.wait_forgotten(id);
+    lock(&side.streams).next = id;
+    let (mut third, reused) = side.open();
+    assert_eq!(reused, id);
+    assert_eq!(recv(&mut remote).unwrap(), (id, OPEN, vec![]));
+    send_to(&remote, id, DATA, b"for the new connection");
+    let mut data = [0u8; 22];
+    third.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"for the new connection");
+
+    // an old stream ending late leaves the new one alone
+    let (old, _) = Stream::new();
+    end(&side.streams, id, &old);
+    end(&side.streams, id, &old);
+    assert!(side.stream(id).is_some_and(|stream| !Arc::ptr_eq(&stream, &old)));
+  }
+
+}
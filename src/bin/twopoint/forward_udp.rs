@@ -0,0 +1,360 @@
+//! `twopoint forward-udp`, carrying a UDP service over a link.
+//!
+//! The side with `--listen` takes datagrams from local clients and sends them
+//! to the other side, each client on its own unreliable channel, so the
+//! channel ID is the flow ID. The side with `--to` sends each flow on to the
+//! target from a socket of its own, and sends the replies back on the same
+//! channel. Flows that are idle for a minute are forgotten on both sides, and
+//! the side with `--to` forgets all of them when the link starts a new session.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use twopoint::{Channel, ChannelMode, PeerSender, PeerReceiver, can_reconnect, is_message_too_large, is_new_session, to_unspecified};
+
+use crate::args::{self, Args};
+use crate::lock;
+
+/// Time after which an idle flow is forgotten
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest UDP datagram
+const MAXIMUM_DATAGRAM_SIZE: usize = 65535;
+
+pub fn run(mut args: Args) -> Result<(), args::Error> {
+  let listen = args.optional::<SocketAddr>("--listen")?;
+  let to = args.optional::<SocketAddr>("--to")?;
+  let via = args.optional::<SocketAddr>("--via")?;
+  let peer = crate::link(&mut args, via)?;
+  args.finish()?;
+  let (sender, receiver) = peer.split();
+  match (listen, to) {
+    (Some(listen), None) => Ok(listen_side(UdpSocket::bind(listen)?, sender, receiver)?),
+    (None, Some(to)) => Ok(target_side(to, sender, receiver, FLOW_TIMEOUT)?),
+    _ => Err("forward-udp needs either --listen or --to".into()),
+  }
+}
+
+/// Sends a datagram on a flow's channel, dropping it if the link is not up.
+fn forward(channel: &mut Channel, datagram: &[u8]) -> io::Result<()> {
+  match channel.send(&mut datagram.to_vec()) {
+    Ok(()) => Ok(()),
+    // datagrams are lost while the other side can't be reached, like on any link
+    Err(e) if can_reconnect(&e) || is_message_too_large(&e) => Ok(()),
+    Err(e) => Err(e),
+  }
+}
+
+/// Local clients, one flow each.
+#[derive(Default)]
+struct Clients {
+  by_addr: HashMap<SocketAddr, u16>,
+  by_flow: HashMap<u16, Client>,
+  next: u16,
+}
+
+struct Client {
+  addr: SocketAddr,
+  channel: Channel,
+  seen: Instant,
+}
+
+impl Clients {
+
+  /// Returns the client at the given address, making up a new flow for it if needed.
+  fn get(&mut self, addr: SocketAddr, sender: &PeerSender, now: Instant) -> io::Result<&mut Client> {
+    let flow = match self.by_addr.get(&addr) {
+      Some(&flow) => flow,
+      None => {
+        self.prune(now);
+        let flow = (0..=u16::MAX)
+          .map(|offset| self.next.wrapping_add(offset))
+          .find(|flow| !self.by_flow.contains_key(flow))
+          .ok_or_else(|| io::Error::other("too many flows"))?;
+        self.next = flow.wrapping_add(1);
+        self.by_addr.insert(addr, flow);
+        self.by_flow.insert(flow, Client { addr, channel: sender.channel(flow, ChannelMode::Unreliable), seen: now });
+        flow
+      }
+    };
+    let client = self.by_flow.get_mut(&flow).expect("flows are indexed by address");
+    client.seen = now;
+    Ok(client)
+  }
+
+  fn prune(&mut self, now: Instant) {
+    let by_addr = &mut self.by_addr;
+    self.by_flow.retain(|_, client| {
+      let alive = now < client.seen + FLOW_TIMEOUT;
+      if !alive {
+        by_addr.remove(&client.addr);
+      }
+      alive
+    });
+  }
+
+}
+
+fn listen_side(local: UdpSocket, sender: PeerSender, mut receiver: PeerReceiver) -> io::Result<()> {
+  eprintln!("twopoint: forwarding datagrams sent to {}", local.local_addr()?);
+  let local = Arc::new(local);
+  let clients = Arc::new(Mutex::new(Clients::default()));
+
+  let replies = {
+    let (local, clients) = (Arc::clone(&local), Arc::clone(&clients));
+    thread::spawn(move || -> io::Result<()> {
+      let mut buffer = Vec::new();
+      loop {
+        let flow = match crate::recv_channel(&mut receiver, &mut buffer) {
+          Ok(Some(flow)) => flow,
+          Ok(None) => continue,
+          // datagrams are not kept in step with the other side, so flows carry on in a new session
+          Err(e) if is_new_session(&e) => continue,
+          Err(e) => return Err(e),
+        };
+        let addr = lock(&clients).by_flow.get(&flow).map(|client| client.addr);
+        if let Some(addr) = addr {
+          local.send_to(&buffer, addr)?;
+        }
+      }
+    })
+  };
+
+  let mut buffer = vec![0u8; MAXIMUM_DATAGRAM_SIZE];
+  loop {
+    if replies.is_finished() {
+      return replies.join().expect("reply thread panicked");
+    }
+    let (len, from) = match local.recv_from(&mut buffer) {
+      Ok(received) => received,
+      // ICMP errors from clients that have gone away
+      Err(e) if can_reconnect(&e) => continue,
+      Err(e) => return Err(e),
+    };
+    let mut clients = lock(&clients);
+    let client = clients.get(from, &sender, Instant::now())?;
+    forward(&mut client.channel, &buffer[..len])?;
+  }
+}
+
+/// Flows to the target, one socket each.
+type Flows = HashMap<u16, Flow>;
+
+struct Flow {
+  socket: Arc<UdpSocket>,
+  seen: Instant,
+}
+
+fn target_side(to: SocketAddr, sender: PeerSender, mut receiver: PeerReceiver, timeout: Duration) -> io::Result<()> {
+  eprintln!("twopoint: forwarding datagrams on to {to}");
+  let flows = Arc::new(Mutex::new(Flows::new()));
+  let mut buffer = Vec::new();
+  loop {
+    let flow = match crate::recv_channel(&mut receiver, &mut buffer) {
+      Ok(Some(flow)) => flow,
+      Ok(None) => continue,
+      // a restarted other side numbers its flows from scratch
+      Err(e) if is_new_session(&e) => {
+        lock(&flows).clear();
+        continue;
+      }
+      Err(e) => return Err(e),
+    };
+    let socket = {
+      let mut table = lock(&flows);
+      match table.get_mut(&flow) {
+        Some(existing) => {
+          existing.seen = Instant::now();
+          Arc::clone(&existing.socket)
+        }
+        None => {
+          let socket = Arc::new(UdpSocket::bind(to_unspecified(to))?);
+          socket.connect(to)?;
+          socket.set_read_timeout(Some(timeout))?;
+          table.insert(flow, Flow { socket: Arc::clone(&socket), seen: Instant::now() });
+          let channel = sender.channel(flow, ChannelMode::Unreliable);
+          let (replies, flows) = (Arc::clone(&socket), Arc::clone(&flows));
+          thread::spawn(move || reply(flow, channel, replies, &flows, timeout));
+          socket
+        }
+      }
+    };
+    match socket.send(&buffer) {
+      Ok(_) => {}
+      // the target is not up, like a lost datagram
+      Err(e) if can_reconnect(&e) => {}
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Sends replies from the target back on the flow's channel, until the flow is idle for `timeout` or forgotten.
+fn reply(flow: u16, mut channel: Channel, socket: Arc<UdpSocket>, flows: &Mutex<Flows>, timeout: Duration) {
+  let mut buffer = vec![0u8; MAXIMUM_DATAGRAM_SIZE];
+  loop {
+    match socket.recv(&mut buffer) {
+      Ok(len) => {
+        if let Err(e) = forward(&mut channel, &buffer[..len]) {
+          eprintln!("twopoint: failed to forward a reply: {e}");
+        }
+      }
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+        let mut flows = lock(flows);
+        // the flow ID may have been taken over by a new flow already
+        match flows.get(&flow) {
+          Some(existing) if !Arc::ptr_eq(&existing.socket, &socket) => return,
+          Some(existing) if existing.seen.elapsed() >= timeout => {
+            flows.remove(&flow);
+            return;
+          }
+          Some(_) => {}
+          None => return,
+        }
+      }
+      // ICMP errors while the target is not up
+      Err(e) if can_reconnect(&e) => {}
+      Err(e) => {
+        eprintln!("twopoint: failed to receive a reply: {e}");
+        let mut flows = lock(flows);
+        if flows.get(&flow).is_some_and(|existing| Arc::ptr_eq(&existing.socket, &socket)) {
+          flows.remove(&flow);
+        }
+        return;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use twopoint::{Peer, Role};
+
+  use crate::tests::{TIMEOUT, peers};
+
+  /// Receives the next datagram on any flow.
+  fn recv(remote: &mut Peer) -> (u16, Vec<u8>) {
+    let mut buffer = Vec::new();
+    loop {
+      if let Some(flow) = remote.recv_channel(&mut buffer).expect("no datagram arrived") {
+        return (flow, buffer);
+      }
+    }
+  }
+
+  fn send(remote: &Peer, flow: u16, datagram: &[u8]) {
+    remote.channel(flow, ChannelMode::Unreliable).send(&mut datagram.to_vec()).expect("failed to send");
+  }
+
+  fn client() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket
+  }
+
+  /// Starts a UDP server that answers every datagram with the address it came from.
+  fn target() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+      let mut buffer = [0u8; 64];
+      while let Ok((_, from)) = socket.recv_from(&mut buffer) {
+        let _ = socket.send_to(from.to_string().as_bytes(), from);
+      }
+    });
+    addr
+  }
+
+  /// Sends a datagram on a flow and returns the address the target saw it come from.
+  fn source(remote: &mut Peer, flow: u16) -> String {
+    send(remote, flow, b"where from");
+    let (reply_flow, reply) = recv(remote);
+    assert_eq!(reply_flow, flow);
+    String::from_utf8(reply).unwrap()
+  }
+
+  #[test]
+  fn test_listen_side() {
+    let (peer, mut remote) = peers(Role::Initiator);
+    let (sender, receiver) = peer.split();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = local.local_addr().unwrap();
+    thread::spawn(move || listen_side(local, sender, receiver));
+
+    // every client gets a flow of its own, and keeps it
+    let (first, second) = (client(), client());
+    first.send_to(b"from the first", addr).unwrap();
+    let (first_flow, datagram) = recv(&mut remote);
+    assert_eq!(datagram, b"from the first");
+    second.send_to(b"from the second", addr).unwrap();
+    let (second_flow, datagram) = recv(&mut remote);
+    assert_eq!(datagram, b"from the second");
+    assert_ne!(first_flow, second_flow);
+    first.send_to(b"again", addr).unwrap();
+    assert_eq!(recv(&mut remote), (first_flow, b"again".to_vec()));
+
+    // and replies on a flow go back to its client only
+    for (client, flow) in [(&first, first_flow), (&second, second_flow)] {
+      let reply = format!("reply on flow {flow}");
+      send(&remote, flow, reply.as_bytes());
+      let mut buffer = [0u8; 64];
+      let (len, from) = client.recv_from(&mut buffer).unwrap();
+      assert_eq!((&buffer[..len], from), (reply.as_bytes(), addr));
+    }
+  }
+
+  #[test]
+  fn test_flow_timeout() {
+    let (peer, _remote) = peers(Role::Initiator);
+    let (sender, _) = peer.split();
+    let [first, second, third] = [1, 2, 3].map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+    let start = Instant::now();
+    let mut clients = Clients::default();
+    let flow = clients.get(first, &sender, start).unwrap().channel.id();
+
+    // a flow in use keeps its ID
+    clients.next = flow;
+    let second_flow = clients.get(second, &sender, start + FLOW_TIMEOUT / 2).unwrap().channel.id();
+    assert_ne!(second_flow, flow);
+
+    // once it has been idle for the timeout, a new client can take its ID over
+    clients.next = flow;
+    assert_eq!(clients.get(third, &sender, start + FLOW_TIMEOUT).unwrap().channel.id(), flow);
+    assert_eq!(clients.by_flow[&flow].addr, third);
+    assert!(!clients.by_addr.contains_key(&first));
+    assert_eq!(clients.by_addr[&second], second_flow);
+
+    // and the client that went quiet gets a new flow when it comes back
+    let back = clients.get(first, &sender, start + FLOW_TIMEOUT).unwrap().channel.id();
+    assert!(back != flow && back != second_flow);
+  }
+
+  #[test]
+  fn test_target_side() {
+    let (peer, mut remote) = peers(Role::Responder);
+    let (sender, receiver) = peer.split();
+    let timeout = Duration::from_millis(300);
+    let to = target();
+    thread::spawn(move || target_side(to, sender, receiver, timeout));
+
+    // every flow goes on to the target from a socket of its own, kept while the flow is in use
+    let first = source(&mut remote, 5);
+    assert_ne!(source(&mut remote, 6), first);
+    assert_eq!(source(&mut remote, 5), first);
+
+    // an idle flow is forgotten, and its ID starts a new flow
+    thread::sleep(timeout * 3);
+    let second = source(&mut remote, 5);
+    assert_ne!(second, first);
+
+    // so is every flow once the other side starts a new session
+    remote.handshake().expect("handshake failed");
+    assert_ne!(source(&mut remote, 5), second);
+  }
+
+}
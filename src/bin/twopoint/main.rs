@@ -0,0 +1,221 @@
+//! Command-line tool for twopoint links.
+//!
+//! ```text
+//! twopoint <COMMAND> [OPTIONS]
+//! ```
+//!
+//! Every command but `keygen` takes the pre-shared key from `--key` or the
+//! `TWOPOINT_KEY` environment variable. The side given the address of the
+//! other side, with `--via` or as in `connect <PEER>`, is the initiator, and
+//! the other side, bound to `--bind`, waits for it to show up as the responder.
+
+mod args;
+mod forward_tcp;
+mod forward_udp;
+mod ping;
+mod pipe;
+
+use std::env;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use twopoint::{Peer, PeerReceiver, Channel, Key, Role, KeepalivePolicy, CryptoError, ReplayError, NewSessionError, can_retry, can_reconnect, is_dead_peer, is_new_session};
+
+use args::Args;
+
+const USAGE: &str = "\
+usage: twopoint <COMMAND> [OPTIONS]
+
+commands:
+  keygen                                     print a new random key
+  registration-key                           print the key rendezvous and relay servers check registrations with
+  listen                                     pipe stdin and stdout to the other side, waiting for it
+  connect <PEER>                             pipe stdin and stdout to the other side at PEER
+  ping <PEER> [--count <N>]                  measure the round-trip time to a listen side that is not piping yet
+  forward-udp --listen <ADDR> --via <PEER>   forward UDP datagrams sent to ADDR to the other side
+  forward-udp --to <ADDR>                    send datagrams from the other side on to ADDR
+  forward-tcp --listen <ADDR> --via <PEER>   forward TCP connections to ADDR to the other side
+  forward-tcp --to <ADDR>                    connect to ADDR for connections from the other side
+
+options:
+  --key <HEX>     pre-shared key, or set TWOPOINT_KEY
+  --bind <ADDR>   local address of the link [default: 0.0.0.0:7477, or any port with PEER]";
+
+/// Local address the waiting side of a link binds to unless told otherwise
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:7477";
+
+/// Time to wait before sending again while too many messages are in flight, or the link is down
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+fn main() -> ExitCode {
+  let mut args = env::args().skip(1);
+  let command = args.next();
+  let args = args.collect::<Vec<_>>();
+  let result = match command.as_deref() {
+    Some("keygen") => Args::parse(&args).and_then(keygen),
+    Some("registration-key") => Args::parse(&args).and_then(registration_key),
+    Some("listen") => Args::parse(&args).and_then(pipe::listen),
+    Some("connect") => Args::parse(&args).and_then(pipe::connect),
+    Some("ping") => Args::parse(&args).and_then(ping::run),
+    Some("forward-udp") => Args::parse(&args).and_then(forward_udp::run),
+    Some("forward-tcp") => Args::parse(&args).and_then(forward_tcp::run),
+    Some("-h" | "--help") => {
+      println!("{USAGE}");
+      return ExitCode::SUCCESS;
+    }
+    Some(command) => Err(format!("unknown command {command}").into()),
+    None => Err("missing command".into()),
+  };
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(args::Error::Usage(e)) => {
+      eprintln!("twopoint: {e}\n\n{USAGE}");
+      ExitCode::FAILURE
+    }
+    Err(args::Error::Io(e)) => {
+      eprintln!("twopoint: {e}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+fn keygen(args: Args) -> Result<(), args::Error> {
+  args.finish()?;
+  println!("{}", Key::random().to_hex());
+  Ok(())
+}
+
+fn registration_key(mut args: Args) -> Result<(), args::Error> {
+  let key = args.key()?;
+  args.finish()?;
+  println!("{}", twopoint::registration_key(&key).to_hex());
+  Ok(())
+}
+
+/// Sets up the peer for one end of a link, from `--bind` and the key.
+///
+/// Given the address of the other side, the peer is the initiator, connects
+/// to it and handshakes until it answers. Without one, the peer is the
+/// responder and answers the other side's handshake once it shows up. Either
+/// way the peer roams, so both sides follow each other to new addresses, and
+/// sends keepalives.
+pub fn link(args: &mut Args, via: Option<SocketAddr>) -> Result<Peer, args::Error> {
+  link_until(args, via, None)
+}
+
+/// Sets up the peer like [`link`], but stops handshaking once `deadline` has
+/// passed and fails with the last handshake's error.
+pub fn link_until(args: &mut Args, via: Option<SocketAddr>, deadline: Option<Instant>) -> Result<Peer, args::Error> {
+  let key = args.key()?;
+  let bind = match (args.optional::<SocketAddr>("--bind")?, via) {
+    (Some(bind), _) => bind,
+    (None, Some(via)) => twopoint::to_unspecified(via),
+    (None, None) => DEFAULT_BIND_ADDR.parse().expect("default bind address is valid"),
+  };
+  let role = if via.is_some() { Role::Initiator } else { Role::Responder };
+  let mut peer = Peer::with_handshake(UdpSocket::bind(bind)?, key, role);
+  peer.set_roaming(true)?;
+  if let Some(via) = via {
+    peer.connect(via)?;
+    // nothing can be sent before there is a session, and the other side may not be up yet
+    until_answered(via, deadline, || peer.handshake())?;
+  } else {
+    eprintln!("twopoint: waiting for the other side on {}", peer.local_addr());
+  }
+  peer.set_keepalive_policy(Some(KeepalivePolicy::default()));
+  Ok(peer)
+}
+
+/// Sends a message on a reliable channel, waiting while too many messages are
+/// in flight or the other side can't be reached.
+pub fn send_reliable(channel: &mut Channel, message: &mut Vec<u8>) -> io::Result<()> {
+  loop {
+    match channel.send(message) {
+      // reliable messages are copied before they are sent, so the message is left as it is
+      Err(e) if can_reconnect(&e) => thread::sleep(RETRY_INTERVAL),
+      result => return result,
+    }
+  }
+}
+
+/// Handshakes until the other side answers, or until `deadline` has passed.
+///
+/// The deadline is checked between handshakes, each of which gives up on its
+/// own after a few seconds.
+fn until_answered(addr: SocketAddr, deadline: Option<Instant>, mut handshake: impl FnMut() -> io::Result<()>) -> io::Result<()> {
+  while let Err(e) = handshake() {
+    if !can_reconnect(&e) || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+      return Err(e);
+    }
+    eprintln!("twopoint: waiting for {addr} to answer");
+  }
+  Ok(())
+}
+
+/// Receives the next message like [`PeerReceiver::recv_channel`], skipping
+/// datagrams that did not come from the other side and reporting when it
+/// stops answering. Read timeouts are returned like other errors.
+///
+/// Once the other side stops answering, the initiator handshakes again, in
+/// case it restarted. Either way a new session fails with a [`NewSessionError`],
+/// so that streams kept in step with the other side can start over.
+pub fn recv_channel(receiver: &mut PeerReceiver, buffer: &mut Vec<u8>) -> io::Result<Option<u16>> {
+  loop {
+    match receiver.recv_channel(buffer) {
+      Ok(channel) => return Ok(channel),
+      Err(e) if is_dead_peer(&e) => {
+        let remote = receiver.remote_addr();
+        eprintln!("twopoint: {remote} stopped answering");
+        if receiver.role() == Some(Role::Initiator) {
+          until_answered(remote, None, || receiver.handshake())?;
+          return Err(NewSessionError.into());
+        }
+      }
+      Err(e) if can_retry(&e) || is_new_session(&e) => return Err(e),
+      Err(e) if can_reconnect(&e) || is_bad_datagram(&e) => {}
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Returns `true` if the error was caused by a datagram that was not from the other side.
+fn is_bad_datagram(e: &io::Error) -> bool {
+  e.kind() == io::ErrorKind::InvalidData
+    || e.get_ref().is_some_and(|inner| inner.is::<CryptoError>() || inner.is::<ReplayError>())
+}
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  pub const KEY: &str = "5adf5e4a8a779d4cd7985a881b270bcf";
+
+  /// How long anything in a test may take before it counts as stuck
+  pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+  /// Sets up two peers on loopback with a session between them, the first one with the given role.
+  pub fn peers(role: Role) -> (Peer, Peer) {
+    let key = KEY.parse::<Key>().unwrap();
+    let other = if role == Role::Initiator { Role::Responder } else { Role::Initiator };
+    let mut peer = Peer::with_handshake(UdpSocket::bind("127.0.0.1:0").unwrap(), key, role);
+    let mut remote = Peer::with_handshake(UdpSocket::bind("127.0.0.1:0").unwrap(), key, other);
+    peer.connect(remote.local_addr()).unwrap();
+    remote.connect(peer.local_addr()).unwrap();
+    thread::scope(|scope| {
+      let handshaking = scope.spawn(|| remote.handshake());
+      peer.handshake().expect("handshake failed");
+      handshaking.join().expect("handshake panicked").expect("handshake failed");
+    });
+    remote.set_read_timeout(Some(TIMEOUT)).unwrap();
+    (peer, remote)
+  }
+
+}
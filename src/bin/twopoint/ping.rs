@@ -0,0 +1,140 @@
+//! `twopoint ping`, measuring the round-trip time to the other side.
+//!
+//! Sends a numbered ping every second on a channel of its own, which the other
+//! side, running `twopoint listen`, sends straight back. Pings that are not
+//! answered before the next one is due count as lost, and so do all of them
+//! if the other side doesn't answer the handshake in the time they would have
+//! taken.
+//!
+//! The pinger handshakes with the other side like `twopoint connect` does, so
+//! it takes the link over. A `listen` side that is already piping fails, see
+//! [`crate::pipe`], which makes ping a check that the other side can be
+//! reached before the pipe starts, not something to run alongside it.
+
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use twopoint::{ChannelMode, Peer, can_reconnect, can_retry};
+
+use crate::args::{self, Args};
+use crate::pipe::PING;
+
+/// Number of pings sent unless told otherwise
+const DEFAULT_COUNT: u64 = 4;
+
+/// Time between pings, and how long each one waits for its answer
+const INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn run(mut args: Args) -> Result<(), args::Error> {
+  let via = args.operand::<SocketAddr>("peer address")?;
+  let count = args.optional::<u64>("--count")?.unwrap_or(DEFAULT_COUNT);
+  // the other side gets as long to answer the handshake as the pings would have taken
+  let deadline = Instant::now() + INTERVAL.saturating_mul(u32::try_from(count).unwrap_or(u32::MAX));
+  let peer = match crate::link_until(&mut args, Some(via), Some(deadline)) {
+    Ok(peer) => peer,
+    Err(args::Error::Io(e)) if can_reconnect(&e) => {
+      println!("no reply from {via}: the handshake was not answered");
+      summary(count, &[]);
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "no replies").into());
+    }
+    Err(e) => return Err(e),
+  };
+  args.finish()?;
+  Ok(ping(peer, count)?)
+}
+
+fn ping(peer: Peer, count: u64) -> io::Result<()> {
+  let remote = peer.remote_addr();
+  let mut channel = peer.channel(PING, ChannelMode::Unreliable);
+  let (_sender, mut receiver) = peer.split();
+  let mut rtts = Vec::new();
+  let mut buffer = Vec::new();
+
+  for sequence in 0..count {
+    let sent = Instant::now();
+    let due = sent + INTERVAL;
+    match channel.send(&mut sequence.to_be_bytes().to_vec()) {
+      Ok(()) => {}
+      // the other side can't be reached, so this one is lost
+      Err(e) if can_reconnect(&e) => {}
+      Err(e) => return Err(e),
+    }
+    let rtt = loop {
+      let remaining = due.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        break None;
+      }
+      receiver.set_read_timeout(Some(remaining))?;
+      match crate::recv_channel(&mut receiver, &mut buffer) {
+        Ok(Some(PING)) if buffer == sequence.to_be_bytes() => break Some(sent.elapsed()),
+        // late answers to earlier pings, and anything else
+        Ok(_) => {}
+        Err(e) if can_retry(&e) => {}
+        Err(e) => return Err(e),
+      }
+    };
+    match rtt {
+      Some(rtt) => {
+        println!("reply from {remote}: seq={sequence} time={:.3} ms", millis(rtt));
+        rtts.push(rtt);
+        if sequence + 1 < count {
+          thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+      }
+      None => println!("no reply from {remote}: seq={sequence}"),
+    }
+  }
+
+  summary(count, &rtts);
+  if rtts.is_empty() {
+    return Err(io::Error::new(io::ErrorKind::TimedOut, "no replies"));
+  }
+  Ok(())
+}
+
+/// Prints how many of `count` pings were lost, and the round-trip times of the rest.
+fn summary(count: u64, rtts: &[Duration]) {
+  let lost = count - rtts.len() as u64;
+  println!("{count} sent, {} received, {:.0}% lost", rtts.len(), 100.0 * lost as f64 / count.max(1) as f64);
+  if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+    let average = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    println!("round-trip min/avg/max = {:.3}/{:.3}/{:.3} ms", millis(*min), millis(average), millis(*max));
+  }
+}
+
+fn millis(duration: Duration) -> f64 {
+  duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use twopoint::Role;
+
+  use crate::tests::peers;
+
+  #[test]
+  fn test_ping() {
+    // the other side sends every ping straight back, like a pipe does
+    let (peer, mut remote) = peers(Role::Initiator);
+    let answering = thread::spawn(move || {
+      let mut pings = remote.channel(PING, ChannelMode::Unreliable);
+      let mut buffer = Vec::new();
+      for _ in 0..2 {
+        while remote.recv_channel(&mut buffer).expect("no ping arrived") != Some(PING) {}
+        pings.send(&mut buffer).expect("failed to answer");
+      }
+    });
+    ping(peer, 2).expect("pings were not answered");
+    answering.join().expect("answering panicked");
+
+    // pings nobody answers are lost
+    let (peer, _remote) = peers(Role::Initiator);
+    let error = ping(peer, 1).expect_err("a ping was answered");
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+  }
+
+}
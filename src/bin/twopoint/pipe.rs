@@ -0,0 +1,130 @@
+//! `twopoint listen` and `twopoint connect`, piping stdin and stdout over a
+//! link like netcat.
+//!
+//! Standard input is sent to the other side on a reliable ordered channel, and
+//! what the other side sends is written to standard output. Once standard
+//! input runs out, an empty message ends the stream. The command exits when
+//! both sides have ended their streams and the other side has acknowledged
+//! everything, so either side can read from a pipe or from a terminal.
+//!
+//! Both sides also answer `twopoint ping`, on a channel of its own, though
+//! only the `listen` side can be pinged. The pinger handshakes like any other
+//! initiator, so it takes the link over like a restarted other side would: a
+//! pipe carries on in the new session as long as nothing has gone through its
+//! stream yet, and fails once it has, so ping a `listen` side only before it
+//! starts piping.
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use twopoint::{Channel, ChannelMode, NewReno, Peer, can_retry, is_new_session};
+
+use crate::args::{self, Args};
+
+/// Channel carrying standard input and output
+pub const STREAM: u16 = 0;
+/// Channel carrying pings and their answers
+pub const PING: u16 = 1;
+
+/// How often the receiving side checks whether it is done
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time to wait for the last acknowledgements before giving up on them
+const LINGER: Duration = Duration::from_secs(5);
+
+pub fn listen(mut args: Args) -> Result<(), args::Error> {
+  let peer = crate::link(&mut args, None)?;
+  args.finish()?;
+  Ok(pipe(peer)?)
+}
+
+pub fn connect(mut args: Args) -> Result<(), args::Error> {
+  let via = args.operand::<SocketAddr>("peer address")?;
+  let peer = crate::link(&mut args, Some(via))?;
+  args.finish()?;
+  Ok(pipe(peer)?)
+}
+
+fn pipe(peer: Peer) -> io::Result<()> {
+  // standard input can be read a lot faster than the link carries it
+  peer.set_congestion_control(Some(Box::new(NewReno::new())));
+  let chunk = peer.max_channel_payload_size().max(1);
+  let (sender, mut receiver) = peer.split();
+  let stream = sender.channel(STREAM, ChannelMode::ReliableOrdered);
+  let mut pings = sender.channel(PING, ChannelMode::Unreliable);
+
+  let (started, ended) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+  {
+    let mut stream = sender.channel(STREAM, ChannelMode::ReliableOrdered);
+    let (started, ended) = (Arc::clone(&started), Arc::clone(&ended));
+    thread::spawn(move || {
+      if let Err(e) = send_input(&mut stream, chunk, &started) {
+        eprintln!("twopoint: failed to send: {e}");
+        process::exit(1);
+      }
+      ended.store(true, Ordering::Release);
+    });
+  }
+
+  receiver.set_read_timeout(Some(POLL_INTERVAL))?;
+  let mut stdout = io::stdout().lock();
+  let mut buffer = Vec::new();
+  let mut other_started = false;
+  let mut other_ended = false;
+  let mut done = None;
+  loop {
+    if done.is_none() && other_ended && ended.load(Ordering::Acquire) {
+      done = Some(Instant::now());
+    }
+    if let Some(done) = done
+      && (stream.in_flight() == 0 || done.elapsed() >= LINGER)
+    {
+      return Ok(());
+    }
+    match crate::recv_channel(&mut receiver, &mut buffer) {
+      Ok(Some(STREAM)) => {
+        other_started = true;
+        if buffer.is_empty() {
+          other_ended = true;
+        } else {
+          stdout.write_all(&buffer)?;
+          stdout.flush()?;
+        }
+      }
+      // a ping that can't be answered is lost, like any other
+      Ok(Some(PING)) => {
+        let _ = pings.send(&mut buffer);
+      }
+      Ok(_) => {}
+      Err(e) if can_retry(&e) => {}
+      // like a ping before the other side shows up, nothing of the stream went to the old session
+      Err(e) if is_new_session(&e) && !other_started && !started.load(Ordering::Acquire) => {}
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Sends standard input on the stream until it runs out, then ends the stream.
+///
+/// `started` is set before anything is sent.
+fn send_input(stream: &mut Channel, chunk: usize, started: &AtomicBool) -> io::Result<()> {
+  let mut stdin = io::stdin().lock();
+  let mut buffer = vec![0u8; chunk];
+  loop {
+    let len = match stdin.read(&mut buffer) {
+      Ok(len) => len,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    };
+    started.store(true, Ordering::Release);
+    crate::send_reliable(stream, &mut buffer[..len].to_vec())?;
+    if len == 0 {
+      return Ok(());
+    }
+  }
+}
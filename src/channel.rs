@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::reliable::{Reliable, Delivery, Acknowledgement, ACK_SIZE};
+
+/// Channel header size in bytes (channel ID + mode + sequence number)
+pub const CHANNEL_HEADER_SIZE: usize = 11;
+/// Channel acknowledgement size in bytes (channel ID + acknowledgement)
+pub const CHANNEL_ACK_SIZE: usize = 2 + ACK_SIZE;
+
+/// Delivery guarantees of a [`Channel`](crate::Channel).
+///
+/// The sender picks the mode, and the other side delivers each message the
+/// way the mode it was sent with says. Both sides should use the same mode
+/// for a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum ChannelMode {
+  /// Messages may be lost or reordered, like with [`Peer::send`](crate::Peer::send).
+  Unreliable = 0,
+  /// Messages are retransmitted until acknowledged and delivered in the order
+  /// they were sent, held back only by earlier messages on the same channel.
+  ReliableOrdered = 1,
+  /// Messages are retransmitted until acknowledged and delivered as soon as
+  /// they arrive, exactly once.
+  ReliableUnordered = 2,
+  /// Messages may be lost, and any message older than one already delivered is dropped.
+  LatestOnly = 3,
+}
+
+impl ChannelMode {
+
+  pub const fn from_u8(value: u8) -> Option<Self> {
+    match value {
+      0 => Some(Self::Unreliable),
+      1 => Some(Self::ReliableOrdered),
+      2 => Some(Self::ReliableUnordered),
+      3 => Some(Self::LatestOnly),
+      _ => None,
+    }
+  }
+
+  /// Returns `true` if messages are retransmitted until acknowledged.
+  pub const fn is_reliable(self) -> bool {
+    matches!(self, Self::ReliableOrdered | Self::ReliableUnordered)
+  }
+
+}
+
+/// Delivery state for every channel of one end of a link.
+///
+/// Every channel message starts with a small header, inside the encryption:
+///
+/// ```text
+/// +---------+------+----------+---------+
+/// | channel | mode | sequence | payload |
+/// +---------+------+----------+---------+
+///      2       1        8
+/// ```
+///
+/// Reliable channels number their messages and acknowledge them on their own,
+/// so a lost message only holds back its own channel. Latest-only channels
+/// number their messages too, to tell which one is newest.
+#[derive(Default)]
+pub struct Channels {
+  channels: HashMap<u16, State>,
+}
+
+#[derive(Default)]
+struct State {
+  reliable: Reliable,
+  /// Mode the channel was last sent on, for retransmissions
+  mode: Option<ChannelMode>,
+  /// Last latest-only sequence number sent
+  sent: u64,
+  /// Newest latest-only sequence number delivered
+  latest: Option<u64>,
+}
+
+impl Channels {
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes a message about to be sent and returns its sequence number,
+  /// queueing a copy of it if the channel is reliable.
+  ///
+  /// Returns `None` if too many messages are already waiting to be acknowledged.
+  pub fn push(&mut self, channel: u16, mode: ChannelMode, message: &[u8]) -> Option<u64> {
+    let state = self.channels.entry(channel).or_default();
+    state.mode = Some(mode);
+    match mode {
+      ChannelMode::ReliableOrdered | ChannelMode::ReliableUnordered => state.reliable.sender.push(message),
+      ChannelMode::LatestOnly => {
+        state.sent += 1;
+        Some(state.sent)
+      }
+      ChannelMode::Unreliable => Some(0),
+    }
+  }
+
+  /// Returns the mode and message with the given sequence number, if it is
+  /// still waiting to be acknowledged.
+  pub fn message(&self, channel: u16, sequence: u64) -> Option<(ChannelMode, &[u8])> {
+    let state = self.channels.get(&channel)?;
+    Some((state.mode?, state.reliable.sender.message(sequence)?))
+  }
+
+  /// Takes in an authenticated channel message, returning whether it should
+  /// be delivered and the acknowledgement to send back, if any.
+  pub fn receive(&mut self, channel: u16, mode: ChannelMode, sequence: u64, message: &[u8]) -> (Delivery, Option<[u8; ACK_SIZE]>) {
+    let state = self.channels.entry(channel).or_default();
+    let delivery = match mode {
+      ChannelMode::Unreliable => Delivery::Now,
+      ChannelMode::LatestOnly => {
+        if state.latest.is_some_and(|latest| sequence <= latest) {
+          return (Delivery::Dropped, None);
+        }
+        state.latest = Some(sequence);
+        Delivery::Now
+      }
+      ChannelMode::ReliableOrdered => state.reliable.receiver.receive(sequence, message),
+      ChannelMode::ReliableUnordered => state.reliable.receiver.receive_unordered(sequence),
+    };
+    if delivery == Delivery::Desync {
+      return (delivery, None);
+    }
+    let ack = mode.is_reliable().then(|| state.reliable.receiver.ack());
+    (delivery, ack)
+  }
+
+  /// Takes the next held back message that is ready to be delivered, along with its channel.
+  pub fn pop(&mut self) -> Option<(u16, Vec<u8>)> {
+    self.channels.iter_mut().find_map(|(channel, state)| Some((*channel, state.reliable.receiver.pop()?)))
+  }
+
+  /// Puts back a message taken with [`Channels::pop`] that could not be delivered after all.
+  pub fn unpop(&mut self, channel: u16, message: Vec<u8>) {
+    self.channels.entry(channel).or_default().reliable.receiver.unpop(message);
+  }
+
+  /// Returns the channel, sequence number and size of a queued reliable message, if any.
+  pub fn next_unsent(&self) -> Option<(u16, u64, usize)> {
+    self.channels.iter().find_map(|(channel, state)| {
+      let (sequence, len) = state.reliable.sender.next_unsent()?;
+      Some((*channel, sequence, len))
+    })
+  }
+
+  /// Records that a queued reliable message was sent for the first time.
+  pub fn mark_sent(&mut self, channel: u16, sequence: u64, now: Instant) {
+    if let Some(state) = self.channels.get_mut(&channel) {
+      state.reliable.sender.mark_sent(sequence, now);
+    }
+  }
+
+  /// Takes in an acknowledgement for one channel.
+  ///
+  /// Returns `None` if it acknowledges messages that were never sent, see [`Sender::acknowledge`](crate::reliable::Sender::acknowledge).
+  pub fn acknowledge(&mut self, channel: u16, ack: &[u8; ACK_SIZE]) -> Option<Acknowledgement> {
+    self.channels.entry(channel).or_default().reliable.sender.acknowledge(ack)
+  }
+
+  /// Returns the channels and sequence numbers whose retransmission timeout has run out.
+  pub fn expired(&mut self, now: Instant) -> Vec<(u16, u64)> {
+    self.channels.iter_mut()
+      .flat_map(|(channel, state)| state.reliable.sender.expired(now).into_iter().map(|sequence| (*channel, sequence)))
+      .collect()
+  }
+
+  /// Returns when retransmissions next need to be checked on, if any channel is reliable.
+  pub fn deadline(&self, now: Instant) -> Option<Instant> {
+    self.channels.values().filter_map(|state| state.reliable.deadline(now)).min()
+  }
+
+  /// Returns the number of messages on the channel waiting to be acknowledged.
+  pub fn in_flight(&self, channel: u16) -> usize {
+    self.channels.get(&channel).map_or(0, |state| state.reliable.sender.in_flight())
+  }
+
+}
+
+/// Returns the header for a channel message.
+pub fn header(channel: u16, mode: ChannelMode, sequence: u64) -> [u8; CHANNEL_HEADER_SIZE] {
+  let mut header = [0u8; CHANNEL_HEADER_SIZE];
+  header[0..2].copy_from_slice(&channel.to_be_bytes());
+  header[2] = mode as u8;
+  header[3..11].copy_from_slice(&sequence.to_be_bytes());
+  header
+}
+
+/// Splits a channel message into its channel, mode, sequence number and payload.
+pub fn parse(message: &[u8]) -> Option<(u16, ChannelMode, u64, &[u8])> {
+  let (header, payload) = message.split_first_chunk::<CHANNEL_HEADER_SIZE>()?;
+  let channel = u16::from_be_bytes(header[0..2].try_into().unwrap());
+  let mode = ChannelMode::from_u8(header[2])?;
+  let sequence = u64::from_be_bytes(header[3..11].try_into().unwrap());
+  Some((channel, mode, sequence, payload))
+}
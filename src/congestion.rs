@@ -0,0 +1,455 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Congestion control algorithm for reliable messages.
+///
+/// The algorithm decides how many bytes of reliable messages may be waiting to
+/// be acknowledged at once, the congestion window, and how quickly they are
+/// sent, the pacing rate. Messages that don't fit into the window are queued
+/// and sent from inside `recv()` once acknowledgements make room for them.
+///
+/// [`NewReno`] and [`Bbr`] are built in, other algorithms can be plugged in by
+/// implementing this trait. The peer keeps track of the bytes in flight and
+/// does the pacing itself, the algorithm only has to follow the events below.
+pub trait CongestionControl: Send {
+
+  /// Returns the congestion window in bytes.
+  ///
+  /// Something may always be sent while nothing is in flight, however small the window.
+  fn window(&self) -> usize;
+
+  /// Returns the rate to pace reliable messages out at in bytes per second,
+  /// or `None` to send them as soon as the window has room. A rate that is not
+  /// finite and positive doesn't pace either.
+  fn pacing_rate(&self) -> Option<f64> {
+    None
+  }
+
+  /// Called when reliable messages of `bytes` in total are acknowledged, with
+  /// the round-trip time if it could be measured and the bytes still in flight.
+  fn on_ack(&mut self, now: Instant, bytes: usize, rtt: Option<Duration>, in_flight: usize);
+
+  /// Called when a message was lost and is being retransmitted early.
+  fn on_loss(&mut self, now: Instant);
+
+  /// Called when retransmission timeouts ran out.
+  fn on_timeout(&mut self, now: Instant);
+
+  /// Starts over with nothing known about the path, once the reliable senders started over.
+  fn reset(&mut self);
+
+}
+
+/// Limit on the rate of unreliable messages, enforced with a token bucket.
+///
+/// The bucket holds up to `burst` bytes and refills at `bytes_per_second`.
+/// Sending takes the message's size out of the bucket, and fails with an
+/// [`io::ErrorKind::WouldBlock`](std::io::ErrorKind::WouldBlock) error while
+/// the bucket is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+  /// Bytes that may be sent per second on average.
+  pub bytes_per_second: u64,
+  /// Bytes that may be sent at once after a quiet period.
+  pub burst: u64,
+}
+
+impl RateLimit {
+
+  /// Default number of bytes that may be sent at once
+  pub const DEFAULT_BURST: u64 = 64 * 1024;
+
+  /// Creates a rate limit of `bytes_per_second` with the default burst size.
+  pub const fn new(bytes_per_second: u64) -> Self {
+    Self { bytes_per_second, burst: Self::DEFAULT_BURST }
+  }
+
+}
+
+/// Assumed size of a message when growing and shrinking the window
+const MESSAGE_SIZE: usize = 1200;
+/// Window before anything is known about the path, as in RFC 6928
+const INITIAL_WINDOW: usize = 10 * MESSAGE_SIZE;
+/// Smallest window, so that something can always be sent
+const MINIMUM_WINDOW: usize = 2 * MESSAGE_SIZE;
+
+/// Congestion and rate limiting state shared by every sender of a peer.
+pub struct Congestion {
+  controller: Option<Box<dyn CongestionControl>>,
+  /// Bytes of reliable messages sent and not yet acknowledged
+  in_flight: usize,
+  /// Earliest time the pacer lets the next reliable message go
+  next_send: Instant,
+  limiter: Option<TokenBucket>,
+}
+
+impl Congestion {
+
+  pub fn new() -> Self {
+    Self { controller: None, in_flight: 0, next_send: Instant::now(), limiter: None }
+  }
+
+  /// Switches to a new algorithm.
+  pub fn set_algorithm(&mut self, algorithm: Option<Box<dyn CongestionControl>>) {
+    self.controller = algorithm;
+    self.next_send = Instant::now();
+  }
+
+  /// Returns the congestion window in bytes, or `None` without congestion control.
+  pub fn window(&self) -> Option<usize> {
+    self.controller.as_ref().map(|controller| controller.window())
+  }
+
+  /// Returns `true` if a reliable message of `bytes` may be sent now.
+  ///
+  /// Something may always be sent while nothing is in flight, however small the window.
+  pub fn can_send(&self, now: Instant, bytes: usize) -> bool {
+    let Some(window) = self.window() else {
+      return true;
+    };
+    (self.in_flight == 0 || self.in_flight + bytes <= window) && now >= self.next_send
+  }
+
+  /// Returns when the pacer next lets a message go, if it is holding one back.
+  pub fn deadline(&self, now: Instant) -> Option<Instant> {
+    self.controller.as_ref()?;
+    (self.next_send > now).then_some(self.next_send)
+  }
+
+  /// Records that a reliable message of `bytes` was sent for the first time.
+  pub fn sent(&mut self, now: Instant, bytes: usize) {
+    self.in_flight += bytes;
+    let Some(controller) = &self.controller else {
+      return;
+    };
+    if let Some(rate) = controller.pacing_rate().filter(|rate| rate.is_finite() && *rate > 0.0)
+      && let Ok(interval) = Duration::try_from_secs_f64(bytes as f64 / rate)
+      && let Some(next_send) = self.next_send.max(now).checked_add(interval)
+    {
+      self.next_send = next_send;
+    }
+  }
+
+  /// Records that reliable messages of `bytes` in total were acknowledged.
+  pub fn acknowledged(&mut self, now: Instant, bytes: usize, rtt: Option<Duration>) {
+    self.in_flight = self.in_flight.saturating_sub(bytes);
+    let in_flight = self.in_flight;
+    if let Some(controller) = &mut self.controller {
+      controller.on_ack(now, bytes, rtt, in_flight);
+    }
+  }
+
+  /// Records that a message was lost and is being retransmitted early.
+  pub fn lost(&mut self, now: Instant) {
+    if let Some(controller) = &mut self.controller {
+      controller.on_loss(now);
+    }
+  }
+
+  /// Records that retransmission timeouts ran out.
+  pub fn timed_out(&mut self, now: Instant) {
+    if let Some(controller) = &mut self.controller {
+      controller.on_timeout(now);
+    }
+  }
+
+  /// Forgets everything in flight, after the reliable senders started over.
+  pub fn reset(&mut self) {
+    self.in_flight = 0;
+    self.next_send = Instant::now();
+    if let Some(controller) = &mut self.controller {
+      controller.reset();
+    }
+  }
+
+  pub fn rate_limit(&self) -> Option<RateLimit> {
+    self.limiter.as_ref().map(|limiter| limiter.limit)
+  }
+
+  /// Sets the rate limit for unreliable messages, starting with a full bucket.
+  pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+    self.limiter = limit.map(TokenBucket::new);
+  }
+
+  /// Takes `bytes` out of the rate limit's bucket, returning `false` if it is empty.
+  pub fn take(&mut self, now: Instant, bytes: usize) -> bool {
+    match &mut self.limiter {
+      Some(limiter) => limiter.take(now, bytes),
+      None => true,
+    }
+  }
+
+}
+
+impl Default for Congestion {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Token bucket for a [`RateLimit`].
+///
+/// A message is let through as long as the bucket is not empty, even if it is
+/// larger than what is left, so messages larger than the burst size still get
+/// through once the bucket has refilled.
+struct TokenBucket {
+  limit: RateLimit,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+
+  fn new(limit: RateLimit) -> Self {
+    Self { limit, tokens: limit.burst as f64, updated: Instant::now() }
+  }
+
+  fn take(&mut self, now: Instant, bytes: usize) -> bool {
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.limit.bytes_per_second as f64).min(self.limit.burst as f64);
+    self.updated = now;
+    if self.tokens <= 0.0 {
+      return false;
+    }
+    self.tokens -= bytes as f64;
+    true
+  }
+
+}
+
+/// NewReno congestion control, as in RFC 5681 and RFC 6582.
+///
+/// The window grows by the acknowledged bytes during slow start, and by about
+/// one message per round trip after that. A loss halves it, at most once per
+/// round trip, and a retransmission timeout starts over from the smallest window.
+pub struct NewReno {
+  window: usize,
+  threshold: usize,
+  /// Growth carried over between acknowledgements in congestion avoidance
+  acknowledged: usize,
+  srtt: Option<Duration>,
+  /// Losses until then belong to the same round trip as the last one
+  recovery_until: Option<Instant>,
+}
+
+impl NewReno {
+
+  pub fn new() -> Self {
+    Self { window: INITIAL_WINDOW, threshold: usize::MAX, acknowledged: 0, srtt: None, recovery_until: None }
+  }
+
+}
+
+impl Default for NewReno {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl CongestionControl for NewReno {
+
+  fn window(&self) -> usize {
+    self.window
+  }
+
+  /// Paces at a little over one window per round trip, to smooth out bursts.
+  fn pacing_rate(&self) -> Option<f64> {
+    let srtt = self.srtt?.as_secs_f64().max(0.000_1);
+    Some(1.25 * self.window as f64 / srtt)
+  }
+
+  fn on_ack(&mut self, _now: Instant, bytes: usize, rtt: Option<Duration>, _in_flight: usize) {
+    if let Some(rtt) = rtt {
+      self.srtt = Some(self.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+    }
+    if self.window < self.threshold {
+      self.window += bytes;
+      return;
+    }
+    self.acknowledged += bytes;
+    if self.acknowledged >= self.window {
+      self.acknowledged -= self.window;
+      self.window += MESSAGE_SIZE;
+    }
+  }
+
+  fn on_loss(&mut self, now: Instant) {
+    if self.recovery_until.is_some_and(|until| now < until) {
+      return;
+    }
+    self.threshold = (self.window / 2).max(MINIMUM_WINDOW);
+    self.window = self.threshold;
+    self.acknowledged = 0;
+    self.recovery_until = Some(now + self.srtt.unwrap_or(Duration::from_secs(1)));
+  }
+
+  fn on_timeout(&mut self, now: Instant) {
+    self.threshold = (self.window / 2).max(MINIMUM_WINDOW);
+    self.window = MINIMUM_WINDOW;
+    self.acknowledged = 0;
+    self.recovery_until = Some(now + self.srtt.unwrap_or(Duration::from_secs(1)));
+  }
+
+  fn reset(&mut self) {
+    *self = Self::new();
+  }
+
+}
+
+/// Phase of [`Bbr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+  /// Doubling the sending rate every round trip until the bandwidth stops growing
+  Startup,
+  /// Draining the queue built up during startup
+  Drain,
+  /// Cycling the sending rate around the bottleneck bandwidth to notice changes
+  ProbeBandwidth,
+}
+
+/// Congestion control in the style of BBR.
+///
+/// Measures the bottleneck bandwidth, as the highest delivery rate over the
+/// last few round trips, and the minimum round-trip time, then paces at the
+/// bandwidth and keeps twice the bandwidth-delay product in flight. Losses
+/// don't shrink the window, only a retransmission timeout does until the next
+/// round trip. There is no separate phase for probing the round-trip time,
+/// the minimum simply expires after a while.
+pub struct Bbr {
+  phase: Phase,
+  /// Highest delivery rate of each of the last few round trips, in bytes per second
+  bandwidth: VecDeque<f64>,
+  min_rtt: Option<(Duration, Instant)>,
+  /// Bytes acknowledged in the current round trip, and when it started
+  round: (usize, Instant),
+  /// Bandwidth at the last round trip where it grew, and round trips since then
+  plateau: (f64, u32),
+  /// Position in the pacing gain cycle
+  cycle: usize,
+  /// Smallest window until the next round trip, after a retransmission timeout
+  recovering: bool,
+}
+
+impl Bbr {
+
+  /// Pacing gain during startup, 2/ln(2)
+  const STARTUP_GAIN: f64 = 2.885;
+  /// Pacing gains cycled through once the bandwidth is known
+  const CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+  /// Window in bandwidth-delay products
+  const WINDOW_GAIN: f64 = 2.0;
+  /// Number of round trips the bandwidth is the highest of
+  const BANDWIDTH_ROUNDS: usize = 10;
+  /// How long the minimum round-trip time is trusted for
+  const MIN_RTT_EXPIRY: Duration = Duration::from_secs(10);
+
+  pub fn new() -> Self {
+    Self {
+      phase: Phase::Startup,
+      bandwidth: VecDeque::new(),
+      min_rtt: None,
+      round: (0, Instant::now()),
+      plateau: (0.0, 0),
+      cycle: 0,
+      recovering: false,
+    }
+  }
+
+  fn bottleneck_bandwidth(&self) -> Option<f64> {
+    self.bandwidth.iter().copied().reduce(f64::max)
+  }
+
+  fn bdp(&self) -> Option<f64> {
+    Some(self.bottleneck_bandwidth()? * self.min_rtt?.0.as_secs_f64())
+  }
+
+  fn pacing_gain(&self) -> f64 {
+    match self.phase {
+      Phase::Startup => Self::STARTUP_GAIN,
+      Phase::Drain => 1.0 / Self::STARTUP_GAIN,
+      Phase::ProbeBandwidth => Self::CYCLE[self.cycle],
+    }
+  }
+
+}
+
+impl Default for Bbr {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl CongestionControl for Bbr {
+
+  fn window(&self) -> usize {
+    if self.recovering {
+      return MINIMUM_WINDOW;
+    }
+    match self.bdp() {
+      Some(bdp) => ((Self::WINDOW_GAIN * bdp) as usize).max(MINIMUM_WINDOW),
+      None => INITIAL_WINDOW,
+    }
+  }
+
+  fn pacing_rate(&self) -> Option<f64> {
+    Some(self.pacing_gain() * self.bottleneck_bandwidth()?)
+  }
+
+  fn on_ack(&mut self, now: Instant, bytes: usize, rtt: Option<Duration>, in_flight: usize) {
+    if let Some(rtt) = rtt
+      && self.min_rtt.is_none_or(|(min_rtt, measured)| rtt <= min_rtt || now - measured > Self::MIN_RTT_EXPIRY)
+    {
+      self.min_rtt = Some((rtt, now));
+    }
+    self.round.0 += bytes;
+
+    // a round trip is over once a minimum round-trip time has gone by
+    let Some((min_rtt, _)) = self.min_rtt else {
+      return;
+    };
+    let elapsed = now - self.round.1;
+    if elapsed < min_rtt || elapsed.is_zero() {
+      return;
+    }
+    let rate = self.round.0 as f64 / elapsed.as_secs_f64();
+    self.round = (0, now);
+    self.recovering = false;
+    self.bandwidth.push_back(rate);
+    if self.bandwidth.len() > Self::BANDWIDTH_ROUNDS {
+      self.bandwidth.pop_front();
+    }
+
+    let bandwidth = self.bottleneck_bandwidth().unwrap_or(0.0);
+    match self.phase {
+      Phase::Startup => {
+        if bandwidth >= self.plateau.0 * 1.25 {
+          self.plateau = (bandwidth, 0);
+        } else {
+          self.plateau.1 += 1;
+          if self.plateau.1 >= 3 {
+            self.phase = Phase::Drain;
+          }
+        }
+      }
+      Phase::Drain => {
+        if self.bdp().is_some_and(|bdp| in_flight as f64 <= bdp) {
+          self.phase = Phase::ProbeBandwidth;
+          self.cycle = 0;
+        }
+      }
+      Phase::ProbeBandwidth => self.cycle = (self.cycle + 1) % Self::CYCLE.len(),
+    }
+  }
+
+  fn on_loss(&mut self, _now: Instant) {}
+
+  fn on_timeout(&mut self, now: Instant) {
+    self.recovering = true;
+    self.round = (0, now);
+  }
+
+  fn reset(&mut self) {
+    *self = Self::new();
+  }
+
+}
@@ -1,57 +1,647 @@
-use rand::SeedableRng;
-use rand_chacha::ChaCha8Rng;
-use aes_gcm::{aead::{AeadCore, AeadInOut, KeyInit}, Aes128Gcm, Key as CryptoKey, Nonce};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use aes_gcm::{aead::{AeadInOut, KeyInit}, Aes128Gcm, Aes256Gcm};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use aes_gcm_siv::{Aes128GcmSiv, Aes256GcmSiv};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::util::{unix_nanos, random_connection_id};
 use crate::key::Key;
-use crate::error::CryptoError;
+use crate::error::{CryptoError, ReplayError, NoSessionError, InvalidKeyError};
+use crate::replay::ReplayWindow;
+use crate::message::{MessageType, Header};
+use crate::rekey::{RekeyPolicy, Epoch, SendChain, RecvChain, Lookup};
+
+/// Which end of a handshake a peer is on.
+///
+/// Both ends derive the same session key, so each direction gets its own half
+/// of the nonce space to keep nonces unique. The two peers of a link must be
+/// set up with opposite roles, otherwise every message fails to decrypt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+  /// The side that starts the conversation, usually the client.
+  Initiator,
+  /// The side that answers, usually the server.
+  Responder,
+}
+
+impl Role {
+
+  /// Returns the role of the other end of the link.
+  pub const fn opposite(self) -> Self {
+    match self {
+      Self::Initiator => Self::Responder,
+      Self::Responder => Self::Initiator,
+    }
+  }
+
+  const fn direction(self) -> u32 {
+    match self {
+      Self::Initiator => 0,
+      Self::Responder => 1,
+    }
+  }
+
+}
+
+/// Authenticated encryption algorithm used to seal messages.
+///
+/// Every suite uses a 96-bit nonce and a 128-bit tag, so the wire format and
+/// message overhead are the same whichever one is chosen. Both peers of a link
+/// must use the same suite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum CipherSuite {
+  /// AES-128-GCM with a 128-bit key, fast on hardware with AES instructions.
+  #[default]
+  Aes128Gcm,
+  /// AES-256-GCM with a 256-bit key.
+  Aes256Gcm,
+  /// ChaCha20-Poly1305 with a 256-bit key, fast on hardware without AES instructions.
+  ChaCha20Poly1305,
+  /// AES-128-GCM-SIV with a 128-bit key, resistant to nonce misuse.
+  Aes128GcmSiv,
+  /// AES-256-GCM-SIV with a 256-bit key, resistant to nonce misuse.
+  Aes256GcmSiv,
+}
+
+impl CipherSuite {
+
+  /// Returns the key size in bytes required by this suite.
+  pub const fn key_size(self) -> usize {
+    match self {
+      Self::Aes128Gcm | Self::Aes128GcmSiv => Key::SIZE_128,
+      Self::Aes256Gcm | Self::ChaCha20Poly1305 | Self::Aes256GcmSiv => Key::SIZE_256,
+    }
+  }
+
+  /// Fails with [`InvalidKeyError::InvalidLength`] unless `key` has the length this suite requires.
+  pub const fn check(self, key: &Key) -> Result<(), InvalidKeyError> {
+    if key.len() != self.key_size() {
+      return Err(InvalidKeyError::InvalidLength);
+    }
+    Ok(())
+  }
+
+  /// Returns the AES-GCM suite matching the length of `key`.
+  pub const fn for_key(key: &Key) -> Self {
+    match key.len() {
+      Key::SIZE_128 => Self::Aes128Gcm,
+      _ => Self::Aes256Gcm,
+    }
+  }
+
+}
+
+/// Keyed instance of a [`CipherSuite`].
+#[derive(Clone)]
+pub enum Cipher {
+  Aes128Gcm(Aes128Gcm),
+  Aes256Gcm(Aes256Gcm),
+  ChaCha20Poly1305(ChaCha20Poly1305),
+  Aes128GcmSiv(Aes128GcmSiv),
+  Aes256GcmSiv(Aes256GcmSiv),
+}
+
+macro_rules! dispatch {
+  ($cipher:expr, $inner:ident => $body:expr) => {
+    match $cipher {
+      Cipher::Aes128Gcm($inner) => $body,
+      Cipher::Aes256Gcm($inner) => $body,
+      Cipher::ChaCha20Poly1305($inner) => $body,
+      Cipher::Aes128GcmSiv($inner) => $body,
+      Cipher::Aes256GcmSiv($inner) => $body,
+    }
+  };
+}
+
+impl Cipher {
+
+  /// Creates a cipher for `suite`, failing if the key has the wrong length.
+  pub fn new(suite: CipherSuite, key: &Key) -> Result<Self, InvalidKeyError> {
+    suite.check(key)?;
+    let key: &[u8] = key;
+    let cipher = match suite {
+      CipherSuite::Aes128Gcm => Self::Aes128Gcm(Aes128Gcm::new_from_slice(key).unwrap()),
+      CipherSuite::Aes256Gcm => Self::Aes256Gcm(Aes256Gcm::new_from_slice(key).unwrap()),
+      CipherSuite::ChaCha20Poly1305 => Self::ChaCha20Poly1305(ChaCha20Poly1305::new_from_slice(key).unwrap()),
+      CipherSuite::Aes128GcmSiv => Self::Aes128GcmSiv(Aes128GcmSiv::new_from_slice(key).unwrap()),
+      CipherSuite::Aes256GcmSiv => Self::Aes256GcmSiv(Aes256GcmSiv::new_from_slice(key).unwrap()),
+    };
+    Ok(cipher)
+  }
+
+  /// Encrypts the buffer in-place and appends the tag.
+  pub fn encrypt(&self, nonce: &[u8; Crypto::NONCE_SIZE], aad: &[u8], buffer: &mut Vec<u8>) -> Result<(), CryptoError> {
+    dispatch!(self, cipher => cipher.encrypt_in_place(&(*nonce).into(), aad, buffer)?);
+    Ok(())
+  }
 
+  /// Authenticates and decrypts the buffer in-place, removing the tag.
+  ///
+  /// The contents of the buffer are unspecified if authentication fails.
+  pub fn decrypt(&self, nonce: &[u8; Crypto::NONCE_SIZE], aad: &[u8], buffer: &mut Vec<u8>) -> Result<(), CryptoError> {
+    dispatch!(self, cipher => cipher.decrypt_in_place(&(*nonce).into(), aad, buffer)?);
+    Ok(())
+  }
+
+  /// Encrypts the buffer in-place and returns the tag separately.
+  pub fn encrypt_detached(&self, nonce: &[u8; Crypto::NONCE_SIZE], aad: &[u8], buffer: &mut [u8]) -> Result<[u8; Crypto::TAG_SIZE], CryptoError> {
+    let tag = dispatch!(self, cipher => cipher.encrypt_inout_detached(&(*nonce).into(), aad, buffer.into())?);
+    Ok(tag.into())
+  }
+
+  /// Authenticates and decrypts the buffer in-place against a separate tag.
+  ///
+  /// The contents of the buffer are unspecified if authentication fails.
+  pub fn decrypt_detached(&self, nonce: &[u8; Crypto::NONCE_SIZE], aad: &[u8], buffer: &mut [u8], tag: &[u8; Crypto::TAG_SIZE]) -> Result<(), CryptoError> {
+    dispatch!(self, cipher => cipher.decrypt_inout_detached(&(*nonce).into(), aad, buffer.into(), &(*tag).into())?);
+    Ok(())
+  }
+
+}
+
+/// Encryption state for one end of a link.
+///
+/// Clones share all of their state, since they represent the same endpoint:
+/// they must never hand out the same counter twice, must not accept a message
+/// that another clone already accepted, and must all switch keys together.
+///
+/// With a handshake, messages are only ever sealed with a session key, which
+/// the handshake derives fresh for every session, so the message counter can
+/// start over from zero with each one. Until the first session key is set,
+/// nothing can be sealed or opened.
+///
+/// Without a handshake, both sides seal with the pre-shared key from the
+/// start. Since they share it, every sender seals with a key of its own,
+/// derived from the pre-shared key and its connection ID, which every message
+/// then carries. These keys are never ratcheted, and the message counter
+/// starts from the current time in nanoseconds, so that a restarted sender
+/// keeps counting up past what the other side's replay window has seen.
+///
+/// Each message carries its counter, and the header flags carry the key epoch
+/// it was sealed in, so the receiving side knows when the sender has ratcheted.
+///
+/// Messages may carry the sender's connection ID and associated data, which
+/// are sent in the clear right after the header and authenticated along with
+/// the message:
+///
+/// ```text
+/// +--------+-----------------+--------------+-----+------------+-----+---------+
+/// | header | [connection id] | [aad length] | aad | ciphertext | tag | counter |
+/// +--------+-----------------+--------------+-----+------------+-----+---------+
+///      4            8                2                              16       8
+/// ```
+///
+/// The connection ID is only present if [`Header::FLAGS_CONNECTION_ID`] is set,
+/// and the length only if [`Header::FLAGS_AAD`] is set.
+#[derive(Clone)]
 pub struct Crypto {
-  cipher: Aes128Gcm,
-  csprng: ChaCha8Rng,
+  shared: Arc<Shared>,
+}
+
+struct Shared {
+  role: Option<Role>,
+  suite: CipherSuite,
+  keys: Mutex<Keys>,
+  window: Mutex<ReplayWindow>,
+}
+
+struct Keys {
+  policy: RekeyPolicy,
+  connection_id: Option<u64>,
+  session: Option<Session>,
+  fixed: Option<StaticKeys>,
+}
+
+/// Keys and message counter of the current session.
+struct Session {
+  send: SendChain,
+  recv: RecvChain,
+  counter: u64,
+}
+
+impl Session {
+
+  fn new(epoch: Epoch) -> Self {
+    Self { send: SendChain::new(epoch.clone()), recv: RecvChain::new(epoch), counter: 0 }
+  }
+
+}
+
+/// Keys and message counter of a peer that seals with the pre-shared key.
+struct StaticKeys {
+  psk: Key,
+  send: Epoch,
+  /// The other side's connection ID and key, once a message from it authenticated
+  recv: Option<(u64, Epoch)>,
+  counter: u64,
+}
+
+impl StaticKeys {
+
+  /// Derives the key the sender with the given connection ID seals with.
+  fn derive(psk: Key, suite: CipherSuite, connection_id: u64) -> Result<Epoch, InvalidKeyError> {
+    let mut key = [0u8; Key::MAXIMUM_SIZE];
+    let key = &mut key[..suite.key_size()];
+    Hkdf::<Sha256>::new(Some(b"twopoint static v1"), &psk)
+      .expand_multi_info(&[b"sender", &connection_id.to_be_bytes()], key)
+      .expect("key length is valid for sha256");
+    Epoch::new(suite, Key::try_from(&*key)?)
+  }
+
 }
 
 impl Crypto {
 
-  /// AES-128-GCM tag size in bytes
+  /// Tag size in bytes, the same for every cipher suite
   pub const TAG_SIZE: usize = 16;
-  /// AES-128-GCM nonce size in bytes
+  /// Nonce size in bytes, the same for every cipher suite
   pub const NONCE_SIZE: usize = 12;
+  /// Message counter size in bytes
+  pub const COUNTER_SIZE: usize = 8;
+
+  /// Connection ID size in bytes
+  pub const CONNECTION_ID_SIZE: usize = 8;
+  /// Associated data length size in bytes
+  pub const AAD_LENGTH_SIZE: usize = 2;
+  /// Maximum associated data length in bytes
+  pub const MAXIMUM_AAD_LENGTH: usize = u16::MAX as usize;
 
-  /// Minimum buffer length in bytes for an encrypted message (tag + nonce)
-  pub const MINIMUM_BUFFER_LENGTH: usize = Self::TAG_SIZE + Self::NONCE_SIZE;
+  /// Minimum buffer length in bytes for an encrypted message (header + tag + counter)
+  pub const MINIMUM_BUFFER_LENGTH: usize = Header::SIZE + Self::TAG_SIZE + Self::COUNTER_SIZE;
 
-  pub fn new(key: Key) -> Self {
+  /// Creates the encryption state without a session, until [`Crypto::set_key`] starts one.
+  pub fn without_session(role: Role, suite: CipherSuite, policy: RekeyPolicy) -> Self {
     Self {
-      cipher: Aes128Gcm::new(&CryptoKey::<Aes128Gcm>::try_from(*key).unwrap()),
-      csprng: ChaCha8Rng::from_os_rng(),
+      shared: Arc::new(Shared {
+        role: Some(role),
+        suite,
+        keys: Mutex::new(Keys { policy, connection_id: None, session: None, fixed: None }),
+        window: Mutex::new(ReplayWindow::new()),
+      }),
     }
   }
 
-  pub fn encrypt(&mut self, buffer: &mut Vec<u8>) -> Result<(), CryptoError> {
-    let nonce = Aes128Gcm::generate_nonce_with_rng(&mut self.csprng);
-    self.cipher.encrypt_in_place(&nonce, &[], buffer)?;
-    buffer.extend_from_slice(nonce.as_slice());
+  /// Creates the encryption state for sealing with the pre-shared key, sending
+  /// the given connection ID, without a handshake.
+  pub fn with_static_key(psk: Key, suite: CipherSuite, connection_id: u64) -> Result<Self, InvalidKeyError> {
+    let fixed = StaticKeys {
+      psk,
+      send: StaticKeys::derive(psk, suite, connection_id)?,
+      recv: None,
+      counter: unix_nanos(),
+    };
+    Ok(Self {
+      shared: Arc::new(Shared {
+        role: None,
+        suite,
+        keys: Mutex::new(Keys {
+          policy: RekeyPolicy::default(),
+          connection_id: Some(connection_id),
+          session: None,
+          fixed: Some(fixed),
+        }),
+        window: Mutex::new(ReplayWindow::new()),
+      }),
+    })
+  }
+
+  /// Returns the role this side of the link was set up with, or `None` without a handshake.
+  pub fn role(&self) -> Option<Role> {
+    self.shared.role
+  }
+
+  /// Returns the cipher suite messages are sealed with.
+  pub fn suite(&self) -> CipherSuite {
+    self.shared.suite
+  }
+
+  /// Switches every clone over to a new session key, freshly negotiated by a handshake.
+  ///
+  /// Both ratchet chains restart from the new key, and the message counter and
+  /// the replay window start over, since the other side starts a new session
+  /// too. The key must never have been set before, or nonces would repeat.
+  pub fn set_key(&self, key: Key) -> Result<(), InvalidKeyError> {
+    let epoch = Epoch::new(self.suite(), key)?;
+    let mut keys = self.keys();
+    keys.session = Some(Session::new(epoch));
+    *self.window() = ReplayWindow::new();
     Ok(())
   }
 
-  pub fn decrypt(&mut self, buffer: &mut Vec<u8>) -> Result<(), CryptoError> {
+  /// Returns `true` once a session key has been set, and always without a handshake.
+  pub fn has_session(&self) -> bool {
+    let keys = self.keys();
+    keys.session.is_some() || keys.fixed.is_some()
+  }
+
+  /// Returns the current rekeying policy.
+  pub fn rekey_policy(&self) -> RekeyPolicy {
+    self.keys().policy
+  }
+
+  /// Sets the rekeying policy for every clone.
+  pub fn set_rekey_policy(&self, policy: RekeyPolicy) {
+    self.keys().policy = policy;
+  }
+
+  /// Returns the connection ID sent with every message, if any.
+  pub fn connection_id(&self) -> Option<u64> {
+    self.keys().connection_id
+  }
+
+  /// Sets the connection ID sent with every message, for every clone.
+  ///
+  /// Without a handshake, the sending key is derived from the connection ID,
+  /// so it changes along with it, and `None` picks a new random one.
+  pub fn set_connection_id(&self, connection_id: Option<u64>) {
+    let mut keys = self.keys();
+    if let Some(fixed) = &mut keys.fixed {
+      let connection_id = connection_id.unwrap_or_else(random_connection_id);
+      fixed.send = StaticKeys::derive(fixed.psk, self.suite(), connection_id).expect("suite was checked against the key");
+      keys.connection_id = Some(connection_id);
+    } else {
+      keys.connection_id = connection_id;
+    }
+  }
+
+  /// Reads the connection ID of an encrypted message without authenticating it.
+  ///
+  /// The connection ID is only trustworthy once the message has been decrypted.
+  pub fn peek_connection_id(buffer: &[u8]) -> Option<u64> {
+    let header = Header::parse(buffer).ok()?;
+    if !header.message_type.is_encrypted() || header.flags & Header::FLAGS_CONNECTION_ID == 0 {
+      return None;
+    }
+    let connection_id = buffer[Header::SIZE..].first_chunk::<{ Self::CONNECTION_ID_SIZE }>()?;
+    Some(u64::from_be_bytes(*connection_id))
+  }
+
+  /// Encrypts the buffer in-place, prepending the header and appending the tag and counter.
+  pub fn encrypt(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.encrypt_with_aad(&[], buffer)
+  }
+
+  /// Encrypts the buffer in-place like [`Crypto::encrypt`], as a message of the given encrypted type.
+  pub fn encrypt_as(&self, message_type: MessageType, buffer: &mut Vec<u8>) -> io::Result<()> {
     let len = buffer.len();
-    if len < Self::MINIMUM_BUFFER_LENGTH {
-      return Err(CryptoError);
+    buffer.resize(len + self.overhead(&[]), 0);
+    let len = self.encrypt_slice_as(message_type, &[], buffer, len)?;
+    buffer.truncate(len);
+    Ok(())
+  }
+
+  /// Encrypts the buffer in-place like [`Crypto::encrypt`], also prepending `aad`
+  /// in the clear and binding it to the message.
+  ///
+  /// Fails with [`io::ErrorKind::InvalidInput`] if `aad` is longer than
+  /// [`Crypto::MAXIMUM_AAD_LENGTH`].
+  pub fn encrypt_with_aad(&self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    let len = buffer.len();
+    buffer.resize(len + self.overhead(aad), 0);
+    let len = self.encrypt_slice(aad, buffer, len)?;
+    buffer.truncate(len);
+    Ok(())
+  }
+
+  /// Encrypts the message in the first `len` bytes of the buffer in-place,
+  /// returning the length of the encrypted message.
+  ///
+  /// The buffer must have room for the message plus [`Crypto::overhead`], the
+  /// message is moved back to make room for the header, connection ID and
+  /// associated data.
+  /// Fails with [`io::ErrorKind::InvalidInput`] if it does not, or if `aad` is
+  /// longer than [`Crypto::MAXIMUM_AAD_LENGTH`].
+  pub fn encrypt_slice(&self, aad: &[u8], buffer: &mut [u8], len: usize) -> io::Result<usize> {
+    self.encrypt_slice_as(MessageType::Data, aad, buffer, len)
+  }
+
+  /// Encrypts the message in the first `len` bytes of the buffer in-place like
+  /// [`Crypto::encrypt_slice`], as a message of the given encrypted type.
+  pub fn encrypt_slice_as(&self, message_type: MessageType, aad: &[u8], buffer: &mut [u8], len: usize) -> io::Result<usize> {
+    debug_assert!(message_type.is_encrypted());
+    if aad.len() > Self::MAXIMUM_AAD_LENGTH {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "associated data is too long"));
+    }
+
+    let mut guard = self.keys();
+    let keys = &mut *guard;
+    let policy = keys.policy;
+    let connection_id = keys.connection_id;
+    if buffer.len() < len + Self::overhead_with(connection_id, aad) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too small for the encrypted message"));
+    }
+    let (epoch, counter) = match (&mut keys.fixed, &mut keys.session) {
+      (Some(fixed), _) => (&fixed.send, next_counter(&mut fixed.counter)?),
+      (None, Some(session)) => {
+        let counter = next_counter(&mut session.counter)?;
+        (session.send.next(&policy), counter)
+      }
+      (None, None) => return Err(NoSessionError.into()),
+    };
+
+    let mut flags = epoch.wire();
+    let mut prefix = Header::SIZE;
+    if connection_id.is_some() {
+      flags |= Header::FLAGS_CONNECTION_ID;
+      prefix += Self::CONNECTION_ID_SIZE;
+    }
+    if !aad.is_empty() {
+      flags |= Header::FLAGS_AAD;
+      prefix += Self::AAD_LENGTH_SIZE + aad.len();
+    }
+    buffer.copy_within(..len, prefix);
+    buffer[..Header::SIZE].copy_from_slice(&Header::new(message_type).with_flags(flags).to_bytes());
+    if let Some(connection_id) = connection_id {
+      buffer[Header::SIZE..][..Self::CONNECTION_ID_SIZE].copy_from_slice(&connection_id.to_be_bytes());
+    }
+    if !aad.is_empty() {
+      buffer[prefix - aad.len() - Self::AAD_LENGTH_SIZE..][..Self::AAD_LENGTH_SIZE].copy_from_slice(&(aad.len() as u16).to_be_bytes());
+      buffer[prefix - aad.len()..prefix].copy_from_slice(aad);
     }
-    let nonce = Nonce::try_from(&buffer[len - Self::NONCE_SIZE..]).unwrap();
-    buffer.truncate(len - Self::NONCE_SIZE);
-    self.cipher.decrypt_in_place(&nonce, &[], buffer)?;
+
+    // everything in front of the message is authenticated as associated data
+    let nonce = Self::nonce(self.role(), counter);
+    let (aad, rest) = buffer.split_at_mut(prefix);
+    let (message, rest) = rest.split_at_mut(len);
+    let tag = epoch.cipher.encrypt_detached(&nonce, aad, message)?;
+    drop(guard);
+
+    rest[..Self::TAG_SIZE].copy_from_slice(&tag);
+    rest[Self::TAG_SIZE..][..Self::COUNTER_SIZE].copy_from_slice(&counter.to_be_bytes());
+    Ok(prefix + len + Self::TAG_SIZE + Self::COUNTER_SIZE)
+  }
+
+  /// Encrypts `message` into `buffer`, returning the length of the encrypted message.
+  ///
+  /// The buffer must have room for the message plus [`Crypto::overhead`].
+  pub fn encrypt_into(&self, aad: &[u8], message: &[u8], buffer: &mut [u8]) -> io::Result<usize> {
+    if buffer.len() < message.len() + self.overhead(aad) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too small for the encrypted message"));
+    }
+    buffer[..message.len()].copy_from_slice(message);
+    self.encrypt_slice(aad, buffer, message.len())
+  }
+
+  /// Authenticates and decrypts the buffer in-place, discarding any associated data.
+  ///
+  /// Fails with [`HeaderError`](crate::HeaderError) if the header is invalid, with
+  /// [`CryptoError`] if the message is malformed or does not authenticate, and with
+  /// [`ReplayError`] if its counter has already been accepted or is too old for the
+  /// replay window.
+  pub fn decrypt(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let len = self.decrypt_slice(buffer)?;
+    buffer.truncate(len);
     Ok(())
   }
 
-}
+  /// Authenticates and decrypts the buffer in-place like [`Crypto::decrypt`],
+  /// replacing the contents of `aad` with the message's associated data.
+  pub fn decrypt_with_aad(&self, buffer: &mut Vec<u8>, aad: &mut Vec<u8>) -> io::Result<()> {
+    let (associated, message) = self.open(MessageType::Data, buffer)?;
+    aad.clear();
+    aad.extend_from_slice(&buffer[associated]);
+    buffer.truncate(message.end);
+    buffer.drain(..message.start);
+    Ok(())
+  }
 
-impl Clone for Crypto {
-  fn clone(&self) -> Self {
-    Self {
-      cipher: self.cipher.clone(),
-      csprng: ChaCha8Rng::from_os_rng(),
+  /// Authenticates and decrypts the encrypted message filling the buffer in-place,
+  /// moving the message to the start and returning its length.
+  pub fn decrypt_slice(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.decrypt_slice_as(MessageType::Data, buffer)
+  }
+
+  /// Authenticates and decrypts the buffer in-place like [`Crypto::decrypt_slice`],
+  /// failing unless the message is of the given encrypted type.
+  pub fn decrypt_slice_as(&self, message_type: MessageType, buffer: &mut [u8]) -> io::Result<usize> {
+    let (_, message) = self.open(message_type, buffer)?;
+    let len = message.len();
+    buffer.copy_within(message, 0);
+    Ok(len)
+  }
+
+  /// Returns the number of bytes encryption adds to a message with the given associated data.
+  pub fn overhead(&self, aad: &[u8]) -> usize {
+    Self::overhead_with(self.connection_id(), aad)
+  }
+
+  const fn overhead_with(connection_id: Option<u64>, aad: &[u8]) -> usize {
+    let connection_id = if connection_id.is_some() { Self::CONNECTION_ID_SIZE } else { 0 };
+    let aad = if aad.is_empty() { 0 } else { Self::AAD_LENGTH_SIZE + aad.len() };
+    Self::MINIMUM_BUFFER_LENGTH + connection_id + aad
+  }
+
+  /// Authenticates and decrypts a message in-place, returning where its associated data and plaintext are.
+  fn open(&self, message_type: MessageType, buffer: &mut [u8]) -> io::Result<(Range<usize>, Range<usize>)> {
+    let header = Header::parse(buffer)?;
+    let len = buffer.len();
+    if len < Self::MINIMUM_BUFFER_LENGTH || header.message_type != message_type {
+      return Err(CryptoError.into());
+    }
+
+    let mut start = Header::SIZE;
+    let mut connection_id = None;
+    if header.flags & Header::FLAGS_CONNECTION_ID != 0 {
+      connection_id = buffer[start..].first_chunk::<{ Self::CONNECTION_ID_SIZE }>().map(|id| u64::from_be_bytes(*id));
+      start += Self::CONNECTION_ID_SIZE;
+    }
+    let mut associated = start..start;
+    if header.flags & Header::FLAGS_AAD != 0 {
+      let length = buffer.get(start..).and_then(|rest| rest.first_chunk::<{ Self::AAD_LENGTH_SIZE }>()).ok_or(CryptoError)?;
+      let start = start + Self::AAD_LENGTH_SIZE;
+      associated = start..start + usize::from(u16::from_be_bytes(*length));
+    }
+    let end = len - Self::TAG_SIZE - Self::COUNTER_SIZE;
+    if associated.end > end {
+      return Err(CryptoError.into());
     }
+    let message = associated.end..end;
+
+    let counter = u64::from_be_bytes(buffer[len - Self::COUNTER_SIZE..].try_into().unwrap());
+
+    // cheap rejection before spending time on authentication
+    if !self.window().check(counter) {
+      return Err(ReplayError.into());
+    }
+
+    // messages from the other side are sealed with the other direction
+    let nonce = Self::nonce(self.role().map(Role::opposite), counter);
+    let (aad, rest) = buffer.split_at_mut(message.start);
+    let (ciphertext, rest) = rest.split_at_mut(message.len());
+    let tag = rest.first_chunk::<{ Self::TAG_SIZE }>().unwrap();
+
+    let mut guard = self.keys();
+    let keys = &mut *guard;
+    let overlap = keys.policy.overlap;
+    if let Some(fixed) = &mut keys.fixed {
+      // the key is derived from the sender's connection ID, and our own messages sent back to us are not the other side's
+      let sender = connection_id.filter(|&sender| Some(sender) != keys.connection_id).ok_or(CryptoError)?;
+      let known = fixed.recv.as_ref().filter(|(known, _)| *known == sender).map(|(_, epoch)| epoch);
+      let derived = match known {
+        Some(_) => None,
+        None => Some(StaticKeys::derive(fixed.psk, self.suite(), sender)?),
+      };
+      let epoch = known.or(derived.as_ref()).expect("either known or derived");
+      epoch.cipher.decrypt_detached(&nonce, aad, ciphertext, tag)?;
+      if !self.window().update(counter) {
+        return Err(ReplayError.into());
+      }
+      // the other side restarted or changed its connection ID, follow along
+      if let Some(derived) = derived {
+        fixed.recv = Some((sender, derived));
+      }
+      return Ok((associated, message));
+    }
+
+    let session = keys.session.as_mut().ok_or(CryptoError)?;
+    let lookup = session.recv.lookup(header.flags & Header::FLAGS_KEY_EPOCH).ok_or(CryptoError)?;
+    lookup.epoch().cipher.decrypt_detached(&nonce, aad, ciphertext, tag)?;
+
+    // only authenticated counters may move the window
+    if !self.window().update(counter) {
+      return Err(ReplayError.into());
+    }
+
+    // the other side has ratcheted, follow along
+    if let Lookup::Ahead(epoch) = lookup {
+      session.recv.advance(epoch, overlap);
+    }
+
+    Ok((associated, message))
+  }
+
+  /// Builds the nonce for a message, the direction followed by the counter.
+  ///
+  /// Without a handshake every sender has a key of its own, so all of them
+  /// use the same direction.
+  fn nonce(role: Option<Role>, counter: u64) -> [u8; Self::NONCE_SIZE] {
+    let direction = role.map_or(STATIC_DIRECTION, Role::direction);
+    let mut nonce = [0u8; Self::NONCE_SIZE];
+    nonce[..4].copy_from_slice(&direction.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+  }
+
+  fn keys(&self) -> MutexGuard<'_, Keys> {
+    self.shared.keys.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn window(&self) -> MutexGuard<'_, ReplayWindow> {
+    self.shared.window.lock().unwrap_or_else(|e| e.into_inner())
   }
+
+}
+
+/// Nonce direction of messages sealed without a handshake
+const STATIC_DIRECTION: u32 = 2;
+
+/// Hands out the next message counter, refusing to wrap around, since a repeated counter means a repeated nonce.
+fn next_counter(counter: &mut u64) -> Result<u64, CryptoError> {
+  let next = *counter;
+  *counter = next.checked_add(1).ok_or(CryptoError)?;
+  Ok(next)
 }
@@ -0,0 +1,465 @@
+use std::io;
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::util::reserve_datagram;
+use crate::key::Key;
+use crate::error::InvalidKeyError;
+use crate::crypto::{Crypto, CipherSuite, Role};
+use crate::rekey::RekeyPolicy;
+use crate::message::{MessageType, Header};
+use crate::handshake::Responder;
+
+/// Limits on the sessions an [`Endpoint`] keeps.
+///
+/// Sessions that nothing has authenticated for in `idle_timeout` are dropped,
+/// and once there are `max_sessions`, counting handshakes that have been
+/// answered but not used yet, handshakes from new clients go unanswered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionPolicy {
+  /// Maximum number of sessions.
+  pub max_sessions: usize,
+  /// How long a session may go without an authenticated message before it is dropped.
+  pub idle_timeout: Duration,
+}
+
+impl SessionPolicy {
+
+  /// Default maximum number of sessions
+  pub const DEFAULT_MAX_SESSIONS: usize = 4096;
+  /// Default time a session may stay idle
+  pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+}
+
+impl Default for SessionPolicy {
+  fn default() -> Self {
+    Self {
+      max_sessions: Self::DEFAULT_MAX_SESSIONS,
+      idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+    }
+  }
+}
+
+/// A UDP server that talks to many peers over one socket.
+///
+/// Datagrams are received with `recv_from` and sorted into a [`Session`] per
+/// remote address, each with its own encryption state. Clients that send a
+/// connection ID, such as roaming [`Peer`](crate::Peer)s, keep their session
+/// when their address changes: once a message with a known connection ID
+/// authenticates from a new address, the session moves over to that address.
+///
+/// The endpoint always takes the [`Role::Responder`] role, so clients connect
+/// to it with a [`Peer`](crate::Peer) in the [`Role::Initiator`] role.
+///
+/// Sessions are only created by a handshake, so clients have to call
+/// [`Peer::handshake`](crate::Peer::handshake) before sending anything. A
+/// session only counts once the first message sealed with its new key arrives,
+/// so recorded initiations replayed from other addresses never get one, and
+/// messages from addresses without a session are dropped. The number of
+/// sessions is limited by a [`SessionPolicy`].
+///
+/// All methods take `&self`, so the endpoint can be shared between threads.
+pub struct Endpoint {
+  socket: Arc<UdpSocket>,
+  key: Key,
+  crypto: Crypto,
+  sessions: Mutex<Sessions>,
+}
+
+struct Sessions {
+  policy: SessionPolicy,
+  by_addr: HashMap<SocketAddr, Session>,
+  by_connection_id: HashMap<u64, SocketAddr>,
+  /// Sessions whose handshake was answered, but whose client hasn't sent anything with the new key yet
+  pending: HashMap<SocketAddr, Session>,
+  /// Initiations answered recently and where they came from, so that a
+  /// recorded one can't start a session for another address
+  initiations: HashMap<Vec<u8>, (SocketAddr, Instant)>,
+  swept: Instant,
+}
+
+impl Sessions {
+
+  /// How often sessions are checked for having gone idle
+  const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+  fn new() -> Self {
+    Self {
+      policy: SessionPolicy::default(),
+      by_addr: HashMap::new(),
+      by_connection_id: HashMap::new(),
+      pending: HashMap::new(),
+      initiations: HashMap::new(),
+      swept: Instant::now(),
+    }
+  }
+
+  /// Drops idle sessions, and initiations too old to be accepted by a new responder anyway.
+  fn sweep(&mut self, now: Instant) {
+    let timeout = self.policy.idle_timeout;
+    self.by_addr.retain(|_, session| now.duration_since(session.last_heard()) < timeout);
+    self.pending.retain(|_, session| now.duration_since(session.last_heard()) < timeout);
+    let by_addr = &self.by_addr;
+    self.by_connection_id.retain(|_, addr| by_addr.contains_key(addr));
+    self.initiations.retain(|_, (_, answered)| now.duration_since(*answered) < Responder::CLOCK_SKEW);
+    self.swept = now;
+  }
+
+  fn len(&self) -> usize {
+    self.by_addr.len() + self.pending.len()
+  }
+
+}
+
+impl Endpoint {
+
+  /// Creates a new endpoint with the given socket and pre-shared key.
+  ///
+  /// Messages are sealed with AES-128-GCM or AES-256-GCM depending on the
+  /// length of the key, use [`Endpoint::with_suite`] to pick a different cipher suite.
+  pub fn new(socket: UdpSocket, key: Key) -> Self {
+    Self::with_suite(socket, key, CipherSuite::for_key(&key))
+      .expect("key length always matches its default suite")
+  }
+
+  /// Creates a new endpoint with the given socket, pre-shared key and cipher suite.
+  ///
+  /// Returns an error if the key length does not match the suite.
+  pub fn with_suite(socket: UdpSocket, key: Key, suite: CipherSuite) -> Result<Self, InvalidKeyError> {
+    suite.check(&key)?;
+    Ok(Self {
+      socket: Arc::new(socket),
+      key,
+      crypto: Crypto::without_session(Role::Responder, suite, RekeyPolicy::default()),
+      sessions: Mutex::new(Sessions::new()),
+    })
+  }
+
+  /// Creates a new endpoint bound to `bind_addr`.
+  pub fn bind<A: ToSocketAddrs>(bind_addr: A, key: Key) -> io::Result<Self> {
+    Ok(Self::new(UdpSocket::bind(bind_addr)?, key))
+  }
+
+  /// Returns the cipher suite messages are sealed with.
+  pub fn suite(&self) -> CipherSuite {
+    self.crypto.suite()
+  }
+
+  /// Returns the policy for ratcheting keys forward.
+  pub fn rekey_policy(&self) -> RekeyPolicy {
+    self.crypto.rekey_policy()
+  }
+
+  /// Sets the policy for ratcheting keys forward in sessions created from now on.
+  pub fn set_rekey_policy(&self, policy: RekeyPolicy) {
+    self.crypto.set_rekey_policy(policy)
+  }
+
+  /// Returns the limits on the sessions this endpoint keeps.
+  pub fn session_policy(&self) -> SessionPolicy {
+    self.sessions().policy
+  }
+
+  /// Sets the limits on the sessions this endpoint keeps.
+  ///
+  /// Sessions over a lowered limit are kept until they go idle.
+  pub fn set_session_policy(&self, policy: SessionPolicy) {
+    self.sessions().policy = policy;
+  }
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    &self.socket
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.socket.local_addr().expect("couldn't get local address")
+  }
+
+  /// Sets the read timeout for receive operations.
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.socket.set_read_timeout(timeout)
+  }
+
+  /// Sets the write timeout for send operations.
+  pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.socket.set_write_timeout(timeout)
+  }
+
+  /// Returns the session for the given remote address, if there is one.
+  pub fn get(&self, addr: SocketAddr) -> Option<Session> {
+    self.sessions().by_addr.get(&addr).cloned()
+  }
+
+  /// Removes the session for the given remote address.
+  ///
+  /// Handles to the removed session can still send, but messages from its
+  /// client are dropped until it handshakes again. Sessions that go idle for
+  /// longer than [`SessionPolicy::idle_timeout`] are removed the same way.
+  pub fn remove(&self, addr: SocketAddr) -> Option<Session> {
+    let mut sessions = self.sessions();
+    sessions.by_connection_id.retain(|_, other| *other != addr);
+    sessions.pending.remove(&addr);
+    sessions.by_addr.remove(&addr)
+  }
+
+  /// Returns the number of sessions, not counting handshakes whose client hasn't sent anything yet.
+  pub fn session_count(&self) -> usize {
+    self.sessions().by_addr.len()
+  }
+
+  /// Returns the remote addresses of all sessions.
+  pub fn remote_addrs(&self) -> Vec<SocketAddr> {
+    self.sessions().by_addr.keys().copied().collect()
+  }
+
+  /// Receives and decrypts a message from any client into the buffer,
+  /// returning the session it belongs to.
+  ///
+  /// The buffer is used like in [`Peer::recv`](crate::Peer::recv), grown to
+  /// fit the largest possible datagram if needed, and handshakes are answered
+  /// internally.
+  pub fn recv_from(&self, buffer: &mut Vec<u8>) -> io::Result<Session> {
+    reserve_datagram(buffer);
+    let (len, session) = self.recv_into(buffer)?;
+    buffer.truncate(len);
+    Ok(session)
+  }
+
+  /// Receives and decrypts a message from any client into the start of the
+  /// buffer, without allocating, returning the message length and the session
+  /// it belongs to.
+  ///
+  /// Datagrams that are malformed, don't authenticate for a session or were
+  /// already received are dropped without ending the call, only socket errors
+  /// such as the read timeout are returned.
+  pub fn recv_into(&self, buffer: &mut [u8]) -> io::Result<(usize, Session)> {
+    loop {
+      let (len, addr) = self.socket.recv_from(buffer)?;
+      let datagram = &mut buffer[..len];
+      let Ok(header) = Header::parse(datagram) else {
+        continue;
+      };
+      match header.message_type {
+        MessageType::Data => {
+          if let Some((len, session)) = self.open(MessageType::Data, datagram, addr) {
+            return Ok((len, session));
+          }
+        }
+        // keepalives only keep the session alive, and may move it to a new address
+        MessageType::Keepalive => {
+          self.open(MessageType::Keepalive, datagram, addr);
+        }
+        MessageType::HandshakeInitiation => self.answer_initiation(datagram, addr)?,
+        // the endpoint never initiates handshakes
+        MessageType::HandshakeResponse => {}
+        // reliable delivery, fragmentation, path MTU discovery, channels, hole punching and rendezvous are only supported by Peer
+        MessageType::Reliable | MessageType::Ack | MessageType::Fragment | MessageType::Probe | MessageType::ProbeAck
+          | MessageType::Channel | MessageType::ChannelAck | MessageType::Punch | MessageType::PunchAck
+          | MessageType::Register | MessageType::Introduction => {}
+      }
+    }
+  }
+
+  /// Authenticates and decrypts a message from `addr`, returning its length
+  /// and the session it belongs to, or `None` if it belongs to no session.
+  ///
+  /// A client that handshook again, maybe from a new address, may still have
+  /// an old session, so a session whose handshake was just answered is tried
+  /// as well.
+  fn open(&self, message_type: MessageType, datagram: &mut [u8], addr: SocketAddr) -> Option<(usize, Session)> {
+    let connection_id = Crypto::peek_connection_id(datagram);
+    let (found, pending) = {
+      let sessions = self.sessions();
+      (Self::find(&sessions, addr, connection_id), sessions.pending.get(&addr).cloned())
+    };
+    // a message that fails to authenticate is left as it was
+    let (len, session) = [found, pending].into_iter().flatten()
+      .find_map(|session| Some((session.crypto.decrypt_slice_as(message_type, datagram).ok()?, session)))?;
+    self.track(&session, addr, connection_id);
+    Some((len, session))
+  }
+
+  /// Finds the session a message belongs to, by connection ID first and then
+  /// by address.
+  ///
+  /// The connection ID wins, since a client that roams may show up at an
+  /// address that another client used before.
+  fn find(sessions: &Sessions, addr: SocketAddr, connection_id: Option<u64>) -> Option<Session> {
+    connection_id
+      .and_then(|connection_id| sessions.by_connection_id.get(&connection_id))
+      .and_then(|addr| sessions.by_addr.get(addr))
+      .or_else(|| sessions.by_addr.get(&addr))
+      .cloned()
+  }
+
+  /// Answers a handshake initiation from `addr`.
+  ///
+  /// A client with a session handshakes with the responder of its session,
+  /// any other client gets a new session that stays pending until its first
+  /// message arrives. An initiation that was already answered for another
+  /// address is a recorded one and goes unanswered, and so do new clients
+  /// once there are as many sessions as the [`SessionPolicy`] allows.
+  fn answer_initiation(&self, packet: &[u8], addr: SocketAddr) -> io::Result<()> {
+    let session = {
+      let sessions = self.sessions();
+      if sessions.initiations.get(packet).is_some_and(|(answered, _)| *answered != addr) {
+        return Ok(());
+      }
+      match sessions.by_addr.get(&addr).or_else(|| sessions.pending.get(&addr)) {
+        Some(session) => session.clone(),
+        None if sessions.len() >= sessions.policy.max_sessions => return Ok(()),
+        None => self.new_session(addr),
+      }
+    };
+    if session.answer_initiation(packet)? {
+      let mut sessions = self.sessions();
+      sessions.initiations.insert(packet.to_vec(), (addr, Instant::now()));
+      if !sessions.by_addr.get(&addr).is_some_and(|other| other.same(&session)) {
+        sessions.pending.insert(addr, session);
+      }
+    }
+    Ok(())
+  }
+
+  /// Makes up a session for a client at `addr`, which has no key until its handshake is answered.
+  fn new_session(&self, addr: SocketAddr) -> Session {
+    Session {
+      socket: Arc::clone(&self.socket),
+      addr: Arc::new(Mutex::new(addr)),
+      last_heard: Arc::new(Mutex::new(Instant::now())),
+      key: self.key,
+      crypto: Crypto::without_session(Role::Responder, self.suite(), self.rekey_policy()),
+      responder: Arc::new(Mutex::new(Responder::new())),
+    }
+  }
+
+  /// Records that a message from `addr` just authenticated for `session`.
+  ///
+  /// New sessions are added, sessions found by connection ID move over to the
+  /// new address, taking it over from any other session, and a session that
+  /// the connection ID now points away from is dropped, since its client has
+  /// started over with a new handshake.
+  fn track(&self, session: &Session, addr: SocketAddr, connection_id: Option<u64>) {
+    let mut sessions = self.sessions();
+    session.heard();
+    if sessions.pending.get(&addr).is_some_and(|pending| pending.same(session)) {
+      sessions.pending.remove(&addr);
+    }
+    let previous = session.set_remote_addr(addr);
+    if previous != addr && sessions.by_addr.get(&previous).is_some_and(|other| other.same(session)) {
+      sessions.by_addr.remove(&previous);
+    }
+    if let Some(displaced) = sessions.by_addr.insert(addr, session.clone())
+      && !displaced.same(session)
+    {
+      // another client used to be at this address, and has moved on
+      sessions.by_connection_id.retain(|_, other| *other != addr);
+    }
+    if let Some(connection_id) = connection_id
+      && let Some(stale) = sessions.by_connection_id.insert(connection_id, addr)
+      && stale != addr
+    {
+      sessions.by_addr.remove(&stale);
+    }
+  }
+
+  /// Locks the sessions, dropping idle ones every now and then.
+  fn sessions(&self) -> MutexGuard<'_, Sessions> {
+    let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Instant::now();
+    if now.duration_since(sessions.swept) >= Sessions::SWEEP_INTERVAL {
+      sessions.sweep(now);
+    }
+    sessions
+  }
+
+}
+
+/// One client of an [`Endpoint`].
+///
+/// Clones share the same encryption state and can be used from different threads.
+#[derive(Clone)]
+pub struct Session {
+  socket: Arc<UdpSocket>,
+  addr: Arc<Mutex<SocketAddr>>,
+  last_heard: Arc<Mutex<Instant>>,
+  key: Key,
+  crypto: Crypto,
+  responder: Arc<Mutex<Responder>>,
+}
+
+impl Session {
+
+  /// Returns the client's address, which changes if the client roams.
+  pub fn remote_addr(&self) -> SocketAddr {
+    *self.addr.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn set_remote_addr(&self, addr: SocketAddr) -> SocketAddr {
+    std::mem::replace(&mut self.addr.lock().unwrap_or_else(|e| e.into_inner()), addr)
+  }
+
+  /// Returns when a message from the client last authenticated, or when its handshake was answered.
+  fn last_heard(&self) -> Instant {
+    *self.last_heard.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn heard(&self) {
+    *self.last_heard.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+  }
+
+  /// Returns `true` if both handles refer to the same session.
+  fn same(&self, other: &Session) -> bool {
+    Arc::ptr_eq(&self.addr, &other.addr)
+  }
+
+  /// Encrypts and sends the contents of the buffer to the client.
+  ///
+  /// See [`Peer::send`](crate::Peer::send).
+  pub fn send(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.crypto.encrypt(buffer)?;
+    self.socket.send_to(buffer, self.remote_addr())?;
+    Ok(())
+  }
+
+  /// Encrypts and sends the contents of the buffer to the client along with associated data.
+  ///
+  /// See [`Peer::send_with_aad`](crate::Peer::send_with_aad).
+  pub fn send_with_aad(&self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.crypto.encrypt_with_aad(aad, buffer)?;
+    self.socket.send_to(buffer, self.remote_addr())?;
+    Ok(())
+  }
+
+  /// Encrypts the message into `scratch` and sends it to the client, without allocating.
+  ///
+  /// See [`Peer::send_slice`](crate::Peer::send_slice).
+  pub fn send_slice(&self, message: &[u8], scratch: &mut [u8]) -> io::Result<()> {
+    let len = self.crypto.encrypt_into(&[], message, scratch)?;
+    self.socket.send_to(&scratch[..len], self.remote_addr())?;
+    Ok(())
+  }
+
+  /// Answers a handshake initiation, returning `true` if a new session key was derived.
+  fn answer_initiation(&self, packet: &[u8]) -> io::Result<bool> {
+    let mut responder = self.responder.lock().unwrap_or_else(|e| e.into_inner());
+    let Ok((response, key)) = responder.respond(self.key, self.crypto.suite(), packet) else {
+      return Ok(false);
+    };
+    self.socket.send_to(response, self.remote_addr())?;
+    match key {
+      Some(key) => {
+        self.crypto.set_key(key)?;
+        self.heard();
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+}
@@ -1,8 +1,10 @@
+#![allow(clippy::io_other_error)]
+
 use std::io;
 
 /// Opaque error for encryption and decryption failures.
 ///
-/// This error is returned when AES-GCM operations fail, typically due to
+/// This error is returned when encryption or decryption fails, typically due to
 /// corrupted data or authentication failures.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct CryptoError;
@@ -27,10 +29,248 @@ impl std::fmt::Display for CryptoError {
 
 impl std::error::Error for CryptoError {}
 
+/// Error for messages rejected by the replay window.
+///
+/// This error is returned when a message authenticates correctly but its
+/// sequence number has already been received, or is too old to be checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ReplayError;
+
+impl From<ReplayError> for io::Error {
+  fn from(_: ReplayError) -> Self {
+      io::Error::new(io::ErrorKind::Other, ReplayError)
+  }
+}
+
+impl std::fmt::Display for ReplayError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "duplicate or replayed message")
+  }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Opaque error for handshake failures.
+///
+/// This error is returned when a handshake message is malformed, does not
+/// authenticate, or when the other side does not answer in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct HandshakeError;
+
+impl From<HandshakeError> for io::Error {
+  fn from(_: HandshakeError) -> Self {
+      io::Error::new(io::ErrorKind::Other, HandshakeError)
+  }
+}
+
+impl std::fmt::Display for HandshakeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "handshake failed")
+  }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Error for sending before there is a session.
+///
+/// Messages are only ever sealed with a session key, so nothing can be sent
+/// until a [`handshake`](crate::Peer::handshake) has started a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct NoSessionError;
+
+impl From<NoSessionError> for io::Error {
+  fn from(_: NoSessionError) -> Self {
+      io::Error::new(io::ErrorKind::NotConnected, NoSessionError)
+  }
+}
+
+impl std::fmt::Display for NoSessionError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "no session, handshake first")
+  }
+}
+
+impl std::error::Error for NoSessionError {}
+
+/// Error for reliable messages or acknowledgements that don't belong to this side's stream.
+///
+/// Both sides start their reliable streams over on every handshake, so within
+/// a session this only happens if one side started over on its own. Handshake
+/// again to start both over together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DesyncError;
+
+impl From<DesyncError> for io::Error {
+  fn from(_: DesyncError) -> Self {
+      io::Error::new(io::ErrorKind::ConnectionReset, DesyncError)
+  }
+}
+
+impl std::fmt::Display for DesyncError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "reliable stream out of sync, handshake again")
+  }
+}
+
+impl std::error::Error for DesyncError {}
+
+/// Error for hole punching that did not reach the other side.
+///
+/// This error is returned by [`Peer::punch`](crate::Peer::punch) when none of
+/// the candidate addresses answered in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PunchError;
+
+impl From<PunchError> for io::Error {
+  fn from(_: PunchError) -> Self {
+      io::Error::new(io::ErrorKind::TimedOut, PunchError)
+  }
+}
+
+impl std::fmt::Display for PunchError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "no candidate address answered")
+  }
+}
+
+impl std::error::Error for PunchError {}
+
+/// Error for a rendezvous that did not find the other side.
+///
+/// This error is returned by [`Peer::rendezvous`](crate::Peer::rendezvous) when
+/// the rendezvous server has not introduced the other side before the read
+/// timeout ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct RendezvousError;
+
+impl From<RendezvousError> for io::Error {
+  fn from(_: RendezvousError) -> Self {
+      io::Error::new(io::ErrorKind::TimedOut, RendezvousError)
+  }
+}
+
+impl std::fmt::Display for RendezvousError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "rendezvous server did not introduce the other side")
+  }
+}
+
+impl std::error::Error for RendezvousError {}
+
+/// Error for a peer that has stopped answering.
+///
+/// This error is returned by `recv()` once nothing has been heard from the
+/// other side for as long as the [`KeepalivePolicy`](crate::KeepalivePolicy)
+/// allows. It is returned once per silence, receiving carries on afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DeadPeerError;
+
+impl From<DeadPeerError> for io::Error {
+  fn from(_: DeadPeerError) -> Self {
+      io::Error::new(io::ErrorKind::TimedOut, DeadPeerError)
+  }
+}
+
+impl std::fmt::Display for DeadPeerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "peer stopped answering")
+  }
+}
+
+impl std::error::Error for DeadPeerError {}
+
+/// Error for a session that the other side replaced with a new one.
+///
+/// This error is returned by `recv()` when the responder answers a handshake
+/// that starts a new session in place of one that was already in use, usually
+/// because the other side restarted. Reliable messages and channels start over
+/// with the new session, so whatever was built on top of them should too.
+/// Receiving carries on afterwards.
+///
+/// Its kind is [`io::ErrorKind::ConnectionAborted`], unlike the
+/// [`io::ErrorKind::ConnectionReset`] of a [`DesyncError`], so a restart of the
+/// other side can be told apart from its stream starting over on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct NewSessionError;
+
+impl From<NewSessionError> for io::Error {
+  fn from(_: NewSessionError) -> Self {
+      io::Error::new(io::ErrorKind::ConnectionAborted, NewSessionError)
+  }
+}
+
+impl std::fmt::Display for NewSessionError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "other side started a new session")
+  }
+}
+
+impl std::error::Error for NewSessionError {}
+
+/// Error for messages that are too large to be sent.
+///
+/// This error is returned when a message is larger than the maximum message
+/// size of the [`FragmentPolicy`](crate::FragmentPolicy), or, with path MTU
+/// discovery on, when a message that is never fragmented does not fit into
+/// the largest datagram known to make it through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageTooLargeError {
+  /// Size of the message in bytes.
+  pub len: usize,
+  /// Largest message size in bytes that could have been sent.
+  pub max: usize,
+}
+
+impl From<MessageTooLargeError> for io::Error {
+  fn from(e: MessageTooLargeError) -> Self {
+      io::Error::new(io::ErrorKind::InvalidInput, e)
+  }
+}
+
+impl std::fmt::Display for MessageTooLargeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "message of {} bytes is larger than the maximum of {} bytes", self.len, self.max)
+  }
+}
+
+impl std::error::Error for MessageTooLargeError {}
+
+/// Error returned when a datagram does not start with a valid header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+  /// The datagram is too short to contain a header.
+  Truncated,
+  /// The datagram does not belong to this protocol.
+  InvalidMagic,
+  /// The datagram was sent by a peer using an incompatible protocol version.
+  UnsupportedVersion(u8),
+  /// The datagram contains a message type this version does not know about.
+  UnknownMessageType(u8),
+}
+
+impl From<HeaderError> for io::Error {
+  fn from(e: HeaderError) -> Self {
+      io::Error::new(io::ErrorKind::InvalidData, e)
+  }
+}
+
+impl std::fmt::Display for HeaderError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Truncated => write!(f, "truncated header"),
+      Self::InvalidMagic => write!(f, "invalid header magic"),
+      Self::UnsupportedVersion(version) => write!(f, "unsupported protocol version {version}"),
+      Self::UnknownMessageType(message_type) => write!(f, "unknown message type {message_type}"),
+    }
+  }
+}
+
+impl std::error::Error for HeaderError {}
+
 /// Error returned when a key cannot be parsed or has an invalid format.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InvalidKeyError {
-  /// The key length is not exactly 16 bytes.
+  /// The key length is not 16 or 32 bytes, or does not match the cipher suite.
   InvalidLength,
   /// The key contains invalid hexadecimal characters.
   InvalidHex(hex::FromHexError),
@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::message::MessageType;
+use crate::reliable::SEQUENCE_SIZE;
+use crate::channel::CHANNEL_HEADER_SIZE;
+
+/// Fragment header size in bytes (message ID + index + count + message type)
+pub const FRAGMENT_HEADER_SIZE: usize = 9;
+
+/// Limits on messages that are too large for a single datagram.
+///
+/// Such messages are split into fragments, each encrypted and authenticated
+/// on its own, and put back together by the other side. If any fragment is
+/// lost the whole message is lost, unless it was sent reliably, in which case
+/// the whole message is sent again. Both peers should use the same policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentPolicy {
+  /// Largest message that may be sent or received.
+  pub max_message_size: usize,
+  /// How long the fragments of an incomplete message are kept around.
+  pub reassembly_timeout: Duration,
+  /// Maximum number of bytes held in incomplete messages, the oldest ones are dropped to make room.
+  pub reassembly_buffer: usize,
+}
+
+impl FragmentPolicy {
+
+  /// Default largest message size
+  pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+  /// Default time the fragments of an incomplete message are kept for
+  pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+  /// Default number of bytes held in incomplete messages
+  pub const DEFAULT_REASSEMBLY_BUFFER: usize = 4 << 20;
+
+}
+
+impl Default for FragmentPolicy {
+  fn default() -> Self {
+    Self {
+      max_message_size: Self::DEFAULT_MAX_MESSAGE_SIZE,
+      reassembly_timeout: Self::DEFAULT_REASSEMBLY_TIMEOUT,
+      reassembly_buffer: Self::DEFAULT_REASSEMBLY_BUFFER,
+    }
+  }
+}
+
+/// Splits outgoing messages into fragments and puts incoming ones back together.
+///
+/// Every fragment starts with a small header, inside the encryption, saying
+/// which message it belongs to, where it goes and what kind of message the
+/// fragments add up to:
+///
+/// ```text
+/// +------------+-------+-------+------+---------+
+/// | message id | index | count | type | payload |
+/// +------------+-------+-------+------+---------+
+///        4         2       2      1
+/// ```
+pub struct Fragments {
+  policy: FragmentPolicy,
+  next: u32,
+  partial: HashMap<u32, Partial>,
+  buffered: usize,
+}
+
+struct Partial {
+  message_type: MessageType,
+  count: u16,
+  fragments: BTreeMap<u16, Vec<u8>>,
+  size: usize,
+  started: Instant,
+}
+
+impl Fragments {
+
+  pub fn new() -> Self {
+    Self {
+      policy: FragmentPolicy::default(),
+      next: 0,
+      partial: HashMap::new(),
+      buffered: 0,
+    }
+  }
+
+  pub fn policy(&self) -> FragmentPolicy {
+    self.policy
+  }
+
+  pub fn set_policy(&mut self, policy: FragmentPolicy) {
+    self.policy = policy;
+  }
+
+  /// Splits `message` into fragment plaintexts of at most `size` bytes each,
+  /// returning `None` if it needs more fragments than can be numbered.
+  pub fn split(&mut self, message_type: MessageType, message: &[u8], size: usize) -> Option<Vec<Vec<u8>>> {
+    let payload = size.checked_sub(FRAGMENT_HEADER_SIZE).filter(|payload| *payload > 0)?;
+    let count = u16::try_from(message.len().div_ceil(payload)).ok()?;
+    let id = self.next;
+    self.next = self.next.wrapping_add(1);
+    let fragments = message.chunks(payload).enumerate().map(|(index, chunk)| {
+      let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+      fragment.extend_from_slice(&id.to_be_bytes());
+      fragment.extend_from_slice(&(index as u16).to_be_bytes());
+      fragment.extend_from_slice(&count.to_be_bytes());
+      fragment.push(message_type as u8);
+      fragment.extend_from_slice(chunk);
+      fragment
+    });
+    Some(fragments.collect())
+  }
+
+  /// Takes in an authenticated fragment, returning the whole message once
+  /// every fragment of it has arrived.
+  ///
+  /// Malformed fragments, and fragments of messages that would be too large,
+  /// are dropped along with the rest of their message. The maximum message
+  /// size is for what the application sent, so the sequence number or
+  /// channel header in front of it does not count against it.
+  pub fn reassemble(&mut self, fragment: &[u8]) -> Option<(MessageType, Vec<u8>)> {
+    let now = Instant::now();
+    self.expire(now);
+
+    let (header, payload) = fragment.split_first_chunk::<FRAGMENT_HEADER_SIZE>()?;
+    let id = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let index = u16::from_be_bytes(header[4..6].try_into().unwrap());
+    let count = u16::from_be_bytes(header[6..8].try_into().unwrap());
+    let message_type = MessageType::from_u8(header[8])
+      .filter(|message_type| matches!(message_type, MessageType::Data | MessageType::Reliable | MessageType::Channel))?;
+    if index >= count {
+      return None;
+    }
+
+    let partial = self.partial.entry(id).or_insert_with(|| Partial {
+      message_type,
+      count,
+      fragments: BTreeMap::new(),
+      size: 0,
+      started: now,
+    });
+    if partial.message_type != message_type || partial.count != count {
+      self.remove(id);
+      return None;
+    }
+    if partial.fragments.contains_key(&index) {
+      return None;
+    }
+    if partial.size + payload.len() > self.policy.max_message_size + header_size(message_type) {
+      self.remove(id);
+      return None;
+    }
+    partial.fragments.insert(index, payload.to_vec());
+    partial.size += payload.len();
+    self.buffered += payload.len();
+
+    if partial.fragments.len() == usize::from(count) {
+      let partial = self.remove(id)?;
+      let message = partial.fragments.into_values().flatten().collect();
+      return Some((message_type, message));
+    }
+
+    // make room by dropping the oldest incomplete messages
+    while self.buffered > self.policy.reassembly_buffer {
+      let oldest = self.partial.iter().min_by_key(|(_, partial)| partial.started).map(|(id, _)| *id)?;
+      self.remove(oldest);
+    }
+    None
+  }
+
+  /// Drops incomplete messages that have been waiting for too long.
+  fn expire(&mut self, now: Instant) {
+    let timeout = self.policy.reassembly_timeout;
+    let expired = self.partial.iter()
+      .filter(|(_, partial)| now.duration_since(partial.started) >= timeout)
+      .map(|(id, _)| *id)
+      .collect::<Vec<_>>();
+    for id in expired {
+      self.remove(id);
+    }
+  }
+
+  fn remove(&mut self, id: u32) -> Option<Partial> {
+    let partial = self.partial.remove(&id)?;
+    self.buffered -= partial.size;
+    Some(partial)
+  }
+
+}
+
+/// Returns the size of what goes in front of the application's message in a message of the given type.
+const fn header_size(message_type: MessageType) -> usize {
+  match message_type {
+    MessageType::Reliable => SEQUENCE_SIZE,
+    MessageType::Channel => CHANNEL_HEADER_SIZE,
+    _ => 0,
+  }
+}
+
+impl Default for Fragments {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,222 @@
+use std::time::Duration;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret};
+
+use crate::util::unix_nanos;
+use crate::key::Key;
+use crate::error::HandshakeError;
+use crate::crypto::{Crypto, CipherSuite, Cipher};
+use crate::message::{MessageType, Header};
+
+/// Domain separation label mixed into every derived key
+const LABEL: &[u8] = b"twopoint handshake v1";
+
+/// X25519 public key size in bytes
+const PUBLIC_KEY_SIZE: usize = 32;
+/// Initiation timestamp size in bytes
+const TIMESTAMP_SIZE: usize = 8;
+/// Tag size in bytes
+const TAG_SIZE: usize = Crypto::TAG_SIZE;
+
+/// The initiator's half of a handshake.
+///
+/// The initiation message carries a fresh ephemeral X25519 public key and
+/// an encrypted timestamp, sealed with a key derived from the pre-shared key
+/// so that only someone holding the pre-shared key can start a handshake.
+///
+/// Handshake messages are sealed with the same cipher suite as the session,
+/// and all derived keys have the length the suite requires.
+pub struct Initiator {
+  suite: CipherSuite,
+  secret: ReusableSecret,
+  public: PublicKey,
+  packet: Vec<u8>,
+}
+
+impl Initiator {
+
+  /// Initiation message size in bytes (header + public key + timestamp + tag)
+  pub const INITIATION_SIZE: usize = Header::SIZE + PUBLIC_KEY_SIZE + TIMESTAMP_SIZE + TAG_SIZE;
+
+  pub fn new(psk: Key, suite: CipherSuite) -> Self {
+    Self::at(psk, suite, unix_nanos())
+  }
+
+  /// Creates an initiation carrying the given timestamp, in nanoseconds since the Unix epoch.
+  pub(crate) fn at(psk: Key, suite: CipherSuite, timestamp: u64) -> Self {
+    let secret = ReusableSecret::random();
+    let public = PublicKey::from(&secret);
+
+    let mut packet = Vec::with_capacity(Self::INITIATION_SIZE);
+    packet.extend_from_slice(&Header::new(MessageType::HandshakeInitiation).to_bytes());
+    packet.extend_from_slice(public.as_bytes());
+
+    let mut timestamp = timestamp.to_be_bytes().to_vec();
+    seal(suite, initiation_key(psk, suite, &public), &packet, &mut timestamp);
+    packet.extend_from_slice(&timestamp);
+
+    Self { suite, secret, public, packet }
+  }
+
+  /// Returns the initiation message to send to the responder.
+  ///
+  /// The same message should be sent again if no response arrives.
+  pub fn packet(&self) -> &[u8] {
+    &self.packet
+  }
+
+  /// Checks the responder's response and derives the session key.
+  pub fn finish(&self, psk: Key, packet: &[u8]) -> Result<Key, HandshakeError> {
+    if packet.len() != Responder::RESPONSE_SIZE || Header::message_type(packet) != Some(MessageType::HandshakeResponse) {
+      return Err(HandshakeError);
+    }
+
+    let (header, tag) = packet.split_at(Header::SIZE + PUBLIC_KEY_SIZE);
+    let responder = PublicKey::from(<[u8; PUBLIC_KEY_SIZE]>::try_from(&header[Header::SIZE..]).unwrap());
+
+    let shared = self.secret.diffie_hellman(&responder);
+    let (response_key, session_key) = session_keys(psk, self.suite, &shared, &self.public, &responder)?;
+
+    let mut empty = tag.to_vec();
+    open(self.suite, response_key, header, &mut empty)?;
+    Ok(session_key)
+  }
+
+}
+
+/// The responder's side of handshakes.
+///
+/// Remembers the newest accepted initiation timestamp so that recorded
+/// initiation messages cannot be replayed to reset the session, and keeps
+/// the last response around to resend it if the initiator retransmits.
+///
+/// A new responder has not accepted anything yet, so it starts out only
+/// accepting initiations from the last [`Responder::CLOCK_SKEW`], which stops
+/// initiations recorded before a restart from being replayed to it. An
+/// initiator whose clock is further behind than that can't start a handshake
+/// until the responder has been up for as long.
+pub struct Responder {
+  timestamp: u64,
+  initiation: Vec<u8>,
+  packet: Vec<u8>,
+}
+
+impl Responder {
+
+  /// Response message size in bytes (header + public key + tag)
+  pub const RESPONSE_SIZE: usize = Header::SIZE + PUBLIC_KEY_SIZE + TAG_SIZE;
+
+  /// How far behind the responder's clock an initiator's clock may be
+  pub const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+  pub fn new() -> Self {
+    Self {
+      timestamp: unix_nanos().saturating_sub(Self::CLOCK_SKEW.as_nanos() as u64),
+      initiation: Vec::new(),
+      packet: Vec::new(),
+    }
+  }
+
+  /// Answers an initiation message.
+  ///
+  /// Returns the response message to send back to the initiator, along with
+  /// the new session key if this initiation started a new session. A repeated
+  /// initiation gets the previous response again, without a new session key.
+  pub fn respond(&mut self, psk: Key, suite: CipherSuite, packet: &[u8]) -> Result<(&[u8], Option<Key>), HandshakeError> {
+    if packet.len() != Initiator::INITIATION_SIZE || Header::message_type(packet) != Some(MessageType::HandshakeInitiation) {
+      return Err(HandshakeError);
+    }
+
+    if !self.initiation.is_empty() && self.initiation == packet {
+      return Ok((&self.packet, None));
+    }
+
+    let (header, sealed) = packet.split_at(Header::SIZE + PUBLIC_KEY_SIZE);
+    let initiator = PublicKey::from(<[u8; PUBLIC_KEY_SIZE]>::try_from(&header[Header::SIZE..]).unwrap());
+
+    let mut timestamp = sealed.to_vec();
+    open(suite, initiation_key(psk, suite, &initiator), header, &mut timestamp)?;
+    let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+    if timestamp <= self.timestamp {
+      return Err(HandshakeError);
+    }
+
+    let secret = ReusableSecret::random();
+    let public = PublicKey::from(&secret);
+
+    let shared = secret.diffie_hellman(&initiator);
+    let (response_key, session_key) = session_keys(psk, suite, &shared, &initiator, &public)?;
+
+    let mut response = Vec::with_capacity(Self::RESPONSE_SIZE);
+    response.extend_from_slice(&Header::new(MessageType::HandshakeResponse).to_bytes());
+    response.extend_from_slice(public.as_bytes());
+
+    let mut tag = Vec::with_capacity(TAG_SIZE);
+    seal(suite, response_key, &response, &mut tag);
+    response.extend_from_slice(&tag);
+
+    self.timestamp = timestamp;
+    self.initiation = packet.to_vec();
+    self.packet = response;
+
+    Ok((&self.packet, Some(session_key)))
+  }
+
+}
+
+impl Default for Responder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Derives the key that seals the initiation message from the pre-shared key.
+fn initiation_key(psk: Key, suite: CipherSuite, initiator: &PublicKey) -> Key {
+  expand(&Hkdf::<Sha256>::new(Some(LABEL), &psk), suite, &[b"initiation", initiator.as_bytes()])
+}
+
+/// Derives the response key and session key from the pre-shared key and the ephemeral exchange.
+///
+/// Mixing in the pre-shared key authenticates both sides, while the ephemeral
+/// exchange makes the session key unrecoverable once both secrets are dropped.
+fn session_keys(
+  psk: Key,
+  suite: CipherSuite,
+  shared: &SharedSecret,
+  initiator: &PublicKey,
+  responder: &PublicKey,
+) -> Result<(Key, Key), HandshakeError> {
+  // reject low-order points, which would make the exchange predictable
+  if !shared.was_contributory() {
+    return Err(HandshakeError);
+  }
+  let hkdf = Hkdf::<Sha256>::new(Some(&psk), shared.as_bytes());
+  let response = expand(&hkdf, suite, &[LABEL, b"response", initiator.as_bytes(), responder.as_bytes()]);
+  let session = expand(&hkdf, suite, &[LABEL, b"session", initiator.as_bytes(), responder.as_bytes()]);
+  Ok((response, session))
+}
+
+pub(crate) fn expand(hkdf: &Hkdf<Sha256>, suite: CipherSuite, info: &[&[u8]]) -> Key {
+  let mut key = [0u8; Key::MAXIMUM_SIZE];
+  let key = &mut key[..suite.key_size()];
+  hkdf.expand_multi_info(info, key).expect("key length is valid for sha256");
+  Key::try_from(&*key).expect("suite key size is a valid key length")
+}
+
+/// Seals a handshake message, a zero nonce is fine since every handshake key seals exactly one message.
+pub(crate) fn seal(suite: CipherSuite, key: Key, aad: &[u8], buffer: &mut Vec<u8>) {
+  Cipher::new(suite, &key)
+    .expect("derived key matches the suite")
+    .encrypt(&[0; Crypto::NONCE_SIZE], aad, buffer)
+    .expect("buffer can grow");
+}
+
+/// Opens a handshake message sealed by [`seal`].
+pub(crate) fn open(suite: CipherSuite, key: Key, aad: &[u8], buffer: &mut Vec<u8>) -> Result<(), HandshakeError> {
+  Cipher::new(suite, &key)
+    .expect("derived key matches the suite")
+    .decrypt(&[0; Crypto::NONCE_SIZE], aad, buffer)
+    .map_err(|_| HandshakeError)
+}
@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+/// When keepalives are sent, and when the other side is given up on.
+///
+/// A peer with a keepalive policy sends an empty encrypted message whenever it
+/// has not sent anything for `interval`, so that an idle link still carries
+/// traffic in both directions. If nothing authenticates from the other side for
+/// `misses` intervals in a row, the other side is considered dead. Both peers
+/// should use the same policy, otherwise an idle peer without one looks dead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeepalivePolicy {
+  /// How long the link may be idle before a keepalive is sent.
+  pub interval: Duration,
+  /// Number of intervals without hearing from the other side before it is considered dead.
+  pub misses: u32,
+}
+
+impl KeepalivePolicy {
+
+  /// Default idle time before a keepalive is sent
+  pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+  /// Default number of missed intervals before the other side is considered dead
+  pub const DEFAULT_MISSES: u32 = 3;
+
+  /// Returns how long the other side may stay silent before it is considered dead.
+  pub fn timeout(&self) -> Duration {
+    self.interval.saturating_mul(self.misses)
+  }
+
+}
+
+impl Default for KeepalivePolicy {
+  fn default() -> Self {
+    Self {
+      interval: Self::DEFAULT_INTERVAL,
+      misses: Self::DEFAULT_MISSES,
+    }
+  }
+}
+
+/// What needs doing after a call to [`Liveness::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Poll {
+  /// A keepalive should be sent now.
+  pub keepalive: bool,
+  /// The other side has just been found dead.
+  pub died: bool,
+}
+
+/// Tracks when the link last carried traffic in each direction.
+pub struct Liveness {
+  policy: Option<KeepalivePolicy>,
+  last_heard: Option<Instant>,
+  last_sent: Instant,
+  since: Instant,
+  dead: bool,
+}
+
+impl Liveness {
+
+  pub fn new() -> Self {
+    let now = Instant::now();
+    Self { policy: None, last_heard: None, last_sent: now, since: now, dead: false }
+  }
+
+  pub fn policy(&self) -> Option<KeepalivePolicy> {
+    self.policy
+  }
+
+  /// Sets the policy, the other side gets a full timeout from now before it is considered dead.
+  pub fn set_policy(&mut self, policy: Option<KeepalivePolicy>) {
+    self.policy = policy;
+    self.since = Instant::now();
+    self.dead = false;
+  }
+
+  /// Returns when a message from the other side last authenticated.
+  pub fn last_heard(&self) -> Option<Instant> {
+    self.last_heard
+  }
+
+  /// Returns `false` once the other side has been found dead, until it is heard from again.
+  pub fn is_alive(&self) -> bool {
+    !self.dead
+  }
+
+  /// Records that a message from the other side authenticated.
+  pub fn heard(&mut self) {
+    self.last_heard = Some(Instant::now());
+    self.dead = false;
+  }
+
+  /// Records that a message was sent to the other side.
+  pub fn sent(&mut self) {
+    self.last_sent = Instant::now();
+  }
+
+  /// Returns when [`Liveness::poll`] next has something to do, or `None` without a policy.
+  pub fn deadline(&self) -> Option<Instant> {
+    let policy = self.policy?;
+    let keepalive = self.last_sent + policy.interval;
+    if self.dead {
+      Some(keepalive)
+    } else {
+      Some(keepalive.min(self.silent_since() + policy.timeout()))
+    }
+  }
+
+  /// Checks the timers, marking the other side as dead if it has been silent for too long.
+  pub fn poll(&mut self, now: Instant) -> Poll {
+    let Some(policy) = self.policy else {
+      return Poll::default();
+    };
+    let keepalive = now >= self.last_sent + policy.interval;
+    let died = !self.dead && now >= self.silent_since() + policy.timeout();
+    self.dead |= died;
+    Poll { keepalive, died }
+  }
+
+  fn silent_since(&self) -> Instant {
+    match self.last_heard {
+      Some(last_heard) => last_heard.max(self.since),
+      None => self.since,
+    }
+  }
+
+}
+
+impl Default for Liveness {
+  fn default() -> Self {
+    Self::new()
+  }
+}
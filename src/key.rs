@@ -3,24 +3,77 @@ use std::str::FromStr;
 
 use crate::error::InvalidKeyError;
 
-/// A 128-bit encryption key for securing peer communications.
+/// A 128-bit or 256-bit encryption key for securing peer communications.
 ///
 /// Keys can be created from byte arrays, byte slices, or hex strings.
-/// All cryptographic operations use AES-128-GCM encryption.
+/// The key length must match the [`CipherSuite`](crate::CipherSuite) it is used with.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Key([u8; 16]);
+pub struct Key {
+  bytes: [u8; Self::MAXIMUM_SIZE],
+  len: usize,
+}
+
+impl Key {
+
+  /// Size of a 128-bit key in bytes
+  pub const SIZE_128: usize = 16;
+  /// Size of a 256-bit key in bytes
+  pub const SIZE_256: usize = 32;
+
+  /// Largest supported key size in bytes
+  pub const MAXIMUM_SIZE: usize = Self::SIZE_256;
+
+  /// Returns the key length in bytes.
+  pub const fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Always returns `false`, keys are never empty.
+  pub const fn is_empty(&self) -> bool {
+    false
+  }
+
+  /// Returns a random 256-bit key from the operating system's random number generator.
+  pub fn random() -> Self {
+    let mut bytes = [0u8; Self::SIZE_256];
+    getrandom::getrandom(&mut bytes).expect("operating system random number generator failed");
+    Self::from(bytes)
+  }
+
+  /// Returns the key as a hex string, in the form [`Key::from_str`] parses.
+  pub fn to_hex(&self) -> String {
+    hex::encode(&**self)
+  }
+
+}
 
 impl From<[u8; 16]> for Key {
   fn from(array: [u8; 16]) -> Self {
-    Self(array)
+    let mut bytes = [0u8; Self::MAXIMUM_SIZE];
+    bytes[..16].copy_from_slice(&array);
+    Self { bytes, len: 16 }
+  }
+}
+
+impl From<[u8; 32]> for Key {
+  fn from(array: [u8; 32]) -> Self {
+    Self { bytes: array, len: 32 }
   }
 }
 
 impl TryFrom<&[u8]> for Key {
   type Error = InvalidKeyError;
 
+  /// Creates a key from a slice of exactly 16 or 32 bytes.
   fn try_from(slice: &[u8]) -> Result<Self, Self::Error> {
-    Ok(Self(slice.try_into().map_err(|_| InvalidKeyError::InvalidLength)?))
+    match slice.len() {
+      Self::SIZE_128 | Self::SIZE_256 => {
+        let mut bytes = [0u8; Self::MAXIMUM_SIZE];
+        bytes[..slice.len()].copy_from_slice(slice);
+        Ok(Self { bytes, len: slice.len() })
+      }
+      _ => Err(InvalidKeyError::InvalidLength),
+    }
   }
 }
 
@@ -29,28 +82,22 @@ impl FromStr for Key {
 
   /// Parses a key from a hex string.
   ///
-  /// The string must represent exactly 16 bytes (32 hex characters).
+  /// The string must represent exactly 16 or 32 bytes (32 or 64 hex characters).
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     Self::try_from(hex::decode(s)?.as_ref())
   }
 }
 
 impl Deref for Key {
-  type Target = [u8; 16];
+  type Target = [u8];
 
   fn deref(&self) -> &Self::Target {
-    &self.0
-  }
-}
-
-impl AsRef<[u8; 16]> for Key {
-  fn as_ref(&self) -> &[u8; 16] {
-    &self.0
+    &self.bytes[..self.len]
   }
 }
 
 impl AsRef<[u8]> for Key {
   fn as_ref(&self) -> &[u8] {
-    &self.0
+    self
   }
 }
@@ -1,46 +1,209 @@
 //! Encrypted UDP messaging between two endpoints.
 //!
 //! This crate provides a simple interface for establishing encrypted UDP connections
-//! between peers using AES-128-GCM encryption. Each peer can connect to one remote
+//! between peers using AES-GCM, AES-GCM-SIV or ChaCha20-Poly1305 encryption. Each peer can connect to one remote
 //! endpoint at a time and exchange binary messages securely.
 //!
 //! # Encryption Overhead
 //!
-//! All messages have a 28-byte overhead (16-byte authentication tag + 12-byte nonce)
-//! added during encryption. Ensure receive buffers are large enough to accommodate
-//! this overhead plus your message data.
+//! All messages have a 28-byte overhead (4-byte header + 16-byte authentication tag +
+//! 8-byte message counter) added during encryption. The header carries the protocol
+//! version and message type in the clear, and is authenticated along with the message.
+//! Ensure receive buffers are large enough to accommodate this overhead plus your message data.
+//!
+//! Messages sent with [`Peer::send_with_aad`] also carry associated data in the clear,
+//! which adds its own length plus 2 bytes. Peers with a connection ID, see
+//! [`Peer::set_connection_id`], add another 8 bytes, and so do all peers set up
+//! without a handshake, which always send one.
 //!
 //! # Security
 //!
 //! The encryption implementation was created without formal cryptography experience,
-//! though I believe it is generally sound. I use AES-128-GCM with ChaCha8 CSPRNG
-//! generated nonces where reuse is theoretically possible after ~2^96 nonces.
-//! You probably shouldn't put this into production.
+//! though I believe it is generally sound. You probably shouldn't put this into production.
+//!
+//! Nonces are built from a direction bit and a per-sender message counter shared
+//! between clones of a [`Peer`]. With a handshake, the direction is chosen by each
+//! peer's [`Role`], and every session key is derived from fresh ephemeral keys on both
+//! sides, so counters start from zero in each session without ever repeating a nonce
+//! under the same key, even across restarts. Without one, each sender seals with a key
+//! of its own, derived from the pre-shared key and its random connection ID, and its
+//! counter starts from the current time in nanoseconds, so that it keeps counting up
+//! past what the other side has seen when the sender restarts.
+//!
+//! Received counters are tracked in a sliding window so that duplicated or replayed
+//! messages are rejected while moderately reordered ones are still accepted.
+//!
+//! # Forward Secrecy
+//!
+//! Peers created with [`Peer::new`] seal messages with keys derived from the pre-shared
+//! [`Key`] and can send right away, so anyone who learns the pre-shared key can decrypt
+//! recorded traffic. Peers created with [`Peer::with_handshake`] opt in to forward
+//! secrecy instead, the pre-shared key never seals one of their messages.
+//! [`Peer::handshake`] runs an X25519 exchange authenticated by the pre-shared key and
+//! starts a session with a fresh key that cannot be recovered later, so learning the
+//! pre-shared key doesn't decrypt recorded traffic. Sending fails with a
+//! [`NoSessionError`] until the first handshake. Both sides of a link have to be set up
+//! the same way.
+//!
+//! # Rekeying
+//!
+//! AES-GCM should only seal a limited number of messages under one key, so each peer
+//! with a handshake ratchets its sending key forward after a number of messages or
+//! amount of time, as configured by a [`RekeyPolicy`]. Keys derived straight from the
+//! pre-shared key are never ratcheted. The key epoch, modulo 64, travels in the header
+//! of every message, so the other side follows along and keeps accepting the
+//! previous key for a short overlap while reordered messages are still in flight.
+//! A side that misses every message of more than 62 epochs in a row can no longer
+//! follow, and has to [handshake](Peer::handshake) again.
+//!
+//! # NAT Traversal
+//!
+//! Peers behind NATs usually cannot reach each other's local address. Given candidate
+//! addresses for the other side, learned from a rendezvous, [`Peer::punch`] sends
+//! authenticated punches to all of them at once from both sides, opening mappings in
+//! both NATs, and connects to whichever address answers first. Every round of punches
+//! carries a fresh random challenge that the answer has to echo, so recorded punches
+//! can't lure a peer to another address.
+//!
+//! A [`RendezvousServer`], such as the `twopoint-rendezvous` binary, tells the two
+//! sides where to punch to. Both call [`Peer::rendezvous`] with the same session name
+//! and get back the address the server saw the other side at. Peers register under a
+//! session ID derived from the pre-shared key, so the server never learns the key.
+//! The server is given the [`registration_key`] instead, a one-way derivation of the
+//! pre-shared key, and only takes in registrations that authenticate with it, each
+//! just once and only within 30 seconds of being made, so registrations can be
+//! neither forged nor replayed to take a peer's place.
+//!
+//! Some NATs map every destination to a different port, so punching cannot get
+//! through them. With a [`RelayServer`] set as a fallback, see [`Peer::set_relay`],
+//! peers that fail to punch through meet at the relay instead, which forwards their
+//! datagrams to each other as they are, without ever holding the key.
+//!
+//! # Keepalives
+//!
+//! A plain `recv()` cannot tell a quiet peer from a dead one. With a [`KeepalivePolicy`]
+//! set, a peer sends an encrypted keepalive whenever it has been idle for a while and
+//! tracks when it last heard from the other side, see [`Peer::last_heard`]. Once the
+//! other side misses too many intervals, `recv()` fails with a [`DeadPeerError`].
+//!
+//! # Reliable Delivery
+//!
+//! Messages sent with [`Peer::send_reliable`] carry a sequence number and are
+//! retransmitted until the other side acknowledges them, with selective
+//! acknowledgements and a retransmission timeout estimated from the round-trip
+//! time. The other side delivers them from `recv()` in order, alongside ordinary
+//! unreliable messages sent over the same peer.
+//!
+//! # Fragmentation
+//!
+//! Messages too large for a single datagram are split into fragments by [`Peer::send`]
+//! and [`Peer::send_reliable`], each encrypted and authenticated on its own, and put
+//! back together by `recv()`. Incomplete messages only take up a bounded amount of
+//! memory for a limited time, as configured by a [`FragmentPolicy`].
+//!
+//! # Path MTU Discovery
+//!
+//! Fragments are sized to fit a conservative 1200-byte datagram. With an [`MtuPolicy`]
+//! set, a peer instead probes the path with padded encrypted datagrams that must not
+//! be fragmented along the way, and uses the largest size the other side acknowledges,
+//! see [`Peer::mtu`] and [`Peer::max_payload_size`]. Messages that are never fragmented
+//! fail with a [`MessageTooLargeError`] when they don't fit.
+//!
+//! # Channels
+//!
+//! Independent message flows can share one peer as channels, see [`Peer::channel`].
+//! Each channel is sent with its own [`ChannelMode`]: unreliable, reliable and ordered,
+//! reliable and unordered, or latest-only. Reliable channels are acknowledged and
+//! retransmitted on their own, so a lost message never holds up another channel, and
+//! [`Peer::recv_channel`] says which channel a message came in on.
+//!
+//! # Congestion Control
+//!
+//! By default reliable messages go out as fast as they are sent. With a
+//! [`CongestionControl`] algorithm set, the built-in [`NewReno`] or [`Bbr`] or one
+//! of your own, they are queued while the congestion window is full and paced out
+//! at the rate the algorithm allows, see [`Peer::set_congestion_control`]. Unreliable messages can be held to a
+//! [`RateLimit`] instead, a token bucket that refuses messages while it is empty.
 //!
 //! # Core Types
 //!
 //! - [`Peer`] - A UDP endpoint that can send and receive encrypted messages
-//! - [`Key`] - A 128-bit encryption key for securing communications
+//! - [`PeerSender`] / [`PeerReceiver`] - The two halves of a split [`Peer`]
+//! - [`Channel`] - A handle for sending on one channel of a [`Peer`]
+//! - [`Endpoint`] - A server that talks to many peers over one socket, one [`Session`] each
+//! - [`RendezvousServer`] - A server that introduces peers behind NATs to each other
+//! - [`RelayServer`] - A server that forwards datagrams between peers that cannot reach each other
+//! - `AsyncPeer` - The same for tokio, behind the `tokio` feature
+//! - [`Key`] - A 128-bit or 256-bit encryption key for securing communications
+//! - [`CipherSuite`] - The encryption algorithm messages are sealed with
+//! - [`Role`] - Which end of a handshake a peer is on
+//! - [`RekeyPolicy`] - When keys are ratcheted forward
+//! - [`KeepalivePolicy`] - When keepalives are sent and the other side is given up on
+//! - [`SessionPolicy`] - How many sessions an [`Endpoint`] keeps, and for how long
+//! - [`FragmentPolicy`] - How large messages may be, and how they are put back together
+//! - [`MtuPolicy`] - Which datagram sizes path MTU discovery tries
+//! - [`ChannelMode`] - The delivery guarantees of a channel
+//! - [`CongestionControl`] - How quickly reliable messages are sent, implemented by [`NewReno`] and [`Bbr`]
+//! - [`RateLimit`] - How quickly unreliable messages may be sent
 //!
 //! # Errors
 //!
 //! - [`CryptoError`] - Encryption/decryption failures
+//! - [`ReplayError`] - Duplicated or replayed messages
+//! - [`HandshakeError`] - Handshake failures
+//! - [`NoSessionError`] - Sending before a handshake started a session
+//! - [`DesyncError`] - Reliable messages from a stream that started over on its own
+//! - [`PunchError`] - Hole punching that did not reach the other side
+//! - [`RendezvousError`] - A rendezvous that did not find the other side
+//! - [`DeadPeerError`] - The other side stopped answering
+//! - [`NewSessionError`] - The other side replaced the session with a new one
+//! - [`MessageTooLargeError`] - Messages too large to be sent
+//! - [`HeaderError`] - Datagrams from other protocols or incompatible versions
 //! - [`InvalidKeyError`] - Invalid key format or length
 
 mod util;
 mod error;
 mod key;
+mod replay;
+mod message;
+mod rekey;
+mod keepalive;
+mod reliable;
+mod fragment;
+mod mtu;
+mod channel;
+mod congestion;
 mod crypto;
+mod handshake;
+mod punch;
 mod peer;
+mod endpoint;
+mod rendezvous;
+mod relay;
+#[cfg(feature = "tokio")]
+mod async_peer;
 
 pub use util::*;
-pub use error::{CryptoError, InvalidKeyError};
+pub use error::{CryptoError, ReplayError, HandshakeError, NoSessionError, DesyncError, PunchError, RendezvousError, DeadPeerError, NewSessionError, MessageTooLargeError, HeaderError, InvalidKeyError};
 pub use key::Key;
-pub use peer::Peer;
+pub use rekey::RekeyPolicy;
+pub use keepalive::KeepalivePolicy;
+pub use fragment::FragmentPolicy;
+pub use mtu::MtuPolicy;
+pub use channel::ChannelMode;
+pub use congestion::{CongestionControl, NewReno, Bbr, RateLimit};
+pub use crypto::{CipherSuite, Role};
+pub use peer::{Peer, PeerSender, PeerReceiver, Channel};
+pub use endpoint::{Endpoint, Session, SessionPolicy};
+pub use rendezvous::{RendezvousServer, registration_key};
+pub use relay::RelayServer;
+#[cfg(feature = "tokio")]
+pub use async_peer::AsyncPeer;
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::net::UdpSocket;
   use std::time::Duration;
 
   fn create_test_key() -> Key {
@@ -48,6 +211,33 @@ mod tests {
     "5adf5e4a8a779d4cd7985a881b270bcf".parse().unwrap()
   }
 
+  /// Returns one side of a session sealed with `key`, as if a handshake had derived it.
+  fn session_crypto(key: Key, role: Role) -> crypto::Crypto {
+    let crypto = crypto::Crypto::without_session(role, CipherSuite::Aes128Gcm, RekeyPolicy::default());
+    crypto.set_key(key).expect("key length should match the suite");
+    crypto
+  }
+
+  /// Runs a handshake between two peers that can reach each other.
+  fn handshake(initiator: &mut Peer, responder: &mut Peer) {
+    std::thread::scope(|scope| {
+      let responding = scope.spawn(|| responder.handshake());
+      initiator.handshake().expect("initiator handshake failed");
+      responding.join().expect("responder panicked").expect("responder handshake failed");
+    });
+  }
+
+  /// Runs a handshake from a bare socket with whatever is at `addr`, which
+  /// `answer` gets to take in the initiation, returning the socket's side of the session.
+  fn handshake_from(socket: &UdpSocket, addr: std::net::SocketAddr, key: Key, answer: impl FnOnce()) -> crypto::Crypto {
+    let initiator = handshake::Initiator::new(key, CipherSuite::Aes128Gcm);
+    socket.send_to(initiator.packet(), addr).expect("failed to send initiation");
+    answer();
+    let mut response = [0u8; handshake::Responder::RESPONSE_SIZE];
+    let (len, _) = socket.recv_from(&mut response).expect("no handshake response arrived");
+    session_crypto(initiator.finish(key, &response[..len]).expect("invalid handshake response"), Role::Initiator)
+  }
+
   #[test]
   fn test_peer_connect_and_disconnect() {
     let key = create_test_key();
@@ -57,6 +247,7 @@ mod tests {
     let peer1 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create peer1");
     let peer2 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create peer2");
 
+    let peer1_addr = peer1.local_addr();
     let peer2_addr = peer2.local_addr();
 
     // connect peer1 to peer2
@@ -70,6 +261,11 @@ mod tests {
 
     // verify disconnection
     assert!(peer1.remote_addr_optional().is_none(), "peer1 should be disconnected");
+    assert_eq!(peer1.local_addr(), peer1_addr, "peer1 should keep its local address");
+
+    // a wildcard socket must not be tied to the loopback address by disconnecting
+    let wildcard = Peer::setup("0.0.0.0:0", "0.0.0.0:0", key).expect("failed to create peer");
+    assert!(wildcard.local_addr().ip().is_unspecified(), "peer should stay bound to any address");
   }
 
   #[test]
@@ -100,11 +296,9 @@ mod tests {
     assert!(peer1.remote_addr_optional().is_some(), "peer1 should be connected");
     assert!(peer2.remote_addr_optional().is_some(), "peer2 should be connected");
 
-    // clone peers so we can have mutable references
-    let mut peer1_sender = peer1.clone();
-    let mut peer1_receiver = peer1;
-    let mut peer2_sender = peer2.clone();
-    let mut peer2_receiver = peer2;
+    // split peers so we can have mutable references
+    let (mut peer1_sender, mut peer1_receiver) = peer1.split();
+    let (mut peer2_sender, mut peer2_receiver) = peer2.split();
 
     // set read timeouts to avoid hanging in tests
     peer1_receiver.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
@@ -137,14 +331,59 @@ mod tests {
     assert_eq!(&recv_buffer1, message2, "message from peer2 to peer1 was corrupted");
   }
 
+  #[test]
+  fn test_static_key() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create peer1");
+    let mut peer2 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create peer2");
+    let peer1_addr = peer1.local_addr();
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1_addr).expect("failed to connect peer2 to peer1");
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+
+    // without a handshake there is no role, and nothing to handshake with
+    assert_eq!(peer1.role(), None);
+    assert!(peer1.connection_id().is_some(), "static peers always send a connection ID");
+    assert_ne!(peer1.connection_id(), peer2.connection_id());
+    let error = peer1.handshake().expect_err("static peer handshaked");
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+
+    // a message of peer2's own sent back to it doesn't count as one from peer1
+    peer2.send(&mut b"echo".to_vec()).expect("failed to send message");
+    let mut packet = vec![0u8; 1024];
+    let len = peer1.socket().recv(&mut packet).expect("failed to capture packet");
+    peer1.socket().send(&packet[..len]).expect("failed to reflect packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv(&mut recv_buffer).expect_err("reflected message was accepted");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<CryptoError>()));
+
+    // a restarted peer has a new connection ID and key, and is followed right away
+    peer1.send(&mut b"before the restart".to_vec()).expect("failed to send message");
+    let len = peer2.socket().recv(&mut packet).expect("failed to capture packet");
+    let before = packet[..len].to_vec();
+    drop(peer1);
+    let mut restarted = Peer::setup(peer1_addr, peer2.local_addr(), key).expect("failed to restart peer1");
+    restarted.send(&mut b"after the restart".to_vec()).expect("failed to send message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"after the restart");
+
+    // and a message from before the restart can't be replayed after it
+    restarted.socket().send(&before).expect("failed to replay packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv(&mut recv_buffer).expect_err("replayed packet should be rejected");
+    assert!(is_replay(&error), "error was not a replay");
+  }
+
   #[test]
   fn test_server_client_connection_switching() {
     let key = create_test_key();
 
     // create three peers - one server and two clients
-    let server = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create server");
-    let client1 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create client1");
-    let client2 = Peer::setup("127.0.0.1:0", "0.0.0.0:0", key).expect("failed to create client2");
+    let server = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create server");
+    let client1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create client1");
+    let client2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create client2");
 
     // get addresses
     let server_addr = server.local_addr();
@@ -152,12 +391,12 @@ mod tests {
     let client2_addr = client2.local_addr();
 
     // create mutable clones for sending/receiving
-    let mut server_sender = server.clone();
-    let mut server_receiver = server.clone();
-    let mut client1_sender = client1.clone();
-    let mut client1_receiver = client1.clone();
-    let mut client2_sender = client2.clone();
-    let mut client2_receiver = client2.clone();
+    let mut server_sender = server.try_clone().expect("failed to clone server");
+    let mut server_receiver = server.try_clone().expect("failed to clone server");
+    let mut client1_sender = client1.try_clone().expect("failed to clone client1");
+    let mut client1_receiver = client1.try_clone().expect("failed to clone client1");
+    let mut client2_sender = client2.try_clone().expect("failed to clone client2");
+    let mut client2_receiver = client2.try_clone().expect("failed to clone client2");
 
     // set timeouts to avoid hanging
     server_receiver.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
@@ -172,6 +411,9 @@ mod tests {
     assert_eq!(server.remote_addr(), client1_addr, "server should be connected to client1");
     assert_eq!(client1.remote_addr(), server_addr, "client1 should be connected to server");
 
+    // each client gets a session of its own, so the clients never share keys
+    handshake(&mut client1_sender, &mut server_receiver);
+
     // test communication between server and client1
     let server_to_client1_msg = b"hello client1 from server !! :3";
     let client1_to_server_msg = b"hello server from client1 !! :3";
@@ -206,6 +448,8 @@ mod tests {
     assert_eq!(server.remote_addr(), client2_addr, "server should be connected to client2");
     assert_eq!(client2.remote_addr(), server_addr, "client2 should be connected to server");
 
+    handshake(&mut client2_sender, &mut server_receiver);
+
     // test communication between server and client2
     let server_to_client2_msg = b"hello client2 from server !! :D";
     let client2_to_server_msg = b"hello server from client2 !! :D";
@@ -242,4 +486,1483 @@ mod tests {
     // verify the error is a timeout
     assert!(can_retry(&result.unwrap_err()), "error was not a timeout");
   }
+
+  #[test]
+  fn test_replay_window() {
+    let mut window = replay::ReplayWindow::new();
+
+    // in-order and moderately reordered sequence numbers are accepted
+    assert!(window.update(100), "first sequence number should be accepted");
+    assert!(window.update(102), "newer sequence number should be accepted");
+    assert!(window.update(101), "reordered sequence number should be accepted");
+
+    // duplicates are rejected
+    assert!(!window.update(101), "duplicate sequence number should be rejected");
+    assert!(!window.update(102), "duplicate sequence number should be rejected");
+
+    // jump far ahead, old sequence numbers fall out of the window
+    let far = 102 + replay::ReplayWindow::SIZE + 10;
+    assert!(window.update(far), "far newer sequence number should be accepted");
+    assert!(!window.update(103), "sequence number outside of the window should be rejected");
+    assert!(window.update(far - 1), "sequence number inside of the window should be accepted");
+    assert!(!window.check(far), "duplicate sequence number should be rejected");
+  }
+
+  #[test]
+  fn test_peer_rejects_replay() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    // send a message once and capture it like an attacker on the path would
+    let message = b"pay alice 10 treats";
+    peer1.send(&mut message.to_vec()).expect("failed to send message");
+    let mut packet = vec![0u8; 1024];
+    let len = peer2.socket().recv(&mut packet).expect("failed to capture packet");
+    packet.truncate(len);
+
+    // the original is accepted
+    peer1.socket().send(&packet).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive original packet");
+    assert_eq!(&recv_buffer, message, "original message was corrupted");
+
+    // the replayed copy is rejected with a distinct error
+    peer1.socket().send(&packet).expect("failed to replay packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let result = peer2.recv(&mut recv_buffer);
+    assert!(is_replay(&result.expect_err("replayed packet should be rejected")), "error was not a replay");
+  }
+
+  #[test]
+  fn test_crypto_counter_nonces() {
+    let key = create_test_key();
+
+    let initiator = session_crypto(key, Role::Initiator);
+    let initiator_clone = initiator.clone();
+    let responder = session_crypto(key, Role::Responder);
+
+    // clones share one counter, so they never produce the same nonce
+    let mut packet1 = b"meow".to_vec();
+    let mut packet2 = b"meow".to_vec();
+    initiator.encrypt(&mut packet1).expect("failed to encrypt");
+    initiator_clone.encrypt(&mut packet2).expect("failed to encrypt");
+    assert_ne!(packet1, packet2, "clones should use different counters");
+
+    // every session key is fresh, so its counter starts over from zero
+    assert!(packet1.ends_with(&0u64.to_be_bytes()), "first counter of a session should be zero");
+    assert!(packet2.ends_with(&1u64.to_be_bytes()), "counter should count up");
+
+    // nothing is sealed or opened without a session key
+    let unkeyed = crypto::Crypto::without_session(Role::Initiator, CipherSuite::Aes128Gcm, RekeyPolicy::default());
+    let error = unkeyed.encrypt(&mut b"meow".to_vec()).expect_err("encrypting without a session should fail");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<NoSessionError>()), "error was not a missing session");
+    unkeyed.decrypt(&mut packet1.clone()).expect_err("decrypting without a session should fail");
+
+    // the other role can decrypt both
+    responder.decrypt(&mut packet1.clone()).expect("failed to decrypt first packet");
+    responder.decrypt(&mut packet2.clone()).expect("failed to decrypt second packet");
+
+    // but a message reflected back at the sender is not accepted
+    let mut reflected = packet1.clone();
+    let result = session_crypto(key, Role::Initiator).decrypt(&mut reflected);
+    assert!(!is_replay(&result.expect_err("reflected packet should be rejected")), "error should not be a replay");
+  }
+
+  #[test]
+  fn test_peer_handshake() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer1.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+
+    // nothing can be sent before there is a session
+    let error = peer1.send(&mut b"too early".to_vec()).expect_err("sending before the handshake should fail");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<NoSessionError>()), "error was not a missing session");
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+    let error = peer1.send_reliable(&mut b"too early".to_vec()).expect_err("sending before the handshake should fail");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<NoSessionError>()), "error was not a missing session");
+    assert_eq!(peer1.reliable_in_flight(), 0);
+
+    // run the handshake explicitly on both sides
+    let responder = std::thread::spawn(move || {
+      peer2.handshake().expect("responder handshake failed");
+      peer2
+    });
+    peer1.handshake().expect("initiator handshake failed");
+    let mut peer2 = responder.join().unwrap();
+
+    // the session key works in both directions
+    let mut send_buffer = b"forward secret hello".to_vec();
+    peer1.send(&mut send_buffer).expect("failed to send from peer1");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive at peer2");
+    assert_eq!(&recv_buffer, b"forward secret hello");
+
+    let mut send_buffer = b"forward secret hi back".to_vec();
+    peer2.send(&mut send_buffer).expect("failed to send from peer2");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer1.recv(&mut recv_buffer).expect("failed to receive at peer1");
+    assert_eq!(&recv_buffer, b"forward secret hi back");
+
+    // messages sealed with the pre-shared key are never accepted
+    let mut packet = b"old key".to_vec();
+    session_crypto(key, Role::Initiator).encrypt(&mut packet).expect("failed to encrypt");
+    peer1.socket().send(&packet).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    assert!(peer2.recv(&mut recv_buffer).is_err(), "pre-shared key message should be rejected");
+
+    // a responder that is already receiving answers a new handshake by itself, and says that the session was replaced
+    let responder = std::thread::spawn(move || {
+      let mut recv_buffer = vec![0u8; 1024];
+      let error = peer2.recv(&mut recv_buffer).expect_err("replaced session was not reported");
+      assert!(is_new_session(&error), "unexpected error: {error}");
+      peer2.recv(&mut recv_buffer).expect("failed to receive at peer2 after rehandshake");
+      (peer2, recv_buffer)
+    });
+    peer1.handshake().expect("initiator rehandshake failed");
+    let mut send_buffer = b"new session".to_vec();
+    peer1.send(&mut send_buffer).expect("failed to send from peer1");
+    let (peer2, recv_buffer) = responder.join().unwrap();
+    assert_eq!(&recv_buffer, b"new session");
+
+    // the initiator never answers a handshake
+    let initiation = handshake::Initiator::new(key, CipherSuite::Aes128Gcm);
+    peer2.socket().send(initiation.packet()).expect("failed to send initiation");
+    peer1.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer1.recv(&mut recv_buffer).expect_err("initiator should not deliver anything");
+    peer2.socket().set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    peer2.socket().recv(&mut recv_buffer).expect_err("initiator should not answer an initiation");
+
+    // a restarted responder does not take initiations recorded before it started
+    let recorded = handshake::Initiator::at(key, CipherSuite::Aes128Gcm, unix_nanos() - 300_000_000_000);
+    assert!(handshake::Responder::new().respond(key, CipherSuite::Aes128Gcm, recorded.packet()).is_err());
+    let current = handshake::Initiator::new(key, CipherSuite::Aes128Gcm);
+    assert!(handshake::Responder::new().respond(key, CipherSuite::Aes128Gcm, current.packet()).is_ok());
+  }
+
+  #[test]
+  fn test_crypto_rekeying() {
+    let key = create_test_key();
+
+    let sender = session_crypto(key, Role::Initiator);
+    let receiver = session_crypto(key, Role::Responder);
+
+    // ratchet after every two messages
+    sender.set_rekey_policy(RekeyPolicy { messages: 2, ..RekeyPolicy::default() });
+
+    let packets: Vec<Vec<u8>> = (0..10u8)
+      .map(|i| {
+        let mut packet = vec![i; 16];
+        sender.encrypt(&mut packet).expect("failed to encrypt");
+        packet
+      })
+      .collect();
+
+    // packets sealed under different keys differ even for the same epoch position
+    assert_ne!(packets[0][1..17], packets[2][1..17], "keys should have been ratcheted");
+
+    // the receiver follows the ratchet, skipping an epoch is fine too
+    for i in [0, 1, 2, 5, 6] {
+      let mut packet = packets[i].clone();
+      receiver.decrypt(&mut packet).expect("failed to decrypt");
+      assert_eq!(packet, vec![i as u8; 16]);
+    }
+
+    // a reordered packet from the previous epoch is still accepted during the overlap
+    let mut packet = packets[4].clone();
+    receiver.decrypt(&mut packet).expect("failed to decrypt reordered packet");
+    assert_eq!(packet, vec![4; 16]);
+
+    // but not once the overlap is over
+    receiver.set_rekey_policy(RekeyPolicy { overlap: Duration::ZERO, ..RekeyPolicy::default() });
+    let mut packet = packets[8].clone();
+    receiver.decrypt(&mut packet).expect("failed to decrypt");
+    let mut packet = packets[7].clone();
+    assert!(receiver.decrypt(&mut packet).is_err(), "packet from expired key should be rejected");
+
+    // packets claiming any epoch ahead that don't authenticate leave the receiver where it is
+    let epoch = |packet: &[u8]| packet[3] & message::Header::FLAGS_KEY_EPOCH;
+    for ahead in 1..=rekey::RecvChain::MAXIMUM_AHEAD as u8 {
+      let mut forged = packets[9].clone();
+      forged[3] = (forged[3] & !message::Header::FLAGS_KEY_EPOCH) | ((epoch(&forged) + ahead) & message::Header::FLAGS_KEY_EPOCH);
+      assert!(receiver.decrypt(&mut forged).is_err(), "forged packet should be rejected");
+    }
+    let mut packet = packets[9].clone();
+    receiver.decrypt(&mut packet).expect("failed to decrypt after forged packets");
+    assert_eq!(packet, vec![9; 16]);
+
+    // a receiver that missed many epochs in a row still catches up
+    sender.set_rekey_policy(RekeyPolicy { messages: 1, ..RekeyPolicy::default() });
+    let packets: Vec<Vec<u8>> = (0..rekey::Epoch::MODULUS * 2)
+      .map(|i| {
+        let mut packet = i.to_be_bytes().to_vec();
+        sender.encrypt(&mut packet).expect("failed to encrypt");
+        packet
+      })
+      .collect();
+    let skipped = rekey::RecvChain::MAXIMUM_AHEAD as usize - 1;
+    let mut packet = packets[skipped].clone();
+    receiver.decrypt(&mut packet).expect("failed to decrypt after missing many epochs");
+    assert_eq!(packet, (skipped as u64).to_be_bytes());
+
+    // but not once the sender is so far ahead that its epoch on the wire looks like an old one
+    let mut packet = packets[skipped + rekey::Epoch::MODULUS as usize - 1].clone();
+    assert!(receiver.decrypt(&mut packet).is_err(), "packet from an epoch too far ahead should be rejected");
+  }
+
+  #[test]
+  fn test_cipher_suites() {
+    let key128 = create_test_key();
+    let key256: Key = "6f0b7d0c2a9e4f1d8c3b5a7e9d1f2c4b6a8e0d2c4f6b8a0e2d4c6f8b0a2e4d6c".parse().unwrap();
+
+    let suites = [
+      (CipherSuite::Aes128Gcm, key128),
+      (CipherSuite::Aes256Gcm, key256),
+      (CipherSuite::ChaCha20Poly1305, key256),
+      (CipherSuite::Aes128GcmSiv, key128),
+      (CipherSuite::Aes256GcmSiv, key256),
+    ];
+
+    for (suite, key) in suites {
+      let mut peer1 = Peer::with_handshake_and_suite(UdpSocket::bind("127.0.0.1:0").unwrap(), key, Role::Initiator, suite)
+        .expect("failed to create peer1");
+      let mut peer2 = Peer::with_handshake_and_suite(UdpSocket::bind("127.0.0.1:0").unwrap(), key, Role::Responder, suite)
+        .expect("failed to create peer2");
+
+      peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+      peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+      peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+
+      let responder = std::thread::spawn(move || {
+        peer2.handshake().expect("responder handshake failed");
+        peer2
+      });
+      peer1.handshake().expect("initiator handshake failed");
+      let mut peer2 = responder.join().unwrap();
+
+      let mut send_buffer = b"suite test".to_vec();
+      peer1.send(&mut send_buffer).expect("failed to send");
+      let mut recv_buffer = vec![0u8; 1024];
+      peer2.recv(&mut recv_buffer).expect("failed to receive");
+      assert_eq!(&recv_buffer, b"suite test", "message was corrupted with {suite:?}");
+    }
+
+    // the key length has to match the suite
+    let result = Peer::with_handshake_and_suite(UdpSocket::bind("127.0.0.1:0").unwrap(), key128, Role::Initiator, CipherSuite::ChaCha20Poly1305);
+    assert_eq!(result.err(), Some(InvalidKeyError::InvalidLength));
+
+    // and keys pick the matching AES-GCM suite by default
+    let peer = Peer::with_handshake(UdpSocket::bind("127.0.0.1:0").unwrap(), key256, Role::Initiator);
+    assert_eq!(peer.suite(), CipherSuite::Aes256Gcm);
+  }
+
+  #[test]
+  fn test_peer_rejects_incompatible_version() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    peer1.send(&mut b"from the future".to_vec()).expect("failed to send message");
+    let mut packet = vec![0u8; 1024];
+    let len = peer2.socket().recv(&mut packet).expect("failed to capture packet");
+    packet.truncate(len);
+
+    // a peer speaking a newer version is told apart from a corrupted message
+    let mut newer = packet.clone();
+    newer[1] = message::Header::VERSION + 1;
+    peer1.socket().send(&newer).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv(&mut recv_buffer).expect_err("newer version should be rejected");
+    let error = error.get_ref().and_then(|e| e.downcast_ref::<HeaderError>());
+    assert_eq!(error, Some(&HeaderError::UnsupportedVersion(message::Header::VERSION + 1)));
+
+    // so is traffic from another protocol
+    let mut foreign = packet.clone();
+    foreign[0] ^= 0xff;
+    peer1.socket().send(&foreign).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv(&mut recv_buffer).expect_err("foreign datagram should be rejected");
+    let error = error.get_ref().and_then(|e| e.downcast_ref::<HeaderError>());
+    assert_eq!(error, Some(&HeaderError::InvalidMagic));
+
+    // the untouched message still gets through
+    peer1.socket().send(&packet).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive packet");
+    assert_eq!(&recv_buffer, b"from the future", "message was corrupted");
+  }
+
+  #[test]
+  fn test_peer_associated_data() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    // associated data arrives alongside the message
+    let mut send_buffer = b"hello tenant".to_vec();
+    peer1.send_with_aad(b"tenant-42", &mut send_buffer).expect("failed to send message");
+    assert!(send_buffer.windows(9).any(|window| window == b"tenant-42"), "associated data is not in the clear");
+    let mut recv_buffer = vec![0u8; 1024];
+    let mut aad = Vec::new();
+    peer2.recv_with_aad(&mut recv_buffer, &mut aad).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"hello tenant", "message was corrupted");
+    assert_eq!(&aad, b"tenant-42", "associated data was corrupted");
+
+    // messages without associated data leave it empty
+    let mut send_buffer = b"no metadata".to_vec();
+    peer1.send(&mut send_buffer).expect("failed to send message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv_with_aad(&mut recv_buffer, &mut aad).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"no metadata", "message was corrupted");
+    assert!(aad.is_empty(), "associated data was not cleared");
+
+    // tampering with the associated data is detected
+    peer1.send_with_aad(b"tenant-1", &mut b"reroute me".to_vec()).expect("failed to send message");
+    let mut packet = vec![0u8; 1024];
+    let len = peer2.socket().recv(&mut packet).expect("failed to capture packet");
+    packet.truncate(len);
+    let position = packet.windows(8).position(|window| window == b"tenant-1").unwrap();
+    packet[position + 7] = b'2';
+    peer1.socket().send(&packet).expect("failed to send packet");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv_with_aad(&mut recv_buffer, &mut aad).expect_err("tampered message should be rejected");
+    assert!(error.get_ref().is_some_and(|e| e.is::<CryptoError>()), "error was not a crypto error");
+  }
+
+  #[test]
+  fn test_peer_slices() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    let mut scratch = [0u8; 1024];
+    let mut recv_buffer = [0u8; 1024];
+
+    // encrypted into a scratch buffer
+    peer1.send_slice(b"from a slice", &mut scratch).expect("failed to send message");
+    let len = peer2.recv_into(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer[..len], b"from a slice", "message was corrupted");
+
+    // encrypted in place
+    let message = b"in place";
+    scratch[..message.len()].copy_from_slice(message);
+    peer1.send_in_place(&mut scratch, message.len()).expect("failed to send message");
+    let len = peer2.recv_into(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer[..len], message, "message was corrupted");
+
+    // the buffers must leave room for the overhead
+    let mut small = [0u8; Peer::OVERHEAD + 3];
+    let error = peer1.send_slice(b"four", &mut small).expect_err("scratch buffer should be too small");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    peer1.send_slice(b"two", &mut small).expect("failed to send message");
+    let len = peer2.recv_into(&mut small).expect("failed to receive message");
+    assert_eq!(&small[..len], b"two", "message was corrupted");
+  }
+
+  #[cfg(feature = "tokio")]
+  #[tokio::test]
+  async fn test_async_peer_interop() {
+    let key = create_test_key();
+
+    let peer1 = AsyncPeer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).await.expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).await.expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+
+    // the blocking peer answers the handshake on its own thread
+    let peer2 = std::thread::spawn(move || {
+      peer2.handshake().expect("failed to handshake");
+      let mut recv_buffer = vec![0u8; 1024];
+      peer2.recv(&mut recv_buffer).expect("failed to receive message");
+      assert_eq!(&recv_buffer, b"async to sync", "message was corrupted");
+      let mut send_buffer = b"sync to async".to_vec();
+      peer2.send(&mut send_buffer).expect("failed to send message");
+    });
+
+    peer1.handshake().await.expect("failed to handshake");
+    let mut send_buffer = b"async to sync".to_vec();
+    peer1.send(&mut send_buffer).await.expect("failed to send message");
+    let mut recv_buffer = vec![0u8; 1024];
+    tokio::time::timeout(Duration::from_secs(5), peer1.recv(&mut recv_buffer))
+      .await
+      .expect("timed out waiting for message")
+      .expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"sync to async", "message was corrupted");
+
+    peer2.join().expect("sync peer panicked");
+  }
+
+  #[cfg(feature = "tokio")]
+  #[tokio::test]
+  async fn test_async_peer_static_key() {
+    let key = create_test_key();
+
+    let peer1 = AsyncPeer::setup("127.0.0.1:0", "0.0.0.0:0", key).await.expect("failed to create peer1");
+    let mut peer2 = Peer::setup("127.0.0.1:0", peer1.local_addr(), key).expect("failed to create peer2");
+    peer1.connect(peer2.local_addr()).await.expect("failed to connect peer1 to peer2");
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+
+    // neither side handshakes before sending
+    peer1.send(&mut b"async to sync".to_vec()).await.expect("failed to send message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"async to sync", "message was corrupted");
+    peer2.send(&mut b"sync to async".to_vec()).expect("failed to send message");
+    let mut recv_buffer = vec![0u8; 1024];
+    tokio::time::timeout(Duration::from_secs(5), peer1.recv(&mut recv_buffer))
+      .await
+      .expect("timed out waiting for message")
+      .expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"sync to async", "message was corrupted");
+  }
+
+  #[cfg(feature = "tokio")]
+  #[tokio::test]
+  async fn test_async_peer_new_session() {
+    let key = create_test_key();
+
+    let responder = AsyncPeer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).await.expect("failed to create responder");
+    let mut initiator = Peer::setup_with_handshake("127.0.0.1:0", responder.local_addr(), key, Role::Initiator).expect("failed to create initiator");
+    responder.connect(initiator.local_addr()).await.expect("failed to connect responder");
+
+    // the initiator handshakes twice, like it would after a restart
+    let initiator = std::thread::spawn(move || {
+      for message in [b"first session", b"other session"] {
+        initiator.handshake().expect("failed to handshake");
+        initiator.send(&mut message.to_vec()).expect("failed to send message");
+      }
+    });
+
+    // the first session is just started, the second one replaces it and says so once
+    let mut recv_buffer = Vec::new();
+    let recv = async |recv_buffer: &mut Vec<u8>| {
+      tokio::time::timeout(Duration::from_secs(5), responder.recv(recv_buffer)).await.expect("timed out waiting for message")
+    };
+    recv(&mut recv_buffer).await.expect("failed to receive message");
+    assert_eq!(recv_buffer, b"first session");
+    let error = recv(&mut recv_buffer).await.expect_err("replaced session was not reported");
+    assert!(is_new_session(&error), "unexpected error: {error}");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    recv(&mut recv_buffer).await.expect("failed to receive message after the new session");
+    assert_eq!(recv_buffer, b"other session");
+
+    initiator.join().expect("initiator panicked");
+  }
+
+  #[test]
+  fn test_endpoint_sessions() {
+    let key = create_test_key();
+
+    let endpoint = Endpoint::bind("127.0.0.1:0", key).expect("failed to create endpoint");
+    endpoint.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+
+    // two clients talk to the same port at the same time, each with its own handshake
+    let clients: Vec<_> = ["client1", "client2"].into_iter().map(|name| {
+      let mut client = Peer::setup_with_handshake("127.0.0.1:0", endpoint.local_addr(), key, Role::Initiator).expect("failed to create client");
+      client.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+      let addr = client.local_addr();
+      let thread = std::thread::spawn(move || {
+        client.handshake().expect("failed to handshake");
+        client.send(&mut format!("hello from {name}").into_bytes()).expect("failed to send from client");
+        let mut recv_buffer = vec![0u8; 1024];
+        client.recv(&mut recv_buffer).expect("failed to receive at client");
+        assert_eq!(recv_buffer, format!("hi {name}").into_bytes());
+      });
+      (addr, thread)
+    }).collect();
+
+    // the endpoint answers each client through its own session
+    for _ in 0..2 {
+      let mut recv_buffer = Vec::new();
+      let session = endpoint.recv_from(&mut recv_buffer).expect("failed to receive at endpoint");
+      assert!(clients.iter().any(|(addr, _)| *addr == session.remote_addr()), "message from an unknown client");
+      let name = recv_buffer.strip_prefix(b"hello from ").expect("unexpected message").to_vec();
+      session.send(&mut [b"hi ".as_slice(), &name].concat()).expect("failed to send to client");
+    }
+    for (_, thread) in clients {
+      thread.join().expect("client panicked");
+    }
+    assert_eq!(endpoint.session_count(), 2);
+
+    // datagrams that don't authenticate don't create sessions
+    let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut packet = b"let me in".to_vec();
+    session_crypto(Key::from([7u8; 16]), Role::Initiator).encrypt(&mut packet).expect("failed to encrypt");
+    stranger.send_to(&packet, endpoint.local_addr()).unwrap();
+    endpoint.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let mut recv_buffer = vec![0u8; 1024];
+    endpoint.recv_from(&mut recv_buffer).err().expect("stranger should be rejected");
+    assert_eq!(endpoint.session_count(), 2);
+    assert!(endpoint.get(stranger.local_addr().unwrap()).is_none());
+  }
+
+  #[test]
+  fn test_endpoint_limits() {
+    let key = create_test_key();
+
+    let endpoint = Endpoint::bind("127.0.0.1:0", key).expect("failed to create endpoint");
+    endpoint.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let idle = || {
+      let error = endpoint.recv_from(&mut vec![0u8; 1024]).err().expect("nothing should be delivered");
+      assert!(can_retry(&error), "unexpected error: {error}");
+    };
+    let socket = || {
+      let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+      socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+      socket
+    };
+
+    // a session only counts once its client sends something with the new key
+    let client = socket();
+    let initiator = handshake::Initiator::new(key, CipherSuite::Aes128Gcm);
+    client.send_to(initiator.packet(), endpoint.local_addr()).unwrap();
+    idle();
+    let mut response = [0u8; handshake::Responder::RESPONSE_SIZE];
+    client.recv(&mut response).expect("handshake was not answered");
+    let crypto = session_crypto(initiator.finish(key, &response).expect("invalid handshake response"), Role::Initiator);
+    assert_eq!(endpoint.session_count(), 0);
+
+    // the same initiation replayed from another address gets nothing
+    let eve = socket();
+    eve.send_to(initiator.packet(), endpoint.local_addr()).unwrap();
+    idle();
+    eve.recv(&mut response).expect_err("replayed initiation was answered");
+
+    // malformed datagrams, and messages replayed from another address, are skipped without ending the call
+    let mut packet = b"hello".to_vec();
+    crypto.encrypt(&mut packet).expect("failed to encrypt");
+    eve.send_to(b"garbage", endpoint.local_addr()).unwrap();
+    eve.send_to(&packet, endpoint.local_addr()).unwrap();
+    client.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    let session = endpoint.recv_from(&mut recv_buffer).expect("message was not received");
+    assert_eq!(&recv_buffer, b"hello");
+    assert_eq!(session.remote_addr(), client.local_addr().unwrap());
+    assert_eq!(endpoint.session_count(), 1);
+    assert!(endpoint.get(eve.local_addr().unwrap()).is_none());
+
+    // and so are messages replayed from the same address
+    client.send_to(&packet, endpoint.local_addr()).unwrap();
+    idle();
+
+    // new clients go unanswered once the endpoint is full
+    endpoint.set_session_policy(SessionPolicy { max_sessions: 1, idle_timeout: Duration::from_secs(60) });
+    let newcomer = socket();
+    let initiation = handshake::Initiator::new(key, CipherSuite::Aes128Gcm);
+    newcomer.send_to(initiation.packet(), endpoint.local_addr()).unwrap();
+    idle();
+    newcomer.recv(&mut response).expect_err("handshake was answered over the limit");
+
+    // until idle sessions expire
+    endpoint.set_session_policy(SessionPolicy { max_sessions: 1, idle_timeout: Duration::from_millis(100) });
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(endpoint.session_count(), 0, "idle session was kept");
+    let mut packet = b"still there?".to_vec();
+    crypto.encrypt(&mut packet).expect("failed to encrypt");
+    client.send_to(&packet, endpoint.local_addr()).unwrap();
+    idle();
+    let crypto = handshake_from(&newcomer, endpoint.local_addr(), key, idle);
+    let mut packet = b"my turn".to_vec();
+    crypto.encrypt(&mut packet).expect("failed to encrypt");
+    newcomer.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    let session = endpoint.recv_from(&mut recv_buffer).expect("message was not received");
+    assert_eq!(&recv_buffer, b"my turn");
+    assert_eq!(session.remote_addr(), newcomer.local_addr().unwrap());
+    assert_eq!(endpoint.session_count(), 1);
+  }
+
+  #[test]
+  fn test_roaming() {
+    let key = create_test_key();
+
+    // the client moves between two sockets, like a phone switching networks
+    let wifi = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let cellular = UdpSocket::bind("127.0.0.1:0").unwrap();
+    wifi.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    cellular.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    // an endpoint keeps the session
+    let endpoint = Endpoint::bind("127.0.0.1:0", key).expect("failed to create endpoint");
+    endpoint.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let answer = || {
+      endpoint.recv_from(&mut vec![0u8; 1024]).err().expect("only a handshake should arrive");
+    };
+    let client = handshake_from(&wifi, endpoint.local_addr(), key, answer);
+    client.set_connection_id(Some(random_connection_id()));
+
+    let mut packet = b"on wifi".to_vec();
+    client.encrypt(&mut packet).expect("failed to encrypt");
+    wifi.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    let session = endpoint.recv_from(&mut recv_buffer).expect("failed to receive at endpoint");
+    assert_eq!(session.remote_addr(), wifi.local_addr().unwrap());
+
+    let mut packet = b"on cellular".to_vec();
+    client.encrypt(&mut packet).expect("failed to encrypt");
+    cellular.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    let roamed = endpoint.recv_from(&mut recv_buffer).expect("failed to receive at endpoint");
+    assert_eq!(&recv_buffer, b"on cellular");
+    assert_eq!(session.remote_addr(), cellular.local_addr().unwrap(), "session did not follow the client");
+    assert_eq!(roamed.remote_addr(), cellular.local_addr().unwrap());
+    assert_eq!(endpoint.session_count(), 1);
+
+    // replies go to the new address
+    session.send(&mut b"welcome back".to_vec()).expect("failed to send from endpoint");
+    let mut packet = vec![0u8; 1024];
+    let len = cellular.recv(&mut packet).expect("reply did not arrive at the new address");
+    packet.truncate(len);
+    client.decrypt(&mut packet).expect("failed to decrypt reply");
+    assert_eq!(&packet, b"welcome back");
+
+    // the connection ID wins over an address that another client has picked up since
+    let newcomer = handshake_from(&wifi, endpoint.local_addr(), key, answer);
+    let mut packet = b"my address now".to_vec();
+    newcomer.encrypt(&mut packet).expect("failed to encrypt");
+    wifi.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    let other = endpoint.recv_from(&mut recv_buffer).expect("failed to receive at endpoint");
+    assert_eq!(other.remote_addr(), wifi.local_addr().unwrap());
+    assert_eq!(endpoint.session_count(), 2);
+
+    let mut packet = b"back on wifi".to_vec();
+    client.encrypt(&mut packet).expect("failed to encrypt");
+    wifi.send_to(&packet, endpoint.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    endpoint.recv_from(&mut recv_buffer).expect("message was not matched to its session by connection ID");
+    assert_eq!(&recv_buffer, b"back on wifi");
+    assert_eq!(session.remote_addr(), wifi.local_addr().unwrap(), "session did not follow the client");
+    assert_eq!(endpoint.session_count(), 1);
+
+    // a roaming peer follows the client too
+    let mut peer = Peer::setup_with_handshake("127.0.0.1:0", wifi.local_addr().unwrap(), key, Role::Responder).expect("failed to create peer");
+    peer.set_roaming(true).expect("failed to enable roaming");
+    peer.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    assert_eq!(peer.remote_addr(), wifi.local_addr().unwrap());
+    let client = handshake_from(&wifi, peer.local_addr(), key, || {
+      peer.recv(&mut vec![0u8; 1024]).expect_err("only a handshake should arrive");
+    });
+    client.set_connection_id(Some(random_connection_id()));
+
+    let mut packet = b"moved again".to_vec();
+    client.encrypt(&mut packet).expect("failed to encrypt");
+    cellular.send_to(&packet, peer.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    peer.recv(&mut recv_buffer).expect("failed to receive at peer");
+    assert_eq!(&recv_buffer, b"moved again");
+    assert_eq!(peer.remote_addr(), cellular.local_addr().unwrap(), "peer did not follow the client");
+
+    // but not to where forged messages come from
+    wifi.send_to(&packet, peer.local_addr()).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    peer.recv(&mut recv_buffer).expect_err("replayed message should be rejected");
+    assert_eq!(peer.remote_addr(), cellular.local_addr().unwrap(), "peer followed a replayed message");
+  }
+
+  #[test]
+  fn test_wildcard_bind() {
+    let key = create_test_key();
+
+    // disconnecting must not tie a socket bound to every interface to one of them, or give up its port
+    let mut peer1 = Peer::with_handshake(UdpSocket::bind("0.0.0.0:0").unwrap(), key, Role::Initiator);
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    let bound = peer1.local_addr();
+    assert!(bound.ip().is_unspecified());
+    let peer1_addr = std::net::SocketAddr::from(([127, 0, 0, 1], bound.port()));
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer1.disconnect().expect("failed to disconnect peer1");
+    assert_eq!(peer1.local_addr(), bound, "disconnecting changed the local address");
+    assert_eq!(peer1.remote_addr_optional(), None);
+
+    peer1.connect(peer2.local_addr()).expect("failed to reconnect peer1 to peer2");
+    peer2.connect(peer1_addr).expect("failed to connect peer2 to peer1");
+    handshake(&mut peer1, &mut peer2);
+
+    // roaming disconnects the socket too
+    peer1.set_roaming(true).expect("failed to enable roaming");
+    assert_eq!(peer1.local_addr(), bound, "roaming changed the local address");
+    peer1.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    peer1.send(&mut b"anyone there?".to_vec()).expect("failed to send from peer1");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive at peer2");
+    assert_eq!(&recv_buffer, b"anyone there?");
+    peer2.send(&mut b"still here".to_vec()).expect("failed to send from peer2");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer1.recv(&mut recv_buffer).expect("failed to receive at peer1");
+    assert_eq!(&recv_buffer, b"still here");
+  }
+
+  #[test]
+  fn test_keepalive() {
+    let key = create_test_key();
+
+    let policy = KeepalivePolicy { interval: Duration::from_millis(50), misses: 4 };
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer1.set_keepalive_policy(Some(policy));
+    peer2.set_keepalive_policy(Some(policy));
+    peer1.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
+    assert!(peer1.last_heard().is_none());
+    handshake(&mut peer1, &mut peer2);
+
+    // both sides idle, but the keepalives keep them alive until the read timeout
+    let peer2 = std::thread::spawn(move || {
+      let mut recv_buffer = vec![0u8; 1024];
+      let error = peer2.recv(&mut recv_buffer).expect_err("nothing but keepalives should arrive");
+      assert!(can_retry(&error) && !is_dead_peer(&error), "peer2 should time out normally");
+      assert!(peer2.is_alive());
+      peer2
+    });
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer1.recv(&mut recv_buffer).expect_err("nothing but keepalives should arrive");
+    assert!(can_retry(&error) && !is_dead_peer(&error), "peer1 should time out normally");
+    assert!(peer1.is_alive());
+    assert!(peer1.last_heard().is_some(), "no keepalive arrived");
+    assert_eq!(peer1.socket().read_timeout().unwrap(), Some(Duration::from_millis(500)), "read timeout was not restored");
+    // once the other side stops receiving it stops sending keepalives too
+    let _peer2 = peer2.join().expect("peer2 panicked");
+    peer1.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer1.recv(&mut recv_buffer).expect_err("peer2 should be found dead");
+    assert!(is_dead_peer(&error), "error was not a dead peer error");
+    assert!(!peer1.is_alive());
+  }
+
+  #[test]
+  fn test_reliable_delivery() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+    handshake(&mut peer1, &mut peer2);
+
+    // a lossy link in the middle drops the second datagram from peer1
+    let link = UdpSocket::bind("127.0.0.1:0").unwrap();
+    link.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    peer1.connect(link.local_addr().unwrap()).expect("failed to connect peer1 to link");
+    peer2.connect(link.local_addr().unwrap()).expect("failed to connect peer2 to link");
+    let (peer1_addr, peer2_addr) = (peer1.local_addr(), peer2.local_addr());
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let link = {
+      let done = std::sync::Arc::clone(&done);
+      std::thread::spawn(move || {
+        let mut buffer = [0u8; 1024];
+        let mut from_peer1 = 0;
+        while !done.load(std::sync::atomic::Ordering::Relaxed) {
+          let Ok((len, from)) = link.recv_from(&mut buffer) else { continue };
+          if from == peer1_addr {
+            from_peer1 += 1;
+            if from_peer1 != 2 {
+              link.send_to(&buffer[..len], peer2_addr).unwrap();
+            }
+          } else {
+            link.send_to(&buffer[..len], peer1_addr).unwrap();
+          }
+        }
+      })
+    };
+
+    for i in 0..6u8 {
+      peer1.send_reliable(&mut vec![i; 10]).expect("failed to send reliable message");
+    }
+    peer1.send(&mut b"unreliable".to_vec()).expect("failed to send message");
+    assert_eq!(peer1.reliable_in_flight(), 6);
+
+    // peer1 has to keep receiving to take in acknowledgements
+    peer1.set_read_timeout(Some(Duration::from_millis(100))).expect("failed to set timeout");
+    let peer1 = std::thread::spawn(move || {
+      let deadline = std::time::Instant::now() + Duration::from_secs(5);
+      while peer1.reliable_in_flight() > 0 && std::time::Instant::now() < deadline {
+        let mut recv_buffer = vec![0u8; 1024];
+        if let Err(e) = peer1.recv(&mut recv_buffer) {
+          assert!(can_retry(&e), "unexpected error: {e}");
+        }
+      }
+      peer1
+    });
+
+    // the unreliable message overtakes the held back reliable ones
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+    let mut received = Vec::new();
+    for _ in 0..7 {
+      let mut recv_buffer = vec![0u8; 1024];
+      peer2.recv(&mut recv_buffer).expect("failed to receive message");
+      received.push(recv_buffer);
+    }
+    let mut expected = vec![vec![0u8; 10], b"unreliable".to_vec()];
+    expected.extend((1..6u8).map(|i| vec![i; 10]));
+    assert_eq!(received, expected, "reliable messages were not delivered in order");
+
+    let peer1 = peer1.join().expect("peer1 panicked");
+    assert_eq!(peer1.reliable_in_flight(), 0, "reliable messages were not acknowledged");
+    assert!(peer1.rtt().is_some());
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    link.join().expect("link panicked");
+  }
+
+  #[test]
+  fn test_reliable_desync() {
+    let key = create_test_key();
+
+    // the other side is a bare socket, so it can send whatever it likes within the session
+    let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+    remote.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let mut peer = Peer::setup_with_handshake("127.0.0.1:0", remote.local_addr().unwrap(), key, Role::Responder).expect("failed to create peer");
+    peer.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let session = handshake_from(&remote, peer.local_addr(), key, || {
+      peer.recv(&mut vec![0u8; 1024]).expect_err("only a handshake should arrive");
+    });
+    remote.connect(peer.local_addr()).unwrap();
+    let seal = |message_type, message: &[u8]| {
+      let mut buffer = vec![0u8; 256];
+      buffer[..message.len()].copy_from_slice(message);
+      let len = session.encrypt_slice_as(message_type, &[], &mut buffer, message.len()).expect("failed to encrypt");
+      buffer.truncate(len);
+      buffer
+    };
+    let is_desync = |error: &std::io::Error| {
+      error.kind() == std::io::ErrorKind::ConnectionReset && error.get_ref().is_some_and(|inner| inner.is::<DesyncError>())
+    };
+
+    // a reliable message further ahead than any sender could get is reported, and not acknowledged
+    let message = [(1u64 << 40).to_be_bytes().as_slice(), b"another stream"].concat();
+    remote.send(&seal(message::MessageType::Reliable, &message)).unwrap();
+    let error = peer.recv(&mut vec![0u8; 1024]).expect_err("message from another stream should be rejected");
+    assert!(is_desync(&error), "error was not a desync: {error}");
+    remote.recv(&mut [0u8; 1024]).expect_err("message from another stream was acknowledged");
+
+    // and so are acknowledgements of messages that were never sent, on their own and on channels
+    let mut ack = [0u8; 16];
+    ack[..8].copy_from_slice(&5u64.to_be_bytes());
+    remote.send(&seal(message::MessageType::Ack, &ack)).unwrap();
+    let error = peer.recv(&mut vec![0u8; 1024]).expect_err("acknowledgement from another stream should be rejected");
+    assert!(is_desync(&error), "error was not a desync: {error}");
+    remote.send(&seal(message::MessageType::ChannelAck, &[[0u8, 7].as_slice(), &ack].concat())).unwrap();
+    let error = peer.recv(&mut vec![0u8; 1024]).expect_err("channel acknowledgement from another stream should be rejected");
+    assert!(is_desync(&error), "error was not a desync: {error}");
+    let ack = [u64::MAX.to_be_bytes(), u64::MAX.to_be_bytes()].concat();
+    remote.send(&seal(message::MessageType::Ack, &ack)).unwrap();
+    let error = peer.recv(&mut vec![0u8; 1024]).expect_err("acknowledgement past the last sequence number should be rejected");
+    assert!(is_desync(&error), "error was not a desync: {error}");
+
+    // the stream itself is still fine
+    let message = [0u64.to_be_bytes().as_slice(), b"same stream"].concat();
+    remote.send(&seal(message::MessageType::Reliable, &message)).unwrap();
+    let mut recv_buffer = vec![0u8; 1024];
+    peer.recv(&mut recv_buffer).expect("failed to receive reliable message");
+    assert_eq!(&recv_buffer, b"same stream");
+  }
+
+  #[test]
+  fn test_fragmentation() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer1.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    // far larger than a datagram, both unreliable and reliable
+    let large = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+    peer1.send(&mut large.clone()).expect("failed to send large message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive large message");
+    assert_eq!(recv_buffer, large, "large message was corrupted");
+
+    let medium = vec![7u8; 5000];
+    peer1.send_reliable(&mut medium.clone()).expect("failed to send reliable message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive reliable message");
+    assert_eq!(recv_buffer, medium, "reliable message was corrupted");
+
+    // small messages still go out as a single datagram
+    peer1.send(&mut b"small".to_vec()).expect("failed to send small message");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive small message");
+    assert_eq!(&recv_buffer, b"small");
+
+    // messages over the limit are refused
+    peer1.set_fragment_policy(FragmentPolicy { max_message_size: 50_000, ..FragmentPolicy::default() });
+    let error = peer1.send(&mut large.clone()).expect_err("message should be too large");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    // and so are incoming ones
+    peer2.set_fragment_policy(FragmentPolicy { max_message_size: 50_000, ..FragmentPolicy::default() });
+    peer2.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    peer1.set_fragment_policy(FragmentPolicy::default());
+    peer1.send(&mut large.clone()).expect("failed to send large message");
+    let mut recv_buffer = vec![0u8; 1024];
+    let error = peer2.recv(&mut recv_buffer).expect_err("message over the limit should be dropped");
+    assert!(can_retry(&error), "error was not a timeout");
+
+    // the sequence number and channel header don't count against the limit
+    peer1.set_fragment_policy(FragmentPolicy { max_message_size: 5000, ..FragmentPolicy::default() });
+    peer2.set_fragment_policy(FragmentPolicy { max_message_size: 5000, ..FragmentPolicy::default() });
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    let largest = vec![3u8; 5000];
+    peer1.send_reliable(&mut largest.clone()).expect("failed to send reliable message at the limit");
+    let mut recv_buffer = vec![0u8; 1024];
+    peer2.recv(&mut recv_buffer).expect("failed to receive reliable message at the limit");
+    assert_eq!(recv_buffer, largest, "reliable message at the limit was corrupted");
+    let mut channel = peer1.channel(1, ChannelMode::ReliableOrdered);
+    channel.send(&mut largest.clone()).expect("failed to send channel message at the limit");
+    let mut recv_buffer = vec![0u8; 1024];
+    assert_eq!(peer2.recv_channel(&mut recv_buffer).expect("failed to receive channel message at the limit"), Some(1));
+    assert_eq!(recv_buffer, largest, "channel message at the limit was corrupted");
+    let error = peer1.send_reliable(&mut vec![3u8; 5001]).expect_err("message should be too large");
+    assert!(is_message_too_large(&error), "error was not about the message size");
+  }
+
+  #[test]
+  fn test_path_mtu_discovery() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+
+    peer1.set_read_timeout(Some(Duration::from_millis(100))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+
+    assert_eq!(peer1.mtu(), MtuPolicy::DEFAULT_MIN);
+    peer1.set_mtu_policy(Some(MtuPolicy::default())).expect("failed to set MTU policy");
+    peer2.set_mtu_policy(Some(MtuPolicy::default())).expect("failed to set MTU policy");
+
+    // the other side answers probes from inside recv
+    let peer2 = std::thread::spawn(move || {
+      let mut recv_buffer = Vec::new();
+      peer2.recv(&mut recv_buffer).expect("failed to receive message");
+      recv_buffer
+    });
+
+    // loopback takes any size, so the search ends at the maximum
+    let mut recv_buffer = Vec::new();
+    for _ in 0..50 {
+      if peer1.mtu() == MtuPolicy::DEFAULT_MAX {
+        break;
+      }
+      let error = peer1.recv(&mut recv_buffer).expect_err("no message should arrive");
+      assert!(can_retry(&error), "error was not a timeout");
+    }
+    assert_eq!(peer1.mtu(), MtuPolicy::DEFAULT_MAX);
+    assert_eq!(peer1.max_payload_size(), MtuPolicy::DEFAULT_MAX - Peer::OVERHEAD);
+    assert_eq!(peer1.max_channel_payload_size(), peer1.max_payload_size() - channel::CHANNEL_HEADER_SIZE);
+
+    // messages that are never fragmented must fit
+    let mut scratch = vec![0u8; 2048];
+    let too_large = vec![1u8; peer1.max_payload_size() + 1];
+    let error = peer1.send_slice(&too_large, &mut scratch).expect_err("message should be too large");
+    assert!(is_message_too_large(&error), "error was not a message too large error");
+
+    let message = vec![2u8; peer1.max_payload_size()];
+    peer1.send_slice(&message, &mut scratch).expect("failed to send message");
+    assert_eq!(peer2.join().expect("peer2 thread panicked"), message);
+  }
+
+  #[test]
+  fn test_path_mtu_limit() {
+    // a policy past what a probe's size field holds only probes sizes that fit it
+    let mut path_mtu = mtu::PathMtu::new();
+    path_mtu.set_policy(Some(MtuPolicy { min: 60_000, max: 100_000, ..MtuPolicy::default() }));
+    let now = std::time::Instant::now();
+    let mut probes = 0;
+    while let Some(size) = path_mtu.poll(now) {
+      assert!(size <= u16::MAX as usize, "probe of {size} bytes doesn't fit its size field");
+      path_mtu.refuse(size);
+      probes += 1;
+    }
+    assert!(probes > 0, "nothing was probed");
+    assert_eq!(path_mtu.current(), 60_000);
+  }
+
+  #[test]
+  fn test_channels() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+    peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+    handshake(&mut peer1, &mut peer2);
+
+    // a lossy link in the middle drops the second datagram from peer1
+    let link = UdpSocket::bind("127.0.0.1:0").unwrap();
+    link.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    peer1.connect(link.local_addr().unwrap()).expect("failed to connect peer1 to link");
+    peer2.connect(link.local_addr().unwrap()).expect("failed to connect peer2 to link");
+    let (peer1_addr, peer2_addr) = (peer1.local_addr(), peer2.local_addr());
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let link = {
+      let done = std::sync::Arc::clone(&done);
+      std::thread::spawn(move || {
+        let mut buffer = [0u8; 1024];
+        let mut from_peer1 = 0;
+        while !done.load(std::sync::atomic::Ordering::Relaxed) {
+          let Ok((len, from)) = link.recv_from(&mut buffer) else { continue };
+          if from == peer1_addr {
+            from_peer1 += 1;
+            if from_peer1 != 2 {
+              link.send_to(&buffer[..len], peer2_addr).unwrap();
+            }
+          } else {
+            link.send_to(&buffer[..len], peer1_addr).unwrap();
+          }
+        }
+      })
+    };
+
+    let mut ordered = peer1.channel(1, ChannelMode::ReliableOrdered);
+    let mut unordered = peer1.channel(2, ChannelMode::ReliableUnordered);
+    let mut latest = peer1.channel(3, ChannelMode::LatestOnly);
+    let mut unreliable = peer1.channel(4, ChannelMode::Unreliable);
+    assert_eq!((ordered.id(), ordered.mode()), (1, ChannelMode::ReliableOrdered));
+
+    for i in 0..3u8 {
+      ordered.send(&mut vec![i; 10]).expect("failed to send ordered message");
+    }
+    unordered.send(&mut b"unordered".to_vec()).expect("failed to send unordered message");
+    latest.send(&mut b"latest".to_vec()).expect("failed to send latest-only message");
+    unreliable.send(&mut b"unreliable".to_vec()).expect("failed to send unreliable message");
+    peer1.send(&mut b"plain".to_vec()).expect("failed to send message");
+    assert_eq!(ordered.in_flight(), 3);
+    assert_eq!(unordered.in_flight(), 1);
+
+    // peer1 has to keep receiving to take in acknowledgements
+    peer1.set_read_timeout(Some(Duration::from_millis(100))).expect("failed to set timeout");
+    let peer1 = std::thread::spawn(move || {
+      let deadline = std::time::Instant::now() + Duration::from_secs(5);
+      while ordered.in_flight() + unordered.in_flight() > 0 && std::time::Instant::now() < deadline {
+        let mut recv_buffer = vec![0u8; 1024];
+        if let Err(e) = peer1.recv(&mut recv_buffer) {
+          assert!(can_retry(&e), "unexpected error: {e}");
+        }
+      }
+      (ordered.in_flight(), unordered.in_flight())
+    });
+
+    // only the ordered channel waits for its lost message
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+    let mut received = Vec::new();
+    for _ in 0..7 {
+      let mut recv_buffer = vec![0u8; 1024];
+      let channel = peer2.recv_channel(&mut recv_buffer).expect("failed to receive message");
+      received.push((channel, recv_buffer));
+    }
+    let expected = vec![
+      (Some(1), vec![0u8; 10]),
+      (Some(2), b"unordered".to_vec()),
+      (Some(3), b"latest".to_vec()),
+      (Some(4), b"unreliable".to_vec()),
+      (None, b"plain".to_vec()),
+      (Some(1), vec![1u8; 10]),
+      (Some(1), vec![2u8; 10]),
+    ];
+    assert_eq!(received, expected, "channel messages were not delivered as expected");
+
+    assert_eq!(peer1.join().expect("peer1 panicked"), (0, 0), "channel messages were not acknowledged");
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    link.join().expect("link panicked");
+  }
+
+  /// Algorithm with a fixed window, counting the acknowledged bytes.
+  struct FixedWindow(usize, std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+  impl CongestionControl for FixedWindow {
+    fn window(&self) -> usize {
+      self.0
+    }
+
+    fn on_ack(&mut self, _now: std::time::Instant, bytes: usize, _rtt: Option<Duration>, _in_flight: usize) {
+      self.1.fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn on_loss(&mut self, _now: std::time::Instant) {}
+
+    fn on_timeout(&mut self, _now: std::time::Instant) {}
+
+    fn reset(&mut self) {}
+  }
+
+  /// Paces at a rate of nothing, which must not stall the sender.
+  struct ZeroRate;
+
+  impl CongestionControl for ZeroRate {
+    fn window(&self) -> usize {
+      3000
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+      Some(0.0)
+    }
+
+    fn on_ack(&mut self, _now: std::time::Instant, _bytes: usize, _rtt: Option<Duration>, _in_flight: usize) {}
+
+    fn on_loss(&mut self, _now: std::time::Instant) {}
+
+    fn on_timeout(&mut self, _now: std::time::Instant) {}
+
+    fn reset(&mut self) {}
+  }
+
+  #[test]
+  fn test_congestion_control() {
+    let key = create_test_key();
+
+    let acknowledged = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let algorithms: [(&str, Box<dyn CongestionControl>); 4] = [
+      ("NewReno", Box::new(NewReno::new())),
+      ("BBR", Box::new(Bbr::new())),
+      ("fixed window", Box::new(FixedWindow(3000, std::sync::Arc::clone(&acknowledged)))),
+      ("zero rate", Box::new(ZeroRate)),
+    ];
+    for (name, algorithm) in algorithms {
+      let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+      let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+
+      peer1.connect(peer2.local_addr()).expect("failed to connect peer1 to peer2");
+      peer2.connect(peer1.local_addr()).expect("failed to connect peer2 to peer1");
+      handshake(&mut peer1, &mut peer2);
+
+      peer1.set_congestion_control(Some(algorithm));
+      let initial_window = peer1.congestion_window().expect("congestion control should be on");
+
+      // far more than the initial window, so most of it is queued at first
+      for i in 0..100u8 {
+        peer1.send_reliable(&mut vec![i; 1000]).expect("failed to send reliable message");
+      }
+      assert_eq!(peer1.reliable_in_flight(), 100);
+
+      peer1.set_read_timeout(Some(Duration::from_millis(100))).expect("failed to set timeout");
+      let peer1 = std::thread::spawn(move || {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while peer1.reliable_in_flight() > 0 && std::time::Instant::now() < deadline {
+          let mut recv_buffer = vec![0u8; 1024];
+          if let Err(e) = peer1.recv(&mut recv_buffer) {
+            assert!(can_retry(&e), "unexpected error: {e}");
+          }
+        }
+        peer1
+      });
+
+      peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+      for i in 0..100u8 {
+        let mut recv_buffer = vec![0u8; 1024];
+        peer2.recv(&mut recv_buffer).expect("failed to receive reliable message");
+        assert_eq!(recv_buffer, vec![i; 1000], "{name}: reliable messages were not delivered in order");
+      }
+
+      let peer1 = peer1.join().expect("peer1 panicked");
+      assert_eq!(peer1.reliable_in_flight(), 0, "{name}: reliable messages were not acknowledged");
+      if name == "NewReno" {
+        assert!(peer1.congestion_window().unwrap() > initial_window, "window did not grow");
+      }
+    }
+    assert!(acknowledged.load(std::sync::atomic::Ordering::Relaxed) >= 100 * 1000, "custom algorithm did not see every acknowledgement");
+
+    // unreliable messages are refused once the bucket runs dry
+    let mut sink = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create sink");
+    let mut peer = Peer::setup_with_handshake("127.0.0.1:0", sink.local_addr(), key, Role::Initiator).expect("failed to create peer");
+    sink.connect(peer.local_addr()).expect("failed to connect sink to peer");
+    handshake(&mut peer, &mut sink);
+    peer.set_rate_limit(Some(RateLimit { bytes_per_second: 1000, burst: 1500 }));
+    peer.send(&mut vec![0u8; 1000]).expect("first message should fit the burst");
+    peer.send(&mut vec![0u8; 1000]).expect("second message should be let through");
+    let error = peer.send(&mut vec![0u8; 1000]).expect_err("third message should be refused");
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+    peer.set_rate_limit(None);
+    peer.send(&mut vec![0u8; 1000]).expect("message without a rate limit should be sent");
+  }
+
+  #[test]
+  fn test_hole_punching() {
+    let key = create_test_key();
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    let (peer1_addr, peer2_addr) = (peer1.local_addr(), peer2.local_addr());
+
+    // nobody listens at the first candidate
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let peer2 = std::thread::spawn(move || {
+      let addr = peer2.punch(&[dead, peer1_addr]).expect("peer2 failed to punch");
+      (peer2, addr)
+    });
+    let addr = peer1.punch(&[dead, peer2_addr]).expect("peer1 failed to punch");
+    let (mut peer2, addr2) = peer2.join().expect("peer2 thread panicked");
+    assert_eq!(addr, peer2_addr);
+    assert_eq!(addr2, peer1_addr);
+    assert_eq!(peer1.remote_addr(), peer2_addr);
+    assert_eq!(peer2.remote_addr(), peer1_addr);
+
+    peer2.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    handshake(&mut peer1, &mut peer2);
+    peer1.send(&mut b"through".to_vec()).expect("failed to send message");
+    let mut recv_buffer = Vec::new();
+    peer2.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"through");
+
+    // nobody answers at all
+    let mut lonely = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer");
+    assert!(lonely.punch(&[]).is_err());
+
+    // recorded punches and answers to old challenges don't lure a peer away
+    let recorded_punch = punch::punch(key, CipherSuite::Aes128Gcm, &punch::challenge());
+    let recorded_answer = punch::answer(key, CipherSuite::Aes128Gcm, &recorded_punch).expect("failed to answer punch");
+    let eve = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let eve_addr = eve.local_addr().unwrap();
+    let mut victim = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer");
+    victim.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    let victim_addr = victim.local_addr();
+    let replayer = std::thread::spawn(move || {
+      eve.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+      let mut buffer = [0u8; 1500];
+      let mut answered = false;
+      while let Ok((len, _)) = eve.recv_from(&mut buffer) {
+        // the victim's own punch sent back, a recorded punch, and a recorded answer
+        eve.send_to(&buffer[..len], victim_addr).unwrap();
+        eve.send_to(&recorded_punch, victim_addr).unwrap();
+        eve.send_to(&recorded_answer, victim_addr).unwrap();
+        if let Ok((len, _)) = eve.recv_from(&mut buffer) {
+          answered |= punch::answered(key, CipherSuite::Aes128Gcm, &buffer[..len]).is_some();
+        }
+      }
+      answered
+    });
+    let error = victim.punch(&[eve_addr]).expect_err("victim punched through to a replayer");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<PunchError>()));
+    assert!(replayer.join().expect("replayer thread panicked"), "recorded punch went unanswered");
+    assert!(victim.remote_addr_optional().is_none());
+  }
+
+  #[test]
+  fn test_rendezvous() {
+    let key = create_test_key();
+
+    let mut server = RendezvousServer::bind("127.0.0.1:0", registration_key(&key)).expect("failed to bind rendezvous server");
+    let server_addr = server.local_addr();
+    std::thread::spawn(move || server.serve());
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    let (peer1_addr, peer2_addr) = (peer1.local_addr(), peer2.local_addr());
+    peer1.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+    peer2.set_read_timeout(Some(Duration::from_secs(5))).expect("failed to set timeout");
+
+    let peer2 = std::thread::spawn(move || {
+      let addr = peer2.rendezvous(server_addr, "test session").expect("peer2 failed to rendezvous");
+      let punched = peer2.punch(&[addr]).expect("peer2 failed to punch");
+      (peer2, addr, punched)
+    });
+    let addr = peer1.rendezvous(server_addr, "test session").expect("peer1 failed to rendezvous");
+    let punched = peer1.punch(&[addr]).expect("peer1 failed to punch");
+    let (mut peer2, addr2, punched2) = peer2.join().expect("peer2 thread panicked");
+    assert_eq!((addr, punched), (peer2_addr, peer2_addr));
+    assert_eq!((addr2, punched2), (peer1_addr, peer1_addr));
+
+    handshake(&mut peer1, &mut peer2);
+    peer1.send(&mut b"introduced".to_vec()).expect("failed to send message");
+    let mut recv_buffer = Vec::new();
+    peer2.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"introduced");
+
+    // a peer with another key is never introduced, even under the same name
+    let other_key = Key::from([0x24u8; 32]);
+    let mut stranger = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", other_key, Role::Initiator).expect("failed to create peer");
+    stranger.set_read_timeout(Some(Duration::from_secs(1))).expect("failed to set timeout");
+    let error = stranger.rendezvous(server_addr, "test session").expect_err("stranger was introduced");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<RendezvousError>()));
+  }
+
+  #[test]
+  fn test_rendezvous_replay() {
+    let key = create_test_key();
+    let mut server = RendezvousServer::bind("127.0.0.1:0", registration_key(&key)).expect("failed to bind rendezvous server");
+    let server_addr = server.local_addr();
+    std::thread::spawn(move || server.serve());
+
+    let socket = || {
+      let socket = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+      socket.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
+      socket
+    };
+    let (peer, eavesdropper, forger, other) = (socket(), socket(), socket(), socket());
+    let session = rendezvous::session_id(&key, "test session");
+    let mut buffer = [0u8; rendezvous::INTRODUCTION_SIZE + 1];
+
+    // a recorded registration replayed from elsewhere and one made without the key are both ignored
+    let registration = rendezvous::registration(&session, &registration_key(&key));
+    peer.send_to(&registration, server_addr).expect("failed to send registration");
+    eavesdropper.send_to(&registration, server_addr).expect("failed to send registration");
+    let forged = rendezvous::registration(&session, &registration_key(&Key::from([0x24u8; 32])));
+    forger.send_to(&forged, server_addr).expect("failed to send registration");
+    let error = peer.recv_from(&mut buffer).expect_err("peer was introduced");
+    assert!(can_retry(&error));
+
+    // a fresh registration from the other side gets the two introduced
+    let registration = rendezvous::registration(&session, &registration_key(&key));
+    other.send_to(&registration, server_addr).expect("failed to send registration");
+    let (len, _) = peer.recv_from(&mut buffer).expect("peer was not introduced");
+    assert_eq!(rendezvous::parse_introduction(&buffer[..len], &session), Some(other.local_addr().unwrap()));
+  }
+
+  #[test]
+  fn test_relay() {
+    let key = create_test_key();
+
+    let mut relay = RelayServer::bind("127.0.0.1:0", registration_key(&key)).expect("failed to bind relay server");
+    let relay_addr = relay.local_addr();
+    std::thread::spawn(move || relay.serve());
+
+    let mut peer1 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer1");
+    let mut peer2 = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Responder).expect("failed to create peer2");
+    for peer in [&mut peer1, &mut peer2] {
+      peer.set_relay(Some((relay_addr, "test relay")));
+      peer.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
+    }
+    assert_eq!(peer1.relay(), Some(relay_addr));
+
+    // neither side can be reached directly
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let peer2 = std::thread::spawn(move || {
+      let addr = peer2.punch(&[dead]).expect("peer2 failed to fall back to the relay");
+      (peer2, addr)
+    });
+    let addr = peer1.punch(&[dead]).expect("peer1 failed to fall back to the relay");
+    let (mut peer2, addr2) = peer2.join().expect("peer2 thread panicked");
+    assert_eq!(addr, relay_addr);
+    assert_eq!(addr2, relay_addr);
+    assert!(peer1.is_relayed());
+    assert!(peer2.is_relayed());
+
+    handshake(&mut peer1, &mut peer2);
+    let mut recv_buffer = Vec::new();
+    peer1.send(&mut b"relayed".to_vec()).expect("failed to send message");
+    peer2.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"relayed");
+    peer2.send(&mut b"relayed back".to_vec()).expect("failed to send message");
+    peer1.recv(&mut recv_buffer).expect("failed to receive message");
+    assert_eq!(&recv_buffer, b"relayed back");
+
+    // without the other side at the relay, punching still fails
+    let mut lonely = Peer::setup_with_handshake("127.0.0.1:0", "0.0.0.0:0", key, Role::Initiator).expect("failed to create peer");
+    lonely.set_relay(Some((relay_addr, "lonely relay")));
+    lonely.set_read_timeout(Some(Duration::from_millis(200))).expect("failed to set timeout");
+    let error = lonely.punch(&[dead]).expect_err("lonely peer punched through");
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<PunchError>()));
+  }
+
+  #[test]
+  fn test_relay_replay() {
+    let key = create_test_key();
+    let mut relay = RelayServer::bind("127.0.0.1:0", registration_key(&key)).expect("failed to bind relay server");
+    let relay_addr = relay.local_addr();
+    std::thread::spawn(move || relay.serve());
+
+    let socket = || {
+      let socket = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+      socket.set_read_timeout(Some(Duration::from_millis(500))).expect("failed to set timeout");
+      socket
+    };
+    let (peer, eavesdropper, other) = (socket(), socket(), socket());
+    let session = rendezvous::session_id(&key, "test relay");
+    let mut buffer = [0u8; 64];
+
+    // the eavesdropper replays the peer's registration, which doesn't count a second time
+    let registration = rendezvous::registration(&session, &registration_key(&key));
+    peer.send_to(&registration, relay_addr).expect("failed to send registration");
+    eavesdropper.send_to(&registration, relay_addr).expect("failed to send registration");
+    let registration = rendezvous::registration(&session, &registration_key(&key));
+    other.send_to(&registration, relay_addr).expect("failed to send registration");
+    for socket in [&peer, &other] {
+      let (len, _) = socket.recv_from(&mut buffer).expect("peers were not introduced");
+      assert_eq!(message::Header::message_type(&buffer[..len]), Some(message::MessageType::Introduction));
+    }
+
+    // nothing is forwarded to or from the eavesdropper
+    let datagram = message::Header::new(message::MessageType::Data).to_bytes();
+    eavesdropper.send_to(&datagram, relay_addr).expect("failed to send datagram");
+    let error = peer.recv_from(&mut buffer).expect_err("datagram from the eavesdropper was forwarded");
+    assert!(can_retry(&error));
+    peer.send_to(&datagram, relay_addr).expect("failed to send datagram");
+    let (len, _) = other.recv_from(&mut buffer).expect("datagram was not forwarded");
+    assert_eq!(&buffer[..len], &datagram);
+    let error = eavesdropper.recv_from(&mut buffer).expect_err("datagram was forwarded to the eavesdropper");
+    assert!(can_retry(&error));
+
+    // registrations that don't authenticate are not forwarded either
+    let forged = rendezvous::registration(&session, &registration_key(&Key::from([0x24u8; 32])));
+    peer.send_to(&forged, relay_addr).expect("failed to send registration");
+    let error = other.recv_from(&mut buffer).expect_err("forged registration was forwarded");
+    assert!(can_retry(&error));
+  }
+
+  #[test]
+  fn test_random_key() {
+    let key = Key::random();
+    assert_eq!(key.len(), Key::SIZE_256);
+    assert_ne!(key, Key::random(), "random keys should differ");
+    assert_eq!(key.to_hex().len(), 64);
+    assert_eq!(key.to_hex().parse::<Key>().expect("failed to parse hex key"), key);
+
+    let key128 = create_test_key();
+    assert_eq!(key128.to_hex(), "5adf5e4a8a779d4cd7985a881b270bcf");
+  }
+
 }
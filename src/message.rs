@@ -0,0 +1,143 @@
+use crate::error::HeaderError;
+
+/// Kind of datagram, carried in the [`Header`] of every datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum MessageType {
+  /// Encrypted application data.
+  Data = 0,
+  /// First handshake message, sent by the initiator.
+  HandshakeInitiation = 1,
+  /// Second handshake message, sent back by the responder.
+  HandshakeResponse = 2,
+  /// Encrypted empty message that tells the other side this one is still alive.
+  Keepalive = 3,
+  /// Encrypted application data that is retransmitted until acknowledged.
+  Reliable = 4,
+  /// Encrypted acknowledgement of [`MessageType::Reliable`] messages.
+  Ack = 5,
+  /// Encrypted piece of a message that is too large for a single datagram.
+  Fragment = 6,
+  /// Encrypted padded datagram probing the path MTU.
+  Probe = 7,
+  /// Encrypted acknowledgement of a [`MessageType::Probe`].
+  ProbeAck = 8,
+  /// Encrypted application data sent on a channel.
+  Channel = 9,
+  /// Encrypted acknowledgement of reliable [`MessageType::Channel`] messages.
+  ChannelAck = 10,
+  /// Authenticated random challenge sent to candidate addresses to open a path through NATs.
+  Punch = 11,
+  /// Authenticated answer to a [`MessageType::Punch`], echoing its challenge.
+  PunchAck = 12,
+  /// Cleartext registration of a peer with a rendezvous server.
+  Register = 13,
+  /// Cleartext answer from a rendezvous server with the address of the other side.
+  Introduction = 14,
+}
+
+impl MessageType {
+
+  pub const fn from_u8(value: u8) -> Option<Self> {
+    match value {
+      0 => Some(Self::Data),
+      1 => Some(Self::HandshakeInitiation),
+      2 => Some(Self::HandshakeResponse),
+      3 => Some(Self::Keepalive),
+      4 => Some(Self::Reliable),
+      5 => Some(Self::Ack),
+      6 => Some(Self::Fragment),
+      7 => Some(Self::Probe),
+      8 => Some(Self::ProbeAck),
+      9 => Some(Self::Channel),
+      10 => Some(Self::ChannelAck),
+      11 => Some(Self::Punch),
+      12 => Some(Self::PunchAck),
+      13 => Some(Self::Register),
+      14 => Some(Self::Introduction),
+      _ => None,
+    }
+  }
+
+  /// Returns `true` if messages of this type are sealed by [`Crypto`](crate::crypto::Crypto).
+  pub const fn is_encrypted(self) -> bool {
+    !matches!(
+      self,
+      Self::HandshakeInitiation | Self::HandshakeResponse | Self::Punch | Self::PunchAck | Self::Register | Self::Introduction
+    )
+  }
+
+}
+
+/// Cleartext header at the start of every datagram.
+///
+/// The header identifies the protocol and its version, so that incompatible
+/// peers fail with a clear error instead of a decryption failure, and says
+/// what kind of message follows. It is always authenticated, as associated
+/// data for encrypted messages and as part of the sealed handshake messages.
+///
+/// ```text
+/// +-------+---------+------+-------+
+/// | magic | version | type | flags |
+/// +-------+---------+------+-------+
+///     1        1        1      1
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+  pub message_type: MessageType,
+  pub flags: u8,
+}
+
+impl Header {
+
+  /// Header size in bytes
+  pub const SIZE: usize = 4;
+
+  /// Marks a datagram as belonging to this protocol
+  pub const MAGIC: u8 = 0x74;
+  /// Current protocol version, peers only accept datagrams with the same version
+  pub const VERSION: u8 = 1;
+
+  /// Flag bits holding the sender's key epoch, for encrypted messages
+  pub const FLAGS_KEY_EPOCH: u8 = 0b0011_1111;
+  /// Flag bit set when an encrypted message carries associated data
+  pub const FLAGS_AAD: u8 = 0b0100_0000;
+  /// Flag bit set when an encrypted message carries the sender's connection ID
+  pub const FLAGS_CONNECTION_ID: u8 = 0b1000_0000;
+
+  pub const fn new(message_type: MessageType) -> Self {
+    Self { message_type, flags: 0 }
+  }
+
+  pub const fn with_flags(self, flags: u8) -> Self {
+    Self { flags, ..self }
+  }
+
+  pub const fn to_bytes(self) -> [u8; Self::SIZE] {
+    [Self::MAGIC, Self::VERSION, self.message_type as u8, self.flags]
+  }
+
+  /// Reads the header from the start of a datagram.
+  ///
+  /// Flags that this version does not know about are ignored.
+  pub fn parse(buffer: &[u8]) -> Result<Self, HeaderError> {
+    let Some(&[magic, version, message_type, flags]) = buffer.first_chunk::<{ Self::SIZE }>() else {
+      return Err(HeaderError::Truncated);
+    };
+    if magic != Self::MAGIC {
+      return Err(HeaderError::InvalidMagic);
+    }
+    if version != Self::VERSION {
+      return Err(HeaderError::UnsupportedVersion(version));
+    }
+    let message_type = MessageType::from_u8(message_type)
+      .ok_or(HeaderError::UnknownMessageType(message_type))?;
+    Ok(Self { message_type, flags })
+  }
+
+  /// Reads only the message type, returning `None` if the header is invalid.
+  pub fn message_type(buffer: &[u8]) -> Option<MessageType> {
+    Self::parse(buffer).ok().map(|header| header.message_type)
+  }
+
+}
@@ -0,0 +1,244 @@
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Probe size field size in bytes, at the start of every probe and probe acknowledgement
+pub const PROBE_SIZE_SIZE: usize = 2;
+
+/// Largest datagram size a probe can stand for in its size field
+pub const MAXIMUM_PROBE_SIZE: usize = u16::MAX as usize;
+
+/// Bounds for path MTU discovery.
+///
+/// A peer with an MTU policy probes the path with padded encrypted datagrams,
+/// in the style of DPLPMTUD (RFC 8899), searching for the largest datagram
+/// that makes it to the other side and back. Probes are sent while the peer is
+/// inside `recv()`, and the other side acknowledges them from its own `recv()`.
+/// Once the search is done it starts over every `interval`, in case the path
+/// has changed.
+///
+/// Sizes are UDP payload sizes, so they exclude the IP and UDP headers. Both
+/// peers should use the same policy, since each needs a receive buffer large
+/// enough for the other side's largest probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MtuPolicy {
+  /// Datagram size that is assumed to always work, where the search starts.
+  pub min: usize,
+  /// Largest datagram size that is tried, sizes past 65535 bytes are never tried.
+  pub max: usize,
+  /// How long to wait after a search before searching again.
+  pub interval: Duration,
+}
+
+impl MtuPolicy {
+
+  /// Default datagram size assumed to always work, small enough for nearly every path
+  pub const DEFAULT_MIN: usize = 1200;
+  /// Default largest datagram size, fits into a 1500-byte Ethernet frame over IPv4 or IPv6
+  pub const DEFAULT_MAX: usize = 1452;
+  /// Default time between searches, the PMTU_RAISE_TIMER of RFC 8899
+  pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(600);
+
+}
+
+impl Default for MtuPolicy {
+  fn default() -> Self {
+    Self {
+      min: Self::DEFAULT_MIN,
+      max: Self::DEFAULT_MAX,
+      interval: Self::DEFAULT_INTERVAL,
+    }
+  }
+}
+
+/// Path MTU search state.
+///
+/// Binary search between the largest confirmed size and the smallest size
+/// that failed. A probe that goes unanswered [`PathMtu::MAXIMUM_PROBES`] times
+/// in a row counts as failed.
+pub struct PathMtu {
+  policy: Option<MtuPolicy>,
+  current: usize,
+  high: usize,
+  probe: Option<Probe>,
+  search_at: Option<Instant>,
+}
+
+struct Probe {
+  size: usize,
+  attempts: u32,
+  deadline: Instant,
+}
+
+impl PathMtu {
+
+  /// How long to wait for a probe to be acknowledged
+  pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+  /// Number of times a probe is sent before its size counts as failed, the MAX_PROBES of RFC 8899
+  pub const MAXIMUM_PROBES: u32 = 3;
+
+  pub fn new() -> Self {
+    let min = MtuPolicy::DEFAULT_MIN;
+    Self { policy: None, current: min, high: min, probe: None, search_at: None }
+  }
+
+  pub fn policy(&self) -> Option<MtuPolicy> {
+    self.policy
+  }
+
+  /// Sets the policy, starting a new search right away.
+  pub fn set_policy(&mut self, policy: Option<MtuPolicy>) {
+    self.policy = policy;
+    self.probe = None;
+    match policy {
+      Some(policy) => {
+        self.current = policy.min;
+        self.high = Self::highest(&policy);
+        self.search_at = None;
+      }
+      None => {
+        self.current = MtuPolicy::DEFAULT_MIN;
+        self.high = self.current;
+      }
+    }
+  }
+
+  /// Returns the largest size the search may probe.
+  fn highest(policy: &MtuPolicy) -> usize {
+    policy.max.min(MAXIMUM_PROBE_SIZE).max(policy.min)
+  }
+
+  /// Returns the largest datagram size known to work.
+  pub fn current(&self) -> usize {
+    self.current
+  }
+
+  /// Returns the largest datagram the other side may send.
+  pub fn largest(&self) -> usize {
+    self.policy.map_or(self.current, |policy| policy.max.max(self.current))
+  }
+
+  /// Returns when [`PathMtu::poll`] next has something to do, or `None` without a policy.
+  pub fn deadline(&self) -> Option<Instant> {
+    self.policy?;
+    match (&self.probe, self.search_at) {
+      (Some(probe), _) => Some(probe.deadline),
+      (None, Some(search_at)) => Some(search_at),
+      // a probe is due right away
+      (None, None) => Some(Instant::now()),
+    }
+  }
+
+  /// Checks the timers, returning the size of a probe to send now, if any.
+  pub fn poll(&mut self, now: Instant) -> Option<usize> {
+    let policy = self.policy?;
+    if let Some(probe) = &mut self.probe {
+      if now < probe.deadline {
+        return None;
+      }
+      probe.attempts += 1;
+      if probe.attempts < Self::MAXIMUM_PROBES {
+        probe.deadline = now + Self::PROBE_TIMEOUT;
+        return Some(probe.size);
+      }
+      self.high = probe.size - 1;
+      self.probe = None;
+    }
+    if self.high <= self.current {
+      // search done, start over later in case the path has changed
+      match self.search_at {
+        Some(search_at) if now >= search_at => {
+          self.high = Self::highest(&policy);
+          self.search_at = None;
+        }
+        Some(_) => return None,
+        None => {
+          self.search_at = Some(now + policy.interval);
+          return None;
+        }
+      }
+      if self.high <= self.current {
+        return None;
+      }
+    }
+    let size = self.current + (self.high - self.current).div_ceil(2);
+    self.probe = Some(Probe { size, attempts: 0, deadline: now + Self::PROBE_TIMEOUT });
+    Some(size)
+  }
+
+  /// Takes in an acknowledgement for a probe of the given size.
+  pub fn acknowledge(&mut self, size: usize) {
+    if self.probe.as_ref().is_some_and(|probe| probe.size == size) {
+      self.current = size;
+      self.probe = None;
+    }
+  }
+
+  /// Records that a probe of the given size could not even be sent, since it
+  /// is larger than the local interface allows.
+  pub fn refuse(&mut self, size: usize) {
+    if self.probe.as_ref().is_some_and(|probe| probe.size == size) {
+      self.high = size - 1;
+      self.probe = None;
+    }
+  }
+
+}
+
+impl Default for PathMtu {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Sets or clears the don't-fragment bit on every datagram sent from the socket.
+///
+/// Probes must not be fragmented by the IP layer, otherwise they would always
+/// make it through. Only supported on Linux, elsewhere the system default applies.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+  use std::os::fd::AsRawFd;
+
+  let (level, name, value) = match socket.local_addr()? {
+    std::net::SocketAddr::V4(_) => (
+      libc::IPPROTO_IP,
+      libc::IP_MTU_DISCOVER,
+      if enabled { libc::IP_PMTUDISC_PROBE } else { libc::IP_PMTUDISC_WANT },
+    ),
+    std::net::SocketAddr::V6(_) => (
+      libc::IPPROTO_IPV6,
+      libc::IPV6_MTU_DISCOVER,
+      if enabled { libc::IPV6_PMTUDISC_PROBE } else { libc::IPV6_PMTUDISC_WANT },
+    ),
+  };
+  // SAFETY: the socket is valid for as long as it is borrowed, and the option is a plain int
+  let result = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      level,
+      name,
+      &value as *const libc::c_int as *const libc::c_void,
+      size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if result != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket, _enabled: bool) -> io::Result<()> {
+  Ok(())
+}
+
+/// Returns `true` if sending failed because the datagram is larger than the local interface allows.
+#[cfg(target_os = "linux")]
+pub fn is_too_large(e: &io::Error) -> bool {
+  e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_too_large(_e: &io::Error) -> bool {
+  false
+}
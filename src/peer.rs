@@ -1,25 +1,139 @@
 use std::io;
 use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 use crate::util::*;
 use crate::key::Key;
-use crate::crypto::Crypto;
+use crate::error::{CryptoError, HandshakeError, NoSessionError, DesyncError, NewSessionError, PunchError, RendezvousError, DeadPeerError, MessageTooLargeError, InvalidKeyError};
+use crate::crypto::{Crypto, CipherSuite, Role};
+use crate::rekey::RekeyPolicy;
+use crate::keepalive::{KeepalivePolicy, Liveness};
+use crate::reliable::{Reliable, Delivery, Acknowledgement, SEQUENCE_SIZE, ACK_SIZE};
+use crate::fragment::{FragmentPolicy, Fragments};
+use crate::mtu::{self, MtuPolicy, PathMtu, PROBE_SIZE_SIZE, MAXIMUM_PROBE_SIZE};
+use crate::channel::{self, Channels, ChannelMode, CHANNEL_HEADER_SIZE, CHANNEL_ACK_SIZE};
+use crate::congestion::{Congestion, CongestionControl, RateLimit};
+use crate::message::{MessageType, Header};
+use crate::handshake::{Initiator, Responder};
+use crate::punch;
+use crate::rendezvous::{self, SESSION_ID_SIZE, INTRODUCTION_SIZE};
+use crate::relay::Relay;
+
+/// Number of times the handshake initiation is sent before giving up
+pub(crate) const HANDSHAKE_ATTEMPTS: u32 = 10;
+/// Time to wait for a handshake response before sending the initiation again
+pub(crate) const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// Time to keep punching before giving up, unless the read timeout is shorter
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to wait for an answer before sending the next round of punches
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+/// Time to wait for an introduction before registering with the rendezvous server again
+const REGISTRATION_INTERVAL: Duration = Duration::from_millis(500);
 
 /// A UDP peer that can send and receive encrypted messages.
 ///
 /// Each peer maintains a UDP socket and can connect to at most one remote endpoint
-/// at a time. All messages are encrypted using AES-128-GCM before transmission.
+/// at a time. All messages are encrypted with the peer's [`CipherSuite`] before transmission,
+/// under keys derived from the pre-shared key, or with [`Peer::with_handshake`],
+/// under a session key that [`Peer::handshake`] has to set up first.
+///
+/// Use [`Peer::split`] to send and receive from different threads.
 pub struct Peer {
-  socket: UdpSocket,
+  socket: Arc<UdpSocket>,
+  key: Key,
   crypto: Crypto,
+  responder: Arc<Mutex<Responder>>,
+  roaming: Option<Arc<Mutex<SocketAddr>>>,
+  liveness: Arc<Mutex<Liveness>>,
+  reliable: Arc<Mutex<Reliable>>,
+  fragments: Arc<Mutex<Fragments>>,
+  mtu: Arc<Mutex<PathMtu>>,
+  channels: Arc<Mutex<Channels>>,
+  congestion: Arc<Mutex<Congestion>>,
+  relay: Option<Relay>,
+}
+
+/// A message taken in by [`Peer::recv_datagram`].
+enum Incoming {
+  /// An encrypted data message of the given length, and where it came from if the peer is roaming.
+  Data(usize, Option<SocketAddr>),
+  /// A reliable or channel message of the given length, already decrypted at
+  /// the start of the buffer, and the channel it came in on, if any.
+  Decrypted(usize, Option<u16>),
+  /// A message that is ready but not in the buffer, because it was held back
+  /// until the reliable messages in front of it arrived, or put together from
+  /// fragments, and the channel it came in on, if any.
+  Ready(Vec<u8>, Option<u16>),
 }
 
 impl Peer {
 
+  /// Number of bytes added to every message sent without associated data or a connection ID
+  ///
+  /// Peers set up without a handshake always send a connection ID, which adds another 8 bytes.
+  pub const OVERHEAD: usize = Crypto::MINIMUM_BUFFER_LENGTH;
+
   /// Creates a new peer with the given socket and encryption key.
+  ///
+  /// Messages can be sent right away, sealed with a key derived from the
+  /// pre-shared key and a random connection ID, which every message carries.
+  /// They are sealed with AES-128-GCM or AES-256-GCM depending on the length
+  /// of the key, use [`Peer::with_suite`] to pick a different cipher suite.
+  ///
+  /// Anyone who gets hold of the pre-shared key can decrypt recorded traffic,
+  /// use [`Peer::with_handshake`] for a fresh session key every session instead.
   pub fn new(socket: UdpSocket, key: Key) -> Self {
-    Self { socket, crypto: Crypto::new(key) }
+    Self::with_suite(socket, key, CipherSuite::for_key(&key))
+      .expect("key length always matches its default suite")
+  }
+
+  /// Creates a new peer with the given socket, encryption key and cipher suite.
+  ///
+  /// Both peers of a link must use the same suite. Returns an error if the key
+  /// length does not match the suite.
+  pub fn with_suite(socket: UdpSocket, key: Key, suite: CipherSuite) -> Result<Self, InvalidKeyError> {
+    let crypto = Crypto::with_static_key(key, suite, random_connection_id())?;
+    Ok(Self::with_crypto(socket, key, crypto))
+  }
+
+  /// Creates a new peer with the given socket, encryption key and role, that
+  /// has to [`handshake`](Peer::handshake) before sending.
+  ///
+  /// The two peers of a link must use opposite roles, and both have to be set
+  /// up with a handshake. Messages are sealed with AES-128-GCM or AES-256-GCM
+  /// depending on the length of the key, use [`Peer::with_handshake_and_suite`]
+  /// to pick a different cipher suite.
+  pub fn with_handshake(socket: UdpSocket, key: Key, role: Role) -> Self {
+    Self::with_handshake_and_suite(socket, key, role, CipherSuite::for_key(&key))
+      .expect("key length always matches its default suite")
+  }
+
+  /// Creates a new peer with the given socket, encryption key, role and cipher
+  /// suite, that has to [`handshake`](Peer::handshake) before sending.
+  ///
+  /// Both peers of a link must use the same suite. Returns an error if the key
+  /// length does not match the suite.
+  pub fn with_handshake_and_suite(socket: UdpSocket, key: Key, role: Role, suite: CipherSuite) -> Result<Self, InvalidKeyError> {
+    suite.check(&key)?;
+    Ok(Self::with_crypto(socket, key, Crypto::without_session(role, suite, RekeyPolicy::default())))
+  }
+
+  fn with_crypto(socket: UdpSocket, key: Key, crypto: Crypto) -> Self {
+    Self {
+      socket: Arc::new(socket),
+      key,
+      crypto,
+      responder: Arc::new(Mutex::new(Responder::new())),
+      roaming: None,
+      liveness: Arc::new(Mutex::new(Liveness::new())),
+      reliable: Arc::new(Mutex::new(Reliable::default())),
+      fragments: Arc::new(Mutex::new(Fragments::new())),
+      mtu: Arc::new(Mutex::new(PathMtu::new())),
+      channels: Arc::new(Mutex::new(Channels::new())),
+      congestion: Arc::new(Mutex::new(Congestion::new())),
+      relay: None,
+    }
   }
 
   /// Creates a new peer, binds to `bind_addr`, and connects to `connect_addr`.
@@ -37,7 +151,254 @@ impl Peer {
     Ok(peer)
   }
 
+  /// Creates a new peer like [`Peer::setup`], that has to [`handshake`](Peer::handshake)
+  /// before sending, see [`Peer::with_handshake`].
+  pub fn setup_with_handshake<A1, A2>(bind_addr: A1, connect_addr: A2, key: Key, role: Role) -> io::Result<Self>
+  where
+    A1: ToSocketAddrs,
+    A2: ToSocketAddrs,
+  {
+    let socket = UdpSocket::bind(bind_addr)?;
+    let peer = Self::with_handshake(socket, key, role);
+    peer.connect(connect_addr)?;
+    Ok(peer)
+  }
+
+  /// Returns the role this peer was set up with, or `None` if it was set up without a handshake.
+  pub fn role(&self) -> Option<Role> {
+    self.crypto.role()
+  }
+
+  /// Returns the cipher suite messages are sealed with.
+  pub fn suite(&self) -> CipherSuite {
+    self.crypto.suite()
+  }
+
+  /// Returns the policy for ratcheting keys forward.
+  pub fn rekey_policy(&self) -> RekeyPolicy {
+    self.crypto.rekey_policy()
+  }
+
+  /// Sets the policy for ratcheting keys forward.
+  ///
+  /// The policy is shared with all clones of this peer. Both peers should use
+  /// the same policy, so that each keeps the previous key for long enough.
+  pub fn set_rekey_policy(&self, policy: RekeyPolicy) {
+    self.crypto.set_rekey_policy(policy)
+  }
+
+  /// Returns the policy for sending keepalives, or `None` if keepalives are off.
+  pub fn keepalive_policy(&self) -> Option<KeepalivePolicy> {
+    self.liveness().policy()
+  }
+
+  /// Sets the policy for sending keepalives, `None` turns them off.
+  ///
+  /// Keepalives are sent and the other side is checked on while the peer is
+  /// inside `recv()`, so a peer that never receives never notices a dead peer.
+  /// The policy is shared with all clones of this peer, and the other side gets
+  /// a full timeout from now before it is considered dead.
+  pub fn set_keepalive_policy(&self, policy: Option<KeepalivePolicy>) {
+    self.liveness().set_policy(policy)
+  }
+
+  /// Returns when a message from the other side last authenticated, if ever.
+  ///
+  /// Data messages, keepalives and handshakes all count.
+  pub fn last_heard(&self) -> Option<Instant> {
+    self.liveness().last_heard()
+  }
+
+  /// Returns `false` once the other side has missed too many keepalive
+  /// intervals, until it is heard from again.
+  ///
+  /// Always `true` without a [`KeepalivePolicy`].
+  pub fn is_alive(&self) -> bool {
+    self.liveness().is_alive()
+  }
+
+  /// Returns the limits on messages that are too large for a single datagram.
+  pub fn fragment_policy(&self) -> FragmentPolicy {
+    self.fragments().policy()
+  }
+
+  /// Sets the limits on messages that are too large for a single datagram.
+  ///
+  /// The policy is shared with all clones of this peer. Both peers should use
+  /// the same policy, messages larger than the other side's maximum are dropped.
+  pub fn set_fragment_policy(&self, policy: FragmentPolicy) {
+    self.fragments().set_policy(policy)
+  }
+
+  /// Returns the bounds for path MTU discovery, or `None` if it is off.
+  pub fn mtu_policy(&self) -> Option<MtuPolicy> {
+    self.path_mtu().policy()
+  }
+
+  /// Sets the bounds for path MTU discovery, `None` turns it off.
+  ///
+  /// With discovery on, the socket stops letting datagrams be fragmented by
+  /// the IP layer, and probes are sent while the peer is inside `recv()` to
+  /// find the largest datagram that makes it through. Messages are fragmented
+  /// to fit into it, and messages that are never fragmented fail with a
+  /// [`MessageTooLargeError`] if they don't fit. The policy is shared with all
+  /// clones of this peer, and setting it starts a new search.
+  pub fn set_mtu_policy(&self, policy: Option<MtuPolicy>) -> io::Result<()> {
+    mtu::set_dont_fragment(&self.socket, policy.is_some())?;
+    self.path_mtu().set_policy(policy);
+    Ok(())
+  }
+
+  /// Returns the largest datagram size in bytes known to make it to the
+  /// other side, the minimum of the [`MtuPolicy`] until probes say otherwise.
+  ///
+  /// Without path MTU discovery this is a conservative 1200 bytes.
+  pub fn mtu(&self) -> usize {
+    self.path_mtu().current()
+  }
+
+  /// Returns the largest message that fits into a single datagram, sent
+  /// without associated data.
+  pub fn max_payload_size(&self) -> usize {
+    self.mtu().saturating_sub(self.crypto.overhead(&[]))
+  }
+
+  /// Returns the largest message that fits into a single datagram when sent
+  /// on a channel, which takes the channel header out of [`Peer::max_payload_size`].
+  pub fn max_channel_payload_size(&self) -> usize {
+    self.max_payload_size().saturating_sub(CHANNEL_HEADER_SIZE)
+  }
+
+  /// Sets the congestion control algorithm for reliable messages, `None` turns it off.
+  ///
+  /// With congestion control on, reliable messages, on their own and on
+  /// reliable channels, are queued while the congestion window is full and
+  /// paced out at the rate the algorithm allows, from `send_reliable()` and
+  /// from inside `recv()`. Without it they are sent right away. The setting is
+  /// shared with all clones of this peer. Pass a [`NewReno`](crate::NewReno),
+  /// a [`Bbr`](crate::Bbr), or an algorithm of your own.
+  pub fn set_congestion_control(&self, algorithm: Option<Box<dyn CongestionControl>>) {
+    self.congestion().set_algorithm(algorithm)
+  }
+
+  /// Returns the congestion window in bytes, or `None` without congestion control.
+  pub fn congestion_window(&self) -> Option<usize> {
+    self.congestion().window()
+  }
+
+  /// Returns the rate limit for unreliable messages, or `None` if there is none.
+  pub fn rate_limit(&self) -> Option<RateLimit> {
+    self.congestion().rate_limit()
+  }
+
+  /// Sets the rate limit for unreliable messages, `None` removes it.
+  ///
+  /// Messages sent with [`Peer::send`] and its variants, and on unreliable
+  /// and latest-only channels, fail with an [`io::ErrorKind::WouldBlock`]
+  /// error while the limit is exceeded. Reliable messages are limited by
+  /// congestion control instead. The limit is shared with all clones of this peer.
+  pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+    self.congestion().set_rate_limit(limit)
+  }
+
+  /// Returns the smoothed round-trip time, measured from the acknowledgements
+  /// of reliable messages, or `None` before the first one is acknowledged.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.reliable().sender.rtt()
+  }
+
+  /// Returns the number of reliable messages waiting to be acknowledged.
+  pub fn reliable_in_flight(&self) -> usize {
+    self.reliable().sender.in_flight()
+  }
+
+  /// Returns the connection ID sent with every message, if any.
+  pub fn connection_id(&self) -> Option<u64> {
+    self.crypto.connection_id()
+  }
+
+  /// Sets the connection ID sent with every message.
+  ///
+  /// The connection ID lets an [`Endpoint`](crate::Endpoint) recognise this
+  /// peer after its address changes. It is sent in the clear and adds 8 bytes
+  /// to the overhead of every message.
+  ///
+  /// A peer set up without a handshake always sends one, since its key is
+  /// derived from it, and `None` picks a new random one. The two sides of
+  /// such a link must never use the same connection ID.
+  pub fn set_connection_id(&self, connection_id: Option<u64>) {
+    self.crypto.set_connection_id(connection_id)
+  }
+
+  /// Returns `true` if the peer follows the other side to new addresses.
+  pub fn is_roaming(&self) -> bool {
+    self.roaming.is_some()
+  }
+
+  /// Enables or disables roaming.
+  ///
+  /// A roaming peer no longer relies on the socket being connected to filter
+  /// incoming datagrams. Instead, whenever a message authenticates, the peer
+  /// switches its remote address to wherever the message came from, so it
+  /// keeps working when the other side's IP address or NAT mapping changes.
+  /// An unconnected roaming peer connects to the first peer that authenticates.
+  ///
+  /// Enabling roaming also picks a random connection ID if none is set, so
+  /// that an [`Endpoint`](crate::Endpoint) can follow this peer around in turn.
+  ///
+  /// Roaming should be set up before the peer is cloned or split.
+  pub fn set_roaming(&mut self, enabled: bool) -> io::Result<()> {
+    match (enabled, &self.roaming) {
+      (true, None) => {
+        let remote = self.remote_addr();
+        disconnect_socket(&self.socket)?;
+        self.roaming = Some(Arc::new(Mutex::new(remote)));
+        if self.connection_id().is_none() {
+          self.set_connection_id(Some(random_connection_id()));
+        }
+      }
+      (false, Some(remote)) => {
+        let remote = *remote.lock().unwrap_or_else(|e| e.into_inner());
+        self.roaming = None;
+        if !is_unspecified(remote) {
+          self.socket.connect(remote)?;
+        }
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  /// Returns the relay server that punching falls back to, if any.
+  pub fn relay(&self) -> Option<SocketAddr> {
+    self.relay.map(|relay| relay.server)
+  }
+
+  /// Sets the relay server that [`punch`](Peer::punch) falls back to, and the
+  /// session name to register under with it, or `None` to punch without one.
+  ///
+  /// Both sides have to use the same relay and session name. The peer
+  /// registers under a session ID derived from its pre-shared key and the
+  /// name, authenticated with the [`registration_key`](crate::registration_key)
+  /// the relay is set up with, so the relay is only used with the other side,
+  /// and never learns the key. See [`RelayServer`](crate::RelayServer).
+  ///
+  /// The relay forgets peers that are silent for 30 seconds, so relayed peers
+  /// should set a [`KeepalivePolicy`] with a shorter interval, like the default.
+  ///
+  /// The relay should be set up before the peer is cloned or split.
+  pub fn set_relay(&mut self, relay: Option<(SocketAddr, &str)>) {
+    self.relay = relay.map(|(server, session_name)| Relay::new(server, &self.key, session_name));
+  }
+
+  /// Returns `true` if the peer is talking to the other side through its relay.
+  pub fn is_relayed(&self) -> bool {
+    self.relay.is_some_and(|relay| self.remote_addr_optional() == Some(relay.server))
+  }
+
   /// Returns a reference to the underlying UDP socket.
+  ///
+  /// The socket is not connected while the peer is roaming.
   pub fn socket(&self) -> &UdpSocket {
     &self.socket
   }
@@ -49,6 +410,9 @@ impl Peer {
 
   /// Returns the remote socket address if connected, otherwise `None`.
   pub fn remote_addr_optional(&self) -> Option<SocketAddr> {
+    if let Some(remote) = self.remote_addr_roaming() {
+      return Some(remote).filter(|addr| !is_unspecified(*addr));
+    }
     match self.socket.peer_addr() {
       Ok(addr) => {
         if !is_unspecified(addr) {
@@ -71,8 +435,24 @@ impl Peer {
   ///
   /// This establishes the peer's target for communication. Both `send()` and
   /// `recv()` operations require the peer to be connected to function.
+  /// Connecting to an unspecified address disconnects the peer.
   pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
-    self.socket.connect(addr)
+    match &self.roaming {
+      Some(remote) => {
+        let addr = addr.to_socket_addrs()?.next()
+          .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to"))?;
+        *remote.lock().unwrap_or_else(|e| e.into_inner()) = addr;
+        Ok(())
+      }
+      None => {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if !addrs.is_empty() && addrs.iter().all(|addr| is_unspecified(*addr)) {
+          disconnect_socket(&self.socket)
+        } else {
+          self.socket.connect(addrs.as_slice())
+        }
+      }
+    }
   }
 
   /// Disconnects from the current remote address.
@@ -80,7 +460,13 @@ impl Peer {
   /// After disconnecting, both `send()` and `recv()` calls will fail until
   /// the peer is reconnected to a remote address.
   pub fn disconnect(&self) -> io::Result<()> {
-    self.socket.connect(to_unspecified(self.local_addr()))
+    match &self.roaming {
+      Some(remote) => {
+        *remote.lock().unwrap_or_else(|e| e.into_inner()) = to_unspecified(self.local_addr());
+        Ok(())
+      }
+      None => disconnect_socket(&self.socket),
+    }
   }
 
   /// Sets the read timeout for receive operations.
@@ -93,47 +479,1235 @@ impl Peer {
     self.socket.set_write_timeout(timeout)
   }
 
+  /// Performs a handshake with the connected peer, starting a session with a fresh key.
+  ///
+  /// Only peers set up with [`Peer::with_handshake`] handshake, others fail
+  /// with an [`io::ErrorKind::Unsupported`] error.
+  ///
+  /// The peers exchange ephemeral X25519 keys, authenticated with the pre-shared
+  /// key, and derive a new session key from them. Recorded traffic cannot be
+  /// decrypted later even if the pre-shared key is compromised (forward secrecy).
+  /// The pre-shared key itself never seals a message, so nothing can be sent
+  /// before the first handshake, sending fails with a [`NoSessionError`] until then.
+  ///
+  /// The initiator sends the handshake and retries until the responder answers,
+  /// failing with a [`HandshakeError`] after about five seconds. The responder
+  /// waits for a handshake, subject to the read timeout, discarding any other
+  /// messages until then. Handshakes are also answered automatically by the
+  /// responder's `recv()`, so a responder that is already receiving does not
+  /// need to call this. Only the responder ever answers a handshake.
+  ///
+  /// A new session also starts a new reliable stream, reliable messages that
+  /// are still waiting to be acknowledged are dropped. When a handshake
+  /// answered by `recv()` replaces a session that was already in use, `recv()`
+  /// fails with a [`NewSessionError`] to say so.
+  pub fn handshake(&mut self) -> io::Result<()> {
+    match self.role() {
+      Some(Role::Initiator) => self.initiate(),
+      Some(Role::Responder) => self.await_initiation(),
+      None => Err(io::Error::new(io::ErrorKind::Unsupported, "peer was set up without a handshake")),
+    }
+  }
+
+  /// Punches a path through NATs to the other side, then connects to it.
+  ///
+  /// Both sides call this at about the same time, each with the addresses the
+  /// other side might be reachable at, such as its local address and the public
+  /// address a rendezvous server saw it at. Authenticated punches, each round
+  /// with a fresh random challenge, are sent to every candidate from this
+  /// peer's socket several times a second, opening a mapping in this side's NAT
+  /// for the other side's punches to come in through. Punches from the other
+  /// side are answered, and the first address an answer echoing one of our
+  /// challenges comes from is connected to and returned, even if it is not one
+  /// of the candidates, since NATs may map the other side to a different port.
+  /// Recorded punches and answers can't redirect the peer, since they echo
+  /// challenges it never sent.
+  ///
+  /// Fails with a [`PunchError`] if no candidate answers within about ten
+  /// seconds, or the read timeout if it is shorter, leaving the peer connected
+  /// as it was before. Punches that arrive later are answered by `recv()`.
+  /// Other messages that arrive while punching are dropped. Punching doesn't
+  /// start a session, [`handshake`](Peer::handshake) once it is through.
+  ///
+  /// With a relay set, see [`Peer::set_relay`], a peer that fails to punch
+  /// through registers with the relay instead, and once the other side has
+  /// failed too and shows up there, connects to the relay and returns its
+  /// address. Messages then go through the relay without any other changes.
+  pub fn punch(&mut self, candidates: &[SocketAddr]) -> io::Result<SocketAddr> {
+    if candidates.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "no candidate addresses"));
+    }
+    let timeout = self.socket.read_timeout()?.map_or(PUNCH_TIMEOUT, |timeout| timeout.min(PUNCH_TIMEOUT));
+    let addr = match (self.detached(|peer| peer.punch_through(candidates, timeout)), self.relay) {
+      (Err(e), Some(relay)) if e.get_ref().is_some_and(|inner| inner.is::<PunchError>()) => {
+        // the other side gives up on punching at about the same time, and meets us there
+        match self.detached(|peer| peer.register(relay.server, &relay.session, Some(timeout))) {
+          Ok(_) => relay.server,
+          Err(e) if e.get_ref().is_some_and(|inner| inner.is::<RendezvousError>()) => return Err(PunchError.into()),
+          Err(e) => return Err(e),
+        }
+      }
+      (result, _) => result?,
+    };
+    self.connect(addr)?;
+    Ok(addr)
+  }
+
+  /// Finds the other side through a rendezvous server, returning the address
+  /// the server saw it at.
+  ///
+  /// Both sides call this with the same `session_name` and the address of a
+  /// server such as `twopoint-rendezvous`, then [`punch`](Peer::punch) through
+  /// to the returned address. The peer registers under a session ID derived
+  /// from its pre-shared key and the name, so only peers that share the key
+  /// are introduced to each other, and the server learns neither of them.
+  /// Registrations are authenticated with the [`registration_key`](crate::registration_key)
+  /// the server is set up with, see [`RendezvousServer`](crate::RendezvousServer).
+  ///
+  /// A fresh registration is sent twice a second until the server introduces
+  /// the other side, which keeps this side's NAT mapping to the server open.
+  /// Fails with a [`RendezvousError`] once the read timeout runs out, or keeps
+  /// waiting if there is none. The peer is left connected as it was before,
+  /// and other messages that arrive in the meantime are dropped.
+  pub fn rendezvous<A: ToSocketAddrs>(&mut self, server: A, session_name: &str) -> io::Result<SocketAddr> {
+    let server = server.to_socket_addrs()?.next()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no rendezvous server address"))?;
+    let session = rendezvous::session_id(&self.key, session_name);
+    let timeout = self.socket.read_timeout()?;
+    self.detached(|peer| peer.register(server, &session, timeout))
+  }
+
+  /// Runs `f` with the socket disconnected, so that datagrams can be
+  /// received from any address like with roaming, then puts the connection
+  /// and the read timeout back.
+  fn detached<T>(&self, f: impl FnOnce(&Self) -> io::Result<T>) -> io::Result<T> {
+    let previous = self.remote_addr_optional();
+    if self.roaming.is_none() {
+      disconnect_socket(&self.socket)?;
+    }
+    let timeout = self.socket.read_timeout()?;
+    let result = f(self);
+    self.socket.set_read_timeout(timeout)?;
+    if let Some(previous) = previous && self.roaming.is_none() {
+      self.socket.connect(previous)?;
+    }
+    result
+  }
+
+  fn register(&self, server: SocketAddr, session: &[u8; SESSION_ID_SIZE], timeout: Option<Duration>) -> io::Result<SocketAddr> {
+    let registration_key = rendezvous::registration_key(&self.key);
+    let mut buffer = [0u8; INTRODUCTION_SIZE + 1];
+    let give_up = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      // the server takes every registration in only once
+      let registration = rendezvous::registration(session, &registration_key);
+      match self.socket.send_to(&registration, server) {
+        Ok(_) => {}
+        // the server may not be up yet
+        Err(e) if can_reconnect(&e) => {}
+        Err(e) => return Err(e),
+      }
+      let deadline = Instant::now() + REGISTRATION_INTERVAL;
+      let deadline = give_up.map_or(deadline, |give_up| deadline.min(give_up));
+      loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+          break;
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match self.socket.recv_from(&mut buffer) {
+          Ok(received) => received,
+          Err(e) if can_retry(&e) => break,
+          Err(e) if can_reconnect(&e) => continue,
+          Err(e) => return Err(e),
+        };
+        if from == server && let Some(addr) = rendezvous::parse_introduction(&buffer[..len], session) {
+          return Ok(addr);
+        }
+      }
+      if give_up.is_some_and(|give_up| Instant::now() >= give_up) {
+        return Err(RendezvousError.into());
+      }
+    }
+  }
+
+  fn punch_through(&self, candidates: &[SocketAddr], timeout: Duration) -> io::Result<SocketAddr> {
+    let mut challenges = Vec::new();
+    let mut buffer = vec![0u8; self.path_mtu().largest()];
+    let give_up = Instant::now() + timeout;
+    while Instant::now() < give_up {
+      // every round gets a fresh challenge, earlier ones may still be answered
+      let challenge = punch::challenge();
+      challenges.push(challenge);
+      let punch = punch::punch(self.key, self.suite(), &challenge);
+      for candidate in candidates {
+        // an unreachable candidate can fail the send that follows it with its
+        // ICMP error, which the failed send clears, so each send gets a second try
+        if self.socket.send_to(&punch, candidate).is_err() {
+          let _ = self.socket.send_to(&punch, candidate);
+        }
+      }
+      let deadline = (Instant::now() + PUNCH_INTERVAL).min(give_up);
+      loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+          break;
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match self.socket.recv_from(&mut buffer) {
+          Ok(received) => received,
+          Err(e) if can_retry(&e) => break,
+          // ICMP errors from candidates that nobody is listening at
+          Err(e) if can_reconnect(&e) => continue,
+          Err(e) => return Err(e),
+        };
+        let datagram = &buffer[..len];
+        match Header::message_type(datagram) {
+          Some(MessageType::Punch) => {
+            // the other side's punches only open the way for its answers, since anyone can replay them
+            if let Some(answer) = punch::answer(self.key, self.suite(), datagram) {
+              let _ = self.socket.send_to(&answer, from);
+            }
+          }
+          Some(MessageType::PunchAck)
+            if punch::answered(self.key, self.suite(), datagram).is_some_and(|challenge| challenges.contains(&challenge)) =>
+          {
+            self.liveness().heard();
+            return Ok(from);
+          }
+          _ => {}
+        }
+      }
+    }
+    Err(PunchError.into())
+  }
+
+  fn initiate(&mut self) -> io::Result<()> {
+    let initiator = Initiator::new(self.key, self.suite());
+    let timeout = self.socket.read_timeout()?;
+    let result = self.exchange(&initiator);
+    self.socket.set_read_timeout(timeout)?;
+    self.crypto.set_key(result?)?;
+    self.reset_reliable();
+    Ok(())
+  }
+
+  fn exchange(&mut self, initiator: &Initiator) -> io::Result<Key> {
+    let mut buffer = [0u8; Responder::RESPONSE_SIZE + 1];
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+      self.send_datagram(initiator.packet())?;
+      let deadline = Instant::now() + HANDSHAKE_RETRY_INTERVAL;
+      loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+          break;
+        }
+        self.socket.set_read_timeout(Some(remaining))?;
+        match self.recv_datagram_from(&mut buffer) {
+          Ok((len, from)) => {
+            if let Ok(key) = initiator.finish(self.key, &buffer[..len]) {
+              self.authenticated(from);
+              return Ok(key);
+            }
+          }
+          Err(e) if can_retry(&e) => break,
+          Err(e) => return Err(e),
+        }
+      }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, HandshakeError))
+  }
+
+  fn await_initiation(&mut self) -> io::Result<()> {
+    let mut buffer = [0u8; Initiator::INITIATION_SIZE + 1];
+    loop {
+      let (len, from) = self.recv_datagram_from(&mut buffer)?;
+      if Header::message_type(&buffer[..len]) == Some(MessageType::HandshakeInitiation)
+        && self.answer_initiation(&buffer[..len], from)?
+      {
+        return Ok(());
+      }
+    }
+  }
+
+  /// Answers a handshake initiation, returning `true` if a new session was started.
+  ///
+  /// Invalid or replayed initiations are ignored, and so is every initiation
+  /// unless this peer is the responder of a handshake. A roaming peer answers
+  /// wherever the initiation came from.
+  fn answer_initiation(&self, packet: &[u8], from: Option<SocketAddr>) -> io::Result<bool> {
+    if self.role() != Some(Role::Responder) {
+      return Ok(false);
+    }
+    let mut responder = self.responder.lock().unwrap_or_else(|e| e.into_inner());
+    let Ok((response, key)) = responder.respond(self.key, self.suite(), packet) else {
+      return Ok(false);
+    };
+    match from {
+      Some(from) => self.socket.send_to(response, from)?,
+      None => self.socket.send(response)?,
+    };
+    self.liveness().sent();
+    match key {
+      Some(key) => {
+        self.crypto.set_key(key)?;
+        self.reset_reliable();
+        self.authenticated(from);
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  /// Sends a datagram to the remote address.
+  fn send_datagram(&self, datagram: &[u8]) -> io::Result<()> {
+    match self.remote_addr_roaming() {
+      Some(addr) if is_unspecified(addr) => return Err(io::ErrorKind::NotConnected.into()),
+      Some(addr) => self.socket.send_to(datagram, addr)?,
+      None => self.socket.send(datagram)?,
+    };
+    self.liveness().sent();
+    Ok(())
+  }
+
+  /// Receives a datagram, along with where it came from if the peer is roaming.
+  fn recv_datagram_from(&self, buffer: &mut [u8]) -> io::Result<(usize, Option<SocketAddr>)> {
+    match self.roaming {
+      Some(_) => {
+        let (len, from) = self.socket.recv_from(buffer)?;
+        Ok((len, Some(from)))
+      }
+      None => Ok((self.socket.recv(buffer)?, None)),
+    }
+  }
+
+  /// Records that a message from the other side authenticated, moving a
+  /// roaming peer over to the address it came from.
+  fn authenticated(&self, from: Option<SocketAddr>) {
+    self.liveness().heard();
+    if let (Some(remote), Some(from)) = (&self.roaming, from) {
+      *remote.lock().unwrap_or_else(|e| e.into_inner()) = from;
+    }
+  }
+
+  /// Receives a datagram like [`Peer::recv_datagram_from`], waking up in the
+  /// meantime to send keepalives and to check on the other side.
+  ///
+  /// The socket's read timeout is replaced by `deadline` while waiting, and is
+  /// left as it is if there is nothing to wake up for.
+  fn recv_datagram_timed(&self, buffer: &mut [u8], deadline: Option<Instant>) -> io::Result<(usize, Option<SocketAddr>)> {
+    loop {
+      self.tick()?;
+      let wakeup = self.wakeup();
+      let Some(until) = wakeup.into_iter().chain(deadline).min() else {
+        return self.recv_datagram_from(buffer);
+      };
+      // a zero timeout means blocking forever, so wait at least a little
+      let wait = until.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+      self.socket.set_read_timeout(Some(wait))?;
+      match self.recv_datagram_from(buffer) {
+        // only carry on if it was our own timer that went off, not the read timeout
+        Err(e) if can_retry(&e) && wakeup.is_some_and(|wakeup| Instant::now() >= wakeup) => {}
+        result => return result,
+      }
+    }
+  }
+
+  /// Returns when [`Peer::tick`] next has something to do, if ever.
+  fn wakeup(&self) -> Option<Instant> {
+    let liveness = self.liveness().deadline();
+    let reliable = self.reliable().deadline(Instant::now());
+    let channels = self.channels().deadline(Instant::now());
+    let pacing = self.congestion().deadline(Instant::now());
+    let mtu = self.path_mtu().deadline();
+    liveness.into_iter().chain(reliable).chain(channels).chain(pacing).chain(mtu).min()
+  }
+
+  /// Retransmits reliable messages that timed out and sends queued ones,
+  /// sends a keepalive if the link has been idle and probes the path MTU when
+  /// due, then fails once if the other side has gone silent.
+  fn tick(&self) -> io::Result<()> {
+    let now = Instant::now();
+    let expired = self.reliable().sender.expired(now);
+    let expired_channels = self.channels().expired(now);
+    if !expired.is_empty() || !expired_channels.is_empty() {
+      self.congestion().timed_out(now);
+    }
+    for sequence in expired {
+      self.retransmit(sequence)?;
+    }
+    for (channel, sequence) in expired_channels {
+      self.retransmit_channel(channel, sequence)?;
+    }
+    self.flush()?;
+    let poll = self.liveness().poll(now);
+    // there is nothing to seal keepalives and probes with before the first handshake
+    let ready = self.remote_addr_optional().is_some() && self.crypto.has_session();
+    if poll.keepalive && ready {
+      self.send_keepalive()?;
+    }
+    if ready {
+      let probe = self.path_mtu().poll(now);
+      if let Some(size) = probe
+        && let Err(e) = self.send_probe(size)
+      {
+        // any other failure counts as a lost probe
+        if !mtu::is_too_large(&e) {
+          return Err(e);
+        }
+        self.path_mtu().refuse(size);
+      }
+    }
+    if poll.died {
+      return Err(DeadPeerError.into());
+    }
+    Ok(())
+  }
+
+  fn send_keepalive(&self) -> io::Result<()> {
+    let mut buffer = [0u8; Crypto::MINIMUM_BUFFER_LENGTH + Crypto::CONNECTION_ID_SIZE];
+    let len = self.crypto.encrypt_slice_as(MessageType::Keepalive, &[], &mut buffer, 0)?;
+    self.send_datagram(&buffer[..len])
+  }
+
+  /// Sends an MTU probe padded so that the datagram is exactly `size` bytes.
+  fn send_probe(&self, size: usize) -> io::Result<()> {
+    let Ok(field) = u16::try_from(size) else {
+      return Err(MessageTooLargeError { len: size, max: MAXIMUM_PROBE_SIZE }.into());
+    };
+    let len = size.saturating_sub(self.crypto.overhead(&[])).max(PROBE_SIZE_SIZE);
+    let mut buffer = Vec::with_capacity(size);
+    buffer.extend_from_slice(&field.to_be_bytes());
+    buffer.resize(len, 0);
+    self.crypto.encrypt_as(MessageType::Probe, &mut buffer)?;
+    self.send_datagram(&buffer)
+  }
+
+  fn send_probe_ack(&self, size: [u8; PROBE_SIZE_SIZE]) -> io::Result<()> {
+    let mut buffer = [0u8; PROBE_SIZE_SIZE + Crypto::MINIMUM_BUFFER_LENGTH + Crypto::CONNECTION_ID_SIZE];
+    buffer[..PROBE_SIZE_SIZE].copy_from_slice(&size);
+    let len = self.crypto.encrypt_slice_as(MessageType::ProbeAck, &[], &mut buffer, PROBE_SIZE_SIZE)?;
+    self.send_datagram(&buffer[..len])
+  }
+
+  /// Sends a reliable message, again if it was sent before, unless it has been acknowledged in the meantime.
+  fn retransmit(&self, sequence: u64) -> io::Result<()> {
+    let Some(mut buffer) = self.reliable().sender.message(sequence).map(|message| {
+      let mut buffer = Vec::with_capacity(SEQUENCE_SIZE + message.len() + self.crypto.overhead(&[]));
+      buffer.extend_from_slice(&sequence.to_be_bytes());
+      buffer.extend_from_slice(message);
+      buffer
+    }) else {
+      return Ok(());
+    };
+    self.send_message(MessageType::Reliable, &mut buffer)
+  }
+
+  /// Sends queued reliable messages, on their own and on channels, for as long
+  /// as congestion control lets them go.
+  fn flush(&self) -> io::Result<()> {
+    loop {
+      let now = Instant::now();
+      let next = self.reliable().sender.next_unsent().map(|(sequence, len)| (None, sequence, len));
+      let next = next.or_else(|| {
+        let (channel, sequence, len) = self.channels().next_unsent()?;
+        Some((Some(channel), sequence, len))
+      });
+      let Some((channel, sequence, len)) = next else {
+        return Ok(());
+      };
+      {
+        let mut congestion = self.congestion();
+        if !congestion.can_send(now, len) {
+          return Ok(());
+        }
+        congestion.sent(now, len);
+      }
+      // a failed first transmission is retried like a lost datagram
+      let _ = match channel {
+        None => {
+          self.reliable().sender.mark_sent(sequence, now);
+          self.retransmit(sequence)
+        }
+        Some(channel) => {
+          self.channels().mark_sent(channel, sequence, now);
+          self.retransmit_channel(channel, sequence)
+        }
+      };
+    }
+  }
+
+  /// Takes in the acknowledgement of reliable messages, retransmitting the
+  /// ones it says were lost, and sends the queued messages it makes room for.
+  fn acknowledged(&self, channel: Option<u16>, acknowledgement: Acknowledgement) -> io::Result<()> {
+    let now = Instant::now();
+    {
+      let mut congestion = self.congestion();
+      congestion.acknowledged(now, acknowledgement.bytes, acknowledgement.rtt);
+      if !acknowledgement.retransmit.is_empty() {
+        congestion.lost(now);
+      }
+    }
+    for sequence in acknowledgement.retransmit {
+      match channel {
+        None => self.retransmit(sequence)?,
+        Some(channel) => self.retransmit_channel(channel, sequence)?,
+      }
+    }
+    self.flush()
+  }
+
+  /// Fails with [`io::ErrorKind::WouldBlock`] if sending `len` more bytes of
+  /// unreliable messages would exceed the [`RateLimit`].
+  fn check_rate_limit(&self, len: usize) -> io::Result<()> {
+    if !self.congestion().take(Instant::now(), len) {
+      return Err(io::Error::new(io::ErrorKind::WouldBlock, "rate limit exceeded"));
+    }
+    Ok(())
+  }
+
+  /// Sends a reliable channel message, again if it was sent before, unless it has been acknowledged in the meantime.
+  fn retransmit_channel(&self, channel: u16, sequence: u64) -> io::Result<()> {
+    let Some(mut buffer) = self.channels().message(channel, sequence).map(|(mode, message)| {
+      let mut buffer = Vec::with_capacity(CHANNEL_HEADER_SIZE + message.len() + self.crypto.overhead(&[]));
+      buffer.extend_from_slice(&channel::header(channel, mode, sequence));
+      buffer.extend_from_slice(message);
+      buffer
+    }) else {
+      return Ok(());
+    };
+    self.send_message(MessageType::Channel, &mut buffer)
+  }
+
+  /// Encrypts and sends a message of the given type, splitting it into
+  /// fragments if it does not fit into a single datagram.
+  fn send_message(&self, message_type: MessageType, buffer: &mut Vec<u8>) -> io::Result<()> {
+    if buffer.len() + self.crypto.overhead(&[]) > self.mtu() {
+      return self.send_fragmented(message_type, buffer);
+    }
+    self.crypto.encrypt_as(message_type, buffer)?;
+    self.send_datagram(buffer)
+  }
+
+  fn send_fragmented(&self, message_type: MessageType, message: &[u8]) -> io::Result<()> {
+    let fragments = {
+      let size = self.max_payload_size();
+      self.fragments().split(message_type, message, size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message needs too many fragments"))?
+    };
+    for mut fragment in fragments {
+      self.crypto.encrypt_as(MessageType::Fragment, &mut fragment)?;
+      self.send_datagram(&fragment)?;
+    }
+    Ok(())
+  }
+
+  /// Fails with a [`MessageTooLargeError`] if a message is larger than the [`FragmentPolicy`] allows.
+  fn check_message_size(&self, len: usize) -> io::Result<()> {
+    let max = self.fragment_policy().max_message_size;
+    if len > max {
+      return Err(MessageTooLargeError { len, max }.into());
+    }
+    Ok(())
+  }
+
+  /// Fails with a [`MessageTooLargeError`] if path MTU discovery is on and a
+  /// message that is never fragmented does not fit into a single datagram.
+  fn check_datagram_size(&self, aad: &[u8], len: usize) -> io::Result<()> {
+    let path_mtu = self.path_mtu();
+    if path_mtu.policy().is_none() {
+      return Ok(());
+    }
+    let max = path_mtu.current().saturating_sub(self.crypto.overhead(aad));
+    if len > max {
+      return Err(MessageTooLargeError { len, max }.into());
+    }
+    Ok(())
+  }
+
+  /// Takes in an authenticated reliable message and acknowledges it.
+  ///
+  /// Fails with a [`DesyncError`] if it can't belong to the stream this side is receiving.
+  fn receive_reliable(&self, message: &[u8]) -> io::Result<Delivery> {
+    let (sequence, message) = message.split_first_chunk::<SEQUENCE_SIZE>().ok_or(CryptoError)?;
+    let (delivery, ack) = {
+      let mut reliable = self.reliable();
+      let delivery = reliable.receiver.receive(u64::from_be_bytes(*sequence), message);
+      (delivery, reliable.receiver.ack())
+    };
+    if delivery == Delivery::Desync {
+      return Err(DesyncError.into());
+    }
+    // duplicates are acknowledged again, in case the first acknowledgement got lost
+    self.send_ack(&ack)?;
+    Ok(delivery)
+  }
+
+  fn send_ack(&self, ack: &[u8; ACK_SIZE]) -> io::Result<()> {
+    let mut buffer = [0u8; ACK_SIZE + Crypto::MINIMUM_BUFFER_LENGTH + Crypto::CONNECTION_ID_SIZE];
+    buffer[..ACK_SIZE].copy_from_slice(ack);
+    let len = self.crypto.encrypt_slice_as(MessageType::Ack, &[], &mut buffer, ACK_SIZE)?;
+    self.send_datagram(&buffer[..len])
+  }
+
+  /// Takes in an authenticated channel message and acknowledges it if the
+  /// channel is reliable, returning its channel if it should be delivered now.
+  fn receive_channel(&self, message: &[u8]) -> io::Result<Option<u16>> {
+    let (channel, mode, sequence, message) = channel::parse(message).ok_or(CryptoError)?;
+    let (delivery, ack) = self.channels().receive(channel, mode, sequence, message);
+    if delivery == Delivery::Desync {
+      return Err(DesyncError.into());
+    }
+    if let Some(ack) = ack {
+      self.send_channel_ack(channel, &ack)?;
+    }
+    Ok((delivery == Delivery::Now).then_some(channel))
+  }
+
+  fn send_channel_ack(&self, channel: u16, ack: &[u8; ACK_SIZE]) -> io::Result<()> {
+    let mut buffer = [0u8; CHANNEL_ACK_SIZE + Crypto::MINIMUM_BUFFER_LENGTH + Crypto::CONNECTION_ID_SIZE];
+    buffer[..2].copy_from_slice(&channel.to_be_bytes());
+    buffer[2..CHANNEL_ACK_SIZE].copy_from_slice(ack);
+    let len = self.crypto.encrypt_slice_as(MessageType::ChannelAck, &[], &mut buffer, CHANNEL_ACK_SIZE)?;
+    self.send_datagram(&buffer[..len])
+  }
+
+  fn reset_reliable(&self) {
+    *self.reliable() = Reliable::default();
+    *self.channels() = Channels::default();
+    self.congestion().reset();
+  }
+
+  fn liveness(&self) -> MutexGuard<'_, Liveness> {
+    self.liveness.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn reliable(&self) -> MutexGuard<'_, Reliable> {
+    self.reliable.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn fragments(&self) -> MutexGuard<'_, Fragments> {
+    self.fragments.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn path_mtu(&self) -> MutexGuard<'_, PathMtu> {
+    self.mtu.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn channels(&self) -> MutexGuard<'_, Channels> {
+    self.channels.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn congestion(&self) -> MutexGuard<'_, Congestion> {
+    self.congestion.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn remote_addr_roaming(&self) -> Option<SocketAddr> {
+    self.roaming.as_ref().map(|remote| *remote.lock().unwrap_or_else(|e| e.into_inner()))
+  }
+
   /// Encrypts and sends the contents of the buffer to the connected peer.
   ///
   /// The buffer is modified in-place during encryption - a 28-byte overhead
-  /// (16-byte authentication tag + 12-byte nonce) is appended to the end.
+  /// (4-byte header + 16-byte authentication tag + 8-byte message counter) is added.
   ///
-  /// Returns an error if not connected to a peer, if encryption fails, or on network errors.
+  /// Messages that don't fit into a single datagram are split into fragments
+  /// and put back together by the other side, up to the maximum message size of
+  /// the [`FragmentPolicy`]. The buffer is left unencrypted in that case.
+  ///
+  /// Returns an error if not connected to a peer, with a [`NoSessionError`] before the
+  /// first handshake, if encryption fails, or on network errors.
   pub fn send(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
-    self.crypto.encrypt(buffer)?;
-    self.socket.send(buffer)?;
-    Ok(())
+    self.check_message_size(buffer.len())?;
+    self.check_rate_limit(buffer.len())?;
+    self.send_message(MessageType::Data, buffer)
+  }
+
+  /// Encrypts and sends the contents of the buffer along with associated data.
+  ///
+  /// The associated data, such as a channel or tenant ID, is sent in the clear
+  /// but authenticated along with the message, so it cannot be tampered with.
+  /// It adds its own length plus 2 bytes to the overhead, and can be at most
+  /// 65535 bytes long. Messages with associated data are never fragmented,
+  /// see [`Peer::set_mtu_policy`] for what happens if they don't fit.
+  pub fn send_with_aad(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.check_datagram_size(aad, buffer.len())?;
+    self.check_rate_limit(buffer.len())?;
+    self.crypto.encrypt_with_aad(aad, buffer)?;
+    self.send_datagram(buffer)
+  }
+
+  /// Encrypts the message into `scratch` and sends it to the connected peer, without allocating.
+  ///
+  /// The message is never fragmented. The scratch buffer must be at least [`Peer::OVERHEAD`] bytes longer than
+  /// the message, plus 8 bytes if a connection ID is set, otherwise an
+  /// [`io::ErrorKind::InvalidInput`] error is returned.
+  pub fn send_slice(&mut self, message: &[u8], scratch: &mut [u8]) -> io::Result<()> {
+    self.check_datagram_size(&[], message.len())?;
+    self.check_rate_limit(message.len())?;
+    let len = self.crypto.encrypt_into(&[], message, scratch)?;
+    self.send_datagram(&scratch[..len])
+  }
+
+  /// Encrypts the message in the first `len` bytes of the buffer in-place and
+  /// sends it to the connected peer, without allocating.
+  ///
+  /// The buffer must have [`Peer::OVERHEAD`] bytes of room after the message,
+  /// plus 8 bytes if a connection ID is set, otherwise an
+  /// [`io::ErrorKind::InvalidInput`] error is returned.
+  pub fn send_in_place(&mut self, buffer: &mut [u8], len: usize) -> io::Result<()> {
+    self.check_datagram_size(&[], len)?;
+    self.check_rate_limit(len)?;
+    let len = self.crypto.encrypt_slice(&[], buffer, len)?;
+    self.send_datagram(&buffer[..len])
+  }
+
+  /// Encrypts and sends the contents of the buffer reliably.
+  ///
+  /// Reliable messages are numbered and kept until the other side acknowledges
+  /// them, and are retransmitted if no acknowledgement arrives in time. The
+  /// other side delivers them from `recv()` in the order they were sent, mixed
+  /// in with unreliable messages, so both kinds can share one peer.
+  ///
+  /// Acknowledgements are taken in and retransmissions sent while the peer is
+  /// inside `recv()`, so somebody has to keep receiving. Up to 1024 messages can
+  /// wait to be acknowledged at once, after that an [`io::ErrorKind::WouldBlock`]
+  /// error is returned until some are. Reliable messages add 8 bytes to the
+  /// overhead, for the sequence number.
+  ///
+  /// Large messages are fragmented like with [`Peer::send`], and sent again
+  /// as a whole if any fragment is lost.
+  ///
+  /// With [`Peer::set_congestion_control`], messages may be queued for a while
+  /// before they are first sent. Once a message has been accepted, a failed
+  /// first transmission is retried like a lost datagram, so only errors from
+  /// before that are returned.
+  // takes a Vec like the other send methods, even though the buffer is left as it is
+  #[allow(clippy::ptr_arg)]
+  pub fn send_reliable(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    if self.remote_addr_optional().is_none() {
+      return Err(io::ErrorKind::NotConnected.into());
+    }
+    if !self.crypto.has_session() {
+      return Err(NoSessionError.into());
+    }
+    self.check_message_size(buffer.len())?;
+    self.reliable().sender.push(buffer)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "too many reliable messages waiting to be acknowledged"))?;
+    self.flush()
+  }
+
+  /// Sends a message on a channel, see [`Channel::send`].
+  fn send_channel(&self, id: u16, mode: ChannelMode, buffer: &mut Vec<u8>) -> io::Result<()> {
+    if mode.is_reliable() && self.remote_addr_optional().is_none() {
+      return Err(io::ErrorKind::NotConnected.into());
+    }
+    if mode.is_reliable() && !self.crypto.has_session() {
+      return Err(NoSessionError.into());
+    }
+    self.check_message_size(buffer.len())?;
+    if !mode.is_reliable() {
+      self.check_rate_limit(buffer.len())?;
+    }
+    let sequence = self.channels().push(id, mode, buffer)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "too many channel messages waiting to be acknowledged"))?;
+    if mode.is_reliable() {
+      return self.flush();
+    }
+    buffer.splice(0..0, channel::header(id, mode, sequence));
+    self.send_message(MessageType::Channel, buffer)
   }
 
   /// Receives and decrypts a message into the buffer.
   ///
-  /// The buffer must be large enough to hold the entire encrypted message.
+  /// The buffer must be large enough to hold the entire encrypted message, it
+  /// is grown to fit a full-sized datagram if needed, and to fit messages put
+  /// together from fragments.
   /// After receiving, the buffer is truncated to the message length, then
-  /// the 28-byte crypto overhead is removed from the end during decryption.
+  /// the 28-byte crypto overhead is removed during decryption.
   /// The buffer is resized to match the original message length.
+  /// Associated data sent along with the message is discarded.
+  ///
+  /// Handshake messages are handled internally and do not end the call, and
+  /// so are keepalives, see [`Peer::set_keepalive_policy`]. Only a handshake
+  /// that replaces a session in use ends it, with a [`NewSessionError`].
   ///
   /// Returns an error if not connected to a peer, if decryption fails, or on network errors.
+  /// Duplicated or replayed messages are rejected with a [`ReplayError`](crate::ReplayError),
+  /// and messages from incompatible protocol versions with a [`HeaderError`](crate::HeaderError).
+  /// With keepalives on, a [`DeadPeerError`] is returned once the other side goes silent.
+  /// Reliable messages and acknowledgements that can't belong to this side's
+  /// stream fail with a [`DesyncError`], handshake again to start over.
   pub fn recv(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
-    let len = self.socket.recv(buffer)?;
-    buffer.truncate(len);
-    self.crypto.decrypt(buffer)?;
+    self.recv_channel(buffer).map(|_| ())
+  }
+
+  /// Receives and decrypts a message into the buffer like [`Peer::recv`],
+  /// returning the ID of the channel it came in on, or `None` if it was not
+  /// sent on a channel.
+  pub fn recv_channel(&mut self, buffer: &mut Vec<u8>) -> io::Result<Option<u16>> {
+    self.reserve_datagram(buffer);
+    match self.recv_datagram(buffer)? {
+      Incoming::Data(len, from) => {
+        buffer.truncate(len);
+        self.crypto.decrypt(buffer)?;
+        self.authenticated(from);
+        Ok(None)
+      }
+      Incoming::Decrypted(len, channel) => {
+        buffer.truncate(len);
+        Ok(channel)
+      }
+      Incoming::Ready(message, channel) => {
+        buffer.clear();
+        buffer.extend_from_slice(&message);
+        Ok(channel)
+      }
+    }
+  }
+
+  /// Receives and decrypts a message into the buffer like [`Peer::recv`],
+  /// replacing the contents of `aad` with the message's associated data.
+  ///
+  /// `aad` is left empty if the message was sent without associated data.
+  pub fn recv_with_aad(&mut self, buffer: &mut Vec<u8>, aad: &mut Vec<u8>) -> io::Result<()> {
+    self.reserve_datagram(buffer);
+    match self.recv_datagram(buffer)? {
+      Incoming::Data(len, from) => {
+        buffer.truncate(len);
+        self.crypto.decrypt_with_aad(buffer, aad)?;
+        self.authenticated(from);
+      }
+      Incoming::Decrypted(len, _) => {
+        buffer.truncate(len);
+        aad.clear();
+      }
+      Incoming::Ready(message, _) => {
+        buffer.clear();
+        buffer.extend_from_slice(&message);
+        aad.clear();
+      }
+    }
     Ok(())
   }
 
-}
+  /// Receives and decrypts a message into the start of the buffer, without
+  /// allocating, and returns the message length.
+  ///
+  /// The buffer must be at least [`Peer::OVERHEAD`] bytes longer than the
+  /// message, plus 8 bytes if the other side sends a connection ID, longer
+  /// datagrams are cut off and fail to decrypt. Associated
+  /// data sent along with the message is discarded. Reliable messages that
+  /// were held back and messages put together from fragments are kept if they
+  /// don't fit, and an [`io::ErrorKind::InvalidInput`] error is returned.
+  pub fn recv_into(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    match self.recv_datagram(buffer)? {
+      Incoming::Data(len, from) => {
+        let len = self.crypto.decrypt_slice(&mut buffer[..len])?;
+        self.authenticated(from);
+        Ok(len)
+      }
+      Incoming::Decrypted(len, _) => Ok(len),
+      Incoming::Ready(message, channel) => {
+        let Some(buffer) = buffer.get_mut(..message.len()) else {
+          // keep the message for a call with a bigger buffer
+          match channel {
+            Some(channel) => self.channels().unpop(channel, message),
+            None => self.reliable().receiver.unpop(message),
+          }
+          return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too small for the message"));
+        };
+        buffer.copy_from_slice(&message);
+        Ok(message.len())
+      }
+    }
+  }
+
+  /// Grows the buffer to fit the largest datagram the other side may send.
+  fn reserve_datagram(&self, buffer: &mut Vec<u8>) {
+    let size = self.path_mtu().largest();
+    if buffer.len() < size {
+      buffer.resize(size, 0);
+    }
+  }
+
+  /// Receives datagrams until a data message arrives, answering handshakes
+  /// and taking in keepalives and acknowledgements in the meantime.
+  fn recv_datagram(&mut self, buffer: &mut [u8]) -> io::Result<Incoming> {
+    if self.wakeup().is_none() {
+      return self.recv_datagram_until(buffer, None);
+    }
+    // keepalives wake us up early, so the read timeout has to be kept track of by hand
+    let timeout = self.socket.read_timeout()?;
+    let result = self.recv_datagram_until(buffer, timeout.map(|timeout| Instant::now() + timeout));
+    self.socket.set_read_timeout(timeout)?;
+    result
+  }
+
+  fn recv_datagram_until(&self, buffer: &mut [u8], deadline: Option<Instant>) -> io::Result<Incoming> {
+    loop {
+      if let Some(message) = self.reliable().receiver.pop() {
+        return Ok(Incoming::Ready(message, None));
+      }
+      if let Some((channel, message)) = self.channels().pop() {
+        return Ok(Incoming::Ready(message, Some(channel)));
+      }
+      let (len, from) = self.recv_datagram_timed(buffer, deadline)?;
+      let datagram = &mut buffer[..len];
+      match Header::parse(datagram)?.message_type {
+        MessageType::Data => return Ok(Incoming::Data(len, from)),
+        MessageType::Reliable => {
+          let len = self.crypto.decrypt_slice_as(MessageType::Reliable, datagram)?;
+          self.authenticated(from);
+          if self.receive_reliable(&datagram[..len])? == Delivery::Now {
+            buffer.copy_within(SEQUENCE_SIZE..len, 0);
+            return Ok(Incoming::Decrypted(len - SEQUENCE_SIZE, None));
+          }
+        }
+        MessageType::Channel => {
+          let len = self.crypto.decrypt_slice_as(MessageType::Channel, datagram)?;
+          self.authenticated(from);
+          if let Some(channel) = self.receive_channel(&datagram[..len])? {
+            buffer.copy_within(CHANNEL_HEADER_SIZE..len, 0);
+            return Ok(Incoming::Decrypted(len - CHANNEL_HEADER_SIZE, Some(channel)));
+          }
+        }
+        MessageType::Fragment => {
+          let len = self.crypto.decrypt_slice_as(MessageType::Fragment, datagram)?;
+          self.authenticated(from);
+          let reassembled = self.fragments().reassemble(&datagram[..len]);
+          match reassembled {
+            Some((MessageType::Reliable, message)) => {
+              let delivery = self.receive_reliable(&message)?;
+              if delivery == Delivery::Now {
+                return Ok(Incoming::Ready(message[SEQUENCE_SIZE..].to_vec(), None));
+              }
+            }
+            Some((MessageType::Channel, message)) => {
+              if let Some(channel) = self.receive_channel(&message)? {
+                return Ok(Incoming::Ready(message[CHANNEL_HEADER_SIZE..].to_vec(), Some(channel)));
+              }
+            }
+            Some((_, message)) => return Ok(Incoming::Ready(message, None)),
+            None => {}
+          }
+        }
+        MessageType::Ack => {
+          let len = self.crypto.decrypt_slice_as(MessageType::Ack, datagram)?;
+          self.authenticated(from);
+          let ack = *datagram[..len].first_chunk::<ACK_SIZE>().ok_or(CryptoError)?;
+          let acknowledgement = self.reliable().sender.acknowledge(&ack).ok_or(DesyncError)?;
+          self.acknowledged(None, acknowledgement)?;
+        }
+        MessageType::ChannelAck => {
+          let len = self.crypto.decrypt_slice_as(MessageType::ChannelAck, datagram)?;
+          self.authenticated(from);
+          let ack = datagram[..len].first_chunk::<CHANNEL_ACK_SIZE>().ok_or(CryptoError)?;
+          let (channel, ack) = ack.split_first_chunk::<2>().unwrap();
+          let channel = u16::from_be_bytes(*channel);
+          let acknowledgement = self.channels().acknowledge(channel, ack.try_into().unwrap()).ok_or(DesyncError)?;
+          self.acknowledged(Some(channel), acknowledgement)?;
+        }
+        MessageType::Keepalive => {
+          self.crypto.decrypt_slice_as(MessageType::Keepalive, datagram)?;
+          self.authenticated(from);
+        }
+        MessageType::Punch => {
+          // the other side is still punching, its earlier answers must have been lost
+          let Some(answer) = punch::answer(self.key, self.suite(), datagram) else {
+            return Err(CryptoError.into());
+          };
+          match from {
+            Some(from) => self.socket.send_to(&answer, from)?,
+            None => self.socket.send(&answer)?,
+          };
+          self.liveness().sent();
+        }
+        // late answers to punches that already went through
+        MessageType::PunchAck => {}
+        MessageType::Probe => {
+          // a probe cut off by a small buffer is just a probe that didn't make it
+          let Ok(len) = self.crypto.decrypt_slice_as(MessageType::Probe, datagram) else {
+            continue;
+          };
+          self.authenticated(from);
+          let size = *datagram[..len].first_chunk::<PROBE_SIZE_SIZE>().ok_or(CryptoError)?;
+          self.send_probe_ack(size)?;
+        }
+        MessageType::ProbeAck => {
+          let len = self.crypto.decrypt_slice_as(MessageType::ProbeAck, datagram)?;
+          self.authenticated(from);
+          let size = *datagram[..len].first_chunk::<PROBE_SIZE_SIZE>().ok_or(CryptoError)?;
+          self.path_mtu().acknowledge(usize::from(u16::from_be_bytes(size)));
+        }
+        MessageType::HandshakeInitiation => {
+          let replaced = self.crypto.has_session();
+          if self.answer_initiation(datagram, from)? && replaced {
+            return Err(NewSessionError.into());
+          }
+        }
+        // late duplicate of a response to a finished handshake
+        MessageType::HandshakeResponse => {}
+        // late introduction from a rendezvous server, or a stray registration
+        MessageType::Register | MessageType::Introduction => {}
+      }
+    }
+  }
+
+  /// Splits the peer into a sending half and a receiving half.
+  ///
+  /// Both halves share the same socket and encryption state, so they can be
+  /// moved to different threads without duplicating the socket.
+  pub fn split(self) -> (PeerSender, PeerReceiver) {
+    (PeerSender { peer: self.share() }, PeerReceiver { peer: self })
+  }
+
+  /// Returns a handle for sending on the channel with the given ID.
+  ///
+  /// Channels are independent message flows over this peer's socket and
+  /// encryption state, each sent with the delivery guarantees of its
+  /// [`ChannelMode`]. A lost message on a reliable channel only holds back
+  /// later messages on the same channel. Channel messages are received from
+  /// [`Peer::recv_channel`], which also says which channel they came in on.
+  pub fn channel(&self, id: u16, mode: ChannelMode) -> Channel {
+    Channel { peer: self.share(), id, mode }
+  }
+
+  /// Creates a new handle to the same peer, sharing its socket and encryption state.
+  fn share(&self) -> Peer {
+    Peer {
+      socket: Arc::clone(&self.socket),
+      key: self.key,
+      crypto: self.crypto.clone(),
+      responder: Arc::clone(&self.responder),
+      roaming: self.roaming.clone(),
+      liveness: Arc::clone(&self.liveness),
+      reliable: Arc::clone(&self.reliable),
+      fragments: Arc::clone(&self.fragments),
+      mtu: Arc::clone(&self.mtu),
+      channels: Arc::clone(&self.channels),
+      congestion: Arc::clone(&self.congestion),
+      relay: self.relay,
+    }
+  }
 
-impl Clone for Peer {
-  /// Clones the peer, including its socket and encryption state.
+  /// Creates a new handle to the same peer, sharing its encryption state.
   ///
-  /// This allows for multiple mutable references to the same peer.
-  /// The socket is cloned using `try_clone()`, which may fail in
-  /// extreme cases if the underlying system resources are not available.
+  /// The socket is duplicated with [`UdpSocket::try_clone`], which fails if
+  /// the system is out of file descriptors.
+  pub fn try_clone(&self) -> io::Result<Self> {
+    Ok(Self {
+      socket: Arc::new(self.socket.try_clone()?),
+      key: self.key,
+      crypto: self.crypto.clone(),
+      responder: Arc::clone(&self.responder),
+      roaming: self.roaming.clone(),
+      liveness: Arc::clone(&self.liveness),
+      reliable: Arc::clone(&self.reliable),
+      fragments: Arc::clone(&self.fragments),
+      mtu: Arc::clone(&self.mtu),
+      channels: Arc::clone(&self.channels),
+      congestion: Arc::clone(&self.congestion),
+      relay: self.relay,
+    })
+  }
+
+}
+
+/// The sending half of a [`Peer`], created by [`Peer::split`].
+pub struct PeerSender {
+  peer: Peer,
+}
+
+/// The receiving half of a [`Peer`], created by [`Peer::split`].
+///
+/// Handshakes initiated by the other side are answered while receiving, as with
+/// [`Peer::recv`], and keepalives, acknowledgements and retransmissions of
+/// reliable messages are sent from here too.
+pub struct PeerReceiver {
+  peer: Peer,
+}
+
+impl PeerSender {
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    self.peer.socket()
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.peer.local_addr()
+  }
+
+  /// Returns the remote socket address, or an unspecified address if not connected.
+  pub fn remote_addr(&self) -> SocketAddr {
+    self.peer.remote_addr()
+  }
+
+  /// Sets the write timeout for send operations.
+  pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.peer.set_write_timeout(timeout)
+  }
+
+  /// See [`Peer::is_alive`].
+  pub fn is_alive(&self) -> bool {
+    self.peer.is_alive()
+  }
+
+  /// See [`Peer::channel`].
+  pub fn channel(&self, id: u16, mode: ChannelMode) -> Channel {
+    self.peer.channel(id, mode)
+  }
+
+  /// See [`Peer::mtu`].
+  pub fn mtu(&self) -> usize {
+    self.peer.mtu()
+  }
+
+  /// See [`Peer::max_payload_size`].
+  pub fn max_payload_size(&self) -> usize {
+    self.peer.max_payload_size()
+  }
+
+  /// See [`Peer::max_channel_payload_size`].
+  pub fn max_channel_payload_size(&self) -> usize {
+    self.peer.max_channel_payload_size()
+  }
+
+  /// See [`Peer::send`].
+  pub fn send(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.send(buffer)
+  }
+
+  /// See [`Peer::send_with_aad`].
+  pub fn send_with_aad(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.send_with_aad(aad, buffer)
+  }
+
+  /// See [`Peer::send_slice`].
+  pub fn send_slice(&mut self, message: &[u8], scratch: &mut [u8]) -> io::Result<()> {
+    self.peer.send_slice(message, scratch)
+  }
+
+  /// See [`Peer::send_in_place`].
+  pub fn send_in_place(&mut self, buffer: &mut [u8], len: usize) -> io::Result<()> {
+    self.peer.send_in_place(buffer, len)
+  }
+
+  /// See [`Peer::send_reliable`].
+  pub fn send_reliable(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.send_reliable(buffer)
+  }
+
+}
+
+impl PeerReceiver {
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    self.peer.socket()
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.peer.local_addr()
+  }
+
+  /// Returns the remote socket address, or an unspecified address if not connected.
+  pub fn remote_addr(&self) -> SocketAddr {
+    self.peer.remote_addr()
+  }
+
+  /// Sets the read timeout for receive operations.
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.peer.set_read_timeout(timeout)
+  }
+
+  /// See [`Peer::last_heard`].
+  pub fn last_heard(&self) -> Option<Instant> {
+    self.peer.last_heard()
+  }
+
+  /// See [`Peer::is_alive`].
+  pub fn is_alive(&self) -> bool {
+    self.peer.is_alive()
+  }
+
+  /// Returns the role this peer was set up with, or `None` if it was set up without a handshake.
+  pub fn role(&self) -> Option<Role> {
+    self.peer.role()
+  }
+
+  /// Performs a handshake like [`Peer::handshake`].
   ///
-  /// Returns a new peer with the same configuration.
-  fn clone(&self) -> Self {
-    let socket = self.socket.try_clone().expect("couldn't clone socket");
-    let crypto = self.crypto.clone();
-    Self { socket, crypto }
+  /// Handshakes go through the receiving half, since they wait for an answer.
+  /// This is how an initiator starts over with a responder that may have
+  /// restarted, after a [`DeadPeerError`] for instance.
+  pub fn handshake(&mut self) -> io::Result<()> {
+    self.peer.handshake()
+  }
+
+  /// See [`Peer::recv`].
+  pub fn recv(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.recv(buffer)
+  }
+
+  /// See [`Peer::recv_channel`].
+  pub fn recv_channel(&mut self, buffer: &mut Vec<u8>) -> io::Result<Option<u16>> {
+    self.peer.recv_channel(buffer)
+  }
+
+  /// See [`Peer::recv_with_aad`].
+  pub fn recv_with_aad(&mut self, buffer: &mut Vec<u8>, aad: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.recv_with_aad(buffer, aad)
   }
+
+  /// See [`Peer::recv_into`].
+  pub fn recv_into(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.peer.recv_into(buffer)
+  }
+
+}
+
+/// A handle for sending on one channel of a [`Peer`], created by [`Peer::channel`].
+///
+/// Handles share the peer's socket and encryption state, so they can be moved
+/// to other threads, and several handles for the same channel share its
+/// sequence numbers and retransmissions.
+pub struct Channel {
+  peer: Peer,
+  id: u16,
+  mode: ChannelMode,
 }
 
+impl Channel {
+
+  /// Returns the channel ID.
+  pub fn id(&self) -> u16 {
+    self.id
+  }
+
+  /// Returns the delivery guarantees messages are sent with.
+  pub fn mode(&self) -> ChannelMode {
+    self.mode
+  }
+
+  /// Returns the number of messages on this channel waiting to be acknowledged.
+  pub fn in_flight(&self) -> usize {
+    self.peer.channels().in_flight(self.id)
+  }
+
+  /// Encrypts and sends the contents of the buffer on this channel.
+  ///
+  /// Channel messages add 11 bytes to the overhead, for the channel ID, the
+  /// mode and a sequence number. Large messages are fragmented like with
+  /// [`Peer::send`]. On reliable channels, messages are retransmitted from
+  /// inside `recv()` like with [`Peer::send_reliable`], and an
+  /// [`io::ErrorKind::WouldBlock`] error is returned while 1024 of them are
+  /// waiting to be acknowledged.
+  pub fn send(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    self.peer.send_channel(self.id, self.mode, buffer)
+  }
+
+}
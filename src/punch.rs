@@ -0,0 +1,79 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::key::Key;
+use crate::crypto::{Crypto, CipherSuite};
+use crate::handshake::{expand, seal, open};
+use crate::message::{MessageType, Header};
+
+/// Domain separation label mixed into every punch key
+const LABEL: &[u8] = b"twopoint punch v1";
+
+/// Punch challenge size in bytes
+pub const CHALLENGE_SIZE: usize = 16;
+/// Punch and punch answer size in bytes (header + challenge + tag)
+pub const PUNCH_SIZE: usize = Header::SIZE + CHALLENGE_SIZE + Crypto::TAG_SIZE;
+
+/// Returns a fresh random challenge for a round of punches.
+pub fn challenge() -> [u8; CHALLENGE_SIZE] {
+  let mut challenge = [0u8; CHALLENGE_SIZE];
+  getrandom::getrandom(&mut challenge).expect("operating system random number generator failed");
+  challenge
+}
+
+/// Returns the punch that carries `challenge`.
+///
+/// Punches and their answers carry the challenge in the clear, and are
+/// authenticated with a key derived from the pre-shared key and the challenge,
+/// so they can be sent before there is a session:
+///
+/// ```text
+/// +--------+-----------+-----+
+/// | header | challenge | tag |
+/// +--------+-----------+-----+
+///     4         16       16
+/// ```
+///
+/// Every challenge is only punched with once, and only an answer that echoes
+/// it counts, so a recorded punch or answer can't be replayed to lure a peer
+/// to another address.
+pub fn punch(psk: Key, suite: CipherSuite, challenge: &[u8; CHALLENGE_SIZE]) -> Vec<u8> {
+  sealed(psk, suite, MessageType::Punch, challenge)
+}
+
+/// Checks a punch from the other side, returning the answer to send back if it authenticates.
+pub fn answer(psk: Key, suite: CipherSuite, packet: &[u8]) -> Option<Vec<u8>> {
+  let challenge = opened(psk, suite, MessageType::Punch, packet)?;
+  Some(sealed(psk, suite, MessageType::PunchAck, &challenge))
+}
+
+/// Checks an answer to a punch, returning the challenge it echoes if it authenticates.
+pub fn answered(psk: Key, suite: CipherSuite, packet: &[u8]) -> Option<[u8; CHALLENGE_SIZE]> {
+  opened(psk, suite, MessageType::PunchAck, packet)
+}
+
+/// Derives the key that seals a punch or an answer, a zero nonce is fine
+/// since each key only ever seals the same message.
+fn key(psk: Key, suite: CipherSuite, message_type: MessageType, challenge: &[u8; CHALLENGE_SIZE]) -> Key {
+  expand(&Hkdf::<Sha256>::new(Some(LABEL), &psk), suite, &[&[message_type as u8], challenge])
+}
+
+fn sealed(psk: Key, suite: CipherSuite, message_type: MessageType, challenge: &[u8; CHALLENGE_SIZE]) -> Vec<u8> {
+  let mut packet = Vec::with_capacity(PUNCH_SIZE);
+  packet.extend_from_slice(&Header::new(message_type).to_bytes());
+  packet.extend_from_slice(challenge);
+  let mut tag = Vec::with_capacity(Crypto::TAG_SIZE);
+  seal(suite, key(psk, suite, message_type, challenge), &packet, &mut tag);
+  packet.extend_from_slice(&tag);
+  packet
+}
+
+fn opened(psk: Key, suite: CipherSuite, message_type: MessageType, packet: &[u8]) -> Option<[u8; CHALLENGE_SIZE]> {
+  if packet.len() != PUNCH_SIZE || Header::message_type(packet) != Some(message_type) {
+    return None;
+  }
+  let (aad, tag) = packet.split_at(Header::SIZE + CHALLENGE_SIZE);
+  let challenge = <[u8; CHALLENGE_SIZE]>::try_from(&aad[Header::SIZE..]).unwrap();
+  open(suite, key(psk, suite, message_type, &challenge), aad, &mut tag.to_vec()).ok()?;
+  Some(challenge)
+}
@@ -0,0 +1,203 @@
+use std::time::{Duration, Instant};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::key::Key;
+use crate::error::InvalidKeyError;
+use crate::crypto::{CipherSuite, Cipher};
+use crate::message::Header;
+
+/// Limits on how long one key may be used before it is ratcheted forward.
+///
+/// Both peers ratchet their sending key independently once either limit is
+/// reached, the receiving side follows along as soon as it sees a message
+/// sealed with the next key. Ratcheting is one-way, so an old key cannot be
+/// recovered from a newer one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RekeyPolicy {
+  /// Maximum number of messages sealed with one key.
+  pub messages: u64,
+  /// Maximum amount of time one key is used for.
+  pub interval: Duration,
+  /// How long the previous key is still accepted after the other side moves on,
+  /// so that reordered messages already in flight are not dropped.
+  pub overlap: Duration,
+}
+
+impl RekeyPolicy {
+
+  /// Default maximum number of messages per key
+  pub const DEFAULT_MESSAGES: u64 = 1 << 24;
+  /// Default maximum time per key
+  pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(120);
+  /// Default time the previous key is still accepted for
+  pub const DEFAULT_OVERLAP: Duration = Duration::from_secs(10);
+
+}
+
+impl Default for RekeyPolicy {
+  fn default() -> Self {
+    Self {
+      messages: Self::DEFAULT_MESSAGES,
+      interval: Self::DEFAULT_INTERVAL,
+      overlap: Self::DEFAULT_OVERLAP,
+    }
+  }
+}
+
+/// One key in the ratchet chain, numbered from the key the chain started with.
+#[derive(Clone)]
+pub struct Epoch {
+  pub number: u64,
+  pub suite: CipherSuite,
+  pub key: Key,
+  pub cipher: Cipher,
+}
+
+impl Epoch {
+
+  /// Number of epochs that can be told apart on the wire, one for every value of [`Header::FLAGS_KEY_EPOCH`]
+  pub const MODULUS: u64 = Header::FLAGS_KEY_EPOCH as u64 + 1;
+
+  pub fn new(suite: CipherSuite, key: Key) -> Result<Self, InvalidKeyError> {
+    Ok(Self { number: 0, suite, key, cipher: Cipher::new(suite, &key)? })
+  }
+
+  /// Derives the epoch that follows this one.
+  pub fn next(&self) -> Self {
+    self.skip(1)
+  }
+
+  /// Derives the epoch `count` steps after this one, without setting up a cipher for those in between.
+  pub fn skip(&self, count: u64) -> Self {
+    let key = (0..count).fold(self.key, |key, _| Self::ratchet(&key));
+    self.later(count, key)
+  }
+
+  /// Returns the epoch `count` steps after this one, whose key was derived already.
+  fn later(&self, count: u64, key: Key) -> Self {
+    let cipher = Cipher::new(self.suite, &key).expect("derived key has the same length");
+    Self { number: self.number + count, suite: self.suite, key, cipher }
+  }
+
+  fn ratchet(key: &Key) -> Key {
+    let mut next = [0u8; Key::MAXIMUM_SIZE];
+    let next = &mut next[..key.len()];
+    Hkdf::<Sha256>::new(Some(b"twopoint rekey v1"), key)
+      .expand(b"next", next)
+      .expect("key length is valid for sha256");
+    Key::try_from(&*next).expect("derived key has the same length")
+  }
+
+  /// Returns the epoch number as sent on the wire, in the header flags.
+  pub fn wire(&self) -> u8 {
+    (self.number % Self::MODULUS) as u8
+  }
+
+}
+
+/// Sending half of the ratchet, moves forward according to the [`RekeyPolicy`].
+pub struct SendChain {
+  epoch: Epoch,
+  started: Instant,
+  messages: u64,
+}
+
+impl SendChain {
+
+  pub fn new(epoch: Epoch) -> Self {
+    Self { epoch, started: Instant::now(), messages: 0 }
+  }
+
+  /// Returns the epoch to seal the next message with, ratcheting first if the current one is used up.
+  pub fn next(&mut self, policy: &RekeyPolicy) -> &Epoch {
+    if self.messages >= policy.messages || self.started.elapsed() >= policy.interval {
+      self.epoch = self.epoch.next();
+      self.started = Instant::now();
+      self.messages = 0;
+    }
+    self.messages += 1;
+    &self.epoch
+  }
+
+}
+
+/// Receiving half of the ratchet, follows the other side's sending chain.
+///
+/// The sender only ratchets when it seals a message, so the receiver falls
+/// behind only if it misses every message of an epoch. It catches up over as
+/// many as [`RecvChain::MAXIMUM_AHEAD`] missed epochs at once, a receiver that
+/// falls further behind can't tell where the sender is anymore and has to
+/// handshake again.
+///
+/// Keys of the epochs ahead are derived once and kept until the chain moves
+/// past them, so that messages claiming a far-off epoch cost no more than an
+/// authentication attempt each, however many of them are forged.
+pub struct RecvChain {
+  current: Epoch,
+  previous: Option<(Epoch, Instant)>,
+  /// Keys of the epochs after the current one, as far as they were needed
+  ahead: Vec<Key>,
+}
+
+/// Key that a received message should be opened with.
+pub enum Lookup<'a> {
+  /// The current or previous key.
+  Known(&'a Epoch),
+  /// A newer key, which becomes current once a message authenticates with it.
+  Ahead(Box<Epoch>),
+}
+
+impl Lookup<'_> {
+
+  pub fn epoch(&self) -> &Epoch {
+    match self {
+      Self::Known(epoch) => epoch,
+      Self::Ahead(epoch) => epoch,
+    }
+  }
+
+}
+
+impl RecvChain {
+
+  /// How many epochs the other side may skip ahead, the rest of the wire epochs mean "previous"
+  pub const MAXIMUM_AHEAD: u64 = Epoch::MODULUS - 2;
+
+  pub fn new(epoch: Epoch) -> Self {
+    Self { current: epoch, previous: None, ahead: Vec::new() }
+  }
+
+  /// Finds the key for a message sealed in the given wire epoch.
+  ///
+  /// Returns `None` if the message belongs to a previous key that is no longer accepted.
+  pub fn lookup(&mut self, wire: u8) -> Option<Lookup<'_>> {
+    let ahead = (u64::from(wire) + Epoch::MODULUS - u64::from(self.current.wire())) % Epoch::MODULUS;
+    match ahead {
+      0 => Some(Lookup::Known(&self.current)),
+      ahead if ahead <= Self::MAXIMUM_AHEAD => {
+        while self.ahead.len() < ahead as usize {
+          let last = self.ahead.last().unwrap_or(&self.current.key);
+          self.ahead.push(Epoch::ratchet(last));
+        }
+        Some(Lookup::Ahead(Box::new(self.current.later(ahead, self.ahead[ahead as usize - 1]))))
+      }
+      _ => match &self.previous {
+        Some((epoch, expires)) if Instant::now() < *expires => Some(Lookup::Known(epoch)),
+        _ => None,
+      }
+    }
+  }
+
+  /// Makes `epoch` the current key, keeping the old one around for `overlap`.
+  ///
+  /// Only call it once a message authenticated with the key from [`RecvChain::lookup`].
+  pub fn advance(&mut self, epoch: Box<Epoch>, overlap: Duration) {
+    let passed = usize::try_from(epoch.number - self.current.number).unwrap_or(usize::MAX);
+    self.ahead.drain(..passed.min(self.ahead.len()));
+    let previous = std::mem::replace(&mut self.current, *epoch);
+    self.previous = Some((previous, Instant::now() + overlap));
+  }
+
+}
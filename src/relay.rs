@@ -0,0 +1,109 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Instant;
+
+use crate::util::{can_reconnect, MAXIMUM_DATAGRAM_SIZE};
+use crate::key::Key;
+use crate::message::{MessageType, Header};
+use crate::rendezvous::{self, Registrations, SESSION_ID_SIZE};
+
+/// Relay a [`Peer`](crate::Peer) falls back to when punching fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relay {
+  pub server: SocketAddr,
+  pub session: [u8; SESSION_ID_SIZE],
+}
+
+impl Relay {
+
+  pub fn new(server: SocketAddr, key: &Key, session_name: &str) -> Self {
+    Self { server, session: rendezvous::session_id(key, session_name) }
+  }
+
+}
+
+/// A relay server that forwards datagrams between peers that cannot reach
+/// each other directly, such as two peers behind symmetric NATs.
+///
+/// Peers register under a session ID derived from their pre-shared key, see
+/// [`Peer::set_relay`](crate::Peer::set_relay), and are introduced like with a
+/// [`RendezvousServer`](crate::RendezvousServer), which also takes the
+/// [`registration_key`](crate::registration_key) to check registrations with.
+/// From then on, every twopoint datagram from one of them is forwarded as it
+/// is to the other one, except for registrations and introductions, which
+/// are only ever exchanged with the relay itself. Registrations are taken in
+/// once each, so a recorded one can't be replayed to have datagrams forwarded
+/// to or from another address. The relay never holds the pre-shared key, so it
+/// can neither read nor forge messages.
+///
+/// Peers that go silent are forgotten after 30 seconds, so relayed peers
+/// should send keepalives, see [`KeepalivePolicy`](crate::KeepalivePolicy).
+pub struct RelayServer {
+  socket: UdpSocket,
+  registration_key: Key,
+  registrations: Registrations,
+}
+
+impl RelayServer {
+
+  /// Creates a new relay server with the given socket and registration key.
+  pub fn new(socket: UdpSocket, registration_key: Key) -> Self {
+    Self {
+      socket,
+      registration_key,
+      registrations: Registrations::new(),
+    }
+  }
+
+  /// Creates a new relay server bound to `bind_addr`.
+  pub fn bind<A: ToSocketAddrs>(bind_addr: A, registration_key: Key) -> io::Result<Self> {
+    Ok(Self::new(UdpSocket::bind(bind_addr)?, registration_key))
+  }
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    &self.socket
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.socket.local_addr().expect("couldn't get local address")
+  }
+
+  /// Relays datagrams until the socket fails.
+  pub fn serve(&mut self) -> io::Result<()> {
+    let mut buffer = vec![0u8; MAXIMUM_DATAGRAM_SIZE];
+    loop {
+      match self.serve_one(&mut buffer) {
+        Ok(()) => {}
+        // ICMP errors from peers that have gone away
+        Err(e) if can_reconnect(&e) => {}
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Receives one datagram into `buffer`, answering it if it is a
+  /// registration that authenticates and forwarding it if it comes from a
+  /// registered peer.
+  ///
+  /// Anything else, including registrations that don't authenticate, is
+  /// ignored. Datagrams larger than the buffer are cut off.
+  pub fn serve_one(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+    let (len, from) = self.socket.recv_from(buffer)?;
+    let datagram = &buffer[..len];
+    let now = Instant::now();
+    if let Some((session, timestamp)) = rendezvous::parse_registration(datagram, &self.registration_key) {
+      if let Some((first, second)) = self.registrations.register(session, timestamp, from, now) {
+        rendezvous::introduce(&self.socket, session, first, second)?;
+      }
+    } else if let Some(message_type) = Header::message_type(datagram)
+      && !matches!(message_type, MessageType::Register | MessageType::Introduction)
+      && let Some(to) = self.registrations.other(from, now)
+    {
+      self.socket.send_to(datagram, to)?;
+    }
+    Ok(())
+  }
+
+}
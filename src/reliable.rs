@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Sequence number size in bytes, at the start of every reliable message
+pub const SEQUENCE_SIZE: usize = 8;
+/// Acknowledgement size in bytes (cumulative acknowledgement + selective acknowledgement bitmap)
+pub const ACK_SIZE: usize = 16;
+
+/// Reliable delivery state for one end of a link.
+#[derive(Default)]
+pub struct Reliable {
+  pub sender: Sender,
+  pub receiver: Receiver,
+}
+
+impl Reliable {
+
+  /// How often a receiver checks for messages that another thread started
+  /// sending reliably while it was waiting
+  pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+  /// Returns when retransmissions next need to be checked on, or `None` if
+  /// reliable delivery has never been used.
+  pub fn deadline(&self, now: Instant) -> Option<Instant> {
+    match self.sender.deadline() {
+      Some(deadline) => Some(deadline.min(now + Self::POLL_INTERVAL)),
+      None if self.sender.is_active() => Some(now + Self::POLL_INTERVAL),
+      None => None,
+    }
+  }
+
+}
+
+/// Sending half of reliable delivery.
+///
+/// Every reliable message gets the next sequence number and is kept until the
+/// other side acknowledges it, being retransmitted whenever its retransmission
+/// timeout runs out. The timeout is estimated from the round-trip time as in
+/// RFC 6298, and backs off exponentially while messages keep getting lost.
+///
+/// Acknowledgements carry the next sequence number the receiver expects, every
+/// message below it has arrived, followed by a bitmap of the messages after it
+/// that have arrived out of order:
+///
+/// ```text
+/// +------------+--------+
+/// | cumulative | bitmap |
+/// +------------+--------+
+///        8          8
+/// ```
+///
+/// Bit `i` of the bitmap stands for sequence number `cumulative + 1 + i`. Once
+/// [`Sender::FAST_RETRANSMIT_THRESHOLD`] later messages are acknowledged, a
+/// missing one is retransmitted straight away instead of waiting for its timeout.
+///
+/// Messages are queued when they are pushed, and only count as sent once
+/// [`Sender::mark_sent`] is called, so that congestion control can hold them back.
+///
+/// A stream only makes sense within one session, so both sides start over
+/// together on every handshake. An acknowledgement for messages that were
+/// never sent means the other side's stream is not this one.
+pub struct Sender {
+  next: u64,
+  pending: BTreeMap<u64, Pending>,
+  rtt: RttEstimator,
+}
+
+struct Pending {
+  message: Vec<u8>,
+  /// When the message was first sent, `None` while it is queued
+  sent: Option<Instant>,
+  deadline: Instant,
+  retransmitted: bool,
+}
+
+/// What an acknowledgement taken in by [`Sender::acknowledge`] said.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acknowledgement {
+  /// Total size of the newly acknowledged messages.
+  pub bytes: usize,
+  /// Round-trip time measured from the newest acknowledged message, if any.
+  pub rtt: Option<Duration>,
+  /// Sequence numbers to retransmit early.
+  pub retransmit: Vec<u64>,
+}
+
+impl Sender {
+
+  /// Maximum number of messages waiting to be acknowledged
+  pub const MAXIMUM_IN_FLIGHT: usize = 1024;
+  /// Number of later messages that must be acknowledged before a missing one is retransmitted early
+  pub const FAST_RETRANSMIT_THRESHOLD: u64 = 3;
+
+  pub fn new() -> Self {
+    Self { next: 0, pending: BTreeMap::new(), rtt: RttEstimator::new() }
+  }
+
+  /// Returns `true` once anything has been sent reliably.
+  pub fn is_active(&self) -> bool {
+    self.next > 0
+  }
+
+  /// Returns the number of messages waiting to be acknowledged.
+  pub fn in_flight(&self) -> usize {
+    self.pending.len()
+  }
+
+  /// Returns the smoothed round-trip time, if it has been measured yet.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.rtt.srtt
+  }
+
+  /// Takes a copy of `message`, queues it and assigns it the next sequence number.
+  ///
+  /// Returns `None` if too many messages are already queued or waiting to be acknowledged.
+  pub fn push(&mut self, message: &[u8]) -> Option<u64> {
+    if self.pending.len() >= Self::MAXIMUM_IN_FLIGHT {
+      return None;
+    }
+    let sequence = self.next;
+    self.next += 1;
+    self.pending.insert(sequence, Pending {
+      message: message.to_vec(),
+      sent: None,
+      deadline: Instant::now(),
+      retransmitted: false,
+    });
+    Some(sequence)
+  }
+
+  /// Returns the sequence number and size of the oldest queued message, if any.
+  pub fn next_unsent(&self) -> Option<(u64, usize)> {
+    self.pending.iter()
+      .find(|(_, pending)| pending.sent.is_none())
+      .map(|(sequence, pending)| (*sequence, pending.message.len()))
+  }
+
+  /// Records that a queued message was sent for the first time.
+  pub fn mark_sent(&mut self, sequence: u64, now: Instant) {
+    if let Some(pending) = self.pending.get_mut(&sequence) {
+      pending.sent = Some(now);
+      pending.deadline = now + self.rtt.rto;
+    }
+  }
+
+  /// Returns the message with the given sequence number, if it is still waiting to be acknowledged.
+  pub fn message(&self, sequence: u64) -> Option<&[u8]> {
+    self.pending.get(&sequence).map(|pending| &*pending.message)
+  }
+
+  /// Returns when the oldest retransmission timeout runs out, if anything is in flight.
+  pub fn deadline(&self) -> Option<Instant> {
+    self.pending.values().filter(|pending| pending.sent.is_some()).map(|pending| pending.deadline).min()
+  }
+
+  /// Takes in an acknowledgement.
+  ///
+  /// Returns `None` if it acknowledges messages that were never sent, since
+  /// the other side is then receiving a different stream.
+  pub fn acknowledge(&mut self, ack: &[u8; ACK_SIZE]) -> Option<Acknowledgement> {
+    let (cumulative, bitmap) = ack.split_at(8);
+    let cumulative = u64::from_be_bytes(cumulative.try_into().unwrap());
+    let bitmap = u64::from_be_bytes(bitmap.try_into().unwrap());
+
+    // checked first, so that the sequence numbers below can't overflow
+    if cumulative > self.next {
+      return None;
+    }
+    // the highest bit of the bitmap stands for the newest message acknowledged
+    let newest = (bitmap != 0).then(|| cumulative + 64 - u64::from(bitmap.leading_zeros()));
+    if newest.is_some_and(|newest| newest >= self.next) {
+      return None;
+    }
+
+    let now = Instant::now();
+    let mut acknowledged = self.pending.range(..cumulative).map(|(sequence, _)| *sequence).collect::<Vec<_>>();
+    let mut highest = None;
+    for i in 0..64 {
+      if bitmap & (1 << i) != 0 {
+        let sequence = cumulative + 1 + i;
+        acknowledged.push(sequence);
+        highest = Some(sequence);
+      }
+    }
+    let mut acknowledgement = Acknowledgement::default();
+    for sequence in acknowledged {
+      // queued messages can't have been acknowledged yet
+      let Some(sent) = self.pending.get(&sequence).and_then(|pending| pending.sent) else {
+        continue;
+      };
+      let pending = self.pending.remove(&sequence).unwrap();
+      acknowledgement.bytes += pending.message.len();
+      if !pending.retransmitted {
+        // Karn's algorithm, retransmitted messages don't tell which copy was acknowledged
+        let rtt = now - sent;
+        self.rtt.sample(rtt);
+        acknowledgement.rtt = Some(rtt);
+      }
+    }
+
+    let Some(highest) = highest else {
+      return Some(acknowledgement);
+    };
+    for (sequence, pending) in self.pending.range_mut(..highest) {
+      if highest - sequence >= Self::FAST_RETRANSMIT_THRESHOLD && !pending.retransmitted && pending.sent.is_some() {
+        pending.retransmitted = true;
+        pending.deadline = now + self.rtt.rto;
+        acknowledgement.retransmit.push(*sequence);
+      }
+    }
+    Some(acknowledgement)
+  }
+
+  /// Returns the sequence numbers whose retransmission timeout has run out,
+  /// backing off the timeout if there are any.
+  pub fn expired(&mut self, now: Instant) -> Vec<u64> {
+    let expired = self.pending.iter()
+      .filter(|(_, pending)| pending.sent.is_some() && pending.deadline <= now)
+      .map(|(sequence, _)| *sequence)
+      .collect::<Vec<_>>();
+    if !expired.is_empty() {
+      self.rtt.back_off();
+      for sequence in &expired {
+        let pending = self.pending.get_mut(sequence).unwrap();
+        pending.retransmitted = true;
+        pending.deadline = now + self.rtt.rto;
+      }
+    }
+    expired
+  }
+
+}
+
+impl Default for Sender {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Round-trip time and retransmission timeout estimation, as in RFC 6298.
+struct RttEstimator {
+  srtt: Option<Duration>,
+  rttvar: Duration,
+  rto: Duration,
+}
+
+impl RttEstimator {
+
+  /// Retransmission timeout before the round-trip time has been measured
+  const INITIAL_RTO: Duration = Duration::from_secs(1);
+  /// Smallest retransmission timeout, lower than the RFC recommends since links are usually short
+  const MINIMUM_RTO: Duration = Duration::from_millis(200);
+  /// Largest retransmission timeout, even after backing off
+  const MAXIMUM_RTO: Duration = Duration::from_secs(60);
+
+  fn new() -> Self {
+    Self { srtt: None, rttvar: Duration::ZERO, rto: Self::INITIAL_RTO }
+  }
+
+  fn sample(&mut self, rtt: Duration) {
+    match self.srtt {
+      None => {
+        self.srtt = Some(rtt);
+        self.rttvar = rtt / 2;
+      }
+      Some(srtt) => {
+        self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+        self.srtt = Some((srtt * 7 + rtt) / 8);
+      }
+    }
+    let rto = self.srtt.unwrap() + self.rttvar * 4;
+    self.rto = rto.clamp(Self::MINIMUM_RTO, Self::MAXIMUM_RTO);
+  }
+
+  fn back_off(&mut self) {
+    self.rto = (self.rto * 2).min(Self::MAXIMUM_RTO);
+  }
+
+}
+
+/// Receiving half of reliable delivery.
+///
+/// Messages that arrive early are held back until the gap in front of them is
+/// filled, then handed out in order. Messages taken in with
+/// [`Receiver::receive_unordered`] are delivered right away instead, and only
+/// their sequence numbers are kept track of.
+pub struct Receiver {
+  next: u64,
+  /// Messages that arrived early, `None` for those that were already delivered out of order
+  early: BTreeMap<u64, Option<Vec<u8>>>,
+  ready: VecDeque<Vec<u8>>,
+}
+
+/// What happened to a message passed to [`Receiver::receive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+  /// The message is next in line and should be delivered right away.
+  Now,
+  /// The message was queued, to be delivered once the messages in front of it are.
+  Later,
+  /// The message was already received.
+  Dropped,
+  /// The message is further ahead than the sender could ever get, so the
+  /// other side is sending a different stream.
+  Desync,
+}
+
+impl Receiver {
+
+  /// How far ahead of the next expected message a message may be and still be held on to
+  ///
+  /// Messages acknowledged in the bitmap no longer count as in flight, so the
+  /// sender can get this far ahead while the next expected message is missing.
+  pub const WINDOW: u64 = Sender::MAXIMUM_IN_FLIGHT as u64 + 64;
+
+  pub fn new() -> Self {
+    Self { next: 0, early: BTreeMap::new(), ready: VecDeque::new() }
+  }
+
+  /// Takes in a message, copying it if it can't be delivered right away.
+  pub fn receive(&mut self, sequence: u64, message: &[u8]) -> Delivery {
+    if let Some(delivery) = self.check(sequence) {
+      return delivery;
+    }
+    if sequence != self.next {
+      self.early.insert(sequence, Some(message.to_vec()));
+      return Delivery::Later;
+    }
+    self.next += 1;
+    // messages released earlier still have to go first
+    let now = self.ready.is_empty();
+    if !now {
+      self.ready.push_back(message.to_vec());
+    }
+    self.advance();
+    if now { Delivery::Now } else { Delivery::Later }
+  }
+
+  /// Takes in a message that is delivered as soon as it arrives, never
+  /// returning [`Delivery::Later`].
+  pub fn receive_unordered(&mut self, sequence: u64) -> Delivery {
+    if let Some(delivery) = self.check(sequence) {
+      return delivery;
+    }
+    self.early.insert(sequence, None);
+    self.advance();
+    Delivery::Now
+  }
+
+  /// Returns what happens to a message that can't be taken in at all.
+  fn check(&self, sequence: u64) -> Option<Delivery> {
+    if sequence < self.next || self.early.contains_key(&sequence) {
+      Some(Delivery::Dropped)
+    } else if sequence - self.next >= Self::WINDOW {
+      Some(Delivery::Desync)
+    } else {
+      None
+    }
+  }
+
+  /// Moves past every message that is no longer missing anything in front of it.
+  fn advance(&mut self) {
+    while let Some(message) = self.early.remove(&self.next) {
+      self.ready.extend(message);
+      self.next += 1;
+    }
+  }
+
+  /// Takes the next message that is ready to be delivered.
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    self.ready.pop_front()
+  }
+
+  /// Puts back a message taken with [`Receiver::pop`] that could not be delivered after all.
+  pub fn unpop(&mut self, message: Vec<u8>) {
+    self.ready.push_front(message);
+  }
+
+  /// Returns the acknowledgement to send back for everything received so far.
+  pub fn ack(&self) -> [u8; ACK_SIZE] {
+    let mut bitmap = 0u64;
+    for sequence in self.early.range(self.next + 1..self.next + 65).map(|(sequence, _)| *sequence) {
+      bitmap |= 1 << (sequence - self.next - 1);
+    }
+    let mut ack = [0u8; ACK_SIZE];
+    ack[..8].copy_from_slice(&self.next.to_be_bytes());
+    ack[8..].copy_from_slice(&bitmap.to_be_bytes());
+    ack
+  }
+
+}
+
+impl Default for Receiver {
+  fn default() -> Self {
+    Self::new()
+  }
+}
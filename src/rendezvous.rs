@@ -0,0 +1,364 @@
+use std::io;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::util::{can_reconnect, unix_nanos};
+use crate::key::Key;
+use crate::message::{MessageType, Header};
+
+/// Domain separation label mixed into every rendezvous key
+const LABEL: &[u8] = b"twopoint rendezvous v1";
+
+/// Rendezvous session ID size in bytes
+pub const SESSION_ID_SIZE: usize = 32;
+/// Registration timestamp size in bytes
+const TIMESTAMP_SIZE: usize = 8;
+/// Registration tag size in bytes
+const TAG_SIZE: usize = 16;
+/// Encoded socket address size in bytes (family + IPv6 address + port)
+const ADDRESS_SIZE: usize = 1 + 16 + 2;
+/// Registration size in bytes (header + session ID + timestamp + tag)
+pub const REGISTRATION_SIZE: usize = Header::SIZE + SESSION_ID_SIZE + TIMESTAMP_SIZE + TAG_SIZE;
+/// Introduction size in bytes (header + session ID + address)
+pub const INTRODUCTION_SIZE: usize = Header::SIZE + SESSION_ID_SIZE + ADDRESS_SIZE;
+
+/// Time after which a peer that stopped registering is forgotten
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How far a registration's timestamp may be off from the server's clock, either way
+const REGISTRATION_WINDOW: Duration = Duration::from_secs(30);
+
+/// Derives the session ID that peers sharing `key` register under for `name`.
+///
+/// The ID can only be made up by someone who knows the key, and tells the
+/// server neither the key nor the name.
+pub fn session_id(key: &Key, name: &str) -> [u8; SESSION_ID_SIZE] {
+  let mut id = [0u8; SESSION_ID_SIZE];
+  Hkdf::<Sha256>::new(Some(LABEL), key)
+    .expand_multi_info(&[b"session", name.as_bytes()], &mut id)
+    .expect("session ID length is valid for sha256");
+  id
+}
+
+/// Derives the key that rendezvous and relay servers check registrations
+/// with, for peers sharing the pre-shared key `key`.
+///
+/// The derivation is one-way, so a server set up with the registration key
+/// can tell registrations from those peers apart from forged ones, but can
+/// neither open nor seal their messages.
+pub fn registration_key(key: &Key) -> Key {
+  let mut registration_key = [0u8; 32];
+  Hkdf::<Sha256>::new(Some(LABEL), key)
+    .expand(b"registration", &mut registration_key)
+    .expect("key length is valid for sha256");
+  Key::from(registration_key)
+}
+
+/// Returns the tag that authenticates a registration under `session` made at `timestamp`.
+fn tag(registration_key: &Key, session: &[u8; SESSION_ID_SIZE], timestamp: &[u8; TIMESTAMP_SIZE]) -> [u8; TAG_SIZE] {
+  let mut tag = [0u8; TAG_SIZE];
+  Hkdf::<Sha256>::new(Some(LABEL), registration_key)
+    .expand_multi_info(&[b"register", session, timestamp], &mut tag)
+    .expect("tag length is valid for sha256");
+  tag
+}
+
+/// Returns the registration a peer sends to the server, made now.
+///
+/// ```text
+/// +--------+------------+-----------+-----+
+/// | header | session ID | timestamp | tag |
+/// +--------+------------+-----------+-----+
+///     4         32           8        16
+/// ```
+///
+/// The timestamp is in nanoseconds since the Unix epoch, and the tag is
+/// derived from the registration key, the session ID and the timestamp.
+pub fn registration(session: &[u8; SESSION_ID_SIZE], registration_key: &Key) -> [u8; REGISTRATION_SIZE] {
+  let timestamp = unix_nanos().to_be_bytes();
+  let mut registration = [0u8; REGISTRATION_SIZE];
+  let (header, rest) = registration.split_at_mut(Header::SIZE);
+  let (session_id, rest) = rest.split_at_mut(SESSION_ID_SIZE);
+  let (time, tag_) = rest.split_at_mut(TIMESTAMP_SIZE);
+  header.copy_from_slice(&Header::new(MessageType::Register).to_bytes());
+  session_id.copy_from_slice(session);
+  time.copy_from_slice(&timestamp);
+  tag_.copy_from_slice(&tag(registration_key, session, &timestamp));
+  registration
+}
+
+/// Returns the introduction the server sends back, telling a peer where the
+/// other side of its session was seen.
+///
+/// ```text
+/// +--------+------------+--------+----+------+
+/// | header | session ID | family | ip | port |
+/// +--------+------------+--------+----+------+
+///     4         32          1      16    2
+/// ```
+pub fn introduction(session: &[u8; SESSION_ID_SIZE], addr: SocketAddr) -> [u8; INTRODUCTION_SIZE] {
+  let mut introduction = [0u8; INTRODUCTION_SIZE];
+  introduction[..Header::SIZE].copy_from_slice(&Header::new(MessageType::Introduction).to_bytes());
+  introduction[Header::SIZE..Header::SIZE + SESSION_ID_SIZE].copy_from_slice(session);
+  let address = &mut introduction[Header::SIZE + SESSION_ID_SIZE..];
+  match addr.ip() {
+    IpAddr::V4(ip) => {
+      address[0] = 4;
+      address[1..5].copy_from_slice(&ip.octets());
+    }
+    IpAddr::V6(ip) => {
+      address[0] = 6;
+      address[1..17].copy_from_slice(&ip.octets());
+    }
+  }
+  address[17..19].copy_from_slice(&addr.port().to_be_bytes());
+  introduction
+}
+
+/// Reads the session ID and timestamp from a registration, if it
+/// authenticates with the registration key and was made around now.
+pub fn parse_registration<'a>(datagram: &'a [u8], registration_key: &Key) -> Option<(&'a [u8; SESSION_ID_SIZE], u64)> {
+  if datagram.len() != REGISTRATION_SIZE || Header::message_type(datagram) != Some(MessageType::Register) {
+    return None;
+  }
+  let (session, rest) = datagram[Header::SIZE..].split_first_chunk::<SESSION_ID_SIZE>()?;
+  let (timestamp, received) = rest.split_first_chunk::<TIMESTAMP_SIZE>()?;
+  // every byte is compared, so that a forger can't tell how much of its tag was right
+  let expected = tag(registration_key, session, timestamp);
+  if expected.iter().zip(received).fold(0, |difference, (a, b)| difference | (a ^ b)) != 0 {
+    return None;
+  }
+  let timestamp = u64::from_be_bytes(*timestamp);
+  if unix_nanos().abs_diff(timestamp) > REGISTRATION_WINDOW.as_nanos() as u64 {
+    return None;
+  }
+  Some((session, timestamp))
+}
+
+/// Reads the address from an introduction for the given session.
+pub fn parse_introduction(datagram: &[u8], session: &[u8; SESSION_ID_SIZE]) -> Option<SocketAddr> {
+  if datagram.len() != INTRODUCTION_SIZE || Header::message_type(datagram) != Some(MessageType::Introduction) {
+    return None;
+  }
+  if &datagram[Header::SIZE..Header::SIZE + SESSION_ID_SIZE] != session {
+    return None;
+  }
+  let address = &datagram[Header::SIZE + SESSION_ID_SIZE..];
+  let ip = match address[0] {
+    4 => IpAddr::V4(Ipv4Addr::from(*address[1..].first_chunk::<4>()?)),
+    6 => IpAddr::V6(Ipv6Addr::from(*address[1..].first_chunk::<16>()?)),
+    _ => return None,
+  };
+  let port = u16::from_be_bytes(address[17..19].try_into().ok()?);
+  Some(SocketAddr::new(ip, port))
+}
+
+/// Peers registered with a server, at most two under each session ID.
+pub struct Registrations {
+  sessions: HashMap<[u8; SESSION_ID_SIZE], Vec<Registration>>,
+  by_addr: HashMap<SocketAddr, [u8; SESSION_ID_SIZE]>,
+  /// Timestamps of the registrations taken in under each session ID, and when they were
+  used: HashMap<[u8; SESSION_ID_SIZE], Vec<(u64, Instant)>>,
+  pruned: Instant,
+}
+
+struct Registration {
+  addr: SocketAddr,
+  seen: Instant,
+}
+
+impl Registrations {
+
+  pub fn new() -> Self {
+    Self {
+      sessions: HashMap::new(),
+      by_addr: HashMap::new(),
+      used: HashMap::new(),
+      pruned: Instant::now(),
+    }
+  }
+
+  /// Registers `from` under `session` with a registration made at
+  /// `timestamp`, returning the addresses of both peers once two have registered.
+  ///
+  /// Every registration is only taken in once, so that a recorded one can't be
+  /// replayed from another address. A third peer registering under the same
+  /// ID replaces the one that registered least recently.
+  pub fn register(&mut self, session: &[u8; SESSION_ID_SIZE], timestamp: u64, from: SocketAddr, now: Instant) -> Option<(SocketAddr, SocketAddr)> {
+    self.prune(now);
+    let used = self.used.entry(*session).or_default();
+    if used.iter().any(|(used, _)| *used == timestamp) {
+      return None;
+    }
+    used.push((timestamp, now));
+    if let Some(previous) = self.by_addr.get(&from).copied() {
+      self.remove(&previous, from);
+    }
+    // a peer that went silent is not introduced to a new one
+    let expired: Vec<_> = self.sessions.get(session).into_iter().flatten()
+      .filter(|registration| now >= registration.seen + SESSION_TIMEOUT)
+      .map(|registration| registration.addr)
+      .collect();
+    for addr in expired {
+      self.remove(session, addr);
+    }
+    let registrations = self.sessions.entry(*session).or_default();
+    registrations.push(Registration { addr: from, seen: now });
+    self.by_addr.insert(from, *session);
+    if registrations.len() > 2 {
+      let oldest = registrations[0].addr;
+      self.remove(session, oldest);
+    }
+    match self.sessions.get(session)?.as_slice() {
+      [first, second] => Some((first.addr, second.addr)),
+      _ => None,
+    }
+  }
+
+  /// Returns the address of the other peer registered under the same session
+  /// as `from`, counting this as hearing from `from`.
+  pub fn other(&mut self, from: SocketAddr, now: Instant) -> Option<SocketAddr> {
+    let session = self.by_addr.get(&from)?;
+    let registrations = self.sessions.get_mut(session)?;
+    let mut other = None;
+    for registration in registrations {
+      if registration.addr == from {
+        registration.seen = now;
+      } else if now < registration.seen + SESSION_TIMEOUT {
+        other = Some(registration.addr);
+      }
+    }
+    other
+  }
+
+  fn remove(&mut self, session: &[u8; SESSION_ID_SIZE], addr: SocketAddr) {
+    self.by_addr.remove(&addr);
+    if let Some(registrations) = self.sessions.get_mut(session) {
+      registrations.retain(|registration| registration.addr != addr);
+      if registrations.is_empty() {
+        self.sessions.remove(session);
+      }
+    }
+  }
+
+  /// Forgets peers nobody has heard from for a while.
+  fn prune(&mut self, now: Instant) {
+    if now < self.pruned + SESSION_TIMEOUT {
+      return;
+    }
+    self.pruned = now;
+    // a timestamp may be ahead of the server's clock by as much as it may be behind
+    self.used.retain(|_, used| {
+      used.retain(|(_, taken)| now <= *taken + 2 * REGISTRATION_WINDOW);
+      !used.is_empty()
+    });
+    let by_addr = &mut self.by_addr;
+    self.sessions.retain(|_, registrations| {
+      registrations.retain(|registration| {
+        let alive = now < registration.seen + SESSION_TIMEOUT;
+        if !alive {
+          by_addr.remove(&registration.addr);
+        }
+        alive
+      });
+      !registrations.is_empty()
+    });
+  }
+
+}
+
+impl Default for Registrations {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// A rendezvous server that introduces peers behind NATs to each other.
+///
+/// Peers register with [`Peer::rendezvous`](crate::Peer::rendezvous) under a
+/// session ID derived from their pre-shared key and a shared session name.
+/// Once two peers have registered under the same ID, the server tells each
+/// of them the address it saw the other one at, which is usually the public
+/// address of its NAT, so that they can [`punch`](crate::Peer::punch) through.
+///
+/// The server is set up with the [`registration_key`] of the peers'
+/// pre-shared key, and ignores registrations that don't authenticate with it.
+/// It never learns the pre-shared key itself. Every registration carries a
+/// timestamp, has to be made within 30 seconds of the server's clock and is
+/// only taken in once, so recorded registrations can't be replayed to take a
+/// peer's place.
+///
+/// Peers that stop registering are forgotten after 30 seconds. A third peer
+/// registering under the same ID replaces the one that registered least recently.
+pub struct RendezvousServer {
+  socket: UdpSocket,
+  registration_key: Key,
+  registrations: Registrations,
+}
+
+impl RendezvousServer {
+
+  /// Creates a new rendezvous server with the given socket and registration key, see [`registration_key`].
+  pub fn new(socket: UdpSocket, registration_key: Key) -> Self {
+    Self {
+      socket,
+      registration_key,
+      registrations: Registrations::new(),
+    }
+  }
+
+  /// Creates a new rendezvous server bound to `bind_addr`.
+  pub fn bind<A: ToSocketAddrs>(bind_addr: A, registration_key: Key) -> io::Result<Self> {
+    Ok(Self::new(UdpSocket::bind(bind_addr)?, registration_key))
+  }
+
+  /// Returns a reference to the underlying UDP socket.
+  pub fn socket(&self) -> &UdpSocket {
+    &self.socket
+  }
+
+  /// Returns the local socket address.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.socket.local_addr().expect("couldn't get local address")
+  }
+
+  /// Serves registrations until the socket fails.
+  pub fn serve(&mut self) -> io::Result<()> {
+    loop {
+      match self.serve_one() {
+        Ok(()) => {}
+        // ICMP errors from peers that have gone away since they were introduced
+        Err(e) if can_reconnect(&e) => {}
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Receives one datagram, answering it if it is a registration that authenticates.
+  ///
+  /// Anything else is ignored.
+  pub fn serve_one(&mut self) -> io::Result<()> {
+    let mut buffer = [0u8; REGISTRATION_SIZE + 1];
+    let (len, from) = self.socket.recv_from(&mut buffer)?;
+    let Some((session, timestamp)) = parse_registration(&buffer[..len], &self.registration_key) else {
+      return Ok(());
+    };
+    if let Some((first, second)) = self.registrations.register(session, timestamp, from, Instant::now()) {
+      introduce(&self.socket, session, first, second)?;
+    }
+    Ok(())
+  }
+
+}
+
+/// Tells two peers registered under `session` about each other.
+///
+/// Both sides are told every time, in case an earlier introduction was lost.
+pub fn introduce(socket: &UdpSocket, session: &[u8; SESSION_ID_SIZE], first: SocketAddr, second: SocketAddr) -> io::Result<()> {
+  socket.send_to(&introduction(session, second), first)?;
+  socket.send_to(&introduction(session, first), second)?;
+  Ok(())
+}
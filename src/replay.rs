@@ -0,0 +1,82 @@
+/// Sliding-window filter for rejecting duplicated or replayed sequence numbers.
+///
+/// Tracks the highest sequence number accepted so far plus a bitmap of the
+/// sequence numbers just below it, in the style of the IPsec and WireGuard
+/// anti-replay windows (RFC 6479). Anything older than the window is rejected
+/// outright, so moderate reordering is tolerated but stale packets are not.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+  highest: Option<u64>,
+  bitmap: [u64; Self::WORDS],
+}
+
+impl ReplayWindow {
+
+  /// Number of 64-bit words in the bitmap ring
+  const WORDS: usize = 32;
+
+  /// Number of sequence numbers below the highest one that are still tracked
+  pub const SIZE: u64 = (Self::WORDS as u64 - 1) * 64;
+
+  pub const fn new() -> Self {
+    Self { highest: None, bitmap: [0; Self::WORDS] }
+  }
+
+  /// Returns `true` if `sequence` has not been seen and is not too old.
+  ///
+  /// This does not modify the window, use [`ReplayWindow::update`] once the
+  /// message carrying `sequence` has been authenticated.
+  pub fn check(&self, sequence: u64) -> bool {
+    let Some(highest) = self.highest else {
+      return true;
+    };
+    if sequence > highest {
+      return true;
+    }
+    if highest - sequence >= Self::SIZE {
+      return false;
+    }
+    let (word, bit) = Self::position(sequence);
+    self.bitmap[word] & bit == 0
+  }
+
+  /// Marks `sequence` as seen, sliding the window forward if necessary.
+  ///
+  /// Returns `false` if the sequence number was rejected by [`ReplayWindow::check`].
+  pub fn update(&mut self, sequence: u64) -> bool {
+    if !self.check(sequence) {
+      return false;
+    }
+
+    match self.highest {
+      Some(highest) if sequence <= highest => {}
+      Some(highest) => {
+        // clear every word that the window slides over
+        let current = highest >> 6;
+        let steps = ((sequence >> 6) - current).min(Self::WORDS as u64);
+        for i in 1..=steps {
+          self.bitmap[((current + i) % Self::WORDS as u64) as usize] = 0;
+        }
+        self.highest = Some(sequence);
+      }
+      None => {
+        self.highest = Some(sequence);
+      }
+    }
+
+    let (word, bit) = Self::position(sequence);
+    self.bitmap[word] |= bit;
+    true
+  }
+
+  const fn position(sequence: u64) -> (usize, u64) {
+    (((sequence >> 6) % Self::WORDS as u64) as usize, 1 << (sequence & 63))
+  }
+
+}
+
+impl Default for ReplayWindow {
+  fn default() -> Self {
+    Self::new()
+  }
+}
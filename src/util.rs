@@ -1,5 +1,8 @@
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{ReplayError, DeadPeerError, NewSessionError, MessageTooLargeError};
 
 /// Returns an unspecified address with the same IP version as the input.
 pub const fn to_unspecified(addr: SocketAddr) -> SocketAddr {
@@ -17,6 +20,76 @@ pub const fn is_unspecified(addr: SocketAddr) -> bool {
   }
 }
 
+/// Disconnects a UDP socket, so that it receives from any address again.
+///
+/// The standard library can only do this by connecting to an unspecified
+/// address, which Linux takes to mean the loopback address, tying the socket's
+/// source address to it so that nothing can be sent anywhere else. There the
+/// socket is disconnected with an `AF_UNSPEC` address instead, and bound to
+/// its port again, since Linux lets go of ports the socket was not explicitly
+/// bound to when it is disconnected.
+#[cfg(target_os = "linux")]
+pub(crate) fn disconnect_socket(socket: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+  let fd = socket.as_raw_fd();
+  let (before, _) = socket_name(fd)?;
+  // SAFETY: sockaddr is plain old data, all zeroes is a valid value
+  let mut unspecified: libc::sockaddr = unsafe { std::mem::zeroed() };
+  unspecified.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+  // SAFETY: the socket is valid for as long as it is borrowed, and the address is a valid sockaddr
+  if unsafe { libc::connect(fd, &unspecified, size_of::<libc::sockaddr>() as libc::socklen_t) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let (mut after, len) = socket_name(fd)?;
+  match (socket_port(&before), socket_port(&after)) {
+    (Some(port), Some(0)) if port != 0 => {
+      set_socket_port(&mut after, port);
+      // SAFETY: the socket is valid for as long as it is borrowed, and the address came from getsockname
+      if unsafe { libc::bind(fd, &after as *const libc::sockaddr_storage as *const libc::sockaddr, len) } != 0 {
+        return Err(io::Error::last_os_error());
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn disconnect_socket(socket: &std::net::UdpSocket) -> io::Result<()> {
+  socket.connect(to_unspecified(socket.local_addr()?))
+}
+
+#[cfg(target_os = "linux")]
+fn socket_name(fd: std::os::fd::RawFd) -> io::Result<(libc::sockaddr_storage, libc::socklen_t)> {
+  // SAFETY: sockaddr_storage is plain old data, all zeroes is a valid value
+  let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  let mut len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+  // SAFETY: the address and its length describe a buffer large enough for any socket address
+  if unsafe { libc::getsockname(fd, &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok((addr, len))
+}
+
+#[cfg(target_os = "linux")]
+fn socket_port(addr: &libc::sockaddr_storage) -> Option<u16> {
+  // SAFETY: the family says which kind of address is stored, and sockaddr_storage is large enough for either
+  match i32::from(addr.ss_family) {
+    libc::AF_INET => Some(u16::from_be(unsafe { (*(addr as *const _ as *const libc::sockaddr_in)).sin_port })),
+    libc::AF_INET6 => Some(u16::from_be(unsafe { (*(addr as *const _ as *const libc::sockaddr_in6)).sin6_port })),
+    _ => None,
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_port(addr: &mut libc::sockaddr_storage, port: u16) {
+  // SAFETY: the family says which kind of address is stored, and sockaddr_storage is large enough for either
+  match i32::from(addr.ss_family) {
+    libc::AF_INET => unsafe { (*(addr as *mut _ as *mut libc::sockaddr_in)).sin_port = port.to_be() },
+    libc::AF_INET6 => unsafe { (*(addr as *mut _ as *mut libc::sockaddr_in6)).sin6_port = port.to_be() },
+    _ => {}
+  }
+}
+
 /// Returns `true` if the I/O error indicates a retryable condition.
 ///
 /// This includes timeouts, interruptions, and would-block errors.
@@ -25,6 +98,7 @@ pub const fn is_unspecified(addr: SocketAddr) -> bool {
 /// - [`std::io::ErrorKind::WouldBlock`]
 /// - [`std::io::ErrorKind::TimedOut`]
 /// - [`std::io::ErrorKind::Interrupted`]
+#[allow(clippy::match_like_matches_macro)]
 pub fn can_retry(e: &io::Error) -> bool {
   match e.kind() {
     io::ErrorKind::WouldBlock => true,
@@ -54,6 +128,7 @@ pub fn can_retry(e: &io::Error) -> bool {
 /// - [`std::io::ErrorKind::NetworkUnreachable`]
 /// - [`std::io::ErrorKind::BrokenPipe`]
 /// - [`std::io::ErrorKind::UnexpectedEof`]
+#[allow(clippy::match_like_matches_macro)]
 pub fn can_reconnect(e: &io::Error) -> bool {
   if can_retry(e) {
     return true;
@@ -73,3 +148,59 @@ pub fn can_reconnect(e: &io::Error) -> bool {
     _ => false,
   }
 }
+
+/// Returns `true` if the I/O error was caused by a duplicated or replayed message.
+///
+/// Replayed messages can be safely ignored, the next call to `recv()` will
+/// continue with the following message.
+pub fn is_replay(e: &io::Error) -> bool {
+  e.get_ref().is_some_and(|inner| inner.is::<ReplayError>())
+}
+
+/// Returns `true` if the I/O error was caused by the other side going silent.
+///
+/// See [`KeepalivePolicy`](crate::KeepalivePolicy). This is a good moment to
+/// reconnect, or to handshake again with a peer that may have restarted.
+pub fn is_dead_peer(e: &io::Error) -> bool {
+  e.get_ref().is_some_and(|inner| inner.is::<DeadPeerError>())
+}
+
+/// Returns `true` if the I/O error was caused by the other side starting a new session.
+///
+/// See [`NewSessionError`]. Anything kept in step with the other side over
+/// reliable messages or channels has to start over.
+pub fn is_new_session(e: &io::Error) -> bool {
+  e.get_ref().is_some_and(|inner| inner.is::<NewSessionError>())
+}
+
+/// Returns `true` if the I/O error was caused by a message that is too large to be sent.
+///
+/// See [`MessageTooLargeError`] for the largest message that could have been sent.
+pub fn is_message_too_large(e: &io::Error) -> bool {
+  e.get_ref().is_some_and(|inner| inner.is::<MessageTooLargeError>())
+}
+
+/// Returns a random connection ID from the operating system's random number generator.
+pub fn random_connection_id() -> u64 {
+  let mut bytes = [0u8; 8];
+  getrandom::getrandom(&mut bytes).expect("operating system random number generator failed");
+  u64::from_ne_bytes(bytes)
+}
+
+/// Largest datagram that can arrive, the largest UDP payload
+pub(crate) const MAXIMUM_DATAGRAM_SIZE: usize = 65535;
+
+/// Grows the buffer to fit any datagram, for receivers that don't know how large the other side's may be.
+pub(crate) fn reserve_datagram(buffer: &mut Vec<u8>) {
+  if buffer.len() < MAXIMUM_DATAGRAM_SIZE {
+    buffer.resize(MAXIMUM_DATAGRAM_SIZE, 0);
+  }
+}
+
+/// Returns the current time in nanoseconds since the Unix epoch, or zero if the clock is broken.
+pub(crate) fn unix_nanos() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_nanos() as u64)
+    .unwrap_or(0)
+}
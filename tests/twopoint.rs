@@ -0,0 +1,241 @@
+//! End-to-end tests of the `twopoint` tool, running both sides of a link as
+//! separate processes over loopback.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEY: &str = "5adf5e4a8a779d4cd7985a881b270bcf";
+
+/// How long anything in a test may take before it counts as stuck
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running `twopoint` process, killed when dropped.
+struct Process(Child);
+
+impl Drop for Process {
+  fn drop(&mut self) {
+    let _ = self.0.kill();
+    let _ = self.0.wait();
+  }
+}
+
+impl Process {
+
+  /// Waits for the process to exit, returning whether it succeeded.
+  fn wait(&mut self) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+      if let Some(status) = self.0.try_wait().unwrap() {
+        return status.success();
+      }
+      assert!(Instant::now() < deadline, "twopoint did not exit");
+      thread::sleep(Duration::from_millis(20));
+    }
+  }
+
+}
+
+/// Starts `twopoint` with the given arguments, its standard input and output connected to the given pipes or files.
+fn spawn(args: &[&str], stdin: Stdio, stdout: Stdio) -> Process {
+  let child = Command::new(env!("CARGO_BIN_EXE_twopoint"))
+    .args(args)
+    .env("TWOPOINT_KEY", KEY)
+    .stdin(stdin)
+    .stdout(stdout)
+    .stderr(Stdio::null())
+    .spawn()
+    .expect("failed to start twopoint");
+  Process(child)
+}
+
+fn twopoint(args: &[&str]) -> Process {
+  spawn(args, Stdio::null(), Stdio::null())
+}
+
+/// Starts `twopoint listen` or `twopoint connect` with piped standard input and output.
+fn pipe(args: &[&str]) -> (Process, ChildStdin, ChildStdout) {
+  let mut process = spawn(args, Stdio::piped(), Stdio::piped());
+  let (stdin, stdout) = (process.0.stdin.take().unwrap(), process.0.stdout.take().unwrap());
+  (process, stdin, stdout)
+}
+
+/// Reads everything from a pipe on a thread of its own, so that both sides can write meanwhile.
+fn read_all(mut stdout: ChildStdout) -> thread::JoinHandle<Vec<u8>> {
+  thread::spawn(move || {
+    let mut output = Vec::new();
+    stdout.read_to_end(&mut output).expect("failed to read");
+    output
+  })
+}
+
+/// Returns a loopback address with a port that was free a moment ago.
+fn free_udp_addr() -> SocketAddr {
+  UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn free_tcp_addr() -> SocketAddr {
+  TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Starts a TCP server that echoes everything back, and reports every connection that ends.
+fn echo_server() -> (SocketAddr, mpsc::Receiver<()>) {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let (ended, ends) = mpsc::channel();
+  thread::spawn(move || {
+    for connection in listener.incoming() {
+      let Ok(mut connection) = connection else {
+        continue;
+      };
+      let ended = ended.clone();
+      thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+          match connection.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => {
+              if connection.write_all(&buffer[..len]).is_err() {
+                break;
+              }
+            }
+          }
+        }
+        let _ = connection.shutdown(Shutdown::Write);
+        let _ = ended.send(());
+      });
+    }
+  });
+  (addr, ends)
+}
+
+/// Connects to a forwarded port, waiting for the forwarding side to come up.
+fn connect(addr: SocketAddr) -> TcpStream {
+  let deadline = Instant::now() + TIMEOUT;
+  loop {
+    match TcpStream::connect(addr) {
+      Ok(connection) => {
+        connection.set_read_timeout(Some(TIMEOUT)).unwrap();
+        return connection;
+      }
+      Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+      Err(e) => panic!("failed to connect to {addr}: {e}"),
+    }
+  }
+}
+
+/// Sends a message through a forwarded port and checks that it comes back.
+fn echo(connection: &mut TcpStream, message: &[u8]) {
+  connection.write_all(message).expect("failed to write");
+  let mut echoed = vec![0u8; message.len()];
+  connection.read_exact(&mut echoed).expect("message did not come back");
+  assert_eq!(echoed, message);
+}
+
+#[test]
+fn test_forward_tcp_restart() {
+  let (target, ends) = echo_server();
+  let (link, listen) = (free_udp_addr(), free_tcp_addr());
+  let (target, link, listen) = (target.to_string(), link.to_string(), listen.to_string());
+  let _target_side = twopoint(&["forward-tcp", "--to", &target, "--bind", &link]);
+  let listen_side = || twopoint(&["forward-tcp", "--listen", &listen, "--via", &link, "--bind", "127.0.0.1:0"]);
+
+  let first = listen_side();
+  let mut before = connect(listen.parse().unwrap());
+  echo(&mut before, b"before the restart");
+
+  // the restarted side starts a new session, and the connection the old one carried is reset on the other side too
+  drop(first);
+  let _second = listen_side();
+  ends.recv_timeout(TIMEOUT).expect("connection of the old session was not reset");
+
+  for i in 0..3 {
+    let mut after = connect(listen.parse().unwrap());
+    echo(&mut after, format!("after the restart, connection {i}").as_bytes());
+    after.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    after.read_to_end(&mut rest).expect("connection was not closed");
+    assert!(rest.is_empty());
+  }
+}
+
+#[test]
+fn test_forward_tcp_restart_target() {
+  let (target, _ends) = echo_server();
+  let (link, listen) = (free_udp_addr(), free_tcp_addr());
+  let (target, link, listen) = (target.to_string(), link.to_string(), listen.to_string());
+  let target_side = || twopoint(&["forward-tcp", "--to", &target, "--bind", &link]);
+  let _listen_side = twopoint(&["forward-tcp", "--listen", &listen, "--via", &link, "--bind", "127.0.0.1:0"]);
+
+  let first = target_side();
+  let mut before = connect(listen.parse().unwrap());
+  echo(&mut before, b"before the restart");
+
+  // the listen side handshakes again once the other side has been silent for a while, which resets the connection
+  drop(first);
+  let _second = target_side();
+  before.set_read_timeout(Some(Duration::from_secs(60))).unwrap();
+  let mut rest = Vec::new();
+  let _ = before.read_to_end(&mut rest);
+  assert!(rest.is_empty());
+
+  let mut after = connect(listen.parse().unwrap());
+  echo(&mut after, b"after the restart");
+}
+
+#[test]
+fn test_pipe() {
+  let link = free_udp_addr().to_string();
+  let (mut listen, mut listen_in, listen_out) = pipe(&["listen", "--bind", &link]);
+  let (mut connect, mut connect_in, connect_out) = pipe(&["connect", &link]);
+  let (listen_out, connect_out) = (read_all(listen_out), read_all(connect_out));
+
+  // both directions are carried, more than a datagram at a time, and each side exits once both have ended
+  let upstream = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+  connect_in.write_all(&upstream).unwrap();
+  drop(connect_in);
+  listen_in.write_all(b"downstream").unwrap();
+  drop(listen_in);
+  assert!(listen.wait(), "listen side failed");
+  assert!(connect.wait(), "connect side failed");
+  assert_eq!(listen_out.join().unwrap(), upstream);
+  assert_eq!(connect_out.join().unwrap(), b"downstream");
+}
+
+#[test]
+fn test_ping_unanswered() {
+  // nobody is there, so the ping gives up about when its pings would have been done
+  let (mut ping, _ping_in, ping_out) = pipe(&["ping", &free_udp_addr().to_string(), "--count", "1"]);
+  let ping_out = read_all(ping_out);
+  assert!(!ping.wait(), "ping without an answer succeeded");
+  let output = String::from_utf8(ping_out.join().unwrap()).unwrap();
+  assert!(output.contains("1 sent, 0 received, 100% lost"), "no loss summary in {output:?}");
+}
+
+#[test]
+fn test_ping_before_pipe() {
+  let link = free_udp_addr().to_string();
+  let (mut listen, mut listen_in, mut listen_out) = pipe(&["listen", "--bind", &link]);
+  assert!(twopoint(&["ping", &link, "--count", "2"]).wait(), "ping was not answered");
+
+  // the pipe that connects after the ping takes the link over, and works as if the ping never happened
+  let (mut connect, mut connect_in, connect_out) = pipe(&["connect", &link]);
+  connect_in.write_all(b"upstream").unwrap();
+  drop(connect_in);
+  let (read, upstream) = mpsc::channel();
+  thread::spawn(move || {
+    let mut upstream = [0u8; 8];
+    let _ = read.send(listen_out.read_exact(&mut upstream).map(|()| upstream));
+  });
+  let upstream = upstream.recv_timeout(TIMEOUT).expect("nothing came through").expect("listen side ended early");
+  assert_eq!(&upstream, b"upstream");
+  listen_in.write_all(b"downstream").unwrap();
+  drop(listen_in);
+  let connect_out = read_all(connect_out);
+  assert!(listen.wait(), "listen side failed");
+  assert!(connect.wait(), "connect side failed");
+  assert_eq!(connect_out.join().unwrap(), b"downstream");
+}